@@ -0,0 +1,303 @@
+//! Which endpoints may call each service, and each of its opcodes.
+//!
+//! A service is open to every endpoint unless it has [`AccessRule`]s, from
+//! [`Service::access_rules`] or given by the platform with [`Restricted`], which only narrows
+//! down the rules of the service it wraps. Requests from
+//! endpoints the rules do not allow are answered with `Denied` by the
+//! [`ServiceNode`](crate::ServiceNode) without reaching the service, and logged in an audit log
+//! the Debug service returns to the OS.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use log::warn;
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::Uuid;
+
+use crate::{Result, Service};
+
+/// Denied requests kept in the audit log, older ones are dropped.
+pub const AUDIT_LOG_SIZE: usize = 16;
+
+/// Endpoint id of the normal world, as Hafnium numbers it when it runs without a hypervisor.
+pub const NORMAL_WORLD: u16 = 0x0;
+
+/// An endpoint allowed by an [`AccessRule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caller {
+    /// The FF-A endpoint with this id.
+    Endpoint(u16),
+    /// The partition with this UUID, as resolved by the [`PartitionDirectory`].
+    Partition(Uuid),
+}
+
+/// The endpoints allowed to call some opcodes of a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessRule {
+    /// Opcodes the rule applies to, all those not named by another rule if empty.
+    pub opcodes: &'static [u8],
+    pub callers: &'static [Caller],
+}
+
+/// Whether `rules` allow the endpoint `source_id` to call `opcode`.
+///
+/// The rules naming `opcode` apply, or failing that the rules for all opcodes. An opcode no rule
+/// applies to is open to every endpoint.
+pub fn is_allowed(rules: &[AccessRule], opcode: u8, source_id: u16) -> bool {
+    let named = rules.iter().any(|rule| rule.opcodes.contains(&opcode));
+    let mut applying = rules
+        .iter()
+        .filter(|rule| match named {
+            true => rule.opcodes.contains(&opcode),
+            false => rule.opcodes.is_empty(),
+        })
+        .peekable();
+    if applying.peek().is_none() {
+        return true;
+    }
+
+    let partition = critical_section::with(|cs| *PARTITIONS.borrow_ref(cs)).and_then(|d| d.partition_uuid(source_id));
+    applying.flat_map(|rule| rule.callers).any(|caller| match caller {
+        Caller::Endpoint(id) => *id == source_id,
+        Caller::Partition(uuid) => partition == Some(*uuid),
+    })
+}
+
+/// The `Denied` response to `msg` for `service`, if `rules` do not allow it, once audited.
+///
+/// The response is counted as failed in the [`stats`](crate::stats) like any other.
+pub(crate) fn deny(rules: &[AccessRule], service: Uuid, msg: &MsgSendDirectReq2) -> Option<MsgSendDirectResp2> {
+    let opcode = msg.u8_at(0);
+    if is_allowed(rules, opcode, msg.source_id()) {
+        return None;
+    }
+
+    audit_denied(msg.source_id(), service, opcode);
+    let status = ErrorCode::Denied as i64;
+    Some(MsgSendDirectResp2::from_req_with_payload(
+        msg,
+        RegisterPayload::from_iter(status.to_le_bytes()),
+    ))
+}
+
+/// Maps endpoint ids to partition UUIDs, for rules naming partitions.
+pub trait PartitionDirectory: Sync {
+    fn partition_uuid(&self, endpoint_id: u16) -> Option<Uuid>;
+}
+
+static PARTITIONS: Mutex<RefCell<Option<&'static dyn PartitionDirectory>>> = Mutex::new(RefCell::new(None));
+
+/// Resolve the partitions named by access rules with `directory`. Until then they match no
+/// endpoint.
+pub fn set_partition_directory(directory: &'static dyn PartitionDirectory) {
+    critical_section::with(|cs| PARTITIONS.replace(cs, Some(directory)));
+}
+
+/// A `service` open to the endpoints `rules` allow, among those its own rules allow.
+pub struct Restricted<S> {
+    service: S,
+    rules: &'static [AccessRule],
+}
+
+impl<S: Service> Restricted<S> {
+    pub fn new(service: S, rules: &'static [AccessRule]) -> Self {
+        Self { service, rules }
+    }
+}
+
+impl<S: Service> Service for Restricted<S> {
+    fn service_name(&self) -> &'static str {
+        self.service.service_name()
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        self.service.service_uuid()
+    }
+
+    fn access_rules(&self) -> &[AccessRule] {
+        self.rules
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        match deny(self.service.access_rules(), self.service.service_uuid(), &msg) {
+            Some(denied) => Ok(denied),
+            None => self.service.ffa_msg_send_direct_req2(msg).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub source_id: u16,
+    pub service: Uuid,
+    pub opcode: u8,
+}
+
+struct AuditLog {
+    entries: heapless::Deque<AuditEntry, AUDIT_LOG_SIZE>,
+    denied: u64,
+}
+
+impl AuditLog {
+    const fn new() -> Self {
+        Self {
+            entries: heapless::Deque::new(),
+            denied: 0,
+        }
+    }
+
+    fn record(&mut self, entry: AuditEntry) {
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        let _ = self.entries.push_back(entry);
+        self.denied += 1;
+    }
+}
+
+static AUDIT_LOG: Mutex<RefCell<AuditLog>> = Mutex::new(RefCell::new(AuditLog::new()));
+
+/// Log the request of `source_id` to `opcode` of `service`, denied.
+pub fn audit_denied(source_id: u16, service: Uuid, opcode: u8) {
+    warn!(
+        "Denied opcode {:#x} of {} to endpoint {:#x}",
+        opcode, service, source_id
+    );
+    let entry = AuditEntry {
+        source_id,
+        service,
+        opcode,
+    };
+    critical_section::with(|cs| AUDIT_LOG.borrow_ref_mut(cs).record(entry));
+}
+
+/// The `index`th most recent denied request still kept.
+pub fn audit_entry(index: usize) -> Option<AuditEntry> {
+    critical_section::with(|cs| AUDIT_LOG.borrow_ref(cs).entries.iter().rev().nth(index).copied())
+}
+
+/// Requests denied since boot, including those no longer kept.
+pub fn denied_count() -> u64 {
+    critical_section::with(|cs| AUDIT_LOG.borrow_ref(cs).denied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::uuid;
+
+    const OS: u16 = 0x0;
+    const OTHER_SP: u16 = 0x8003;
+
+    static RULES: [AccessRule; 2] = [
+        AccessRule {
+            opcodes: &[0x4, 0x5],
+            callers: &[Caller::Endpoint(OS)],
+        },
+        AccessRule {
+            opcodes: &[],
+            callers: &[Caller::Endpoint(OS), Caller::Endpoint(OTHER_SP)],
+        },
+    ];
+
+    #[test]
+    fn test_rules_per_opcode() {
+        assert!(is_allowed(&RULES, 0x4, OS));
+        assert!(!is_allowed(&RULES, 0x4, OTHER_SP));
+        assert!(is_allowed(&RULES, 0x1, OTHER_SP));
+        assert!(!is_allowed(&RULES, 0x1, 0x8004));
+        assert!(is_allowed(&RULES[..1], 0x1, 0x8004));
+        assert!(is_allowed(&[], 0x4, 0x8004));
+    }
+
+    struct Directory;
+
+    impl PartitionDirectory for Directory {
+        fn partition_uuid(&self, endpoint_id: u16) -> Option<Uuid> {
+            (endpoint_id == OTHER_SP).then_some(uuid!("25cb5207-ac36-427d-aaef-3aa78877d27e"))
+        }
+    }
+
+    #[test]
+    fn test_denied_before_dispatch() {
+        use crate::test_support::Echo;
+        use crate::{ServiceNode, ServiceNodeHandler, ServiceNodeNone};
+
+        static PARTITION_RULES: [AccessRule; 1] = [AccessRule {
+            opcodes: &[],
+            callers: &[Caller::Partition(uuid!("25cb5207-ac36-427d-aaef-3aa78877d27e"))],
+        }];
+        let _lock = crate::test_support::AUDIT_LOG_LOCK.lock();
+        set_partition_directory(&Directory);
+        let mut node = ServiceNode::new(Restricted::new(Echo, &PARTITION_RULES), ServiceNodeNone);
+        let request = |source_id| {
+            let payload = RegisterPayload::from_iter([0x1]);
+            MsgSendDirectReq2::new(source_id, 0x8002, Echo::UUID, payload)
+        };
+
+        let rsp = embassy_futures::block_on(node.handle(request(OTHER_SP))).unwrap();
+        assert_eq!(rsp.u64_at(0), 0x1);
+        let denied = denied_count();
+        let rsp = embassy_futures::block_on(node.handle(request(OS))).unwrap();
+        assert_eq!(rsp.u64_at(0) as i64, odp_ffa::ErrorCode::Denied as i64);
+        assert_eq!(denied_count(), denied + 1);
+        assert_eq!(audit_entry(0).map(|entry| entry.source_id), Some(OS));
+    }
+
+    #[test]
+    fn test_restricted_keeps_service_rules() {
+        use crate::test_support::{request, Echo};
+        use crate::{ServiceNode, ServiceNodeHandler, ServiceNodeNone};
+
+        struct Guarded;
+
+        impl Service for Guarded {
+            fn service_name(&self) -> &'static str {
+                "Guarded"
+            }
+
+            fn service_uuid(&self) -> Uuid {
+                Echo::UUID
+            }
+
+            fn access_rules(&self) -> &[AccessRule] {
+                &RULES[..1]
+            }
+
+            async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+                Echo.ffa_msg_send_direct_req2(msg).await
+            }
+        }
+
+        static OPEN: [AccessRule; 1] = [AccessRule {
+            opcodes: &[],
+            callers: &[Caller::Endpoint(0x1)],
+        }];
+        let _lock = crate::test_support::AUDIT_LOG_LOCK.lock();
+        let mut node = ServiceNode::new(Restricted::new(Guarded, &OPEN), ServiceNodeNone);
+
+        let rsp = embassy_futures::block_on(node.handle(request(Echo::UUID, &[0x1]))).unwrap();
+        assert_eq!(rsp.u64_at(0), 0x1);
+        let denied = denied_count();
+        let rsp = embassy_futures::block_on(node.handle(request(Echo::UUID, &[0x4]))).unwrap();
+        assert_eq!(rsp.u64_at(0) as i64, odp_ffa::ErrorCode::Denied as i64);
+        assert_eq!(denied_count(), denied + 1);
+    }
+
+    #[test]
+    fn test_audit_log_keeps_recent() {
+        let service = uuid!("330c1273-fde5-4757-9819-5b6539037502");
+        let mut log = AuditLog::new();
+        for opcode in 0..AUDIT_LOG_SIZE as u8 + 2 {
+            log.record(AuditEntry {
+                source_id: OTHER_SP,
+                service,
+                opcode,
+            });
+        }
+
+        assert_eq!(log.denied, AUDIT_LOG_SIZE as u64 + 2);
+        assert_eq!(log.entries.len(), AUDIT_LOG_SIZE);
+        assert_eq!(log.entries.front().unwrap().opcode, 2);
+    }
+}
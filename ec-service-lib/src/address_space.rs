@@ -0,0 +1,435 @@
+//! Bookkeeping of the partition's stage-1 address space.
+//!
+//! [`AddressSpace`] owns the translation tables through a [`TranslationTable`] backend and keeps
+//! the list of regions mapped in them. Regions mapped at runtime, such as memory retrieved from
+//! the normal world or device MMIO, are the only ones services may access through
+//! [`MemoryMapper`], which checks every access against the mapping before touching memory.
+
+use core::cell::RefCell;
+use core::fmt;
+
+use critical_section::Mutex;
+use log::error;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+    /// Executable, and therefore read-only.
+    ReadExecute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Write-back cacheable memory.
+    Normal,
+    /// Device-nGnRE memory, never executable.
+    Device,
+}
+
+/// Attributes of a mapping. Writable mappings are never executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub access: Access,
+    pub memory: MemoryType,
+    /// The region belongs to the normal world.
+    pub non_secure: bool,
+}
+
+impl Attributes {
+    pub const CODE: Self = Self::normal(Access::ReadExecute);
+    pub const RODATA: Self = Self::normal(Access::ReadOnly);
+    pub const DATA: Self = Self::normal(Access::ReadWrite);
+    pub const DEVICE: Self = Self {
+        access: Access::ReadWrite,
+        memory: MemoryType::Device,
+        non_secure: false,
+    };
+
+    const fn normal(access: Access) -> Self {
+        Self {
+            access,
+            memory: MemoryType::Normal,
+            non_secure: false,
+        }
+    }
+
+    pub const fn non_secure(mut self) -> Self {
+        self.non_secure = true;
+        self
+    }
+
+    fn is_valid(&self) -> bool {
+        !(self.memory == MemoryType::Device && self.access == Access::ReadExecute)
+    }
+}
+
+/// A mapped range of pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64,
+    pub attributes: Attributes,
+    /// Mapped at runtime for services to access, rather than part of the partition's image.
+    pub external: bool,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    fn overlaps(&self, base: u64, size: u64) -> bool {
+        base < self.end() && self.base < base + size
+    }
+
+    fn contains(&self, address: u64, len: u64) -> bool {
+        address >= self.base && address.checked_add(len).is_some_and(|end| end <= self.end())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    /// Base or size is not a multiple of [`PAGE_SIZE`], or the range is empty or wraps.
+    Unaligned,
+    /// The attributes combine device memory with execute permission.
+    InvalidAttributes,
+    /// The range overlaps a region that is already mapped.
+    Overlap,
+    /// No mapped region covers the range.
+    NotMapped,
+    /// The range is mapped, but not for the requested access.
+    PermissionDenied,
+    TooManyRegions,
+    /// The address space has not been set up.
+    Uninitialized,
+    /// The translation table backend failed to update the tables.
+    Translation,
+}
+
+/// Backend that writes the translation table entries.
+pub trait TranslationTable {
+    type Error: fmt::Debug;
+
+    fn map(&mut self, base: u64, size: u64, attributes: Attributes) -> Result<(), Self::Error>;
+    fn unmap(&mut self, base: u64, size: u64) -> Result<(), Self::Error>;
+}
+
+/// Translation tables along with the regions mapped in them, up to `N` regions.
+pub struct AddressSpace<T, const N: usize> {
+    table: T,
+    regions: heapless::Vec<Region, N>,
+}
+
+impl<T: TranslationTable, const N: usize> AddressSpace<T, N> {
+    pub fn new(table: T) -> Self {
+        Self {
+            table,
+            regions: heapless::Vec::new(),
+        }
+    }
+
+    /// The translation table backend, for instance to activate it.
+    pub fn table_mut(&mut self) -> &mut T {
+        &mut self.table
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Map part of the partition's image. Services cannot access it through [`check`](Self::check).
+    pub fn map_image(&mut self, base: u64, size: u64, attributes: Attributes) -> Result<(), AddressSpaceError> {
+        self.insert(base, size, attributes, false)
+    }
+
+    /// Map a region for services to access.
+    pub fn map(&mut self, base: u64, size: u64, attributes: Attributes) -> Result<(), AddressSpaceError> {
+        self.insert(base, size, attributes, true)
+    }
+
+    fn insert(
+        &mut self,
+        base: u64,
+        size: u64,
+        attributes: Attributes,
+        external: bool,
+    ) -> Result<(), AddressSpaceError> {
+        if size == 0 || (base | size) & (PAGE_SIZE - 1) != 0 || base.checked_add(size).is_none() {
+            return Err(AddressSpaceError::Unaligned);
+        }
+        if !attributes.is_valid() {
+            return Err(AddressSpaceError::InvalidAttributes);
+        }
+        if self.regions.iter().any(|region| region.overlaps(base, size)) {
+            return Err(AddressSpaceError::Overlap);
+        }
+        if self.regions.is_full() {
+            return Err(AddressSpaceError::TooManyRegions);
+        }
+
+        self.table.map(base, size, attributes).map_err(|e| {
+            error!("Failed to map {:#x}+{:#x}: {:?}", base, size, e);
+            AddressSpaceError::Translation
+        })?;
+
+        let _ = self.regions.push(Region {
+            base,
+            size,
+            attributes,
+            external,
+        });
+        Ok(())
+    }
+
+    /// Unmap a region previously mapped with [`map`](Self::map), given its exact base and size.
+    pub fn unmap(&mut self, base: u64, size: u64) -> Result<(), AddressSpaceError> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.external && region.base == base && region.size == size)
+            .ok_or(AddressSpaceError::NotMapped)?;
+
+        self.table.unmap(base, size).map_err(|e| {
+            error!("Failed to unmap {:#x}+{:#x}: {:?}", base, size, e);
+            AddressSpaceError::Translation
+        })?;
+
+        self.regions.swap_remove(index);
+        Ok(())
+    }
+
+    /// Check that services may access `len` bytes at `address`, for writing if `write` is set.
+    pub fn check(&self, address: u64, len: u64, write: bool) -> Result<Region, AddressSpaceError> {
+        let region = self
+            .regions
+            .iter()
+            .find(|region| region.contains(address, len))
+            .ok_or(AddressSpaceError::NotMapped)?;
+
+        if !region.external || (write && region.attributes.access != Access::ReadWrite) {
+            return Err(AddressSpaceError::PermissionDenied);
+        }
+        Ok(*region)
+    }
+}
+
+/// Access to the partition's address space for services.
+pub trait MemoryMapper: Sync {
+    fn map(&self, base: u64, size: u64, attributes: Attributes) -> Result<(), AddressSpaceError>;
+    fn unmap(&self, base: u64, size: u64) -> Result<(), AddressSpaceError>;
+    fn check(&self, address: u64, len: u64, write: bool) -> Result<Region, AddressSpaceError>;
+
+    /// Copy `buf.len()` bytes from `address`, which must be mapped.
+    fn read(&self, address: u64, buf: &mut [u8]) -> Result<(), AddressSpaceError> {
+        self.check(address, buf.len() as u64, false)?;
+        // SAFETY: The range is mapped and readable, and being external it is not memory of any
+        // Rust object.
+        unsafe { core::ptr::copy_nonoverlapping(address as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Copy `data` to `address`, which must be mapped writable.
+    fn write(&self, address: u64, data: &[u8]) -> Result<(), AddressSpaceError> {
+        self.check(address, data.len() as u64, true)?;
+        // SAFETY: The range is mapped and writable, and being external it is not memory of any
+        // Rust object.
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), address as *mut u8, data.len()) };
+        Ok(())
+    }
+}
+
+/// An [`AddressSpace`] shared between the runtime, which sets it up, and the services.
+pub struct SharedAddressSpace<T, const N: usize> {
+    inner: Mutex<RefCell<Option<AddressSpace<T, N>>>>,
+}
+
+impl<T, const N: usize> Default for SharedAddressSpace<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> SharedAddressSpace<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    pub fn set(&self, address_space: AddressSpace<T, N>) {
+        critical_section::with(|cs| self.inner.replace(cs, Some(address_space)));
+    }
+
+    fn with<R>(
+        &self,
+        f: impl FnOnce(&mut AddressSpace<T, N>) -> Result<R, AddressSpaceError>,
+    ) -> Result<R, AddressSpaceError> {
+        critical_section::with(|cs| match self.inner.borrow_ref_mut(cs).as_mut() {
+            Some(address_space) => f(address_space),
+            None => Err(AddressSpaceError::Uninitialized),
+        })
+    }
+}
+
+impl<T: TranslationTable + Send, const N: usize> MemoryMapper for SharedAddressSpace<T, N> {
+    fn map(&self, base: u64, size: u64, attributes: Attributes) -> Result<(), AddressSpaceError> {
+        self.with(|address_space| address_space.map(base, size, attributes))
+    }
+
+    fn unmap(&self, base: u64, size: u64) -> Result<(), AddressSpaceError> {
+        self.with(|address_space| address_space.unmap(base, size))
+    }
+
+    fn check(&self, address: u64, len: u64, write: bool) -> Result<Region, AddressSpaceError> {
+        self.with(|address_space| address_space.check(address, len, write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[derive(Debug, PartialEq)]
+    enum Op {
+        Map(u64, u64, Attributes),
+        Unmap(u64, u64),
+    }
+
+    #[derive(Default)]
+    struct MockTable {
+        ops: Vec<Op>,
+        fail: bool,
+    }
+
+    impl TranslationTable for MockTable {
+        type Error = ();
+
+        fn map(&mut self, base: u64, size: u64, attributes: Attributes) -> Result<(), ()> {
+            self.ops.push(Op::Map(base, size, attributes));
+            if self.fail {
+                Err(())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn unmap(&mut self, base: u64, size: u64) -> Result<(), ()> {
+            self.ops.push(Op::Unmap(base, size));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_map_unmap() {
+        let mut space = AddressSpace::<_, 4>::new(MockTable::default());
+        space.map_image(0x2040_0000, 0x10000, Attributes::CODE).unwrap();
+        space.map(0x6000_0000, 0x2000, Attributes::DATA.non_secure()).unwrap();
+        assert_eq!(space.regions().len(), 2);
+
+        // Only runtime mappings can be unmapped, and only as a whole
+        assert_eq!(space.unmap(0x2040_0000, 0x10000), Err(AddressSpaceError::NotMapped));
+        assert_eq!(space.unmap(0x6000_0000, 0x1000), Err(AddressSpaceError::NotMapped));
+        space.unmap(0x6000_0000, 0x2000).unwrap();
+        assert_eq!(space.regions().len(), 1);
+
+        assert_eq!(
+            space.table_mut().ops,
+            [
+                Op::Map(0x2040_0000, 0x10000, Attributes::CODE),
+                Op::Map(0x6000_0000, 0x2000, Attributes::DATA.non_secure()),
+                Op::Unmap(0x6000_0000, 0x2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_rejects_invalid_requests() {
+        let mut space = AddressSpace::<_, 2>::new(MockTable::default());
+        space.map(0x1000, 0x2000, Attributes::DATA).unwrap();
+
+        assert_eq!(
+            space.map(0x8000, 0x800, Attributes::DATA),
+            Err(AddressSpaceError::Unaligned)
+        );
+        assert_eq!(
+            space.map(0x8000, 0, Attributes::DATA),
+            Err(AddressSpaceError::Unaligned)
+        );
+        assert_eq!(
+            space.map(0x2000, 0x1000, Attributes::DATA),
+            Err(AddressSpaceError::Overlap)
+        );
+        let device_code = Attributes {
+            memory: MemoryType::Device,
+            ..Attributes::CODE
+        };
+        assert_eq!(
+            space.map(0x8000, 0x1000, device_code),
+            Err(AddressSpaceError::InvalidAttributes)
+        );
+
+        space.map(0x8000, 0x1000, Attributes::DEVICE).unwrap();
+        assert_eq!(
+            space.map(0x10000, 0x1000, Attributes::DATA),
+            Err(AddressSpaceError::TooManyRegions)
+        );
+        // Rejected requests never reach the tables
+        assert_eq!(space.table_mut().ops.len(), 2);
+    }
+
+    #[test]
+    fn test_failed_translation_is_not_tracked() {
+        let mut space = AddressSpace::<_, 2>::new(MockTable {
+            fail: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            space.map(0x1000, 0x1000, Attributes::DATA),
+            Err(AddressSpaceError::Translation)
+        );
+        assert!(space.regions().is_empty());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut space = AddressSpace::<_, 4>::new(MockTable::default());
+        space.map_image(0x1000, 0x1000, Attributes::DATA).unwrap();
+        space.map(0x4000, 0x2000, Attributes::RODATA).unwrap();
+        space.map(0x8000, 0x1000, Attributes::DEVICE).unwrap();
+
+        assert_eq!(space.check(0x4ff0, 0x20, false).unwrap().base, 0x4000);
+        assert_eq!(
+            space.check(0x4000, 0x2000, true),
+            Err(AddressSpaceError::PermissionDenied)
+        );
+        assert_eq!(space.check(0x5ff0, 0x20, false), Err(AddressSpaceError::NotMapped));
+        assert_eq!(space.check(0x1000, 4, false), Err(AddressSpaceError::PermissionDenied));
+        assert_eq!(space.check(u64::MAX, 2, false), Err(AddressSpaceError::NotMapped));
+        assert!(space.check(0x8010, 4, true).is_ok());
+    }
+
+    #[test]
+    fn test_shared_read_write() {
+        static SPACE: SharedAddressSpace<MockTable, 2> = SharedAddressSpace::new();
+        assert_eq!(SPACE.check(0, 1, false), Err(AddressSpaceError::Uninitialized));
+
+        let base = crate::test_support::leak_page(0).as_mut_ptr() as u64;
+
+        SPACE.set(AddressSpace::new(MockTable::default()));
+        assert_eq!(SPACE.write(base, b"ec"), Err(AddressSpaceError::NotMapped));
+        SPACE.map(base, PAGE_SIZE, Attributes::DATA).unwrap();
+
+        SPACE.write(base + 8, b"ec").unwrap();
+        let mut buf = [0; 4];
+        SPACE.read(base + 7, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0ec\0");
+        assert_eq!(
+            SPACE.read(base + PAGE_SIZE - 2, &mut buf),
+            Err(AddressSpaceError::NotMapped)
+        );
+    }
+}
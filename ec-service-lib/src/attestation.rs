@@ -0,0 +1,223 @@
+//! Secure state of the platform and attestation of the partition.
+//!
+//! An [`Attestation`] measures the image once, when created at boot. Each report binds that
+//! measurement, a measurement of the configuration and the secure state to a nonce from the
+//! verifier, signed by an [`AttestationSigner`].
+
+use log::info;
+
+use crate::sha256::{Sha256, DIGEST_SIZE};
+
+/// Secure state flag: only images signed by the platform key boot.
+pub const SECURE_BOOT_ENABLED: u8 = 1 << 0;
+/// Secure state flag: the debug ports are locked.
+pub const DEBUG_LOCKED: u8 = 1 << 1;
+
+pub const NONCE_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+/// Bytes of a report as returned to the OS.
+pub const REPORT_SIZE: usize = SIGNED_SIZE + SIGNATURE_SIZE;
+
+/// Layout version of the reports.
+const REPORT_VERSION: u8 = 1;
+/// Bytes of a report covered by the signature.
+const SIGNED_SIZE: usize = 8 + NONCE_SIZE + 2 * DIGEST_SIZE;
+
+/// Security fuses and counters of the platform.
+pub trait SecurityState: Sync {
+    fn secure_boot_enabled(&self) -> bool;
+    fn debug_locked(&self) -> bool;
+    /// Lowest image security version the platform boots.
+    fn anti_rollback_counter(&self) -> u32;
+    /// Raise the anti-rollback counter to `counter` once an image of that security version is
+    /// committed, leaving it as is if already higher.
+    fn raise_anti_rollback_counter(&self, counter: u32);
+
+    /// The flags reported in `secure_state`.
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.secure_boot_enabled() {
+            flags |= SECURE_BOOT_ENABLED;
+        }
+        if self.debug_locked() {
+            flags |= DEBUG_LOCKED;
+        }
+        flags
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SignatureAlgorithm {
+    HmacSha256 = 1,
+    EcdsaP256Sha256 = 2,
+    Ed25519 = 3,
+}
+
+impl SignatureAlgorithm {
+    /// Bytes of the signatures made with the algorithm, the raw `r || s` for ECDSA.
+    pub fn signature_len(self) -> usize {
+        match self {
+            SignatureAlgorithm::HmacSha256 => DIGEST_SIZE,
+            SignatureAlgorithm::EcdsaP256Sha256 | SignatureAlgorithm::Ed25519 => 64,
+        }
+    }
+}
+
+/// The key attesting the partition.
+pub trait AttestationSigner: Sync {
+    fn algorithm(&self) -> SignatureAlgorithm;
+    /// Sign `digest` into the first [`SignatureAlgorithm::signature_len`] bytes of `signature`.
+    fn sign(&self, digest: &[u8; DIGEST_SIZE], signature: &mut [u8; SIGNATURE_SIZE]);
+}
+
+/// A key held in the image, signing with HMAC-SHA-256.
+///
+/// Anyone with the image can forge its reports, it is meant for tests and development boards.
+pub struct SoftwareKey {
+    key: [u8; DIGEST_SIZE],
+}
+
+impl SoftwareKey {
+    pub const fn new(key: [u8; DIGEST_SIZE]) -> Self {
+        Self { key }
+    }
+}
+
+impl AttestationSigner for SoftwareKey {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::HmacSha256
+    }
+
+    fn sign(&self, digest: &[u8; DIGEST_SIZE], signature: &mut [u8; SIGNATURE_SIZE]) {
+        signature[..DIGEST_SIZE].copy_from_slice(&Sha256::hmac(&self.key, digest));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttestationReport {
+    pub algorithm: SignatureAlgorithm,
+    pub secure_state: u8,
+    pub anti_rollback_counter: u32,
+    pub nonce: [u8; NONCE_SIZE],
+    pub image_digest: [u8; DIGEST_SIZE],
+    pub config_digest: [u8; DIGEST_SIZE],
+    pub signature_len: u8,
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+impl AttestationReport {
+    fn signed_bytes(&self) -> [u8; SIGNED_SIZE] {
+        let mut bytes = [0; SIGNED_SIZE];
+        let iter = [
+            REPORT_VERSION,
+            self.algorithm as u8,
+            self.secure_state,
+            self.signature_len,
+        ]
+        .into_iter()
+        .chain(self.anti_rollback_counter.to_le_bytes())
+        .chain(self.nonce)
+        .chain(self.image_digest)
+        .chain(self.config_digest);
+        for (byte, value) in bytes.iter_mut().zip(iter) {
+            *byte = value;
+        }
+        bytes
+    }
+
+    /// The report as returned to the OS, the signature covering the bytes before it.
+    pub fn to_bytes(&self) -> [u8; REPORT_SIZE] {
+        let mut bytes = [0; REPORT_SIZE];
+        bytes[..SIGNED_SIZE].copy_from_slice(&self.signed_bytes());
+        bytes[SIGNED_SIZE..].copy_from_slice(&self.signature);
+        bytes
+    }
+}
+
+/// Measurement of the image and the key to report it with.
+pub struct Attestation {
+    image_digest: [u8; DIGEST_SIZE],
+    signer: &'static dyn AttestationSigner,
+}
+
+impl Attestation {
+    /// Measure the image made of `sections`, which must not change while it runs.
+    pub fn new(sections: &[&[u8]], signer: &'static dyn AttestationSigner) -> Self {
+        let mut sha = Sha256::new();
+        for section in sections {
+            sha.update(section);
+        }
+        let image_digest = sha.finish();
+        info!("Image measurement {:02x?}", image_digest);
+        Self { image_digest, signer }
+    }
+
+    /// A signed report of the image, the configuration measured as `config_digest` and the
+    /// secure state, for the verifier that sent `nonce`.
+    pub fn report(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        config_digest: [u8; DIGEST_SIZE],
+        security: Option<&dyn SecurityState>,
+    ) -> AttestationReport {
+        let algorithm = self.signer.algorithm();
+        let mut report = AttestationReport {
+            algorithm,
+            secure_state: security.map_or(0, |s| s.flags()),
+            anti_rollback_counter: security.map_or(0, |s| s.anti_rollback_counter()),
+            nonce: *nonce,
+            image_digest: self.image_digest,
+            config_digest,
+            signature_len: algorithm.signature_len() as u8,
+            signature: [0; SIGNATURE_SIZE],
+        };
+
+        let digest = Sha256::digest(&report.signed_bytes());
+        self.signer.sign(&digest, &mut report.signature);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Locked;
+
+    impl SecurityState for Locked {
+        fn secure_boot_enabled(&self) -> bool {
+            true
+        }
+
+        fn debug_locked(&self) -> bool {
+            true
+        }
+
+        fn anti_rollback_counter(&self) -> u32 {
+            7
+        }
+
+        fn raise_anti_rollback_counter(&self, _counter: u32) {}
+    }
+
+    static KEY: SoftwareKey = SoftwareKey::new([0x42; DIGEST_SIZE]);
+
+    #[test]
+    fn test_report() {
+        let attestation = Attestation::new(&[b"code", b"rodata"], &KEY);
+        let report = attestation.report(&[1; NONCE_SIZE], [2; DIGEST_SIZE], Some(&Locked));
+        assert_eq!(report.image_digest, Sha256::digest(b"coderodata"));
+        assert_eq!(report.secure_state, SECURE_BOOT_ENABLED | DEBUG_LOCKED);
+
+        // A verifier with the key checks the signature over the bytes before it
+        let bytes = report.to_bytes();
+        assert_eq!(&bytes[..8], &[REPORT_VERSION, 1, 0x3, 32, 7, 0, 0, 0]);
+        let mac = Sha256::hmac(&[0x42; DIGEST_SIZE], &Sha256::digest(&bytes[..SIGNED_SIZE]));
+        assert_eq!(bytes[SIGNED_SIZE..SIGNED_SIZE + DIGEST_SIZE], mac);
+
+        let other = attestation.report(&[3; NONCE_SIZE], [2; DIGEST_SIZE], None);
+        assert_eq!(other.secure_state, 0);
+        assert_ne!(other.signature, report.signature);
+    }
+}
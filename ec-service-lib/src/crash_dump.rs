@@ -0,0 +1,288 @@
+//! Crash records that survive a restart of the partition.
+//!
+//! The panic handler fills in a [`CrashRecord`] and stores it in a memory region that is not
+//! cleared when the partition is reloaded. After the restart the record is validated through its
+//! magic, version and CRC, and can be read back by the OS through FwMgmt.
+
+use core::fmt;
+
+use crate::crc32::crc32;
+use crate::sp_logger;
+
+/// "ECCD" in little endian.
+pub const CRASH_RECORD_MAGIC: u32 = 0x4443_4345;
+pub const CRASH_RECORD_VERSION: u16 = 1;
+pub const CRASH_RECORD_SIZE: usize = 1024;
+
+const FILE_SIZE: usize = 64;
+const MESSAGE_SIZE: usize = 192;
+const LOG_SIZE: usize = 672;
+
+const FLAG_REGS_VALID: u32 = 1 << 0;
+
+/// Register state of the exception that led to the crash.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct CrashRegs {
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
+    pub sp: u64,
+    pub fp: u64,
+    pub lr: u64,
+}
+
+/// A crash record, laid out exactly as it is stored and returned to the OS.
+///
+/// All fields are little endian and naturally aligned, so the record has no padding.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CrashRecord {
+    magic: u32,
+    version: u16,
+    size: u16,
+    crc: u32,
+    flags: u32,
+    uptime_ms: u64,
+    line: u32,
+    column: u32,
+    regs: CrashRegs,
+    file_len: u16,
+    message_len: u16,
+    log_len: u16,
+    _reserved: u16,
+    file: [u8; FILE_SIZE],
+    message: [u8; MESSAGE_SIZE],
+    log: [u8; LOG_SIZE],
+}
+
+const _: () = assert!(core::mem::size_of::<CrashRecord>() == CRASH_RECORD_SIZE);
+
+/// Writes formatted text into a fixed buffer, dropping whatever does not fit.
+struct Truncate<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// The longest valid UTF-8 prefix of `bytes`, truncation may have split a character.
+fn utf8_prefix(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    }
+}
+
+impl CrashRecord {
+    pub fn new(uptime_ms: u64, file: &str, line: u32, column: u32, message: fmt::Arguments) -> Self {
+        let mut record = Self {
+            magic: CRASH_RECORD_MAGIC,
+            version: CRASH_RECORD_VERSION,
+            size: CRASH_RECORD_SIZE as u16,
+            crc: 0,
+            flags: 0,
+            uptime_ms,
+            line,
+            column,
+            regs: CrashRegs::default(),
+            file_len: 0,
+            message_len: 0,
+            log_len: 0,
+            _reserved: 0,
+            file: [0; FILE_SIZE],
+            message: [0; MESSAGE_SIZE],
+            log: [0; LOG_SIZE],
+        };
+
+        // Keep the end of the path, it is the part that identifies the file
+        let file = &file.as_bytes()[file.len().saturating_sub(FILE_SIZE)..];
+        record.file[..file.len()].copy_from_slice(file);
+        record.file_len = file.len() as u16;
+
+        let mut writer = Truncate {
+            buf: &mut record.message,
+            len: 0,
+        };
+        let _ = fmt::write(&mut writer, message);
+        record.message_len = writer.len as u16;
+
+        record
+    }
+
+    pub fn set_regs(&mut self, regs: CrashRegs) {
+        self.regs = regs;
+        self.flags |= FLAG_REGS_VALID;
+    }
+
+    /// Copy the most recent output of [`sp_logger::SpLogger`] into the record.
+    pub fn capture_recent_log(&mut self) {
+        self.log_len = sp_logger::recent_log(&mut self.log) as u16;
+    }
+
+    pub fn uptime_ms(&self) -> u64 {
+        self.uptime_ms
+    }
+
+    pub fn file(&self) -> &str {
+        utf8_prefix(&self.file[..(self.file_len as usize).min(FILE_SIZE)])
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn column(&self) -> u32 {
+        self.column
+    }
+
+    pub fn message(&self) -> &str {
+        utf8_prefix(&self.message[..(self.message_len as usize).min(MESSAGE_SIZE)])
+    }
+
+    pub fn regs(&self) -> Option<CrashRegs> {
+        (self.flags & FLAG_REGS_VALID != 0).then_some(self.regs)
+    }
+
+    pub fn log(&self) -> &[u8] {
+        &self.log[..(self.log_len as usize).min(LOG_SIZE)]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: The record is repr(C) plain data without padding, as checked by the size assertion.
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, CRASH_RECORD_SIZE) }
+    }
+
+    fn compute_crc(&self) -> u32 {
+        let mut copy = *self;
+        copy.crc = 0;
+        crc32(0, copy.as_bytes())
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == CRASH_RECORD_MAGIC
+            && self.version == CRASH_RECORD_VERSION
+            && self.size as usize == CRASH_RECORD_SIZE
+            && self.crc == self.compute_crc()
+    }
+}
+
+/// Handle to the memory region holding the crash record.
+pub struct CrashDump {
+    region: *mut CrashRecord,
+}
+
+// SAFETY: The region is only accessed through volatile copies of the whole record, a torn read
+// is caught by the CRC.
+unsafe impl Sync for CrashDump {}
+
+impl CrashDump {
+    /// # Safety
+    ///
+    /// `region` must be valid for reads and writes of a [`CrashRecord`], suitably aligned, and
+    /// not used for anything else for the lifetime of the handle. Its contents may be arbitrary.
+    pub const unsafe fn new(region: *mut CrashRecord) -> Self {
+        Self { region }
+    }
+
+    /// The stored record, if the region holds a valid one.
+    pub fn load(&self) -> Option<CrashRecord> {
+        // SAFETY: Any bit pattern is a valid CrashRecord, and the region is valid per `new`.
+        let record = unsafe { core::ptr::read_volatile(self.region) };
+        record.is_valid().then_some(record)
+    }
+
+    pub fn store(&self, record: &CrashRecord) {
+        let mut record = *record;
+        record.crc = record.compute_crc();
+        // SAFETY: The region is valid for writes per `new`.
+        unsafe { core::ptr::write_volatile(self.region, record) };
+    }
+
+    pub fn clear(&self) {
+        // SAFETY: The region is valid for writes per `new`, and `magic` is its first field.
+        unsafe { core::ptr::write_volatile(&raw mut (*self.region).magic, 0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> CrashRecord {
+        let mut record = CrashRecord::new(
+            1234,
+            "src/services/thermal.rs",
+            42,
+            7,
+            format_args!("index {} out of range", 9),
+        );
+        record.set_regs(CrashRegs {
+            elr: 0x4000_1000,
+            esr: 0x9600_0045,
+            ..Default::default()
+        });
+        record
+    }
+
+    #[test]
+    fn test_record_fields() {
+        let record = sample();
+        assert_eq!(record.uptime_ms(), 1234);
+        assert_eq!(record.file(), "src/services/thermal.rs");
+        assert_eq!((record.line(), record.column()), (42, 7));
+        assert_eq!(record.message(), "index 9 out of range");
+        assert_eq!(record.regs().unwrap().esr, 0x9600_0045);
+        assert!(record.log().is_empty());
+        assert_eq!(&record.as_bytes()[..4], b"ECCD");
+    }
+
+    #[test]
+    fn test_record_truncates_long_text() {
+        let path = "a/".repeat(40) + "file.rs";
+        let message = "é".repeat(MESSAGE_SIZE);
+        let record = CrashRecord::new(0, &path, 1, 1, format_args!("x{}", message));
+
+        assert_eq!(record.file().len(), FILE_SIZE);
+        assert!(record.file().ends_with("/a/file.rs"));
+        // A split character is dropped rather than returned as invalid UTF-8
+        assert_eq!(record.message(), "x".to_owned() + &"é".repeat(MESSAGE_SIZE / 2 - 1));
+        assert_eq!(record.regs(), None);
+    }
+
+    #[test]
+    fn test_store_load_clear() {
+        let mut region = core::mem::MaybeUninit::<CrashRecord>::zeroed();
+        // SAFETY: The region outlives the handle and is used for nothing else.
+        let dump = unsafe { CrashDump::new(region.as_mut_ptr()) };
+        assert!(dump.load().is_none());
+
+        dump.store(&sample());
+        let loaded = dump.load().unwrap();
+        assert_eq!(loaded.message(), "index 9 out of range");
+        assert_eq!(loaded.as_bytes()[12..], sample().as_bytes()[12..]);
+
+        dump.clear();
+        assert!(dump.load().is_none());
+    }
+
+    #[test]
+    fn test_load_rejects_corruption() {
+        let mut region = core::mem::MaybeUninit::<CrashRecord>::zeroed();
+        // SAFETY: The region outlives the handle and is used for nothing else.
+        let dump = unsafe { CrashDump::new(region.as_mut_ptr()) };
+        dump.store(&sample());
+
+        // SAFETY: The region was initialized by `store`.
+        unsafe { region.assume_init_mut().message[0] ^= 1 };
+        assert!(dump.load().is_none());
+    }
+}
@@ -0,0 +1,426 @@
+//! Closed loop control of the fans from the thermal sensors.
+//!
+//! A [`FanController`] drives a [`Fan`] from a [`TemperatureSensor`] along a [`FanCurve`], and
+//! watches the tachometer for stalls. The platform runs [`FanControl::update`] periodically on a
+//! [`SharedFan`], which the Thermal service also reads and overrides on behalf of the OS.
+//! Temperatures are in tenths of a Kelvin, as in ACPI.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use log::{error, info, warn};
+
+/// Duty cycle the fan runs at when the temperature is unknown.
+const FAILSAFE_DUTY: u8 = 100;
+
+/// A fan driven at this duty or more is expected to spin.
+const STALL_MIN_DUTY: u8 = 20;
+/// Below this speed, a fan expected to spin is not spinning.
+const STALL_RPM: u32 = 100;
+/// How long a fan may not spin before it is reported stalled, covering spin-up.
+const STALL_TIMEOUT_MS: u64 = 3000;
+
+/// A PWM driven fan with a tachometer.
+pub trait Fan {
+    /// Drive the fan at `percent` of its full duty cycle.
+    fn set_duty(&mut self, percent: u8);
+    /// Speed measured by the tachometer.
+    fn rpm(&self) -> u32;
+}
+
+/// A thermal sensor the fan cools.
+pub trait TemperatureSensor {
+    /// The current temperature in tenths of a Kelvin, or `None` if it cannot be read.
+    fn temperature_dk(&mut self) -> Option<u32>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CurvePoint {
+    pub temperature_dk: u32,
+    pub duty: u8,
+}
+
+/// Duty cycle as a function of temperature, interpolated between points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FanCurve {
+    /// Points in increasing temperature order.
+    points: &'static [CurvePoint],
+    /// How far the temperature must fall before the duty is lowered.
+    hysteresis_dk: u32,
+}
+
+impl FanCurve {
+    /// A curve through `points`, which must be sorted by temperature and not empty.
+    pub const fn new(points: &'static [CurvePoint], hysteresis_dk: u32) -> Self {
+        assert!(!points.is_empty(), "A fan curve needs at least one point");
+        Self { points, hysteresis_dk }
+    }
+
+    /// The duty at `temperature_dk`, held at the first and last points beyond the curve.
+    pub fn duty(&self, temperature_dk: u32) -> u8 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if temperature_dk <= first.temperature_dk {
+            return first.duty;
+        }
+
+        for pair in self.points.windows(2) {
+            let (low, high) = (pair[0], pair[1]);
+            if temperature_dk < high.temperature_dk {
+                let span = (high.temperature_dk - low.temperature_dk) as i64;
+                let offset = (temperature_dk - low.temperature_dk) as i64;
+                let delta = high.duty as i64 - low.duty as i64;
+                return (low.duty as i64 + delta * offset / span) as u8;
+            }
+        }
+        last.duty
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FanPolicy {
+    /// Follow the curve.
+    #[default]
+    Auto,
+    /// Hold the duty set by the OS.
+    Manual(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FanStatus {
+    pub rpm: u32,
+    pub duty: u8,
+    /// Last temperature read, 0 if unknown.
+    pub temperature_dk: u32,
+    pub policy: FanPolicy,
+    pub stalled: bool,
+}
+
+/// State of the control loop of a fan, apart from the hardware.
+struct ControlState {
+    curve: FanCurve,
+    policy: FanPolicy,
+    duty: u8,
+    /// Temperature the current duty was set for, the reference for the hysteresis.
+    reference_dk: u32,
+    temperature_dk: u32,
+    rpm: u32,
+    not_spinning_since_ms: Option<u64>,
+    stalled: bool,
+}
+
+impl ControlState {
+    const fn new(curve: FanCurve) -> Self {
+        Self {
+            curve,
+            policy: FanPolicy::Auto,
+            duty: 0,
+            reference_dk: 0,
+            temperature_dk: 0,
+            rpm: 0,
+            not_spinning_since_ms: None,
+            stalled: false,
+        }
+    }
+
+    /// Record the temperature read and return the duty to drive the fan at.
+    fn next_duty(&mut self, temperature_dk: Option<u32>) -> u8 {
+        self.temperature_dk = temperature_dk.unwrap_or(0);
+        self.duty = match self.policy {
+            FanPolicy::Auto => self.auto_duty(temperature_dk),
+            FanPolicy::Manual(duty) => duty,
+        };
+        self.duty
+    }
+
+    /// The duty for the temperature read, raised at once but only lowered past the hysteresis.
+    fn auto_duty(&mut self, temperature_dk: Option<u32>) -> u8 {
+        let Some(temperature_dk) = temperature_dk else {
+            // Let the curve take over from the failsafe duty once the sensor reads again
+            self.reference_dk = u32::MAX;
+            return FAILSAFE_DUTY;
+        };
+
+        let target = self.curve.duty(temperature_dk);
+        let cooled = temperature_dk + self.curve.hysteresis_dk <= self.reference_dk;
+        if target > self.duty || (target < self.duty && cooled) {
+            self.reference_dk = temperature_dk;
+            target
+        } else {
+            // Track the peak so the hysteresis is measured from it
+            self.reference_dk = self.reference_dk.max(temperature_dk);
+            self.duty
+        }
+    }
+
+    /// Check the fan spins at `rpm`, as measured once driven at the current duty.
+    fn check_stall(&mut self, rpm: u32, now_ms: u64) {
+        self.rpm = rpm;
+        let spinning = self.duty < STALL_MIN_DUTY || rpm >= STALL_RPM;
+        if spinning {
+            if self.stalled {
+                info!("Fan recovered");
+            }
+            self.not_spinning_since_ms = None;
+            self.stalled = false;
+            return;
+        }
+
+        let since = *self.not_spinning_since_ms.get_or_insert(now_ms);
+        if !self.stalled && now_ms.saturating_sub(since) >= STALL_TIMEOUT_MS {
+            error!("Fan stalled at {}% duty", self.duty);
+            self.stalled = true;
+        }
+    }
+
+    fn status(&self) -> FanStatus {
+        FanStatus {
+            rpm: self.rpm,
+            duty: self.duty,
+            temperature_dk: self.temperature_dk,
+            policy: self.policy,
+            stalled: self.stalled,
+        }
+    }
+}
+
+/// Control loop of one fan.
+pub struct FanController<F, S> {
+    fan: F,
+    sensor: S,
+    state: ControlState,
+}
+
+impl<F: Fan, S: TemperatureSensor> FanController<F, S> {
+    pub fn new(fan: F, sensor: S, curve: FanCurve) -> Self {
+        Self {
+            fan,
+            sensor,
+            state: ControlState::new(curve),
+        }
+    }
+}
+
+/// A fan control loop, as seen by the Thermal service.
+pub trait FanControl: Sync {
+    /// Read the sensor, drive the fan and check it spins, at `now_ms` of a monotonic clock.
+    fn update(&self, now_ms: u64);
+    fn status(&self) -> Option<FanStatus>;
+    fn set_policy(&self, policy: FanPolicy) -> bool;
+}
+
+/// A [`FanController`] shared between the platform, which runs it, and the Thermal service.
+///
+/// The fan and sensor are only accessed by [`FanControl::update`], outside of the critical
+/// section guarding the state of the loop, so slow buses do not hold off interrupts. The status
+/// reports the speed measured by the last update.
+pub struct SharedFan<F, S> {
+    state: Mutex<RefCell<Option<ControlState>>>,
+    /// Taken out by the update running, if any.
+    hardware: Mutex<RefCell<Option<(F, S)>>>,
+}
+
+impl<F, S> Default for SharedFan<F, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F, S> SharedFan<F, S> {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(None)),
+            hardware: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    pub fn set(&self, controller: FanController<F, S>) {
+        critical_section::with(|cs| {
+            self.state.replace(cs, Some(controller.state));
+            self.hardware.replace(cs, Some((controller.fan, controller.sensor)));
+        });
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut ControlState) -> R) -> Option<R> {
+        critical_section::with(|cs| self.state.borrow_ref_mut(cs).as_mut().map(f))
+    }
+}
+
+impl<F: Fan + Send, S: TemperatureSensor + Send> FanControl for SharedFan<F, S> {
+    fn update(&self, now_ms: u64) {
+        let Some((mut fan, mut sensor)) = critical_section::with(|cs| self.hardware.borrow_ref_mut(cs).take()) else {
+            return;
+        };
+
+        let temperature_dk = sensor.temperature_dk();
+        if temperature_dk.is_none() {
+            warn!("Fan sensor unreadable, running at {}%", FAILSAFE_DUTY);
+        }
+        if let Some(duty) = self.with(|c| c.next_duty(temperature_dk)) {
+            fan.set_duty(duty);
+            let rpm = fan.rpm();
+            self.with(|c| c.check_stall(rpm, now_ms));
+        }
+
+        critical_section::with(|cs| self.hardware.replace(cs, Some((fan, sensor))));
+    }
+
+    fn status(&self) -> Option<FanStatus> {
+        self.with(|c| c.status())
+    }
+
+    fn set_policy(&self, policy: FanPolicy) -> bool {
+        self.with(|c| {
+            c.policy = match policy {
+                FanPolicy::Manual(duty) => FanPolicy::Manual(duty.min(100)),
+                FanPolicy::Auto => FanPolicy::Auto,
+            };
+            // Let the curve take over from the manual duty at the next update
+            c.reference_dk = u32::MAX;
+        })
+        .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 30C, 50C and 70C.
+    static POINTS: [CurvePoint; 3] = [
+        CurvePoint {
+            temperature_dk: 3031,
+            duty: 0,
+        },
+        CurvePoint {
+            temperature_dk: 3231,
+            duty: 40,
+        },
+        CurvePoint {
+            temperature_dk: 3431,
+            duty: 100,
+        },
+    ];
+    const CURVE: FanCurve = FanCurve::new(&POINTS, 30);
+
+    struct TestFan {
+        duty: u8,
+        spins: bool,
+    }
+
+    impl Fan for TestFan {
+        fn set_duty(&mut self, percent: u8) {
+            self.duty = percent;
+        }
+
+        fn rpm(&self) -> u32 {
+            if self.spins {
+                self.duty as u32 * 50
+            } else {
+                0
+            }
+        }
+    }
+
+    struct TestSensor(Option<u32>);
+
+    impl TemperatureSensor for TestSensor {
+        fn temperature_dk(&mut self) -> Option<u32> {
+            self.0
+        }
+    }
+
+    fn shared(spins: bool, temperature_dk: Option<u32>) -> &'static SharedFan<TestFan, TestSensor> {
+        let shared = std::boxed::Box::leak(std::boxed::Box::new(SharedFan::new()));
+        let fan = TestFan { duty: 0, spins };
+        shared.set(FanController::new(fan, TestSensor(temperature_dk), CURVE));
+        shared
+    }
+
+    fn hardware<R>(fan: &SharedFan<TestFan, TestSensor>, f: impl FnOnce(&mut TestFan, &mut TestSensor) -> R) -> R {
+        critical_section::with(|cs| {
+            let mut hardware = fan.hardware.borrow_ref_mut(cs);
+            let (fan, sensor) = hardware.as_mut().unwrap();
+            f(fan, sensor)
+        })
+    }
+
+    fn set_temperature(fan: &SharedFan<TestFan, TestSensor>, temperature_dk: Option<u32>) {
+        hardware(fan, |_, sensor| sensor.0 = temperature_dk);
+    }
+
+    #[test]
+    fn test_curve_interpolates() {
+        assert_eq!(CURVE.duty(2900), 0);
+        assert_eq!(CURVE.duty(3131), 20);
+        assert_eq!(CURVE.duty(3331), 70);
+        assert_eq!(CURVE.duty(4000), 100);
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let fan = shared(true, Some(3331));
+        fan.update(0);
+        assert_eq!(fan.status().unwrap().duty, 70);
+
+        // Falling within the hysteresis keeps the duty
+        set_temperature(fan, Some(3311));
+        fan.update(1000);
+        assert_eq!(fan.status().unwrap().duty, 70);
+
+        set_temperature(fan, Some(3301));
+        fan.update(2000);
+        assert_eq!(fan.status().unwrap().duty, 61);
+
+        // Rising raises the duty at once
+        set_temperature(fan, Some(3311));
+        fan.update(3000);
+        assert_eq!(fan.status().unwrap().duty, 64);
+
+        set_temperature(fan, None);
+        fan.update(4000);
+        assert_eq!(fan.status().unwrap().duty, FAILSAFE_DUTY);
+    }
+
+    #[test]
+    fn test_curve_resumes_after_sensor_failure() {
+        let fan = shared(true, Some(3131));
+        fan.update(0);
+        assert_eq!(fan.status().unwrap().duty, 20);
+
+        set_temperature(fan, None);
+        fan.update(1000);
+        let status = fan.status().unwrap();
+        assert_eq!((status.duty, status.temperature_dk), (FAILSAFE_DUTY, 0));
+
+        // Back at the same temperature, not held at the failsafe duty by the hysteresis
+        set_temperature(fan, Some(3131));
+        fan.update(2000);
+        assert_eq!(fan.status().unwrap().duty, 20);
+    }
+
+    #[test]
+    fn test_manual_policy_and_stall() {
+        let fan = shared(false, Some(3031));
+        assert!(fan.set_policy(FanPolicy::Manual(150)));
+        fan.update(0);
+        let status = fan.status().unwrap();
+        assert_eq!(
+            (status.duty, status.policy, status.stalled),
+            (100, FanPolicy::Manual(100), false)
+        );
+
+        fan.update(STALL_TIMEOUT_MS - 1);
+        assert!(!fan.status().unwrap().stalled);
+        fan.update(STALL_TIMEOUT_MS);
+        assert!(fan.status().unwrap().stalled);
+
+        hardware(fan, |fan, _| fan.spins = true);
+        fan.update(STALL_TIMEOUT_MS + 1000);
+        assert!(!fan.status().unwrap().stalled);
+
+        fan.set_policy(FanPolicy::Auto);
+        fan.update(STALL_TIMEOUT_MS + 2000);
+        assert_eq!(fan.status().unwrap().duty, 0);
+        assert!(SharedFan::<TestFan, TestSensor>::new().status().is_none());
+    }
+}
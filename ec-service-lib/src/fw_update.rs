@@ -0,0 +1,742 @@
+//! A/B firmware update of the EC.
+//!
+//! The firmware lives in two flash slots. An update is streamed into the slot not running, read
+//! back through an [`ImageVerifier`] and staged for a trial boot. The bootloader boots the
+//! [`BootRecord::trial`] slot once, clearing it first, so an image that fails before the OS
+//! commits it leaves the [`BootRecord::active`] slot to boot at the next reset.
+
+use ed25519_compact::{PublicKey, Signature};
+use log::{error, info, warn};
+
+use crate::attestation::{SecurityState, SIGNATURE_SIZE};
+use crate::sha256::{Sha256, DIGEST_SIZE};
+
+/// Bytes of an Ed25519 public key.
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// Bytes of staged image read back at a time to verify it.
+const VERIFY_CHUNK_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Which slots the bootloader boots, kept in flash beside the slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootRecord {
+    /// Slot holding the committed image.
+    pub active: Slot,
+    /// Slot to boot once at the next reset.
+    pub trial: Option<Slot>,
+    /// Version of the image in each slot, 0 if the slot holds none.
+    pub versions: [u16; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+    OutOfRange,
+    Hardware,
+}
+
+/// Flash holding the two firmware slots and the boot record.
+pub trait FlashStorage {
+    /// Bytes each slot holds.
+    fn slot_size(&self) -> usize;
+    /// Slot the running image was booted from.
+    fn running_slot(&self) -> Slot;
+    fn erase(&mut self, slot: Slot) -> Result<(), FlashError>;
+    fn write(&mut self, slot: Slot, offset: usize, data: &[u8]) -> Result<(), FlashError>;
+    fn read(&mut self, slot: Slot, offset: usize, buf: &mut [u8]) -> Result<(), FlashError>;
+    fn boot_record(&mut self) -> Result<BootRecord, FlashError>;
+    fn set_boot_record(&mut self, record: &BootRecord) -> Result<(), FlashError>;
+}
+
+/// Check of a received image against the header sent with it.
+pub trait ImageVerifier {
+    /// Start digesting a new image.
+    fn begin(&mut self);
+    fn update(&mut self, data: &[u8]);
+    /// Whether the image digested is `header.digest`, and `header.signature` signs
+    /// [`ImageHeader::signed_bytes`].
+    fn verify(&mut self, header: &ImageHeader) -> bool;
+}
+
+/// Verify the SHA-256 digest of images but not their signature, for platforms without a key.
+#[derive(Default)]
+pub struct UnsignedVerifier {
+    sha: Sha256,
+}
+
+impl UnsignedVerifier {
+    pub const fn new() -> Self {
+        Self { sha: Sha256::new() }
+    }
+}
+
+impl ImageVerifier for UnsignedVerifier {
+    fn begin(&mut self) {
+        self.sha = Sha256::new();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.sha.update(data);
+    }
+
+    fn verify(&mut self, header: &ImageHeader) -> bool {
+        warn!("Image signature not checked");
+        core::mem::take(&mut self.sha).finish() == header.digest
+    }
+}
+
+/// Verify the SHA-256 digest of images and the Ed25519 signature of their digest and version by
+/// the key provisioned in the platform.
+pub struct Ed25519Verifier {
+    sha: Sha256,
+    public_key: [u8; PUBLIC_KEY_SIZE],
+}
+
+impl Ed25519Verifier {
+    pub const fn new(public_key: [u8; PUBLIC_KEY_SIZE]) -> Self {
+        Self {
+            sha: Sha256::new(),
+            public_key,
+        }
+    }
+}
+
+impl ImageVerifier for Ed25519Verifier {
+    fn begin(&mut self) {
+        self.sha = Sha256::new();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.sha.update(data);
+    }
+
+    fn verify(&mut self, header: &ImageHeader) -> bool {
+        if core::mem::take(&mut self.sha).finish() != header.digest {
+            return false;
+        }
+        let signature = Signature::new(header.signature);
+        match PublicKey::new(self.public_key).verify(header.signed_bytes(), &signature) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Image signature rejected: {:?}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Description of an image, sent by the OS before the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub size: u32,
+    /// Version of the image, also its security version for the anti-rollback counter.
+    pub version: u16,
+    pub digest: [u8; DIGEST_SIZE],
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+impl ImageHeader {
+    /// The bytes the signature covers, the digest then the version, so that the version checked
+    /// against the anti-rollback counter is the one the image was signed with.
+    pub fn signed_bytes(&self) -> [u8; DIGEST_SIZE + 2] {
+        let mut bytes = [0; DIGEST_SIZE + 2];
+        bytes[..DIGEST_SIZE].copy_from_slice(&self.digest);
+        bytes[DIGEST_SIZE..].copy_from_slice(&self.version.to_le_bytes());
+        bytes
+    }
+}
+
+/// How the running image was booted, as reported by `get_fw_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BootStatus {
+    /// Booted from the committed image.
+    Normal = 1,
+    /// Booted a new image on trial, the next reset returns to the committed one unless committed.
+    Trial = 2,
+    /// Booted from the committed image, with an update staged for the next reset.
+    Staged = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum UpdateState {
+    #[default]
+    Idle = 0,
+    Receiving = 1,
+    Staged = 2,
+    Failed = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateError {
+    /// The running image is on trial, commit or roll it back first.
+    Busy,
+    /// The image does not fit a slot, or a chunk goes past its end.
+    InvalidImage,
+    /// Chunks must arrive in order.
+    OutOfOrder,
+    /// The command does not apply in the current state.
+    NotReady,
+    Flash(FlashError),
+    VerificationFailed,
+    /// The image version is below the anti-rollback counter of the platform.
+    Rollback,
+}
+
+impl From<FlashError> for UpdateError {
+    fn from(e: FlashError) -> Self {
+        UpdateError::Flash(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UpdateProgress {
+    pub state: UpdateState,
+    pub received: u32,
+    pub size: u32,
+}
+
+/// Firmware update as driven by FwMgmt.
+pub trait FirmwareUpdater {
+    fn boot_status(&self) -> BootStatus;
+    /// Version of the running image.
+    fn version(&self) -> u16;
+    fn progress(&self) -> UpdateProgress;
+    /// Start receiving the image described by `header`, dropping any update staged.
+    fn begin(&mut self, header: ImageHeader) -> Result<(), UpdateError>;
+    /// Store the chunk of image at `offset`, which must follow the previous chunk.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), UpdateError>;
+    /// Verify the complete image and stage it for a trial boot.
+    fn end(&mut self) -> Result<(), UpdateError>;
+    /// Keep the image on trial, raising the anti-rollback counter to its version.
+    fn commit(&mut self) -> Result<(), UpdateError>;
+    /// Abort the update received or staged, or give up the image on trial.
+    fn rollback(&mut self) -> Result<(), UpdateError>;
+}
+
+/// For FwMgmt without firmware update.
+pub enum NoFirmwareUpdate {}
+
+impl FirmwareUpdater for NoFirmwareUpdate {
+    fn boot_status(&self) -> BootStatus {
+        match *self {}
+    }
+
+    fn version(&self) -> u16 {
+        match *self {}
+    }
+
+    fn progress(&self) -> UpdateProgress {
+        match *self {}
+    }
+
+    fn begin(&mut self, _header: ImageHeader) -> Result<(), UpdateError> {
+        match *self {}
+    }
+
+    fn write(&mut self, _offset: u32, _data: &[u8]) -> Result<(), UpdateError> {
+        match *self {}
+    }
+
+    fn end(&mut self) -> Result<(), UpdateError> {
+        match *self {}
+    }
+
+    fn commit(&mut self) -> Result<(), UpdateError> {
+        match *self {}
+    }
+
+    fn rollback(&mut self) -> Result<(), UpdateError> {
+        match *self {}
+    }
+}
+
+/// Update of the slot not running in `F`, verified by `V`.
+pub struct AbUpdate<F, V> {
+    flash: F,
+    verifier: V,
+    security: Option<&'static dyn SecurityState>,
+    record: BootRecord,
+    header: Option<ImageHeader>,
+    state: UpdateState,
+    received: u32,
+}
+
+impl<F: FlashStorage, V: ImageVerifier> AbUpdate<F, V> {
+    pub fn new(mut flash: F, verifier: V) -> Self {
+        let running = flash.running_slot();
+        let record = flash.boot_record().unwrap_or_else(|e| {
+            warn!("No boot record ({:?}), committing slot {:?}", e, running);
+            BootRecord {
+                active: running,
+                trial: None,
+                versions: [0; 2],
+            }
+        });
+        info!("Booted slot {:?}, boot record {:?}", running, record);
+
+        Self {
+            flash,
+            verifier,
+            security: None,
+            record,
+            header: None,
+            state: UpdateState::Idle,
+            received: 0,
+        }
+    }
+
+    /// Reject images older than the anti-rollback counter of `security`, and raise it to the
+    /// version of the images committed.
+    pub fn with_security_state(mut self, security: &'static dyn SecurityState) -> Self {
+        self.security = Some(security);
+        self
+    }
+
+    fn save_record(&mut self, record: BootRecord) -> Result<(), UpdateError> {
+        self.flash.set_boot_record(&record)?;
+        self.record = record;
+        Ok(())
+    }
+
+    fn fail(&mut self, e: UpdateError) -> UpdateError {
+        error!("Firmware update failed: {:?}", e);
+        self.state = UpdateState::Failed;
+        e
+    }
+
+    /// Digest the received image as read back from flash.
+    fn verify_staged(&mut self, header: &ImageHeader) -> Result<(), UpdateError> {
+        let slot = self.flash.running_slot().other();
+        let mut chunk = [0; VERIFY_CHUNK_SIZE];
+        self.verifier.begin();
+        for offset in (0..header.size as usize).step_by(VERIFY_CHUNK_SIZE) {
+            let len = (header.size as usize - offset).min(VERIFY_CHUNK_SIZE);
+            self.flash.read(slot, offset, &mut chunk[..len])?;
+            self.verifier.update(&chunk[..len]);
+        }
+
+        if self.verifier.verify(header) {
+            Ok(())
+        } else {
+            Err(UpdateError::VerificationFailed)
+        }
+    }
+
+    /// Whether images of `version` may be installed, given the anti-rollback counter.
+    fn check_rollback(&self, version: u16) -> Result<(), UpdateError> {
+        let Some(counter) = self.security.map(|security| security.anti_rollback_counter()) else {
+            return Ok(());
+        };
+        if (version as u32) < counter {
+            warn!(
+                "Image version {:#x} below anti-rollback counter {:#x}",
+                version, counter
+            );
+            return Err(UpdateError::Rollback);
+        }
+        Ok(())
+    }
+}
+
+impl<F: FlashStorage, V: ImageVerifier> FirmwareUpdater for AbUpdate<F, V> {
+    fn boot_status(&self) -> BootStatus {
+        if self.flash.running_slot() != self.record.active {
+            BootStatus::Trial
+        } else if self.record.trial.is_some() {
+            BootStatus::Staged
+        } else {
+            BootStatus::Normal
+        }
+    }
+
+    fn version(&self) -> u16 {
+        self.record.versions[self.flash.running_slot().index()]
+    }
+
+    fn progress(&self) -> UpdateProgress {
+        UpdateProgress {
+            state: self.state,
+            received: self.received,
+            size: self.header.map_or(0, |header| header.size),
+        }
+    }
+
+    fn begin(&mut self, header: ImageHeader) -> Result<(), UpdateError> {
+        if self.boot_status() == BootStatus::Trial {
+            return Err(UpdateError::Busy);
+        }
+        if header.size == 0 || header.size as usize > self.flash.slot_size() {
+            return Err(UpdateError::InvalidImage);
+        }
+        // Checked again once the signature vouches for the version
+        self.check_rollback(header.version)?;
+
+        // The slot no longer holds a bootable image from here on
+        let slot = self.flash.running_slot().other();
+        let mut record = self.record;
+        record.trial = None;
+        record.versions[slot.index()] = 0;
+        self.save_record(record)?;
+        self.flash.erase(slot).map_err(|e| self.fail(e.into()))?;
+
+        info!(
+            "Receiving {} byte image version {:#x} into slot {:?}",
+            header.size, header.version, slot
+        );
+        self.header = Some(header);
+        self.state = UpdateState::Receiving;
+        self.received = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), UpdateError> {
+        let (UpdateState::Receiving, Some(header)) = (self.state, self.header) else {
+            return Err(UpdateError::NotReady);
+        };
+        if offset != self.received {
+            return Err(UpdateError::OutOfOrder);
+        }
+        if offset as usize + data.len() > header.size as usize {
+            return Err(UpdateError::InvalidImage);
+        }
+
+        let slot = self.flash.running_slot().other();
+        self.flash
+            .write(slot, offset as usize, data)
+            .map_err(|e| self.fail(e.into()))?;
+        self.received += data.len() as u32;
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), UpdateError> {
+        let (UpdateState::Receiving, Some(header)) = (self.state, self.header) else {
+            return Err(UpdateError::NotReady);
+        };
+        if self.received != header.size {
+            return Err(UpdateError::NotReady);
+        }
+
+        self.verify_staged(&header).map_err(|e| self.fail(e))?;
+        self.check_rollback(header.version).map_err(|e| self.fail(e))?;
+        let slot = self.flash.running_slot().other();
+        let mut record = self.record;
+        record.trial = Some(slot);
+        record.versions[slot.index()] = header.version;
+        self.save_record(record).map_err(|e| self.fail(e))?;
+
+        info!("Image version {:#x} staged in slot {:?}", header.version, slot);
+        self.state = UpdateState::Staged;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), UpdateError> {
+        if self.boot_status() != BootStatus::Trial {
+            return Err(UpdateError::NotReady);
+        }
+
+        let running = self.flash.running_slot();
+        let mut record = self.record;
+        record.active = running;
+        self.save_record(record)?;
+        info!("Committed image version {:#x} in slot {:?}", self.version(), running);
+        if let Some(security) = self.security {
+            security.raise_anti_rollback_counter(self.version() as u32);
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), UpdateError> {
+        match self.state {
+            UpdateState::Receiving | UpdateState::Failed => {
+                info!("Firmware update aborted");
+                self.state = UpdateState::Idle;
+                return Ok(());
+            }
+            UpdateState::Staged | UpdateState::Idle if self.record.trial.is_some() => {
+                let mut record = self.record;
+                record.trial = None;
+                self.save_record(record)?;
+                info!("Staged update dropped");
+                self.state = UpdateState::Idle;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if self.boot_status() != BootStatus::Trial {
+            return Err(UpdateError::NotReady);
+        }
+        // The bootloader already cleared the trial, mark the image so it is not taken for committed
+        let running = self.flash.running_slot();
+        let mut record = self.record;
+        record.versions[running.index()] = 0;
+        self.save_record(record)?;
+        info!(
+            "Image on trial given up, slot {:?} boots at the next reset",
+            record.active
+        );
+        Ok(())
+    }
+}
+
+/// Slots in RAM, for platforms without flash for the EC image and for tests.
+pub struct RamFlash<const N: usize> {
+    slots: [[u8; N]; 2],
+    record: Option<BootRecord>,
+    running: Slot,
+}
+
+impl<const N: usize> RamFlash<N> {
+    /// Slots as booted from `running`, before any boot record is written.
+    pub const fn new(running: Slot) -> Self {
+        Self {
+            slots: [[0xff; N]; 2],
+            record: None,
+            running,
+        }
+    }
+
+    fn range(offset: usize, len: usize) -> Result<core::ops::Range<usize>, FlashError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= N => Ok(offset..end),
+            _ => Err(FlashError::OutOfRange),
+        }
+    }
+}
+
+impl<const N: usize> FlashStorage for RamFlash<N> {
+    fn slot_size(&self) -> usize {
+        N
+    }
+
+    fn running_slot(&self) -> Slot {
+        self.running
+    }
+
+    fn erase(&mut self, slot: Slot) -> Result<(), FlashError> {
+        self.slots[slot.index()].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, slot: Slot, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.slots[slot.index()][Self::range(offset, data.len())?].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, slot: Slot, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        buf.copy_from_slice(&self.slots[slot.index()][Self::range(offset, buf.len())?]);
+        Ok(())
+    }
+
+    fn boot_record(&mut self) -> Result<BootRecord, FlashError> {
+        self.record.ok_or(FlashError::Hardware)
+    }
+
+    fn set_boot_record(&mut self, record: &BootRecord) -> Result<(), FlashError> {
+        self.record = Some(*record);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use ed25519_compact::{KeyPair, Seed};
+
+    const IMAGE: [u8; 600] = {
+        let mut image = [0; 600];
+        let mut i = 0;
+        while i < image.len() {
+            image[i] = i as u8;
+            i += 1;
+        }
+        image
+    };
+
+    /// The key pair of the first Ed25519 test vector of RFC 8032.
+    fn test_key() -> KeyPair {
+        let seed = [
+            0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c, 0xc4, 0x44, 0x49,
+            0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60,
+        ];
+        KeyPair::from_seed(Seed::new(seed))
+    }
+
+    fn header(image: &[u8], version: u16) -> ImageHeader {
+        ImageHeader {
+            size: image.len() as u32,
+            version,
+            digest: Sha256::digest(image),
+            signature: [0; SIGNATURE_SIZE],
+        }
+    }
+
+    fn receive(update: &mut impl FirmwareUpdater, image: &[u8]) {
+        for (i, chunk) in image.chunks(96).enumerate() {
+            update.write(i as u32 * 96, chunk).unwrap();
+        }
+    }
+
+    /// Reset into the slot the bootloader picks, as it would.
+    fn reboot(update: AbUpdate<RamFlash<1024>, UnsignedVerifier>) -> AbUpdate<RamFlash<1024>, UnsignedVerifier> {
+        let mut flash = update.flash;
+        let mut record = flash.boot_record().unwrap();
+        flash.running = record.trial.take().unwrap_or(record.active);
+        flash.set_boot_record(&record).unwrap();
+        AbUpdate::new(flash, UnsignedVerifier::new())
+    }
+
+    #[test]
+    fn test_update_commit() {
+        let mut update = AbUpdate::new(RamFlash::<1024>::new(Slot::A), UnsignedVerifier::new());
+        assert_eq!(update.boot_status(), BootStatus::Normal);
+
+        update.begin(header(&IMAGE, 0x0200)).unwrap();
+        assert_eq!(update.write(96, &IMAGE[..96]), Err(UpdateError::OutOfOrder));
+        receive(&mut update, &IMAGE);
+        let progress = update.progress();
+        assert_eq!(
+            (progress.state, progress.received, progress.size),
+            (UpdateState::Receiving, 600, 600)
+        );
+        update.end().unwrap();
+        assert_eq!(update.boot_status(), BootStatus::Staged);
+
+        let mut update = reboot(update);
+        assert_eq!((update.boot_status(), update.version()), (BootStatus::Trial, 0x0200));
+        assert_eq!(update.begin(header(&IMAGE, 0x0300)), Err(UpdateError::Busy));
+        update.commit().unwrap();
+
+        let update = reboot(update);
+        assert_eq!(
+            (update.boot_status(), update.flash.running),
+            (BootStatus::Normal, Slot::B)
+        );
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut update = AbUpdate::new(RamFlash::<1024>::new(Slot::A), UnsignedVerifier::new());
+        assert_eq!(update.begin(header(&[0; 2048], 0x0200)), Err(UpdateError::InvalidImage));
+
+        // A corrupted image is not staged
+        let mut bad = header(&IMAGE, 0x0200);
+        bad.digest[0] ^= 1;
+        update.begin(bad).unwrap();
+        receive(&mut update, &IMAGE);
+        assert_eq!(update.end(), Err(UpdateError::VerificationFailed));
+        assert_eq!(update.progress().state, UpdateState::Failed);
+        update.rollback().unwrap();
+
+        update.begin(header(&IMAGE, 0x0200)).unwrap();
+        receive(&mut update, &IMAGE);
+        update.end().unwrap();
+
+        // An image on trial not committed leaves the previous one to boot
+        let mut update = reboot(update);
+        assert_eq!(update.boot_status(), BootStatus::Trial);
+        update.rollback().unwrap();
+        let update = reboot(update);
+        assert_eq!(
+            (update.boot_status(), update.flash.running),
+            (BootStatus::Normal, Slot::A)
+        );
+    }
+
+    #[test]
+    fn test_signature_checked() {
+        let key = test_key();
+        let mut signed = header(&IMAGE, 0x0200);
+        signed.signature = *key.sk.sign(signed.signed_bytes(), None);
+
+        let mut verifier = Ed25519Verifier::new(*key.pk);
+        verifier.begin();
+        verifier.update(&IMAGE);
+        assert!(verifier.verify(&signed));
+
+        // A bad signature, a good one for another image, or for another version
+        let mut forged = signed;
+        forged.signature[0] ^= 1;
+        verifier.begin();
+        verifier.update(&IMAGE);
+        assert!(!verifier.verify(&forged));
+        verifier.begin();
+        verifier.update(&IMAGE[1..]);
+        assert!(!verifier.verify(&signed));
+        let mut newer = signed;
+        newer.version = 0x0300;
+        verifier.begin();
+        verifier.update(&IMAGE);
+        assert!(!verifier.verify(&newer));
+
+        let mut update = AbUpdate::new(RamFlash::<1024>::new(Slot::A), Ed25519Verifier::new(*key.pk));
+        update.begin(forged).unwrap();
+        receive(&mut update, &IMAGE);
+        assert_eq!(update.end(), Err(UpdateError::VerificationFailed));
+        update.begin(signed).unwrap();
+        receive(&mut update, &IMAGE);
+        update.end().unwrap();
+    }
+
+    #[test]
+    fn test_anti_rollback() {
+        struct Counter(AtomicU32);
+
+        impl SecurityState for Counter {
+            fn secure_boot_enabled(&self) -> bool {
+                true
+            }
+
+            fn debug_locked(&self) -> bool {
+                true
+            }
+
+            fn anti_rollback_counter(&self) -> u32 {
+                self.0.load(Ordering::Relaxed)
+            }
+
+            fn raise_anti_rollback_counter(&self, counter: u32) {
+                self.0.fetch_max(counter, Ordering::Relaxed);
+            }
+        }
+
+        static COUNTER: Counter = Counter(AtomicU32::new(0x0200));
+        let mut update =
+            AbUpdate::new(RamFlash::<1024>::new(Slot::A), UnsignedVerifier::new()).with_security_state(&COUNTER);
+        assert_eq!(update.begin(header(&IMAGE, 0x01ff)), Err(UpdateError::Rollback));
+        assert_eq!(update.progress().state, UpdateState::Idle);
+
+        // Staging an image leaves the counter, committing it raises the counter to its version
+        update.begin(header(&IMAGE, 0x0300)).unwrap();
+        receive(&mut update, &IMAGE);
+        update.end().unwrap();
+        assert_eq!(COUNTER.anti_rollback_counter(), 0x0200);
+        let mut update = reboot(update).with_security_state(&COUNTER);
+        update.commit().unwrap();
+        assert_eq!(COUNTER.anti_rollback_counter(), 0x0300);
+
+        let mut update = reboot(update).with_security_state(&COUNTER);
+        assert_eq!(update.begin(header(&IMAGE, 0x0200)), Err(UpdateError::Rollback));
+    }
+}
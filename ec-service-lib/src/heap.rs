@@ -0,0 +1,189 @@
+//! Global allocator over a memory region handed over at runtime.
+//!
+//! The heap starts out empty, every allocation fails until [`Heap::init`] gives it a region. It
+//! keeps usage statistics that FwMgmt returns to the OS, and logs allocations that cannot be
+//! satisfied before the allocation error handler takes over.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::ptr::{self, NonNull};
+
+use critical_section::Mutex;
+use log::error;
+
+/// Usage of the heap since it was initialized.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    /// Highest value `used` reached.
+    pub peak_used: usize,
+    /// Number of live allocations.
+    pub allocations: u32,
+    pub failed_allocations: u32,
+    /// Size requested by the most recent failed allocation.
+    pub last_failed_size: usize,
+}
+
+struct State {
+    heap: linked_list_allocator::Heap,
+    stats: HeapStats,
+}
+
+pub struct Heap {
+    state: Mutex<RefCell<State>>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl Heap {
+    pub const fn empty() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(State {
+                heap: linked_list_allocator::Heap::empty(),
+                stats: HeapStats {
+                    size: 0,
+                    used: 0,
+                    peak_used: 0,
+                    allocations: 0,
+                    failed_allocations: 0,
+                    last_failed_size: 0,
+                },
+            })),
+        }
+    }
+
+    /// Hand the region of `size` bytes at `base` over to the heap.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for reads and writes, used for nothing else for the rest of the
+    /// program, and the heap must not have been initialized before.
+    pub unsafe fn init(&self, base: *mut u8, size: usize) {
+        critical_section::with(|cs| {
+            let mut state = self.state.borrow_ref_mut(cs);
+            // SAFETY: Guaranteed by the caller.
+            unsafe { state.heap.init(base, size) };
+            state.stats.size = state.heap.size();
+        });
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        critical_section::with(|cs| self.state.borrow_ref(cs).stats)
+    }
+}
+
+// SAFETY: Allocations are served by `linked_list_allocator`, which returns blocks of the
+// requested layout from the region given to `init`, and all accesses are serialized.
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let result = critical_section::with(|cs| {
+            let mut state = self.state.borrow_ref_mut(cs);
+            let result = state.heap.allocate_first_fit(layout);
+            let used = state.heap.used();
+            let stats = &mut state.stats;
+            match result {
+                Ok(_) => {
+                    stats.used = used;
+                    stats.peak_used = stats.peak_used.max(used);
+                    stats.allocations += 1;
+                }
+                Err(()) => {
+                    stats.failed_allocations += 1;
+                    stats.last_failed_size = layout.size();
+                }
+            }
+            result.map_err(|()| *stats)
+        });
+
+        match result {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(stats) => {
+                error!(
+                    "Failed to allocate {} bytes aligned to {}, {} of {} bytes in use",
+                    layout.size(),
+                    layout.align(),
+                    stats.used,
+                    stats.size
+                );
+                ptr::null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        critical_section::with(|cs| {
+            let mut state = self.state.borrow_ref_mut(cs);
+            // SAFETY: The caller passes a block returned by `alloc` with the same layout, so the
+            // pointer is not null.
+            unsafe { state.heap.deallocate(NonNull::new_unchecked(ptr), layout) };
+            state.stats.used = state.heap.used();
+            state.stats.allocations -= 1;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(16))]
+    struct Region([u8; 4096]);
+
+    fn heap(region: &mut Region) -> Heap {
+        let heap = Heap::empty();
+        // SAFETY: The region outlives the heap in every test.
+        unsafe { heap.init(region.0.as_mut_ptr(), region.0.len()) };
+        heap
+    }
+
+    #[test]
+    fn test_stats_track_usage() {
+        let mut region = Region([0; 4096]);
+        let heap = heap(&mut region);
+        assert_eq!(heap.stats().size, 4096);
+
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        // SAFETY: The layout has a non-zero size.
+        let a = unsafe { heap.alloc(layout) };
+        // SAFETY: The layout has a non-zero size.
+        let b = unsafe { heap.alloc(layout) };
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(heap.stats().used, 512);
+        assert_eq!(heap.stats().allocations, 2);
+
+        // SAFETY: Both blocks were allocated above with this layout.
+        unsafe {
+            heap.dealloc(a, layout);
+            heap.dealloc(b, layout);
+        }
+        let stats = heap.stats();
+        assert_eq!((stats.used, stats.peak_used, stats.allocations), (0, 512, 0));
+    }
+
+    #[test]
+    fn test_failed_allocation_is_counted() {
+        let mut region = Region([0; 4096]);
+        let heap = heap(&mut region);
+
+        let layout = Layout::from_size_align(8192, 8).unwrap();
+        // SAFETY: The layout has a non-zero size.
+        assert!(unsafe { heap.alloc(layout) }.is_null());
+
+        let stats = heap.stats();
+        assert_eq!((stats.failed_allocations, stats.last_failed_size), (1, 8192));
+        assert_eq!((stats.used, stats.allocations), (0, 0));
+    }
+
+    #[test]
+    fn test_uninitialized_heap_fails() {
+        let heap = Heap::empty();
+        // SAFETY: The layout has a non-zero size.
+        assert!(unsafe { heap.alloc(Layout::new::<u64>()) }.is_null());
+        assert_eq!(heap.stats().failed_allocations, 1);
+    }
+}
@@ -0,0 +1,117 @@
+//! Identity of the firmware image and of the board it runs on.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Version {
+    /// The version as reported by `get_fw_state`, major in the high byte and minor in the low byte.
+    pub fn packed(&self) -> u16 {
+        (self.major.min(0xff) << 8) | self.minor.min(0xff)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Build metadata of the firmware image, see [`firmware_identity!`](crate::firmware_identity).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareIdentity {
+    pub name: &'static str,
+    pub version: Version,
+    pub git_revision: &'static str,
+    pub build_time: &'static str,
+    /// Cargo features the image was built with, comma separated.
+    pub features: &'static str,
+}
+
+impl fmt::Display for FirmwareIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}) built {}",
+            self.name, self.version, self.git_revision, self.build_time
+        )?;
+        if !self.features.is_empty() {
+            write!(f, " features {}", self.features)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a decimal version component at compile time.
+#[doc(hidden)]
+pub const fn parse_version_component(s: &str) -> u16 {
+    let bytes = s.as_bytes();
+    let mut value = 0u16;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "Version components must be decimal");
+        value = value * 10 + (bytes[i] - b'0') as u16;
+        i += 1;
+    }
+    value
+}
+
+/// The [`FirmwareIdentity`] of the crate invoking the macro.
+///
+/// The name and version come from its manifest. Its build script must set `BUILD_TIME`,
+/// `GIT_REVISION` and `FEATURES` with `cargo:rustc-env`.
+#[macro_export]
+macro_rules! firmware_identity {
+    () => {
+        $crate::identity::FirmwareIdentity {
+            name: env!("CARGO_PKG_NAME"),
+            version: $crate::identity::Version {
+                major: $crate::identity::parse_version_component(env!("CARGO_PKG_VERSION_MAJOR")),
+                minor: $crate::identity::parse_version_component(env!("CARGO_PKG_VERSION_MINOR")),
+                patch: $crate::identity::parse_version_component(env!("CARGO_PKG_VERSION_PATCH")),
+            },
+            git_revision: env!("GIT_REVISION"),
+            build_time: env!("BUILD_TIME"),
+            features: env!("FEATURES"),
+        }
+    };
+}
+
+/// Identification of the board, provided by the platform.
+pub trait BoardInfo: Sync {
+    /// Board id reported by `get_bid`.
+    fn board_id(&self) -> u64;
+    /// Stock keeping unit of the board, among the variants of a board id.
+    fn sku(&self) -> u32;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        const IDENTITY: FirmwareIdentity = FirmwareIdentity {
+            name: "ec",
+            version: Version {
+                major: parse_version_component("1"),
+                minor: parse_version_component("12"),
+                patch: parse_version_component("3"),
+            },
+            git_revision: "0123456789ab",
+            build_time: "2025-01-01T00:00:00+00:00",
+            features: "time-driver",
+        };
+
+        assert_eq!(IDENTITY.version.packed(), 0x010c);
+        assert_eq!(
+            IDENTITY.to_string(),
+            "ec 1.12.3 (0123456789ab) built 2025-01-01T00:00:00+00:00 features time-driver"
+        );
+    }
+}
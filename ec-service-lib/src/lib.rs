@@ -1,12 +1,40 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod access;
+pub mod address_space;
+pub mod attestation;
+pub mod crash_dump;
+mod crc32;
+pub mod fan;
+pub mod fw_update;
+#[cfg(feature = "alloc")]
+pub mod heap;
+pub mod identity;
+mod managed_exit;
+pub mod mem_share;
+pub mod middleware;
+pub mod persist;
 mod service;
 pub mod services;
+pub mod sha256;
 pub mod sp_logger;
+pub mod stats;
+#[cfg(feature = "alloc")]
+pub mod table;
+#[cfg(test)]
+mod test_support;
+
+use core::pin::pin;
 
 use log::{debug, error, info};
-use odp_ffa::{Function, MsgSendDirectReq2, MsgSendDirectResp2, MsgWait, RxTxMap, TryFromSmcCall};
+use managed_exit::{interrupted_response, Checkpoint, Handled, Retry};
+use odp_ffa::{Function, FunctionId, MsgSendDirectReq2, MsgSendDirectResp2, MsgWait, RxTxMap, TryFromSmcCall};
+
+pub use managed_exit::{ManagedExitSource, NoManagedExit, NsInterruptPolicy};
 pub use service::{Result, Service, ServiceNode, ServiceNodeHandler, ServiceNodeNone};
 
 // For reference, here are the UUIDs for services that ec-service-lib defines (not all of them are implemented)
@@ -63,30 +91,80 @@ impl HafEcService {
     }
 }
 
-async fn async_msg_loop(
+async fn async_msg_loop<M: ManagedExitSource>(
     mut handler: impl AsyncFnMut(MsgSendDirectReq2) -> core::result::Result<MsgSendDirectResp2, odp_ffa::Error>,
     mut before_handle_message: impl AsyncFnMut(&MsgSendDirectReq2) -> core::result::Result<(), odp_ffa::Error>,
+    policy: &NsInterruptPolicy<M>,
 ) -> core::result::Result<(), odp_ffa::Error> {
     info!("async_msg_loop: start");
     let mut msg = MsgWait::new().exec()?;
-    info!("async_msg_loop: msg: {:?}", msg);
+    debug!("async_msg_loop: msg: {:?}", msg);
     loop {
         msg = if let Ok(request) = MsgSendDirectReq2::try_from_smc_call(msg.clone()) {
-            info!("async_msg_loop: request: {:?}", request);
+            debug!("async_msg_loop: request: {:?}", request);
             before_handle_message(&request).await?;
-            match handler(request).await {
-                Ok(response) => {
-                    info!("async_msg_loop: response: {:?}", response);
-                    response.exec()?
-                }
-                Err(e) => {
-                    error!("Error handling FFA message: {:?}", e);
-                    MsgWait::new().exec()?
+            let mut handling = pin!(handler(request.clone()));
+            match policy.run(handling.as_mut()).await {
+                Handled::Completed(result) => send_result(result)?,
+                Handled::Interrupted => {
+                    info!("async_msg_loop: managed exit, checkpointing request");
+                    let mut checkpoint = Checkpoint::default();
+                    checkpoint.save(request.clone());
+                    let mut msg = interrupted_response(&request).exec()?;
+
+                    // The handler is kept as it was interrupted, and polled again rather than
+                    // restarted, until the normal world moves on to another request
+                    loop {
+                        if msg.id == FunctionId::MsgRun {
+                            // The normal world lent us CPU time, use it to finish the request
+                            if checkpoint.pending().is_some() {
+                                info!("async_msg_loop: resuming checkpointed request");
+                                match policy.run(handling.as_mut()).await {
+                                    Handled::Completed(result) => checkpoint.complete(result),
+                                    Handled::Interrupted => info!("async_msg_loop: resumed request interrupted again"),
+                                }
+                            }
+                            msg = MsgWait::new().exec()?;
+                        } else if let Ok(retry) = MsgSendDirectReq2::try_from_smc_call(msg.clone()) {
+                            match checkpoint.retry(&retry) {
+                                Some(Retry::Completed(result)) => {
+                                    info!("async_msg_loop: completing resumed request");
+                                    break send_result(result)?;
+                                }
+                                Some(Retry::Pending) => {
+                                    info!("async_msg_loop: retry of a request still pending");
+                                    msg = interrupted_response(&retry).exec()?;
+                                }
+                                // Another request, the interrupted one is dropped
+                                None => break msg,
+                            }
+                        } else {
+                            error!("Unexpected FFA message: {:?}", msg);
+                            msg = MsgWait::new().exec()?;
+                        }
+                    }
                 }
             }
+        } else if msg.id == FunctionId::MsgRun {
+            // Nothing was interrupted, give the CPU time back
+            MsgWait::new().exec()?
         } else {
             error!("Unexpected FFA message: {:?}", msg);
             MsgWait::new().exec()?
+        };
+    }
+}
+
+/// Send the response of a request, or wait for the next message if it failed.
+fn send_result(result: Result<MsgSendDirectResp2>) -> odp_ffa::ExecResult<<MsgWait as Function>::ReturnType> {
+    match result {
+        Ok(response) => {
+            debug!("async_msg_loop: response: {:?}", response);
+            response.exec()
+        }
+        Err(e) => {
+            error!("Error handling FFA message: {:?}", e);
+            MsgWait::new().exec()
         }
     }
 }
@@ -0,0 +1,249 @@
+//! Handling of non-secure interrupts that arrive while a request is in progress.
+//!
+//! With `ns-interrupts-action = <1>` in the partition manifest, the SPMC does not preempt the
+//! partition when a non-secure interrupt fires. It raises the managed-exit virtual interrupt
+//! instead and expects the partition to hand the CPU back promptly by completing the current
+//! direct request. The message loop does so by replying with [`ErrorCode::Interrupted`] and
+//! keeping the request as a [`Checkpoint`]: the work is resumed on the next `FFA_RUN`, and the
+//! result is returned when the normal world re-sends the same request. A retry that arrives
+//! before the work has completed is answered with [`ErrorCode::Interrupted`] again.
+//!
+//! The message loop keeps the future of the interrupted handler and polls it again on `FFA_RUN`,
+//! so the work done before the managed exit is not repeated. A request other than the retry
+//! drops it, the interrupted request then does not complete.
+//!
+//! The managed exit is only noticed while the handler awaits: the executor cannot preempt a
+//! handler that computes without awaiting, and the exit is then delayed until the handler
+//! returns. Handlers doing long work should await
+//! [`yield_now`](embassy_futures::yield_now) between steps.
+
+use core::future::Future;
+
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, RegisterPayload};
+
+use crate::Result;
+
+/// Source of managed-exit notifications, typically backed by the managed-exit virtual interrupt.
+pub trait ManagedExitSource {
+    /// Resolves when the SPMC requests a managed exit.
+    fn wait(&self) -> impl Future<Output = ()>;
+
+    /// Discard a managed-exit request that is no longer relevant.
+    fn clear(&self);
+}
+
+/// How the message loop reacts to non-secure interrupts while it handles a request.
+pub enum NsInterruptPolicy<M: ManagedExitSource> {
+    /// No action is taken by the partition, the SPMC queues or signals the interrupt as
+    /// described by the manifest's `ns-interrupts-action`.
+    Signal,
+    /// Requests are checkpointed and completed early when a managed exit is requested, at the
+    /// next point the handler awaits.
+    ManagedExit(M),
+}
+
+/// Placeholder source for platforms that use [`NsInterruptPolicy::Signal`].
+pub struct NoManagedExit;
+
+impl ManagedExitSource for NoManagedExit {
+    async fn wait(&self) {
+        core::future::pending().await
+    }
+
+    fn clear(&self) {}
+}
+
+impl NsInterruptPolicy<NoManagedExit> {
+    pub const fn signal() -> Self {
+        NsInterruptPolicy::Signal
+    }
+}
+
+/// Outcome of handling a request under a [`NsInterruptPolicy`].
+pub(crate) enum Handled {
+    Completed(Result<MsgSendDirectResp2>),
+    Interrupted,
+}
+
+impl<M: ManagedExitSource> NsInterruptPolicy<M> {
+    pub(crate) async fn run(&self, handler: impl Future<Output = Result<MsgSendDirectResp2>>) -> Handled {
+        match self {
+            NsInterruptPolicy::Signal => Handled::Completed(handler.await),
+            NsInterruptPolicy::ManagedExit(source) => {
+                // Anything raised before this request started does not concern it
+                source.clear();
+                match embassy_futures::select::select(handler, source.wait()).await {
+                    embassy_futures::select::Either::First(result) => Handled::Completed(result),
+                    embassy_futures::select::Either::Second(()) => Handled::Interrupted,
+                }
+            }
+        }
+    }
+}
+
+/// Response sent in place of the real one when a request is cut short by a managed exit.
+pub(crate) fn interrupted_response(request: &MsgSendDirectReq2) -> MsgSendDirectResp2 {
+    let status = ErrorCode::Interrupted as i64;
+    MsgSendDirectResp2::from_req_with_payload(request, RegisterPayload::from_iter(status.to_le_bytes()))
+}
+
+/// What a retry of the checkpointed request is answered with.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Retry {
+    /// The result of the request, resumed to completion.
+    Completed(Result<MsgSendDirectResp2>),
+    /// The request has not completed yet.
+    Pending,
+}
+
+/// A request interrupted by a managed exit, and its result once resumed to completion.
+#[derive(Default)]
+pub(crate) struct Checkpoint {
+    request: Option<MsgSendDirectReq2>,
+    result: Option<Result<MsgSendDirectResp2>>,
+}
+
+impl Checkpoint {
+    pub(crate) fn save(&mut self, request: MsgSendDirectReq2) {
+        self.request = Some(request);
+        self.result = None;
+    }
+
+    /// The interrupted request, if it still needs to run.
+    pub(crate) fn pending(&self) -> Option<&MsgSendDirectReq2> {
+        match self.result {
+            None => self.request.as_ref(),
+            Some(_) => None,
+        }
+    }
+
+    pub(crate) fn complete(&mut self, result: Result<MsgSendDirectResp2>) {
+        if self.request.is_some() {
+            self.result = Some(result);
+        }
+    }
+
+    /// Answer `request` from the checkpoint if it is the retry of the checkpointed request.
+    ///
+    /// A retry that arrives before the result is ready leaves the checkpoint for the next
+    /// `FFA_RUN` to complete. The result is handed out once, and any other request discards the
+    /// checkpoint: the normal world moved on.
+    pub(crate) fn retry(&mut self, request: &MsgSendDirectReq2) -> Option<Retry> {
+        if self.request.as_ref() != Some(request) {
+            self.request = None;
+            self.result = None;
+            return None;
+        }
+
+        match self.result.take() {
+            Some(result) => {
+                self.request = None;
+                Some(Retry::Completed(result))
+            }
+            None => Some(Retry::Pending),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::uuid;
+
+    fn request(cmd: u8) -> MsgSendDirectReq2 {
+        MsgSendDirectReq2::new(
+            1,
+            0x8002,
+            uuid!("31f56da7-593c-4d72-a4b3-8fc7171ac073"),
+            RegisterPayload::from_iter([cmd]),
+        )
+    }
+
+    #[test]
+    fn test_retry_returns_completed_result() {
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.save(request(1));
+        assert_eq!(checkpoint.pending(), Some(&request(1)));
+
+        let response = MsgSendDirectResp2::from_req_with_payload(&request(1), RegisterPayload::from_iter([0xaa]));
+        checkpoint.complete(Ok(response.clone()));
+        assert_eq!(checkpoint.pending(), None);
+
+        assert_eq!(checkpoint.retry(&request(1)), Some(Retry::Completed(Ok(response))));
+        assert_eq!(checkpoint.retry(&request(1)), None);
+    }
+
+    #[test]
+    fn test_retry_before_completion_keeps_checkpoint() {
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.save(request(1));
+
+        assert_eq!(checkpoint.retry(&request(1)), Some(Retry::Pending));
+        assert_eq!(checkpoint.pending(), Some(&request(1)));
+
+        let response = MsgSendDirectResp2::from_req_with_payload(&request(1), RegisterPayload::from_iter([0xaa]));
+        checkpoint.complete(Ok(response.clone()));
+        assert_eq!(checkpoint.retry(&request(1)), Some(Retry::Completed(Ok(response))));
+    }
+
+    #[test]
+    fn test_other_request_discards_checkpoint() {
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.save(request(1));
+        checkpoint.complete(Err(odp_ffa::Error::Other("failed")));
+
+        assert_eq!(checkpoint.retry(&request(2)), None);
+        assert_eq!(checkpoint.retry(&request(1)), None);
+        assert_eq!(checkpoint.pending(), None);
+    }
+
+    #[test]
+    fn test_signal_policy_runs_to_completion() {
+        let policy = NsInterruptPolicy::signal();
+        let handled = embassy_futures::block_on(policy.run(async { Err(odp_ffa::Error::Other("done")) }));
+        assert!(matches!(handled, Handled::Completed(Err(_))));
+    }
+
+    #[test]
+    fn test_managed_exit_interrupts_pending_request() {
+        struct Immediate;
+        impl ManagedExitSource for Immediate {
+            async fn wait(&self) {}
+            fn clear(&self) {}
+        }
+
+        let policy = NsInterruptPolicy::ManagedExit(Immediate);
+        let handled = embassy_futures::block_on(policy.run(core::future::pending()));
+        assert!(matches!(handled, Handled::Interrupted));
+    }
+
+    #[test]
+    fn test_managed_exit_resumes_handler() {
+        use core::cell::Cell;
+
+        /// Requests a managed exit once.
+        struct Once(Cell<bool>);
+        impl ManagedExitSource for Once {
+            async fn wait(&self) {
+                if !self.0.replace(false) {
+                    core::future::pending().await
+                }
+            }
+            fn clear(&self) {}
+        }
+
+        let starts = Cell::new(0);
+        let policy = NsInterruptPolicy::ManagedExit(Once(Cell::new(true)));
+        let mut handling = core::pin::pin!(async {
+            starts.set(starts.get() + 1);
+            embassy_futures::yield_now().await;
+            Err(odp_ffa::Error::Other("done"))
+        });
+
+        let handled = embassy_futures::block_on(policy.run(handling.as_mut()));
+        assert!(matches!(handled, Handled::Interrupted));
+        let handled = embassy_futures::block_on(policy.run(handling.as_mut()));
+        assert!(matches!(handled, Handled::Completed(Err(_))));
+        assert_eq!(starts.get(), 1);
+    }
+}
@@ -0,0 +1,252 @@
+//! Retrieval of the memory the normal world shares with the partition.
+//!
+//! The OS shares memory with `FFA_MEM_SHARE` and passes the handle it got to the partition,
+//! which retrieves the share from the SPMC with `FFA_MEM_RETRIEVE_REQ`. The request and the
+//! response are memory transaction descriptors, exchanged through the partition's RX/TX buffers
+//! and laid out as in FF-A v1.1 onwards. Only the regions the response describes are mapped.
+
+use log::warn;
+use odp_ffa::{Function, MemRetrieveReq, RxRelease};
+
+use crate::address_space::{AddressSpaceError, MemoryMapper, PAGE_SIZE};
+
+/// Regions of a share retrieved at most.
+pub const MAX_REGIONS: usize = 8;
+
+const TRANSACTION_HEADER_SIZE: usize = 48;
+const ACCESS_DESCRIPTOR_SIZE: usize = 16;
+const COMPOSITE_HEADER_SIZE: usize = 16;
+const CONSTITUENT_SIZE: usize = 16;
+
+/// Bytes of a retrieve request for a single receiver.
+pub const RETRIEVE_REQUEST_SIZE: usize = TRANSACTION_HEADER_SIZE + ACCESS_DESCRIPTOR_SIZE;
+/// Bytes of a retrieve response describing [`MAX_REGIONS`] regions.
+const MAX_RESPONSE_SIZE: usize = RETRIEVE_REQUEST_SIZE + COMPOSITE_HEADER_SIZE + MAX_REGIONS * CONSTITUENT_SIZE;
+
+/// Pages of shared memory, contiguous in the physical address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub address: u64,
+    pub page_count: u32,
+}
+
+impl MemoryRegion {
+    pub fn size(&self) -> u64 {
+        self.page_count as u64 * PAGE_SIZE
+    }
+}
+
+pub type Regions = heapless::Vec<MemoryRegion, MAX_REGIONS>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetrieveError {
+    /// The SPMC rejected the request.
+    Ffa(odp_ffa::Error),
+    /// The response does not fit in the RX buffer at once, which is not supported.
+    Fragmented,
+    /// The response is not a valid descriptor, or describes more than [`MAX_REGIONS`] regions.
+    Malformed,
+    /// The RX/TX buffers are not accessible.
+    Mailbox(AddressSpaceError),
+}
+
+impl From<odp_ffa::Error> for RetrieveError {
+    fn from(e: odp_ffa::Error) -> Self {
+        RetrieveError::Ffa(e)
+    }
+}
+
+impl From<AddressSpaceError> for RetrieveError {
+    fn from(e: AddressSpaceError) -> Self {
+        RetrieveError::Mailbox(e)
+    }
+}
+
+/// Retrieves the memory shared with the partition.
+pub trait MemoryRetriever: Sync {
+    /// The regions `sender` shared with `receiver` under `handle`.
+    fn retrieve(&self, handle: u64, sender: u16, receiver: u16) -> Result<Regions, RetrieveError>;
+}
+
+/// The request to retrieve the share `handle` of `sender` for `receiver`, with the attributes
+/// and permissions the sender gave.
+pub fn retrieve_request(handle: u64, sender: u16, receiver: u16) -> [u8; RETRIEVE_REQUEST_SIZE] {
+    let mut request = [0; RETRIEVE_REQUEST_SIZE];
+    request[0..2].copy_from_slice(&sender.to_le_bytes());
+    request[8..16].copy_from_slice(&handle.to_le_bytes());
+    request[24..28].copy_from_slice(&(ACCESS_DESCRIPTOR_SIZE as u32).to_le_bytes());
+    request[28..32].copy_from_slice(&1u32.to_le_bytes());
+    request[32..36].copy_from_slice(&(TRANSACTION_HEADER_SIZE as u32).to_le_bytes());
+    request[TRANSACTION_HEADER_SIZE..][..2].copy_from_slice(&receiver.to_le_bytes());
+    request
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// The regions described by the retrieve response `descriptor`.
+///
+/// Every offset and count is checked against the length of the descriptor, and the regions
+/// must be page aligned and add up to the total page count of the composite descriptor.
+pub fn parse_retrieve_response(descriptor: &[u8]) -> Result<Regions, RetrieveError> {
+    let access_size = u32_at(descriptor, 24).ok_or(RetrieveError::Malformed)? as usize;
+    let access_count = u32_at(descriptor, 28).ok_or(RetrieveError::Malformed)?;
+    let access_offset = u32_at(descriptor, 32).ok_or(RetrieveError::Malformed)? as usize;
+    if access_count == 0 || access_size < ACCESS_DESCRIPTOR_SIZE {
+        return Err(RetrieveError::Malformed);
+    }
+
+    // All receivers share the same composite descriptor, the first one points at it
+    let composite = u32_at(descriptor, access_offset.saturating_add(4)).ok_or(RetrieveError::Malformed)? as usize;
+    let total_pages = u32_at(descriptor, composite).ok_or(RetrieveError::Malformed)?;
+    let range_count = u32_at(descriptor, composite.saturating_add(4)).ok_or(RetrieveError::Malformed)? as usize;
+    if range_count == 0 || range_count > MAX_REGIONS {
+        return Err(RetrieveError::Malformed);
+    }
+
+    let mut regions = Regions::new();
+    let mut pages = 0u64;
+    for i in 0..range_count {
+        let offset = composite + COMPOSITE_HEADER_SIZE + i * CONSTITUENT_SIZE;
+        let address = u64_at(descriptor, offset).ok_or(RetrieveError::Malformed)?;
+        let page_count = u32_at(descriptor, offset + 8).ok_or(RetrieveError::Malformed)?;
+        let region = MemoryRegion { address, page_count };
+        if page_count == 0 || !address.is_multiple_of(PAGE_SIZE) || address.checked_add(region.size()).is_none() {
+            return Err(RetrieveError::Malformed);
+        }
+        pages += page_count as u64;
+        // Bounded by the check of range_count
+        _ = regions.push(region);
+    }
+
+    if pages != total_pages as u64 {
+        return Err(RetrieveError::Malformed);
+    }
+    Ok(regions)
+}
+
+/// Retrieves shares through the RX/TX buffers the partition registered with `FFA_RXTX_MAP`.
+pub struct RxTxRetriever {
+    memory_mapper: &'static dyn MemoryMapper,
+    tx_buffer: u64,
+    rx_buffer: u64,
+}
+
+impl RxTxRetriever {
+    /// The buffers are accessed through `memory_mapper`, which must have them mapped.
+    pub const fn new(memory_mapper: &'static dyn MemoryMapper, tx_buffer: u64, rx_buffer: u64) -> Self {
+        Self {
+            memory_mapper,
+            tx_buffer,
+            rx_buffer,
+        }
+    }
+}
+
+impl MemoryRetriever for RxTxRetriever {
+    fn retrieve(&self, handle: u64, sender: u16, receiver: u16) -> Result<Regions, RetrieveError> {
+        let request = retrieve_request(handle, sender, receiver);
+        self.memory_mapper.write(self.tx_buffer, &request)?;
+        let rsp = MemRetrieveReq::from_tx_buffer(request.len() as u32).exec()?;
+
+        let len = rsp.frag_length as usize;
+        let mut response = [0; MAX_RESPONSE_SIZE];
+        let read = match (rsp.total_length == rsp.frag_length, response.get_mut(..len)) {
+            (true, Some(response)) => self
+                .memory_mapper
+                .read(self.rx_buffer, response)
+                .map_err(RetrieveError::from),
+            (false, _) => Err(RetrieveError::Fragmented),
+            (true, None) => Err(RetrieveError::Malformed),
+        };
+        // The RX buffer is ours until released, whatever it holds
+        if let Err(e) = RxRelease::new().exec() {
+            warn!("Failed to release the RX buffer: {:?}", e);
+        }
+
+        read?;
+        parse_retrieve_response(&response[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A retrieve response for `regions`, with a single receiver.
+    fn response(regions: &[(u64, u32)]) -> std::vec::Vec<u8> {
+        let mut descriptor = retrieve_request(0x1234, 0x0, 0x8002).to_vec();
+        let composite = descriptor.len() as u32;
+        descriptor[TRANSACTION_HEADER_SIZE + 4..][..4].copy_from_slice(&composite.to_le_bytes());
+
+        let total_pages: u32 = regions.iter().map(|(_, pages)| pages).sum();
+        descriptor.extend(total_pages.to_le_bytes());
+        descriptor.extend((regions.len() as u32).to_le_bytes());
+        descriptor.extend([0; 8]);
+        for (address, pages) in regions {
+            descriptor.extend(address.to_le_bytes());
+            descriptor.extend(pages.to_le_bytes());
+            descriptor.extend([0; 4]);
+        }
+        descriptor
+    }
+
+    #[test]
+    fn test_retrieve_request() {
+        let request = retrieve_request(0x1122_3344_5566_7788, 0x1, 0x8002);
+        assert_eq!(request[..2], [0x1, 0x0]);
+        assert_eq!(request[8..16], 0x1122_3344_5566_7788u64.to_le_bytes());
+        assert_eq!(u32_at(&request, 28), Some(1));
+        assert_eq!(request[TRANSACTION_HEADER_SIZE..][..2], [0x02, 0x80]);
+    }
+
+    #[test]
+    fn test_parse_regions() {
+        let regions = parse_retrieve_response(&response(&[(0x8000_0000, 2), (0x9000_0000, 1)])).unwrap();
+        assert_eq!(
+            regions,
+            [
+                MemoryRegion {
+                    address: 0x8000_0000,
+                    page_count: 2
+                },
+                MemoryRegion {
+                    address: 0x9000_0000,
+                    page_count: 1
+                },
+            ]
+        );
+        assert_eq!(regions[0].size(), 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        let valid = response(&[(0x8000_0000, 2)]);
+
+        // Truncated, up to the page count of the last region
+        for len in [0, 30, 60, valid.len() - 5] {
+            assert_eq!(parse_retrieve_response(&valid[..len]), Err(RetrieveError::Malformed));
+        }
+
+        // Composite offset out of the descriptor
+        let mut descriptor = valid.clone();
+        descriptor[TRANSACTION_HEADER_SIZE + 4..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse_retrieve_response(&descriptor), Err(RetrieveError::Malformed));
+
+        // Page counts not adding up, unaligned address, too many regions
+        let mut descriptor = valid.clone();
+        descriptor[RETRIEVE_REQUEST_SIZE] += 1;
+        assert_eq!(parse_retrieve_response(&descriptor), Err(RetrieveError::Malformed));
+        let descriptor = response(&[(0x8000_0010, 2)]);
+        assert_eq!(parse_retrieve_response(&descriptor), Err(RetrieveError::Malformed));
+        let descriptor = response(&[(0x8000_0000, 1); MAX_REGIONS + 1]);
+        assert_eq!(parse_retrieve_response(&descriptor), Err(RetrieveError::Malformed));
+    }
+}
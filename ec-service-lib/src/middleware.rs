@@ -0,0 +1,149 @@
+//! Hooks around the dispatch of every request, for concerns shared by the services such as
+//! tracing, latency metrics, validation, rate limiting or fault injection.
+//!
+//! Each [`Middleware`] wraps a [`ServiceNodeHandler`] in a [`Layered`] handler, added with
+//! `with_middleware` on a [`ServiceNode`](crate::ServiceNode) or on another [`Layered`] handler.
+//! The middleware added last sees the requests first and the responses last.
+
+use core::ops::ControlFlow;
+
+use log::{trace, warn};
+use odp_ffa::{MsgSendDirectReq2, MsgSendDirectResp2};
+
+use crate::{Result, ServiceNodeHandler};
+
+pub trait Middleware {
+    /// Called before the request is dispatched. Breaking answers the request with the result
+    /// given, without dispatching it nor calling the other hooks.
+    fn before(&mut self, _msg: &MsgSendDirectReq2) -> ControlFlow<Result<MsgSendDirectResp2>> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called with the response of a request, which it may change.
+    fn after(&mut self, _msg: &MsgSendDirectReq2, _rsp: &mut MsgSendDirectResp2) {}
+
+    /// Called when a request fails with `error` rather than a response.
+    fn on_error(&mut self, _msg: &MsgSendDirectReq2, _error: &odp_ffa::Error) {}
+}
+
+/// A handler running the hooks of `middleware` around `inner`.
+pub struct Layered<M: Middleware, H: ServiceNodeHandler> {
+    middleware: M,
+    inner: H,
+}
+
+impl<M: Middleware, H: ServiceNodeHandler> Layered<M, H> {
+    pub fn new(middleware: M, inner: H) -> Self {
+        Self { middleware, inner }
+    }
+
+    /// Run the hooks of `middleware` around those of this handler.
+    pub fn with_middleware<N: Middleware>(self, middleware: N) -> Layered<N, Self> {
+        Layered::new(middleware, self)
+    }
+}
+
+impl<M: Middleware, H: ServiceNodeHandler> ServiceNodeHandler for Layered<M, H> {
+    async fn handle(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        if let ControlFlow::Break(result) = self.middleware.before(&msg) {
+            return result;
+        }
+
+        match self.inner.handle(msg.clone()).await {
+            Ok(mut rsp) => {
+                self.middleware.after(&msg, &mut rsp);
+                Ok(rsp)
+            }
+            Err(e) => {
+                self.middleware.on_error(&msg, &e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Log every request with its response or error, at the trace level.
+pub struct Trace;
+
+impl Middleware for Trace {
+    fn before(&mut self, msg: &MsgSendDirectReq2) -> ControlFlow<Result<MsgSendDirectResp2>> {
+        trace!("Request from {:#x} to {}: {:?}", msg.source_id(), msg.uuid(), msg);
+        ControlFlow::Continue(())
+    }
+
+    fn after(&mut self, msg: &MsgSendDirectReq2, rsp: &mut MsgSendDirectResp2) {
+        trace!("Response to {:#x} from {}: {:?}", msg.source_id(), msg.uuid(), rsp);
+    }
+
+    fn on_error(&mut self, msg: &MsgSendDirectReq2, error: &odp_ffa::Error) {
+        warn!(
+            "Request from {:#x} to {} failed: {:?}",
+            msg.source_id(),
+            msg.uuid(),
+            error
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request, Echo};
+    use crate::{ServiceNode, ServiceNodeNone};
+    use odp_ffa::{ErrorCode, Payload, RegisterPayload};
+
+    /// Rejects opcode 0 and records the hooks called, in `log`.
+    struct Recorder {
+        name: u8,
+        log: &'static core::cell::RefCell<std::vec::Vec<(u8, &'static str)>>,
+    }
+
+    impl Middleware for Recorder {
+        fn before(&mut self, msg: &MsgSendDirectReq2) -> ControlFlow<Result<MsgSendDirectResp2>> {
+            self.log.borrow_mut().push((self.name, "before"));
+            if msg.u8_at(0) == 0 {
+                let status = ErrorCode::InvalidParameters as i64;
+                let rsp =
+                    MsgSendDirectResp2::from_req_with_payload(msg, RegisterPayload::from_iter(status.to_le_bytes()));
+                return ControlFlow::Break(Ok(rsp));
+            }
+            ControlFlow::Continue(())
+        }
+
+        fn after(&mut self, _msg: &MsgSendDirectReq2, rsp: &mut MsgSendDirectResp2) {
+            self.log.borrow_mut().push((self.name, "after"));
+            // Inject a fault in the responses it sees
+            let payload = RegisterPayload::from_iter([rsp.u8_at(0) | 0x80]);
+            *rsp = MsgSendDirectResp2::new(rsp.source_id(), rsp.destination_id(), rsp.uuid(), payload);
+        }
+
+        fn on_error(&mut self, _msg: &MsgSendDirectReq2, _error: &odp_ffa::Error) {
+            self.log.borrow_mut().push((self.name, "on_error"));
+        }
+    }
+
+    #[test]
+    fn test_hooks_run_in_layers() {
+        let log = std::boxed::Box::leak(std::boxed::Box::default());
+        let mut handler = ServiceNode::new(Echo, ServiceNodeNone)
+            .with_middleware(Recorder { name: 1, log })
+            .with_middleware(Trace)
+            .with_middleware(Recorder { name: 2, log });
+        let request = |opcode| request(Echo::UUID, &[opcode]);
+
+        let rsp = embassy_futures::block_on(handler.handle(request(0x5))).unwrap();
+        assert_eq!(rsp.u8_at(0), 0x85);
+        assert_eq!((rsp.source_id(), rsp.destination_id()), (2, 1));
+        assert_eq!(log.take(), [(2, "before"), (1, "before"), (1, "after"), (2, "after")]);
+
+        let rsp = embassy_futures::block_on(handler.handle(request(0x0))).unwrap();
+        assert_eq!(rsp.u64_at(0) as i64, ErrorCode::InvalidParameters as i64);
+        assert_eq!(log.take(), [(2, "before")]);
+
+        assert!(embassy_futures::block_on(handler.handle(request(0xff))).is_err());
+        assert_eq!(
+            log.take(),
+            [(2, "before"), (1, "before"), (1, "on_error"), (2, "on_error")]
+        );
+    }
+}
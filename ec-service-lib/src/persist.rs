@@ -0,0 +1,641 @@
+//! State of the services kept across restarts.
+//!
+//! Services implementing [`Persist`] keep a record each in a [`PersistentStore`], under their
+//! [`Persist::KEY`]. The store holds the records in RAM and writes them out as a snapshot to an
+//! [`NvStorage`], either on every change or when [`KeyValueStore::flush`] is called, for instance
+//! on power events. Snapshots rotate over the erase blocks of the storage to spread the wear, and
+//! the newest one with a valid CRC and whole records is loaded at boot.
+//!
+//! A snapshot is a header followed by the records:
+//!
+//! | Offset | Size | Field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | Magic, `ECPS`                              |
+//! | 4      | 2    | Format version                             |
+//! | 6      | 2    | Reserved                                   |
+//! | 8      | 4    | Sequence number, incremented every write   |
+//! | 12     | 4    | Length of the records                      |
+//! | 16     | 4    | CRC-32 of the header before it and records |
+//!
+//! Each record is its key (2 bytes), the version of its layout (1 byte), a reserved byte, its
+//! length (2 bytes) and its data.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use log::{error, info, warn};
+
+use crate::address_space::{AddressSpaceError, MemoryMapper};
+use crate::crc32::crc32;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"ECPS");
+const FORMAT_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 20;
+const RECORD_HEADER_SIZE: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvError {
+    OutOfRange,
+    Hardware,
+}
+
+impl From<AddressSpaceError> for NvError {
+    fn from(_: AddressSpaceError) -> Self {
+        NvError::OutOfRange
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistError {
+    /// The store has not been initialized.
+    Uninitialized,
+    /// No record under the key.
+    NotFound,
+    /// The records do not fit in the store.
+    Full,
+    /// A snapshot is being written, the change is written out with the next one.
+    Busy,
+    Storage(NvError),
+}
+
+impl From<NvError> for PersistError {
+    fn from(e: NvError) -> Self {
+        PersistError::Storage(e)
+    }
+}
+
+/// Non-volatile memory holding the snapshots.
+pub trait NvStorage {
+    /// Bytes of storage.
+    fn capacity(&self) -> usize;
+    /// Bytes erased at once. Snapshots start on erase block boundaries.
+    fn erase_size(&self) -> usize;
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), NvError>;
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError>;
+    fn erase(&mut self, offset: usize, len: usize) -> Result<(), NvError>;
+}
+
+fn check_range(offset: usize, len: usize, capacity: usize) -> Result<core::ops::Range<usize>, NvError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= capacity => Ok(offset..end),
+        _ => Err(NvError::OutOfRange),
+    }
+}
+
+/// Storage in RAM, lost at reset, for tests and platforms without non-volatile memory.
+pub struct RamStorage<const N: usize> {
+    data: [u8; N],
+    erase_size: usize,
+}
+
+impl<const N: usize> RamStorage<N> {
+    pub const fn new(erase_size: usize) -> Self {
+        Self {
+            data: [0xff; N],
+            erase_size,
+        }
+    }
+}
+
+impl<const N: usize> NvStorage for RamStorage<N> {
+    fn capacity(&self) -> usize {
+        N
+    }
+
+    fn erase_size(&self) -> usize {
+        self.erase_size
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), NvError> {
+        buf.copy_from_slice(&self.data[check_range(offset, buf.len(), N)?]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError> {
+        self.data[check_range(offset, data.len(), N)?].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase(&mut self, offset: usize, len: usize) -> Result<(), NvError> {
+        self.data[check_range(offset, len, N)?].fill(0xff);
+        Ok(())
+    }
+}
+
+/// Storage in memory mapped through a [`MemoryMapper`], such as memory shared by another
+/// partition that keeps it across restarts of this one.
+pub struct SharedMemoryStorage {
+    mapper: &'static dyn MemoryMapper,
+    address: u64,
+    size: usize,
+    erase_size: usize,
+}
+
+impl SharedMemoryStorage {
+    /// `size` bytes at `address`, which must be mapped writable.
+    pub fn new(mapper: &'static dyn MemoryMapper, address: u64, size: usize, erase_size: usize) -> Self {
+        Self {
+            mapper,
+            address,
+            size,
+            erase_size,
+        }
+    }
+}
+
+impl NvStorage for SharedMemoryStorage {
+    fn capacity(&self) -> usize {
+        self.size
+    }
+
+    fn erase_size(&self) -> usize {
+        self.erase_size
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), NvError> {
+        let range = check_range(offset, buf.len(), self.size)?;
+        Ok(self.mapper.read(self.address + range.start as u64, buf)?)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError> {
+        let range = check_range(offset, data.len(), self.size)?;
+        Ok(self.mapper.write(self.address + range.start as u64, data)?)
+    }
+
+    fn erase(&mut self, offset: usize, len: usize) -> Result<(), NvError> {
+        let range = check_range(offset, len, self.size)?;
+        for offset in range.clone().step_by(64) {
+            let len = (range.end - offset).min(64);
+            self.mapper.write(self.address + offset as u64, &[0xff; 64][..len])?;
+        }
+        Ok(())
+    }
+}
+
+/// A service whose state is kept in a [`KeyValueStore`].
+pub trait Persist {
+    /// Key of the record, unique among the services.
+    const KEY: u16;
+    /// Version of the layout of the record, passed back to `restore`.
+    const VERSION: u8;
+
+    /// Write the state to keep into `buf`, returning its length or `None` if it does not fit.
+    fn save(&self, buf: &mut [u8]) -> Option<usize>;
+    /// Restore the state from a record saved with layout `version`, returning whether it could.
+    fn restore(&mut self, version: u8, record: &[u8]) -> bool;
+}
+
+/// Bytes of the largest record [`save`] and [`restore`] handle.
+pub const MAX_RECORD_SIZE: usize = 1024;
+
+/// Store the state of `service` in `store`.
+pub fn save<P: Persist>(store: &dyn KeyValueStore, service: &P) {
+    let mut buf = [0; MAX_RECORD_SIZE];
+    let Some(len) = service.save(&mut buf) else {
+        error!("Record {:#x} larger than {} bytes", P::KEY, MAX_RECORD_SIZE);
+        return;
+    };
+    if let Err(e) = store.store(P::KEY, P::VERSION, &buf[..len]) {
+        error!("Failed to store record {:#x}: {:?}", P::KEY, e);
+    }
+}
+
+/// Restore the state of `service` from `store`, if it holds a record of it.
+pub fn restore<P: Persist>(store: &dyn KeyValueStore, service: &mut P) {
+    let mut buf = [0; MAX_RECORD_SIZE];
+    if let Some((version, len)) = store.load(P::KEY, &mut buf) {
+        if !service.restore(version, &buf[..len]) {
+            warn!("Record {:#x} version {} not restored", P::KEY, version);
+        }
+    }
+}
+
+/// Records kept by key, as seen by the services.
+pub trait KeyValueStore: Sync {
+    /// Copy the record under `key` into `buf`, returning its version and length.
+    fn load(&self, key: u16, buf: &mut [u8]) -> Option<(u8, usize)>;
+    /// Replace the record under `key`, written out according to the [`SavePolicy`].
+    fn store(&self, key: u16, version: u8, data: &[u8]) -> Result<(), PersistError>;
+    /// Write out the records changed since the last snapshot.
+    fn flush(&self) -> Result<(), PersistError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SavePolicy {
+    /// Write a snapshot on every change.
+    OnChange,
+    /// Write a snapshot on [`KeyValueStore::flush`] only.
+    Deferred,
+}
+
+/// Records in the layout of a snapshot, in `N` bytes.
+struct Records<const N: usize> {
+    data: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Records<N> {
+    /// Whether `data` is a sequence of whole records.
+    fn well_formed(data: &[u8]) -> bool {
+        let mut offset = 0;
+        while offset < data.len() {
+            let Some(header) = data.get(offset..offset + RECORD_HEADER_SIZE) else {
+                return false;
+            };
+            offset += RECORD_HEADER_SIZE + u16::from_le_bytes([header[4], header[5]]) as usize;
+        }
+        offset == data.len()
+    }
+
+    /// Offset, version and length of the record under `key`.
+    fn find(&self, key: u16) -> Option<(usize, u8, usize)> {
+        let mut offset = 0;
+        while offset + RECORD_HEADER_SIZE <= self.len {
+            let header = &self.data[offset..offset + RECORD_HEADER_SIZE];
+            let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+            if u16::from_le_bytes([header[0], header[1]]) == key {
+                return Some((offset, header[2], len));
+            }
+            offset += RECORD_HEADER_SIZE + len;
+        }
+        None
+    }
+
+    fn set(&mut self, key: u16, version: u8, data: &[u8]) -> Result<(), PersistError> {
+        let old = self
+            .find(key)
+            .map(|(offset, _, len)| (offset, RECORD_HEADER_SIZE + len));
+        let freed = old.map_or(0, |(_, size)| size);
+        if self.len + RECORD_HEADER_SIZE + data.len() > N + freed || data.len() > u16::MAX as usize {
+            return Err(PersistError::Full);
+        }
+
+        if let Some((offset, size)) = old {
+            self.data.copy_within(offset + size..self.len, offset);
+            self.len -= size;
+        }
+        let header = [key.to_le_bytes(), [version, 0], (data.len() as u16).to_le_bytes()];
+        self.data[self.len..self.len + RECORD_HEADER_SIZE].copy_from_slice(header.as_flattened());
+        self.len += RECORD_HEADER_SIZE;
+        self.data[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+        Ok(())
+    }
+}
+
+struct StoreState<const N: usize> {
+    policy: SavePolicy,
+    records: Records<N>,
+    /// Bytes of a slot, holding a snapshot of up to `N` bytes of records in whole erase blocks.
+    slot_size: usize,
+    slots: usize,
+    /// Sequence number of the last snapshot written.
+    sequence: u32,
+    /// Slot of the last snapshot written.
+    slot: usize,
+    dirty: bool,
+}
+
+/// A snapshot taken of the records, written to the storage out of the critical section.
+struct Snapshot<const N: usize> {
+    base: usize,
+    size: usize,
+    header: [u8; HEADER_SIZE],
+    records: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Snapshot<N> {
+    fn write(&self, storage: &mut impl NvStorage) -> Result<(), NvError> {
+        // Written last, the header makes the snapshot valid only once complete
+        storage.erase(self.base, self.size)?;
+        storage.write(self.base + HEADER_SIZE, &self.records[..self.len])?;
+        storage.write(self.base, &self.header)
+    }
+}
+
+impl<const N: usize> StoreState<N> {
+    /// Sequence number and records length of the valid snapshot in `slot`.
+    fn check_slot(&self, storage: &mut impl NvStorage, slot: usize, data: &mut [u8; N]) -> Option<(u32, usize)> {
+        let base = slot * self.slot_size;
+        let mut header = [0; HEADER_SIZE];
+        storage.read(base, &mut header).ok()?;
+        let field = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+        let (sequence, len) = (field(8), field(12) as usize);
+        if field(0) != MAGIC || u16::from_le_bytes([header[4], header[5]]) != FORMAT_VERSION || len > N {
+            return None;
+        }
+
+        storage.read(base + HEADER_SIZE, &mut data[..len]).ok()?;
+        if crc32(crc32(0, &header[..16]), &data[..len]) != field(16) {
+            return None;
+        }
+        if !Records::<N>::well_formed(&data[..len]) {
+            warn!("Snapshot {} in slot {} has malformed records", sequence, slot);
+            return None;
+        }
+        Some((sequence, len))
+    }
+
+    /// Load the newest valid snapshot.
+    fn load(&mut self, storage: &mut impl NvStorage) {
+        let mut data = [0; N];
+        let mut newest = None;
+        for slot in 0..self.slots {
+            if let Some((sequence, len)) = self.check_slot(storage, slot, &mut data) {
+                if newest.is_none_or(|(newest, _, _)| sequence > newest) {
+                    newest = Some((sequence, slot, len));
+                    self.records.data[..len].copy_from_slice(&data[..len]);
+                }
+            }
+        }
+
+        match newest {
+            Some((sequence, slot, len)) => {
+                info!("Loaded snapshot {} from slot {}", sequence, slot);
+                (self.sequence, self.slot, self.records.len) = (sequence, slot, len);
+            }
+            None => info!("No snapshot of the service state"),
+        }
+    }
+
+    /// Snapshot of the records for the slot after the last one.
+    fn snapshot(&mut self) -> Result<Snapshot<N>, PersistError> {
+        if self.slots == 0 {
+            return Err(PersistError::Full);
+        }
+
+        let slot = (self.slot + 1) % self.slots;
+        let sequence = self.sequence.wrapping_add(1);
+        let mut snapshot = Snapshot {
+            base: slot * self.slot_size,
+            size: self.slot_size,
+            header: [0; HEADER_SIZE],
+            records: self.records.data,
+            len: self.records.len,
+        };
+        let header = &mut snapshot.header;
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header[8..12].copy_from_slice(&sequence.to_le_bytes());
+        header[12..16].copy_from_slice(&(snapshot.len as u32).to_le_bytes());
+        let crc = crc32(crc32(0, &header[..16]), &snapshot.records[..snapshot.len]);
+        header[16..].copy_from_slice(&crc.to_le_bytes());
+
+        (self.sequence, self.slot, self.dirty) = (sequence, slot, false);
+        Ok(snapshot)
+    }
+}
+
+/// A store of up to `N` bytes of records, shared by the services, written to `S`.
+pub struct PersistentStore<S, const N: usize> {
+    inner: Mutex<RefCell<Option<StoreState<N>>>>,
+    /// Taken out while a snapshot is written, so that the critical section does not cover it.
+    storage: Mutex<RefCell<Option<S>>>,
+}
+
+impl<S, const N: usize> Default for PersistentStore<S, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, const N: usize> PersistentStore<S, N> {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(None)),
+            storage: Mutex::new(RefCell::new(None)),
+        }
+    }
+}
+
+impl<S: NvStorage, const N: usize> PersistentStore<S, N> {
+    /// Load the records from the newest snapshot in `storage`.
+    pub fn init(&self, mut storage: S, policy: SavePolicy) {
+        let slot_size = (HEADER_SIZE + N).next_multiple_of(storage.erase_size().max(1));
+        let mut state = StoreState {
+            policy,
+            records: Records { data: [0; N], len: 0 },
+            slot_size,
+            slots: storage.capacity() / slot_size,
+            sequence: 0,
+            slot: 0,
+            dirty: false,
+        };
+        if state.slots == 0 {
+            error!("Storage of {} bytes too small for snapshots", storage.capacity());
+        }
+        state.load(&mut storage);
+        critical_section::with(|cs| {
+            self.inner.replace(cs, Some(state));
+            self.storage.replace(cs, Some(storage));
+        });
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut StoreState<N>) -> Result<R, PersistError>) -> Result<R, PersistError> {
+        critical_section::with(|cs| match self.inner.borrow_ref_mut(cs).as_mut() {
+            Some(state) => f(state),
+            None => Err(PersistError::Uninitialized),
+        })
+    }
+
+    /// Write the records out as a new snapshot.
+    fn write(&self) -> Result<(), PersistError> {
+        let (mut storage, snapshot) = critical_section::with(|cs| {
+            let mut storage = self.storage.borrow_ref_mut(cs);
+            let mut inner = self.inner.borrow_ref_mut(cs);
+            let state = inner.as_mut().ok_or(PersistError::Uninitialized)?;
+            if storage.is_none() {
+                return Err(PersistError::Busy);
+            }
+            let snapshot = state.snapshot()?;
+            Ok((storage.take().unwrap(), snapshot))
+        })?;
+
+        let result = snapshot.write(&mut storage);
+        critical_section::with(|cs| {
+            self.storage.replace(cs, Some(storage));
+            if let (Err(_), Some(state)) = (result, self.inner.borrow_ref_mut(cs).as_mut()) {
+                state.dirty = true;
+            }
+        });
+        Ok(result?)
+    }
+}
+
+impl<S: NvStorage + Send, const N: usize> KeyValueStore for PersistentStore<S, N> {
+    fn load(&self, key: u16, buf: &mut [u8]) -> Option<(u8, usize)> {
+        self.with(|state| {
+            let (offset, version, len) = state.records.find(key).ok_or(PersistError::NotFound)?;
+            let data = &state.records.data[offset + RECORD_HEADER_SIZE..][..len];
+            let len = len.min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok((version, len))
+        })
+        .ok()
+    }
+
+    fn store(&self, key: u16, version: u8, data: &[u8]) -> Result<(), PersistError> {
+        let write = self.with(|state| {
+            if state.records.find(key).is_some_and(|(offset, v, len)| {
+                v == version && state.records.data[offset + RECORD_HEADER_SIZE..][..len] == *data
+            }) {
+                return Ok(false);
+            }
+
+            state.records.set(key, version, data)?;
+            state.dirty = true;
+            Ok(state.policy == SavePolicy::OnChange)
+        })?;
+        if write {
+            self.write()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flush(&self) -> Result<(), PersistError> {
+        if self.with(|state| Ok(state.dirty))? {
+            self.write()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(u32);
+
+    impl Persist for Counter {
+        const KEY: u16 = 0x10;
+        const VERSION: u8 = 1;
+
+        fn save(&self, buf: &mut [u8]) -> Option<usize> {
+            buf.get_mut(..4)?.copy_from_slice(&self.0.to_le_bytes());
+            Some(4)
+        }
+
+        fn restore(&mut self, _version: u8, record: &[u8]) -> bool {
+            let Ok(bytes) = record.try_into() else {
+                return false;
+            };
+            self.0 = u32::from_le_bytes(bytes);
+            true
+        }
+    }
+
+    type Store = PersistentStore<RamStorage<512>, 64>;
+
+    /// The storage of `store`, as found after a reset.
+    fn reset(store: &Store) -> RamStorage<512> {
+        critical_section::with(|cs| {
+            store.inner.take(cs);
+            store.storage.take(cs)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_records() {
+        let mut records = Records::<32> { data: [0; 32], len: 0 };
+        records.set(1, 1, &[1, 2]).unwrap();
+        records.set(2, 1, &[3]).unwrap();
+        records.set(1, 2, &[4, 5, 6]).unwrap();
+        assert_eq!(records.find(2), Some((0, 1, 1)));
+        assert_eq!(records.find(1), Some((7, 2, 3)));
+        assert_eq!(records.set(3, 1, &[0; 11]), Err(PersistError::Full));
+
+        assert!(Records::<32>::well_formed(&records.data[..records.len]));
+        assert!(!Records::<32>::well_formed(&records.data[..records.len - 1]));
+        assert!(!Records::<32>::well_formed(&records.data[..records.len + 1]));
+    }
+
+    #[test]
+    fn test_snapshots_rotate_and_reload() {
+        let store = Store::new();
+        assert_eq!(store.flush(), Err(PersistError::Uninitialized));
+        store.init(RamStorage::new(128), SavePolicy::OnChange);
+
+        // Each change writes the next of the 4 slots, wrapping around
+        for i in 1..=7 {
+            save(&store, &Counter(i));
+        }
+        critical_section::with(|cs| {
+            let inner = store.inner.borrow_ref(cs);
+            let state = inner.as_ref().unwrap();
+            assert_eq!((state.slots, state.slot, state.sequence), (4, 3, 7));
+        });
+
+        let mut storage = reset(&store);
+        // A torn write of the next snapshot leaves the previous one valid
+        storage.data[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        storage.data[8] = 8;
+        store.init(storage, SavePolicy::Deferred);
+        let mut counter = Counter::default();
+        restore(&store, &mut counter);
+        assert_eq!(counter.0, 7);
+
+        // Deferred changes are lost without a flush
+        save(&store, &Counter(8));
+        let storage = reset(&store);
+        store.init(storage, SavePolicy::Deferred);
+        restore(&store, &mut counter);
+        assert_eq!(counter.0, 7);
+        save(&store, &Counter(9));
+        store.flush().unwrap();
+        let storage = reset(&store);
+        store.init(storage, SavePolicy::Deferred);
+        restore(&store, &mut counter);
+        assert_eq!(counter.0, 9);
+    }
+
+    #[test]
+    fn test_malformed_snapshot_rejected() {
+        let store = Store::new();
+        store.init(RamStorage::new(128), SavePolicy::OnChange);
+        save(&store, &Counter(1));
+        save(&store, &Counter(2));
+        let mut buf = [0; 8];
+        assert_eq!(store.load(0x99, &mut buf), None);
+
+        // A record running past the end of the snapshot in slot 2, with a CRC matching it
+        let mut storage = reset(&store);
+        let base = 2 * 128;
+        storage.data[base + HEADER_SIZE + 4] = 5;
+        let len = RECORD_HEADER_SIZE + 4;
+        let crc = crc32(
+            crc32(0, &storage.data[base..base + 16]),
+            &storage.data[base + HEADER_SIZE..][..len],
+        );
+        storage.data[base + 16..base + HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+        store.init(storage, SavePolicy::OnChange);
+        let mut counter = Counter::default();
+        restore(&store, &mut counter);
+        assert_eq!(counter.0, 1);
+    }
+
+    #[test]
+    fn test_write_while_busy() {
+        let store = Store::new();
+        store.init(RamStorage::new(128), SavePolicy::OnChange);
+        let storage = critical_section::with(|cs| store.storage.take(cs)).unwrap();
+        assert_eq!(
+            store.store(Counter::KEY, Counter::VERSION, &[1, 0, 0, 0]),
+            Err(PersistError::Busy)
+        );
+
+        // The change stays in RAM until the next snapshot
+        critical_section::with(|cs| store.storage.replace(cs, Some(storage)));
+        store.flush().unwrap();
+        let storage = reset(&store);
+        store.init(storage, SavePolicy::OnChange);
+        let mut counter = Counter::default();
+        restore(&store, &mut counter);
+        assert_eq!(counter.0, 1);
+    }
+}
@@ -1,10 +1,12 @@
 use core::future::Future;
 
 use log::error;
-use odp_ffa::{FunctionId, MsgSendDirectReq2, MsgSendDirectResp2};
+use odp_ffa::{FunctionId, MsgSendDirectReq2, MsgSendDirectResp2, Payload};
 use uuid::Uuid;
 
-use crate::async_msg_loop;
+use crate::access::{self, AccessRule};
+use crate::middleware::{Layered, Middleware};
+use crate::{async_msg_loop, stats, ManagedExitSource, NsInterruptPolicy};
 
 pub type Result<T> = core::result::Result<T, odp_ffa::Error>;
 
@@ -12,6 +14,11 @@ pub trait Service {
     fn service_name(&self) -> &'static str;
     fn service_uuid(&self) -> Uuid;
 
+    /// The endpoints allowed to call the service, all of them if empty, see [`access`].
+    fn access_rules(&self) -> &[AccessRule] {
+        &[]
+    }
+
     fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> impl Future<Output = Result<MsgSendDirectResp2>> {
         async move { self.handler_unimplemented(msg).await }
     }
@@ -19,6 +26,25 @@ pub trait Service {
 
 pub trait ServiceNodeHandler {
     fn handle(&mut self, msg: MsgSendDirectReq2) -> impl Future<Output = Result<MsgSendDirectResp2>>;
+
+    fn run_message_loop(
+        &mut self,
+        before_handle_message: impl AsyncFnMut(&MsgSendDirectReq2) -> core::result::Result<(), odp_ffa::Error>,
+    ) -> impl Future<Output = Result<()>> {
+        async move {
+            self.run_message_loop_with_policy(&NsInterruptPolicy::signal(), before_handle_message)
+                .await
+        }
+    }
+
+    /// Run the message loop, reacting to non-secure interrupts according to `policy`.
+    fn run_message_loop_with_policy<M: ManagedExitSource>(
+        &mut self,
+        policy: &NsInterruptPolicy<M>,
+        before_handle_message: impl AsyncFnMut(&MsgSendDirectReq2) -> core::result::Result<(), odp_ffa::Error>,
+    ) -> impl Future<Output = Result<()>> {
+        async_msg_loop(async |msg| self.handle(msg).await, before_handle_message, policy)
+    }
 }
 
 pub struct ServiceNode<This: Service, Next: ServiceNodeHandler> {
@@ -27,11 +53,9 @@ pub struct ServiceNode<This: Service, Next: ServiceNodeHandler> {
 }
 
 impl<This: Service, Next: ServiceNodeHandler> ServiceNode<This, Next> {
-    pub async fn run_message_loop(
-        &mut self,
-        before_handle_message: impl AsyncFnMut(&MsgSendDirectReq2) -> core::result::Result<(), odp_ffa::Error>,
-    ) -> Result<()> {
-        async_msg_loop(async |msg| self.handle(msg).await, before_handle_message).await
+    /// Run the hooks of `middleware` around the dispatch of every request.
+    pub fn with_middleware<M: Middleware>(self, middleware: M) -> Layered<M, Self> {
+        Layered::new(middleware, self)
     }
 }
 
@@ -51,8 +75,15 @@ impl<S: Service, N: ServiceNodeHandler> ServiceNode<S, N> {
 
 impl<This: Service, Next: ServiceNodeHandler> ServiceNodeHandler for ServiceNode<This, Next> {
     async fn handle(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
-        if msg.uuid() == self.service.service_uuid() {
-            self.service.ffa_msg_send_direct_req2(msg).await
+        let uuid = self.service.service_uuid();
+        if msg.uuid() == uuid {
+            let opcode = msg.u8_at(0);
+            let result = match access::deny(self.service.access_rules(), uuid, &msg) {
+                Some(denied) => Ok(denied),
+                None => self.service.ffa_msg_send_direct_req2(msg).await,
+            };
+            stats::record(uuid, opcode, stats::succeeded(&result));
+            result
         } else {
             self.next.handle(msg).await
         }
@@ -0,0 +1,425 @@
+use crate::access::{self, AccessRule, Caller, NORMAL_WORLD};
+use crate::address_space::{AddressSpaceError, MemoryMapper};
+use crate::{sp_logger, stats, Result, Service};
+use log::{debug, error, warn, LevelFilter};
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::{uuid, Uuid};
+
+// Protocol CMD definitions for Debug
+const EC_DBG_PING: u8 = 0x0;
+const EC_DBG_READ_LOG: u8 = 0x1;
+const EC_DBG_SET_LOG_LEVEL: u8 = 0x2;
+const EC_DBG_PEEK: u8 = 0x3;
+const EC_DBG_GET_STATS: u8 = 0x4;
+const EC_DBG_RESET_STATS: u8 = 0x5;
+const EC_DBG_GET_AUDIT_LOG: u8 = 0x6;
+
+const PAYLOAD_SIZE: usize = 14 * 8;
+
+/// Bytes of log output returned per read log request.
+const LOG_CHUNK_SIZE: usize = 88;
+
+/// Offset of the NUL terminated module name in a set log level request.
+const LOG_MODULE_OFFSET: usize = 16;
+
+/// Bytes of memory returned per EC_DBG_PEEK request.
+const PEEK_MAX_LEN: usize = 96;
+
+/// Offset of the echoed bytes in EC_DBG_PING requests and responses.
+const PING_DATA_OFFSET: usize = 8;
+
+pub(super) struct LogChunkRsp {
+    status: i64,
+    position: u64,
+    chunk_len: u32,
+    chunk: [u8; LOG_CHUNK_SIZE],
+}
+
+impl From<LogChunkRsp> for RegisterPayload {
+    fn from(rsp: LogChunkRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.position.to_le_bytes())
+            .chain(rsp.chunk_len.to_le_bytes())
+            .chain(rsp.chunk.into_iter().take(rsp.chunk_len as usize));
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+/// Return the log output starting at stream `position`, or the oldest output still kept.
+pub(super) fn read_log(position: u64) -> LogChunkRsp {
+    let mut chunk = [0; LOG_CHUNK_SIZE];
+    let (position, len) = sp_logger::read_log(position, &mut chunk);
+    LogChunkRsp {
+        status: 0x0,
+        position,
+        chunk_len: len as u32,
+        chunk,
+    }
+}
+
+/// Set the level of the module named in `msg`, or the default level if the name is empty.
+///
+/// Returns the status of the request.
+pub(super) fn set_log_level(msg: &MsgSendDirectReq2) -> i64 {
+    let module = msg.slice(LOG_MODULE_OFFSET..PAYLOAD_SIZE);
+    let module = module.split(|&b| b == 0).next().unwrap_or_default();
+    let (Some(level), Ok(module)) = (
+        LevelFilter::iter().nth(msg.u8_at(1) as usize),
+        core::str::from_utf8(module),
+    ) else {
+        return ErrorCode::InvalidParameters as i64;
+    };
+
+    if module.is_empty() {
+        sp_logger::set_default_level(level);
+    } else if let Err(e) = sp_logger::set_module_level(module, level) {
+        warn!("Failed to set the log level of {}: {:?}", module, e);
+        return ErrorCode::NoMemory as i64;
+    }
+    0x0
+}
+
+#[derive(Default)]
+struct GenericRsp {
+    status: i64,
+}
+
+impl From<GenericRsp> for RegisterPayload {
+    fn from(rsp: GenericRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes())
+    }
+}
+
+struct PingRsp {
+    status: i64,
+    data: [u8; PAYLOAD_SIZE - PING_DATA_OFFSET],
+}
+
+impl From<PingRsp> for RegisterPayload {
+    fn from(rsp: PingRsp) -> Self {
+        let iter = rsp.status.to_le_bytes().into_iter().chain(rsp.data);
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+struct PeekRsp {
+    status: i64,
+    len: u32,
+    data: [u8; PEEK_MAX_LEN],
+}
+
+impl From<PeekRsp> for RegisterPayload {
+    fn from(rsp: PeekRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.len.to_le_bytes())
+            .chain(rsp.data.into_iter().take(rsp.len as usize));
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+#[derive(Default)]
+struct StatsRsp {
+    status: i64,
+    count: u32,
+    opcode: u8,
+    service: u128,
+    requests: u64,
+    errors: u64,
+    untracked: u64,
+}
+
+impl From<StatsRsp> for RegisterPayload {
+    fn from(rsp: StatsRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.count.to_le_bytes())
+            .chain([rsp.opcode, 0, 0, 0])
+            .chain(rsp.service.to_le_bytes())
+            .chain(rsp.requests.to_le_bytes())
+            .chain(rsp.errors.to_le_bytes())
+            .chain(rsp.untracked.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+#[derive(Default)]
+struct AuditRsp {
+    status: i64,
+    denied: u64,
+    source_id: u16,
+    opcode: u8,
+    service: u128,
+}
+
+impl From<AuditRsp> for RegisterPayload {
+    fn from(rsp: AuditRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.denied.to_le_bytes())
+            .chain(rsp.source_id.to_le_bytes())
+            .chain([rsp.opcode, 0])
+            .chain(rsp.service.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+/// Diagnostics for the OS: log access, memory inspection and request statistics.
+#[derive(Default)]
+pub struct Debug {
+    memory_mapper: Option<&'static dyn MemoryMapper>,
+}
+
+impl Debug {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow peeking at the memory services may access in this address space.
+    pub fn with_memory_mapper(mut self, memory_mapper: &'static dyn MemoryMapper) -> Self {
+        self.memory_mapper = Some(memory_mapper);
+        self
+    }
+
+    /// Echo the request's payload back, for the OS to measure the round trip.
+    fn ping(&self, msg: &MsgSendDirectReq2) -> PingRsp {
+        let mut data = [0; PAYLOAD_SIZE - PING_DATA_OFFSET];
+        data.copy_from_slice(msg.slice(PING_DATA_OFFSET..PAYLOAD_SIZE));
+        PingRsp { status: 0x0, data }
+    }
+
+    fn peek(&self, address: u64, len: u64) -> PeekRsp {
+        let mut rsp = PeekRsp {
+            status: 0x0,
+            len: 0,
+            data: [0; PEEK_MAX_LEN],
+        };
+
+        let Some(memory_mapper) = self.memory_mapper else {
+            rsp.status = ErrorCode::NotSupported as i64;
+            return rsp;
+        };
+        if len > PEEK_MAX_LEN as u64 {
+            rsp.status = ErrorCode::InvalidParameters as i64;
+            return rsp;
+        }
+
+        match memory_mapper.read(address, &mut rsp.data[..len as usize]) {
+            Ok(()) => rsp.len = len as u32,
+            Err(e) => {
+                warn!("Peek of {:#x}+{:#x} rejected: {:?}", address, len, e);
+                rsp.status = match e {
+                    AddressSpaceError::Uninitialized => ErrorCode::NotSupported,
+                    _ => ErrorCode::Denied,
+                } as i64;
+            }
+        }
+        rsp
+    }
+
+    fn get_stats(&self, index: u64) -> StatsRsp {
+        let mut rsp = StatsRsp {
+            count: stats::count() as u32,
+            untracked: stats::untracked(),
+            ..Default::default()
+        };
+
+        match stats::get(index as usize) {
+            Some(entry) => {
+                rsp.opcode = entry.opcode;
+                rsp.service = entry.service.to_u128_le();
+                rsp.requests = entry.requests;
+                rsp.errors = entry.errors;
+            }
+            None => rsp.status = ErrorCode::InvalidParameters as i64,
+        }
+        rsp
+    }
+
+    /// The `index`th most recent request denied by the access rules of the services.
+    fn get_audit_log(&self, index: u64) -> AuditRsp {
+        let mut rsp = AuditRsp {
+            denied: access::denied_count(),
+            ..Default::default()
+        };
+
+        match access::audit_entry(index as usize) {
+            Some(entry) => {
+                rsp.source_id = entry.source_id;
+                rsp.opcode = entry.opcode;
+                rsp.service = entry.service.to_u128_le();
+            }
+            None => rsp.status = ErrorCode::InvalidParameters as i64,
+        }
+        rsp
+    }
+}
+
+const UUID: Uuid = uuid!("0bd66c7c-a288-48a6-afc8-e2200c03eb62");
+
+/// Setting the log level, peeking at memory and resetting the statistics are for the OS only.
+static ACCESS_RULES: [AccessRule; 1] = [AccessRule {
+    opcodes: &[EC_DBG_SET_LOG_LEVEL, EC_DBG_PEEK, EC_DBG_RESET_STATS],
+    callers: &[Caller::Endpoint(NORMAL_WORLD)],
+}];
+
+impl Service for Debug {
+    fn service_name(&self) -> &'static str {
+        "Debug"
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        UUID
+    }
+
+    fn access_rules(&self) -> &[AccessRule] {
+        &ACCESS_RULES
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        let cmd = msg.u8_at(0);
+        debug!("Received Debug command 0x{:x}", cmd);
+
+        let payload = match cmd {
+            EC_DBG_PING => RegisterPayload::from(self.ping(&msg)),
+            EC_DBG_READ_LOG => RegisterPayload::from(read_log(msg.register_at(1))),
+            EC_DBG_SET_LOG_LEVEL => RegisterPayload::from(GenericRsp {
+                status: set_log_level(&msg),
+            }),
+            EC_DBG_PEEK => RegisterPayload::from(self.peek(msg.register_at(1), msg.register_at(2))),
+            EC_DBG_GET_STATS => RegisterPayload::from(self.get_stats(msg.register_at(1))),
+            EC_DBG_RESET_STATS => {
+                stats::reset();
+                RegisterPayload::from(GenericRsp::default())
+            }
+            EC_DBG_GET_AUDIT_LOG => RegisterPayload::from(self.get_audit_log(msg.register_at(1))),
+            _ => {
+                error!("Unknown Debug Command: {}", cmd);
+                return Err(odp_ffa::Error::Other("Unknown Debug Command"));
+            }
+        };
+
+        Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::{AddressSpace, Attributes, SharedAddressSpace, PAGE_SIZE};
+    use crate::test_support::{leak_page, request, NoTable};
+
+    fn status(payload: &RegisterPayload) -> i64 {
+        payload.u64_at(0) as i64
+    }
+
+    #[test]
+    fn test_ping_echoes_payload() {
+        let mut bytes = [0u8; PAYLOAD_SIZE];
+        bytes[0] = EC_DBG_PING;
+        for (i, byte) in bytes.iter_mut().enumerate().skip(PING_DATA_OFFSET) {
+            *byte = i as u8;
+        }
+
+        let payload = RegisterPayload::from(Debug::new().ping(&request(UUID, &bytes)));
+        assert_eq!(status(&payload), 0);
+        assert_eq!(payload.slice(8..PAYLOAD_SIZE), &bytes[8..]);
+    }
+
+    #[test]
+    fn test_peek_only_mapped_memory() {
+        static SPACE: SharedAddressSpace<NoTable, 2> = SharedAddressSpace::new();
+        let base = leak_page(0xab).as_mut_ptr() as u64;
+
+        let debug = Debug::new().with_memory_mapper(&SPACE);
+        assert_eq!(debug.peek(base, 4).status, ErrorCode::NotSupported as i64);
+
+        SPACE.set(AddressSpace::new(NoTable));
+        assert_eq!(debug.peek(base, 4).status, ErrorCode::Denied as i64);
+
+        SPACE.map(base, PAGE_SIZE, Attributes::DATA).unwrap();
+        let rsp = debug.peek(base + 16, 4);
+        assert_eq!((rsp.status, rsp.len), (0, 4));
+        assert_eq!(rsp.data[..4], [0xab; 4]);
+        assert_eq!(
+            debug.peek(base, PEEK_MAX_LEN as u64 + 1).status,
+            ErrorCode::InvalidParameters as i64
+        );
+        assert_eq!(Debug::new().peek(base, 4).status, ErrorCode::NotSupported as i64);
+    }
+
+    #[test]
+    fn test_set_log_level_validates_request() {
+        let mut bytes = [0u8; PAYLOAD_SIZE];
+        bytes[0] = EC_DBG_SET_LOG_LEVEL;
+        bytes[1] = 6;
+        assert_eq!(
+            set_log_level(&request(UUID, &bytes)),
+            ErrorCode::InvalidParameters as i64
+        );
+
+        bytes[1] = 2;
+        bytes[LOG_MODULE_OFFSET..LOG_MODULE_OFFSET + 2].copy_from_slice(&[0xff, 0xfe]);
+        assert_eq!(
+            set_log_level(&request(UUID, &bytes)),
+            ErrorCode::InvalidParameters as i64
+        );
+    }
+
+    #[test]
+    fn test_stats_counted_and_reset() {
+        // Only this test records requests to this service, the others may run meanwhile
+        const SERVICE: Uuid = uuid!("5c1e4f27-0d8a-4b6e-9f3a-2b7c8d9e0a1f");
+        let find = |debug: &Debug| {
+            (0..stats::count() as u64)
+                .map(|index| debug.get_stats(index))
+                .find(|rsp| rsp.service == SERVICE.to_u128_le())
+        };
+
+        let mut debug = Debug::new();
+        stats::record(SERVICE, 0x3, true);
+        stats::record(SERVICE, 0x3, false);
+        let rsp = find(&debug).unwrap();
+        assert_eq!(rsp.status, 0);
+        assert_eq!((rsp.opcode, rsp.requests, rsp.errors), (0x3, 2, 1));
+        assert!(rsp.count >= 1);
+        assert_eq!(
+            debug.get_stats(stats::MAX_STATS_ENTRIES as u64).status,
+            ErrorCode::InvalidParameters as i64
+        );
+
+        let rsp =
+            embassy_futures::block_on(debug.ffa_msg_send_direct_req2(request(UUID, &[EC_DBG_RESET_STATS]))).unwrap();
+        assert_eq!(rsp.u64_at(0), 0);
+        assert!(find(&debug).is_none());
+    }
+
+    #[test]
+    fn test_audit_log_most_recent_first() {
+        let _lock = crate::test_support::AUDIT_LOG_LOCK.lock();
+        let denied = access::denied_count();
+        access::audit_denied(0x8010, UUID, EC_DBG_PEEK);
+        access::audit_denied(0x8011, UUID, EC_DBG_GET_STATS);
+
+        let debug = Debug::new();
+        let rsp = debug.get_audit_log(0);
+        assert_eq!(rsp.status, 0);
+        assert_eq!(rsp.denied, denied + 2);
+        assert_eq!((rsp.source_id, rsp.opcode), (0x8011, EC_DBG_GET_STATS));
+        assert_eq!(rsp.service, UUID.to_u128_le());
+        assert_eq!(debug.get_audit_log(1).source_id, 0x8010);
+        assert_eq!(
+            debug.get_audit_log(access::AUDIT_LOG_SIZE as u64).status,
+            ErrorCode::InvalidParameters as i64
+        );
+    }
+}
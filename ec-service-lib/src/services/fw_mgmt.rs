@@ -1,7 +1,19 @@
+use super::debug::{read_log, set_log_level};
+use crate::access::{AccessRule, Caller, NORMAL_WORLD};
+use crate::address_space::{Attributes, MemoryMapper};
+use crate::attestation::{Attestation, SecurityState, NONCE_SIZE, REPORT_SIZE, SIGNATURE_SIZE};
+use crate::crash_dump::{CrashDump, CRASH_RECORD_SIZE};
+use crate::fw_update::{FirmwareUpdater, ImageHeader, NoFirmwareUpdate, UpdateError};
+#[cfg(feature = "alloc")]
+use crate::heap::Heap;
+use crate::identity::{BoardInfo, FirmwareIdentity};
+use crate::mem_share::{MemoryRetriever, RetrieveError};
+use crate::sha256::{Sha256, DIGEST_SIZE};
 use crate::{Result, Service};
-use log::{debug, error};
-use odp_ffa::{Function, NotificationSet};
-use odp_ffa::{MemRetrieveReq, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use core::fmt::Write;
+use log::{debug, error, warn};
+use odp_ffa::{ErrorCode, Function, NotificationSet};
+use odp_ffa::{MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
 use uuid::{uuid, Uuid};
 
 // Protocol CMD definitions for FwMgmt
@@ -11,6 +23,41 @@ const EC_CAP_GET_SVC_LIST: u8 = 0x2;
 const EC_CAP_GET_BID: u8 = 0x3;
 const EC_CAP_TEST_NFY: u8 = 0x4;
 const EC_CAP_MAP_SHARE: u8 = 0x5;
+const EC_CAP_READ_CRASH_DUMP: u8 = 0x6;
+const EC_CAP_CLEAR_CRASH_DUMP: u8 = 0x7;
+const EC_CAP_GET_HEAP_STATS: u8 = 0x8;
+const EC_CAP_READ_LOG: u8 = 0x9;
+const EC_CAP_SET_LOG_LEVEL: u8 = 0xa;
+const EC_CAP_FW_UPDATE_BEGIN: u8 = 0xb;
+const EC_CAP_FW_UPDATE_WRITE: u8 = 0xc;
+const EC_CAP_FW_UPDATE_WRITE_SHARED: u8 = 0xd;
+const EC_CAP_FW_UPDATE_END: u8 = 0xe;
+const EC_CAP_FW_UPDATE_STATUS: u8 = 0xf;
+const EC_CAP_FW_UPDATE_COMMIT: u8 = 0x10;
+const EC_CAP_FW_UPDATE_ROLLBACK: u8 = 0x11;
+const EC_CAP_GET_FW_IDENTITY: u8 = 0x12;
+const EC_CAP_GET_SECURE_STATE: u8 = 0x13;
+
+// Commands of indirect messages, in the RX buffer the OS passes with EC_CAP_INDIRECT_MSG
+const EC_INDIRECT_GET_BUILD_INFO: u16 = 0x1;
+const EC_INDIRECT_GET_ATTESTATION: u16 = 0x2;
+
+/// Bytes of the header of indirect messages: sequence number, command or length, reserved.
+const INDIRECT_HEADER_SIZE: u64 = 8;
+/// Bytes of data following the header of indirect responses at most.
+const INDIRECT_DATA_SIZE: usize = 256;
+/// Bytes of git revision returned by EC_CAP_GET_FW_IDENTITY at most.
+const GIT_REVISION_SIZE: usize = 40;
+/// Board id reported without a [`BoardInfo`].
+const DEFAULT_BOARD_ID: u64 = 0xdead0001;
+
+/// Bytes of crash record returned per EC_CAP_READ_CRASH_DUMP request.
+const CRASH_DUMP_CHUNK_SIZE: usize = 96;
+
+/// Bytes of image carried by an EC_CAP_FW_UPDATE_WRITE request, from byte 16.
+const FW_UPDATE_CHUNK_SIZE: usize = 96;
+/// Bytes of image copied from shared memory at a time for EC_CAP_FW_UPDATE_WRITE_SHARED.
+const FW_UPDATE_COPY_SIZE: usize = 256;
 
 #[derive(Default)]
 struct FwStateRsp {
@@ -84,19 +131,262 @@ impl From<GenericRsp> for RegisterPayload {
     }
 }
 
+struct CrashDumpChunkRsp {
+    status: i64,
+    total_len: u32,
+    chunk_len: u32,
+    chunk: [u8; CRASH_DUMP_CHUNK_SIZE],
+}
+
+impl From<CrashDumpChunkRsp> for RegisterPayload {
+    fn from(rsp: CrashDumpChunkRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.total_len.to_le_bytes())
+            .chain(rsp.chunk_len.to_le_bytes())
+            .chain(rsp.chunk.into_iter().take(rsp.chunk_len as usize));
+        RegisterPayload::from_iter(iter)
+    }
+}
+
 #[derive(Default)]
-pub struct FwMgmt {}
+struct HeapStatsRsp {
+    status: i64,
+    size: u64,
+    used: u64,
+    peak_used: u64,
+    allocations: u32,
+    failed_allocations: u32,
+    last_failed_size: u64,
+}
+
+impl From<HeapStatsRsp> for RegisterPayload {
+    fn from(rsp: HeapStatsRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.size.to_le_bytes())
+            .chain(rsp.used.to_le_bytes())
+            .chain(rsp.peak_used.to_le_bytes())
+            .chain(rsp.allocations.to_le_bytes())
+            .chain(rsp.failed_allocations.to_le_bytes())
+            .chain(rsp.last_failed_size.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+struct FwIdentityRsp {
+    status: i64,
+    version: [u16; 3],
+    board_id: u64,
+    sku: u32,
+    revision_len: u32,
+    revision: [u8; GIT_REVISION_SIZE],
+}
+
+impl From<FwIdentityRsp> for RegisterPayload {
+    fn from(rsp: FwIdentityRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.version.into_iter().flat_map(u16::to_le_bytes))
+            .chain([0, 0])
+            .chain(rsp.board_id.to_le_bytes())
+            .chain(rsp.sku.to_le_bytes())
+            .chain(rsp.revision_len.to_le_bytes())
+            .chain(rsp.revision.into_iter().take(rsp.revision_len as usize));
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+#[derive(Default)]
+struct SecureStateRsp {
+    status: i64,
+    flags: u8,
+    anti_rollback_counter: u32,
+}
+
+impl From<SecureStateRsp> for RegisterPayload {
+    fn from(rsp: SecureStateRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain([rsp.flags, 0, 0, 0])
+            .chain(rsp.anti_rollback_counter.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+#[derive(Default)]
+struct FwUpdateStatusRsp {
+    status: i64,
+    state: u8,
+    boot_status: u8,
+    received: u32,
+    size: u32,
+}
+
+impl From<FwUpdateStatusRsp> for RegisterPayload {
+    fn from(rsp: FwUpdateStatusRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain([rsp.state, rsp.boot_status, 0, 0])
+            .chain(rsp.received.to_le_bytes())
+            .chain(rsp.size.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+impl From<core::result::Result<(), UpdateError>> for GenericRsp {
+    fn from(result: core::result::Result<(), UpdateError>) -> Self {
+        let code = match result {
+            Ok(()) => return GenericRsp { _status: 0x0 },
+            Err(UpdateError::Busy) => ErrorCode::Busy,
+            Err(UpdateError::InvalidImage | UpdateError::OutOfOrder | UpdateError::VerificationFailed) => {
+                ErrorCode::InvalidParameters
+            }
+            Err(UpdateError::NotReady | UpdateError::Rollback) => ErrorCode::Denied,
+            Err(UpdateError::Flash(_)) => ErrorCode::Aborted,
+        };
+        GenericRsp { _status: code as i64 }
+    }
+}
+
+/// Writes formatted text to `data`, dropping the bytes past its end.
+struct Truncating<'a> {
+    data: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(self.data.len() - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+pub struct FwMgmt<U = NoFirmwareUpdate> {
+    crash_dump: Option<&'static CrashDump>,
+    memory_mapper: Option<&'static dyn MemoryMapper>,
+    memory_retriever: Option<&'static dyn MemoryRetriever>,
+    #[cfg(feature = "alloc")]
+    heap: Option<&'static Heap>,
+    identity: Option<&'static FirmwareIdentity>,
+    board_info: Option<&'static dyn BoardInfo>,
+    security: Option<&'static dyn SecurityState>,
+    attestation: Option<Attestation>,
+    update: Option<U>,
+}
+
+impl Default for FwMgmt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl FwMgmt {
     pub fn new() -> Self {
-        Self::default()
+        Self {
+            crash_dump: None,
+            memory_mapper: None,
+            memory_retriever: None,
+            #[cfg(feature = "alloc")]
+            heap: None,
+            identity: None,
+            board_info: None,
+            security: None,
+            attestation: None,
+            update: None,
+        }
+    }
+}
+
+impl<U: FirmwareUpdater> FwMgmt<U> {
+    /// Update the EC firmware through `update`, and report its version and boot status.
+    pub fn with_firmware_update<T: FirmwareUpdater>(self, update: T) -> FwMgmt<T> {
+        FwMgmt {
+            crash_dump: self.crash_dump,
+            memory_mapper: self.memory_mapper,
+            memory_retriever: self.memory_retriever,
+            #[cfg(feature = "alloc")]
+            heap: self.heap,
+            identity: self.identity,
+            board_info: self.board_info,
+            security: self.security,
+            attestation: self.attestation,
+            update: Some(update),
+        }
+    }
+
+    /// Report the version and build of the running image from `identity`.
+    pub fn with_identity(mut self, identity: &'static FirmwareIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Report the board id and SKU from `board_info`.
+    pub fn with_board_info(mut self, board_info: &'static dyn BoardInfo) -> Self {
+        self.board_info = Some(board_info);
+        self
+    }
+
+    /// Report the secure state of the platform from `security`.
+    pub fn with_security_state(mut self, security: &'static dyn SecurityState) -> Self {
+        self.security = Some(security);
+        self
+    }
+
+    /// Produce attestation reports of the image measured by `attestation`.
+    pub fn with_attestation(mut self, attestation: Attestation) -> Self {
+        self.attestation = Some(attestation);
+        self
+    }
+
+    /// Expose the crash record stored in `crash_dump` to the OS.
+    pub fn with_crash_dump(mut self, crash_dump: &'static CrashDump) -> Self {
+        self.crash_dump = Some(crash_dump);
+        self
+    }
+
+    /// Map memory shared by the OS into the partition's address space.
+    pub fn with_memory_mapper(mut self, memory_mapper: &'static dyn MemoryMapper) -> Self {
+        self.memory_mapper = Some(memory_mapper);
+        self
+    }
+
+    /// Retrieve the memory the OS shares with EC_CAP_MAP_SHARE through `memory_retriever`.
+    pub fn with_memory_retriever(mut self, memory_retriever: &'static dyn MemoryRetriever) -> Self {
+        self.memory_retriever = Some(memory_retriever);
+        self
+    }
+
+    /// Report the usage of `heap` to the OS.
+    #[cfg(feature = "alloc")]
+    pub fn with_heap(mut self, heap: &'static Heap) -> Self {
+        self.heap = Some(heap);
+        self
     }
 
     fn get_fw_state(&self) -> FwStateRsp {
+        // The image knows its own version better than the boot record
+        let fw_version = match (self.identity, &self.update) {
+            (Some(identity), _) => identity.version.packed(),
+            (None, Some(update)) => update.version(),
+            (None, None) => 0x0100,
+        };
         FwStateRsp {
-            fw_version: 0x0100,
-            secure_state: 0x0,
-            boot_status: 0x1,
+            fw_version,
+            secure_state: self.security.map_or(0x0, |security| security.flags()),
+            boot_status: self.update.as_ref().map_or(0x1, |update| update.boot_status() as u8),
         }
     }
 
@@ -107,7 +397,7 @@ impl FwMgmt {
             battery_mask: 0x1,
             fan_mask: 0x1,
             thermal_mask: 0x1,
-            hid_mask: 0x0,
+            hid_mask: 0x1,
             key_mask: 0x7,
         }
     }
@@ -115,16 +405,272 @@ impl FwMgmt {
     fn get_bid(&self) -> GetBidRsp {
         GetBidRsp {
             _status: 0x0,
-            _bid: 0xdead0001,
+            _bid: self.board_info.map_or(DEFAULT_BOARD_ID, |board| board.board_id()),
+        }
+    }
+
+    fn get_fw_identity(&self) -> FwIdentityRsp {
+        let mut rsp = FwIdentityRsp {
+            status: 0x0,
+            version: [0; 3],
+            board_id: DEFAULT_BOARD_ID,
+            sku: 0,
+            revision_len: 0,
+            revision: [0; GIT_REVISION_SIZE],
+        };
+        if let Some(board) = self.board_info {
+            (rsp.board_id, rsp.sku) = (board.board_id(), board.sku());
         }
+
+        let Some(identity) = self.identity else {
+            rsp.status = ErrorCode::NotSupported as i64;
+            return rsp;
+        };
+        let version = identity.version;
+        rsp.version = [version.major, version.minor, version.patch];
+        let revision = identity.git_revision.as_bytes();
+        let len = revision.len().min(GIT_REVISION_SIZE);
+        rsp.revision[..len].copy_from_slice(&revision[..len]);
+        rsp.revision_len = len as u32;
+        rsp
     }
 
-    fn map_share(&self, _address: u64, _length: u64) -> GenericRsp {
-        // TODO - do not hardcode address and length in MemRetrieveReq
-        MemRetrieveReq::new().exec().unwrap();
+    /// Retrieve the memory `sender` shared under `handle` and map the regions it is made of.
+    fn map_share(&self, handle: u64, sender: u16, receiver: u16) -> GenericRsp {
+        let (Some(memory_retriever), Some(memory_mapper)) = (self.memory_retriever, self.memory_mapper) else {
+            return GenericRsp {
+                _status: ErrorCode::NotSupported as i64,
+            };
+        };
+
+        let regions = match memory_retriever.retrieve(handle, sender, receiver) {
+            Ok(regions) => regions,
+            Err(e) => {
+                warn!("Failed to retrieve shared memory {:#x}: {:?}", handle, e);
+                let code = match e {
+                    RetrieveError::Ffa(odp_ffa::Error::ErrorCode(code)) => code,
+                    _ => ErrorCode::InvalidParameters,
+                };
+                return GenericRsp { _status: code as i64 };
+            }
+        };
+
+        for (i, region) in regions.iter().enumerate() {
+            if let Err(e) = memory_mapper.map(region.address, region.size(), Attributes::DATA.non_secure()) {
+                warn!(
+                    "Failed to map shared memory {:#x}+{:#x}: {:?}",
+                    region.address,
+                    region.size(),
+                    e
+                );
+                // The share is mapped whole or not at all
+                for mapped in &regions[..i] {
+                    _ = memory_mapper.unmap(mapped.address, mapped.size());
+                }
+                return GenericRsp {
+                    _status: ErrorCode::InvalidParameters as i64,
+                };
+            }
+        }
         GenericRsp { _status: 0x0 }
     }
 
+    /// Return the part of the stored crash record starting at `offset`.
+    fn read_crash_dump(&self, offset: u64) -> CrashDumpChunkRsp {
+        let mut rsp = CrashDumpChunkRsp {
+            status: 0x0,
+            total_len: 0,
+            chunk_len: 0,
+            chunk: [0; CRASH_DUMP_CHUNK_SIZE],
+        };
+
+        let Some(record) = self.crash_dump.and_then(CrashDump::load) else {
+            rsp.status = ErrorCode::NoData as i64;
+            return rsp;
+        };
+
+        let bytes = record.as_bytes();
+        if offset > bytes.len() as u64 {
+            rsp.status = ErrorCode::InvalidParameters as i64;
+            return rsp;
+        }
+
+        let chunk = &bytes[offset as usize..];
+        let chunk = &chunk[..chunk.len().min(CRASH_DUMP_CHUNK_SIZE)];
+        rsp.total_len = CRASH_RECORD_SIZE as u32;
+        rsp.chunk[..chunk.len()].copy_from_slice(chunk);
+        rsp.chunk_len = chunk.len() as u32;
+        rsp
+    }
+
+    fn clear_crash_dump(&self) -> GenericRsp {
+        match self.crash_dump {
+            Some(crash_dump) => {
+                crash_dump.clear();
+                GenericRsp { _status: 0x0 }
+            }
+            None => GenericRsp {
+                _status: ErrorCode::NotSupported as i64,
+            },
+        }
+    }
+
+    fn get_heap_stats(&self) -> HeapStatsRsp {
+        #[cfg(feature = "alloc")]
+        if let Some(heap) = self.heap {
+            let stats = heap.stats();
+            return HeapStatsRsp {
+                status: 0x0,
+                size: stats.size as u64,
+                used: stats.used as u64,
+                peak_used: stats.peak_used as u64,
+                allocations: stats.allocations,
+                failed_allocations: stats.failed_allocations,
+                last_failed_size: stats.last_failed_size as u64,
+            };
+        }
+
+        HeapStatsRsp {
+            status: ErrorCode::NotSupported as i64,
+            ..Default::default()
+        }
+    }
+
+    fn get_secure_state(&self) -> SecureStateRsp {
+        match self.security {
+            Some(security) => SecureStateRsp {
+                status: 0x0,
+                flags: security.flags(),
+                anti_rollback_counter: security.anti_rollback_counter(),
+            },
+            None => SecureStateRsp {
+                status: ErrorCode::NotSupported as i64,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Measurement of what configures the image beyond its code: its identity and the board.
+    fn config_digest(&self) -> [u8; DIGEST_SIZE] {
+        let mut sha = Sha256::new();
+        if let Some(identity) = self.identity {
+            _ = write!(sha, "{}", identity);
+        }
+        if let Some(board) = self.board_info {
+            sha.update(&board.board_id().to_le_bytes());
+            sha.update(&board.sku().to_le_bytes());
+        }
+        sha.finish()
+    }
+
+    fn build_info(&self, data: &mut [u8; INDIRECT_DATA_SIZE]) -> core::result::Result<usize, ErrorCode> {
+        let identity = self.identity.ok_or(ErrorCode::NotSupported)?;
+        // A build info longer than the buffer is cut at the last byte that fits
+        let mut info = Truncating { data, len: 0 };
+        _ = write!(info, "{}", identity);
+        Ok(info.len)
+    }
+
+    /// Report for the nonce following the header of the request at `rx_buffer`.
+    fn attestation_report(
+        &self,
+        memory_mapper: &dyn MemoryMapper,
+        rx_buffer: u64,
+        data: &mut [u8; INDIRECT_DATA_SIZE],
+    ) -> core::result::Result<usize, ErrorCode> {
+        let attestation = self.attestation.as_ref().ok_or(ErrorCode::NotSupported)?;
+        let mut nonce = [0; NONCE_SIZE];
+        memory_mapper
+            .read(rx_buffer + INDIRECT_HEADER_SIZE, &mut nonce)
+            .map_err(|_| ErrorCode::InvalidParameters)?;
+
+        let report = attestation.report(&nonce, self.config_digest(), self.security);
+        data[..REPORT_SIZE].copy_from_slice(&report.to_bytes());
+        Ok(REPORT_SIZE)
+    }
+
+    /// Run `f` on the firmware updater, if there is one.
+    fn with_update(&mut self, f: impl FnOnce(&mut U) -> core::result::Result<(), UpdateError>) -> GenericRsp {
+        match self.update.as_mut() {
+            Some(update) => f(update).into(),
+            None => GenericRsp {
+                _status: ErrorCode::NotSupported as i64,
+            },
+        }
+    }
+
+    fn fw_update_begin(&mut self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        let mut header = ImageHeader {
+            size: msg.u32_at(4),
+            version: msg.u16_at(8),
+            digest: [0; DIGEST_SIZE],
+            signature: [0; SIGNATURE_SIZE],
+        };
+        header.digest.copy_from_slice(msg.slice(16..16 + DIGEST_SIZE));
+        header.signature.copy_from_slice(msg.slice(48..48 + SIGNATURE_SIZE));
+        self.with_update(|update| update.begin(header))
+    }
+
+    fn fw_update_write(&mut self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        let (offset, len) = (msg.u32_at(4), msg.u32_at(8) as usize);
+        if len > FW_UPDATE_CHUNK_SIZE {
+            return GenericRsp {
+                _status: ErrorCode::InvalidParameters as i64,
+            };
+        }
+        self.with_update(|update| update.write(offset, msg.slice(16..16 + len)))
+    }
+
+    /// Copy `length` bytes of image at `offset` from the memory the OS shares at `address`.
+    fn fw_update_write_shared(&mut self, address: u64, offset: u64, length: u64) -> GenericRsp {
+        let Some(memory_mapper) = self.memory_mapper else {
+            return GenericRsp {
+                _status: ErrorCode::NotSupported as i64,
+            };
+        };
+        if offset.saturating_add(length) > u32::MAX as u64 {
+            return GenericRsp {
+                _status: ErrorCode::InvalidParameters as i64,
+            };
+        }
+
+        let mut chunk = [0; FW_UPDATE_COPY_SIZE];
+        for start in (0..length).step_by(FW_UPDATE_COPY_SIZE) {
+            let len = (length - start).min(FW_UPDATE_COPY_SIZE as u64) as usize;
+            if let Err(e) = memory_mapper.read(address + start, &mut chunk[..len]) {
+                warn!("Failed to read image at {:#x}: {:?}", address + start, e);
+                return GenericRsp {
+                    _status: ErrorCode::InvalidParameters as i64,
+                };
+            }
+
+            let rsp = self.with_update(|update| update.write((offset + start) as u32, &chunk[..len]));
+            if rsp._status != 0 {
+                return rsp;
+            }
+        }
+        GenericRsp { _status: 0x0 }
+    }
+
+    fn fw_update_status(&self) -> FwUpdateStatusRsp {
+        match &self.update {
+            Some(update) => {
+                let progress = update.progress();
+                FwUpdateStatusRsp {
+                    status: 0x0,
+                    state: progress.state as u8,
+                    boot_status: update.boot_status() as u8,
+                    received: progress.received,
+                    size: progress.size,
+                }
+            }
+            None => FwUpdateStatusRsp {
+                status: ErrorCode::NotSupported as i64,
+                ..Default::default()
+            },
+        }
+    }
+
     fn test_notify(&self, msg: MsgSendDirectReq2) -> GenericRsp {
         // let nfy = FfaNotify {
         //     function_id: FunctionId::NotificationSet.into(),
@@ -147,36 +693,82 @@ impl FwMgmt {
         GenericRsp { _status: 0x0 }
     }
 
-    fn process_indirect(&self, seq_num: u16, _rx_buffer: u64, _tx_buffer: u64) -> GenericRsp {
+    /// Answer the indirect message the OS left at `rx_buffer`, in `tx_buffer`.
+    ///
+    /// Both buffers start with the sequence number. The request then has the command, the
+    /// response the length of the data following the header.
+    fn process_indirect(&self, seq_num: u16, rx_buffer: u64, tx_buffer: u64) -> GenericRsp {
         debug!("Processing indirect message: 0x{:x}", seq_num);
-        // let msg = FfaIndirectMsg::new();
-        // let mut in_buf: [u8; 256] = [0; 256];
-        // let mut status;
-
-        // unsafe {
-        //     status = msg.read_indirect_msg(rx_buffer, seq_num, &mut in_buf);
-        // };
+        let Some(memory_mapper) = self.memory_mapper else {
+            return GenericRsp {
+                _status: ErrorCode::NotSupported as i64,
+            };
+        };
 
-        // if status == FfaError::Ok {
-        //     error!("Indirect Message: {:?}", in_buf);
-        // }
+        let mut header = [0; INDIRECT_HEADER_SIZE as usize];
+        if let Err(e) = memory_mapper.read(rx_buffer, &mut header) {
+            warn!("Failed to read indirect message at {:#x}: {:?}", rx_buffer, e);
+            return GenericRsp {
+                _status: ErrorCode::InvalidParameters as i64,
+            };
+        }
+        let [seq_lo, seq_hi, cmd_lo, cmd_hi, ..] = header;
+        if u16::from_le_bytes([seq_lo, seq_hi]) != seq_num {
+            return GenericRsp {
+                _status: ErrorCode::InvalidParameters as i64,
+            };
+        }
 
-        // // Populate TX buffer with response and matching seq num
-        // let buf: [u8; 16] = [
-        //     0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF,
-        // ];
-        // unsafe {
-        //     status = msg.write_indirect_msg(tx_buffer, seq_num, &buf);
-        // };
+        let mut data = [0; INDIRECT_DATA_SIZE];
+        let len = match u16::from_le_bytes([cmd_lo, cmd_hi]) {
+            EC_INDIRECT_GET_BUILD_INFO => self.build_info(&mut data),
+            EC_INDIRECT_GET_ATTESTATION => self.attestation_report(memory_mapper, rx_buffer, &mut data),
+            cmd => {
+                warn!("Unknown indirect message command {:#x}", cmd);
+                Err(ErrorCode::NotSupported)
+            }
+        };
+        let len = match len {
+            Ok(len) => len,
+            Err(e) => return GenericRsp { _status: e as i64 },
+        };
 
-        // GenericRsp { _status: status.into() }
+        let mut header = [0; INDIRECT_HEADER_SIZE as usize];
+        header[..2].copy_from_slice(&seq_num.to_le_bytes());
+        header[2..4].copy_from_slice(&(len as u16).to_le_bytes());
+        let written = memory_mapper
+            .write(tx_buffer, &header)
+            .and_then(|()| memory_mapper.write(tx_buffer + INDIRECT_HEADER_SIZE, &data[..len]));
+        if let Err(e) = written {
+            warn!("Failed to write indirect message at {:#x}: {:?}", tx_buffer, e);
+            return GenericRsp {
+                _status: ErrorCode::InvalidParameters as i64,
+            };
+        }
         GenericRsp { _status: 0x0 }
     }
 }
 
 const UUID: Uuid = uuid!("330c1273-fde5-4757-9819-5b6539037502");
 
-impl Service for FwMgmt {
+/// Commands changing the partition or sharing memory with it are for the OS only.
+static ACCESS_RULES: [AccessRule; 1] = [AccessRule {
+    opcodes: &[
+        EC_CAP_TEST_NFY,
+        EC_CAP_MAP_SHARE,
+        EC_CAP_CLEAR_CRASH_DUMP,
+        EC_CAP_SET_LOG_LEVEL,
+        EC_CAP_FW_UPDATE_BEGIN,
+        EC_CAP_FW_UPDATE_WRITE,
+        EC_CAP_FW_UPDATE_WRITE_SHARED,
+        EC_CAP_FW_UPDATE_END,
+        EC_CAP_FW_UPDATE_COMMIT,
+        EC_CAP_FW_UPDATE_ROLLBACK,
+    ],
+    callers: &[Caller::Endpoint(NORMAL_WORLD)],
+}];
+
+impl<U: FirmwareUpdater> Service for FwMgmt<U> {
     fn service_name(&self) -> &'static str {
         "FwMgmt"
     }
@@ -185,6 +777,10 @@ impl Service for FwMgmt {
         UUID
     }
 
+    fn access_rules(&self) -> &[AccessRule] {
+        &ACCESS_RULES
+    }
+
     async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
         let cmd = msg.u8_at(0);
         debug!("Received FwMgmt command 0x{:x}", cmd);
@@ -198,11 +794,31 @@ impl Service for FwMgmt {
             EC_CAP_GET_FW_STATE => RegisterPayload::from(self.get_fw_state()),
             EC_CAP_GET_SVC_LIST => RegisterPayload::from(self.get_svc_list()),
             EC_CAP_GET_BID => RegisterPayload::from(self.get_bid()),
+            EC_CAP_GET_FW_IDENTITY => RegisterPayload::from(self.get_fw_identity()),
+            EC_CAP_GET_SECURE_STATE => RegisterPayload::from(self.get_secure_state()),
             EC_CAP_TEST_NFY => RegisterPayload::from(self.test_notify(msg.clone())),
             EC_CAP_MAP_SHARE => {
-                // First parameter is pointer to memory descriptor
-                RegisterPayload::from(self.map_share(msg.register_at(1), msg.register_at(2)))
+                // First parameter is the handle the OS got from FFA_MEM_SHARE
+                RegisterPayload::from(self.map_share(msg.register_at(1), msg.source_id(), msg.destination_id()))
             }
+            EC_CAP_READ_CRASH_DUMP => RegisterPayload::from(self.read_crash_dump(msg.register_at(1))),
+            EC_CAP_CLEAR_CRASH_DUMP => RegisterPayload::from(self.clear_crash_dump()),
+            EC_CAP_GET_HEAP_STATS => RegisterPayload::from(self.get_heap_stats()),
+            EC_CAP_READ_LOG => RegisterPayload::from(read_log(msg.register_at(1))),
+            EC_CAP_SET_LOG_LEVEL => RegisterPayload::from(GenericRsp {
+                _status: set_log_level(&msg),
+            }),
+            EC_CAP_FW_UPDATE_BEGIN => RegisterPayload::from(self.fw_update_begin(&msg)),
+            EC_CAP_FW_UPDATE_WRITE => RegisterPayload::from(self.fw_update_write(&msg)),
+            EC_CAP_FW_UPDATE_WRITE_SHARED => RegisterPayload::from(self.fw_update_write_shared(
+                msg.register_at(1),
+                msg.register_at(2),
+                msg.register_at(3),
+            )),
+            EC_CAP_FW_UPDATE_END => RegisterPayload::from(self.with_update(|update| update.end())),
+            EC_CAP_FW_UPDATE_STATUS => RegisterPayload::from(self.fw_update_status()),
+            EC_CAP_FW_UPDATE_COMMIT => RegisterPayload::from(self.with_update(|update| update.commit())),
+            EC_CAP_FW_UPDATE_ROLLBACK => RegisterPayload::from(self.with_update(|update| update.rollback())),
             _ => {
                 error!("Unknown FwMgmt Command: {}", cmd);
                 return Err(odp_ffa::Error::Other("Unknown FwMgmt Command"));
@@ -212,3 +828,315 @@ impl Service for FwMgmt {
         Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::{AddressSpace, AddressSpaceError, Region, SharedAddressSpace, PAGE_SIZE};
+    use crate::fw_update::{AbUpdate, BootStatus, RamFlash, Slot, UnsignedVerifier, UpdateState};
+    use crate::identity::Version;
+    use crate::mem_share::{MemoryRegion, Regions};
+    use crate::test_support::{leak_page, request, NoTable};
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    /// Records the regions mapped, failing to map `fail_at`.
+    struct Mapper {
+        fail_at: u64,
+        mapped: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl MemoryMapper for Mapper {
+        fn map(&self, base: u64, size: u64, attributes: Attributes) -> core::result::Result<(), AddressSpaceError> {
+            assert_eq!(attributes, Attributes::DATA.non_secure());
+            if base == self.fail_at {
+                return Err(AddressSpaceError::Overlap);
+            }
+            self.mapped.lock().unwrap().push((base, size));
+            Ok(())
+        }
+
+        fn unmap(&self, base: u64, size: u64) -> core::result::Result<(), AddressSpaceError> {
+            self.mapped.lock().unwrap().retain(|region| *region != (base, size));
+            Ok(())
+        }
+
+        fn check(&self, _address: u64, _len: u64, _write: bool) -> core::result::Result<Region, AddressSpaceError> {
+            Err(AddressSpaceError::NotMapped)
+        }
+    }
+
+    /// Answers every retrieve of handle 0x1234 from endpoint 0x1 with `result`.
+    struct Retriever(core::result::Result<Regions, RetrieveError>);
+
+    impl MemoryRetriever for Retriever {
+        fn retrieve(&self, handle: u64, sender: u16, receiver: u16) -> core::result::Result<Regions, RetrieveError> {
+            assert_eq!((handle, sender, receiver), (0x1234, 0x1, 0x8002));
+            self.0.clone()
+        }
+    }
+
+    fn map_share(fw_mgmt: &mut FwMgmt) -> i64 {
+        let payload = [EC_CAP_MAP_SHARE, 0, 0, 0, 0, 0, 0, 0]
+            .into_iter()
+            .chain(0x1234u64.to_le_bytes());
+        let msg = MsgSendDirectReq2::new(0x1, 0x8002, UUID, RegisterPayload::from_iter(payload));
+        let rsp = embassy_futures::block_on(fw_mgmt.ffa_msg_send_direct_req2(msg)).unwrap();
+        rsp.u64_at(0) as i64
+    }
+
+    fn regions(regions: &[(u64, u32)]) -> Regions {
+        regions
+            .iter()
+            .map(|&(address, page_count)| MemoryRegion { address, page_count })
+            .collect()
+    }
+
+    #[test]
+    fn test_map_share_maps_retrieved_regions() {
+        let mapper = Box::leak(Box::new(Mapper {
+            fail_at: 0,
+            mapped: Mutex::new(Vec::new()),
+        }));
+        let retriever = Box::leak(Box::new(Retriever(Ok(regions(&[(0x8000_0000, 2), (0x9000_0000, 1)])))));
+
+        let mut fw_mgmt = FwMgmt::new().with_memory_mapper(mapper);
+        assert_eq!(map_share(&mut fw_mgmt), ErrorCode::NotSupported as i64);
+
+        let mut fw_mgmt = fw_mgmt.with_memory_retriever(retriever);
+        assert_eq!(map_share(&mut fw_mgmt), 0);
+        assert_eq!(
+            *mapper.mapped.lock().unwrap(),
+            [(0x8000_0000, 2 * PAGE_SIZE), (0x9000_0000, PAGE_SIZE)]
+        );
+    }
+
+    #[test]
+    fn test_map_share_failures() {
+        let mapper = Box::leak(Box::new(Mapper {
+            fail_at: 0x9000_0000,
+            mapped: Mutex::new(Vec::new()),
+        }));
+
+        // Rejected by the SPMC: its status is returned and nothing is mapped
+        let denied = Retriever(Err(RetrieveError::Ffa(odp_ffa::Error::ErrorCode(ErrorCode::Denied))));
+        let mut fw_mgmt = FwMgmt::new()
+            .with_memory_mapper(mapper)
+            .with_memory_retriever(Box::leak(Box::new(denied)));
+        assert_eq!(map_share(&mut fw_mgmt), ErrorCode::Denied as i64);
+
+        let malformed = Retriever(Err(RetrieveError::Malformed));
+        let mut fw_mgmt = fw_mgmt.with_memory_retriever(Box::leak(Box::new(malformed)));
+        assert_eq!(map_share(&mut fw_mgmt), ErrorCode::InvalidParameters as i64);
+        assert!(mapper.mapped.lock().unwrap().is_empty());
+
+        // A region failing to map unmaps the ones before it
+        let partial = Retriever(Ok(regions(&[(0x8000_0000, 2), (0x9000_0000, 1)])));
+        let mut fw_mgmt = fw_mgmt.with_memory_retriever(Box::leak(Box::new(partial)));
+        assert_eq!(map_share(&mut fw_mgmt), ErrorCode::InvalidParameters as i64);
+        assert!(mapper.mapped.lock().unwrap().is_empty());
+    }
+
+    type Update = AbUpdate<RamFlash<1024>, UnsignedVerifier>;
+
+    const IMAGE: [u8; 200] = {
+        let mut image = [0; 200];
+        let mut i = 0;
+        while i < image.len() {
+            image[i] = i as u8 ^ 0x5a;
+            i += 1;
+        }
+        image
+    };
+
+    fn update() -> FwMgmt<Update> {
+        FwMgmt::new().with_firmware_update(AbUpdate::new(RamFlash::new(Slot::A), UnsignedVerifier::new()))
+    }
+
+    fn send<U: FirmwareUpdater>(fw_mgmt: &mut FwMgmt<U>, bytes: &[u8]) -> MsgSendDirectResp2 {
+        embassy_futures::block_on(fw_mgmt.ffa_msg_send_direct_req2(request(UUID, bytes))).unwrap()
+    }
+
+    fn status<U: FirmwareUpdater>(fw_mgmt: &mut FwMgmt<U>, bytes: &[u8]) -> i64 {
+        send(fw_mgmt, bytes).u64_at(0) as i64
+    }
+
+    fn begin(image: &[u8], version: u16) -> Vec<u8> {
+        let mut bytes = vec![EC_CAP_FW_UPDATE_BEGIN, 0, 0, 0];
+        bytes.extend((image.len() as u32).to_le_bytes());
+        bytes.extend(version.to_le_bytes());
+        bytes.resize(16, 0);
+        bytes.extend(Sha256::digest(image));
+        bytes.extend([0; SIGNATURE_SIZE]);
+        bytes
+    }
+
+    fn write(offset: u32, chunk: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![EC_CAP_FW_UPDATE_WRITE, 0, 0, 0];
+        bytes.extend(offset.to_le_bytes());
+        bytes.extend((chunk.len() as u32).to_le_bytes());
+        bytes.resize(16, 0);
+        bytes.extend(chunk);
+        bytes
+    }
+
+    /// Requests passing 64-bit `registers` from register 1 on, as the OS does.
+    fn registers(cmd: u8, registers: &[u64]) -> Vec<u8> {
+        let mut bytes = vec![cmd, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(registers.iter().flat_map(|register| register.to_le_bytes()));
+        bytes
+    }
+
+    /// Map a page of `fill` in `space`, returning it and its address.
+    fn shared_page(space: &SharedAddressSpace<NoTable, 2>, fill: u8) -> (&'static mut [u8; 4096], u64) {
+        let page = leak_page(fill);
+        let base = page.as_ptr() as u64;
+        space.map(base, PAGE_SIZE, Attributes::DATA).unwrap();
+        (page, base)
+    }
+
+    #[test]
+    fn test_update_commands() {
+        let mut fw_mgmt = update();
+        assert_eq!(status(&mut fw_mgmt, &begin(&IMAGE, 0x0200)), 0);
+        let mut too_long = write(0, &IMAGE[..FW_UPDATE_CHUNK_SIZE]);
+        too_long[8] += 1;
+        assert_eq!(status(&mut fw_mgmt, &too_long), ErrorCode::InvalidParameters as i64);
+        assert_eq!(
+            status(&mut fw_mgmt, &write(96, &IMAGE[96..192])),
+            ErrorCode::InvalidParameters as i64
+        );
+        assert_eq!(status(&mut fw_mgmt, &[EC_CAP_FW_UPDATE_END]), ErrorCode::Denied as i64);
+        for (i, chunk) in IMAGE.chunks(FW_UPDATE_CHUNK_SIZE).enumerate() {
+            assert_eq!(
+                status(&mut fw_mgmt, &write((i * FW_UPDATE_CHUNK_SIZE) as u32, chunk)),
+                0
+            );
+        }
+        assert_eq!(status(&mut fw_mgmt, &[EC_CAP_FW_UPDATE_END]), 0);
+
+        let rsp = send(&mut fw_mgmt, &[EC_CAP_FW_UPDATE_STATUS]);
+        assert_eq!(rsp.u64_at(0), 0);
+        assert_eq!(
+            (rsp.u8_at(8), rsp.u8_at(9), rsp.u32_at(12), rsp.u32_at(16)),
+            (UpdateState::Staged as u8, BootStatus::Staged as u8, 200, 200)
+        );
+
+        // Only an image on trial is committed, the staged one is dropped
+        assert_eq!(
+            status(&mut fw_mgmt, &[EC_CAP_FW_UPDATE_COMMIT]),
+            ErrorCode::Denied as i64
+        );
+        assert_eq!(status(&mut fw_mgmt, &[EC_CAP_FW_UPDATE_ROLLBACK]), 0);
+        let rsp = send(&mut fw_mgmt, &[EC_CAP_FW_UPDATE_STATUS]);
+        assert_eq!(
+            (rsp.u8_at(8), rsp.u8_at(9)),
+            (UpdateState::Idle as u8, BootStatus::Normal as u8)
+        );
+    }
+
+    #[test]
+    fn test_update_not_supported() {
+        let mut fw_mgmt = FwMgmt::new();
+        for cmd in [
+            EC_CAP_FW_UPDATE_END,
+            EC_CAP_FW_UPDATE_STATUS,
+            EC_CAP_FW_UPDATE_COMMIT,
+            EC_CAP_FW_UPDATE_ROLLBACK,
+        ] {
+            assert_eq!(status(&mut fw_mgmt, &[cmd]), ErrorCode::NotSupported as i64);
+        }
+        assert_eq!(
+            status(&mut fw_mgmt, &begin(&IMAGE, 0x0200)),
+            ErrorCode::NotSupported as i64
+        );
+    }
+
+    #[test]
+    fn test_update_write_shared() {
+        static SPACE: SharedAddressSpace<NoTable, 2> = SharedAddressSpace::new();
+        SPACE.set(AddressSpace::new(NoTable));
+        let (page, base) = shared_page(&SPACE, 0);
+        page[..IMAGE.len()].copy_from_slice(&IMAGE);
+
+        let mut fw_mgmt = update();
+        let length = IMAGE.len() as u64;
+        let write_shared = registers(EC_CAP_FW_UPDATE_WRITE_SHARED, &[base, 0, length]);
+        assert_eq!(status(&mut fw_mgmt, &write_shared), ErrorCode::NotSupported as i64);
+
+        let mut fw_mgmt = fw_mgmt.with_memory_mapper(&SPACE);
+        assert_eq!(status(&mut fw_mgmt, &begin(&IMAGE, 0x0200)), 0);
+        // Memory not shared with the service is not read
+        let unmapped = registers(EC_CAP_FW_UPDATE_WRITE_SHARED, &[base + PAGE_SIZE, 0, length]);
+        assert_eq!(status(&mut fw_mgmt, &unmapped), ErrorCode::InvalidParameters as i64);
+        let overflow = registers(EC_CAP_FW_UPDATE_WRITE_SHARED, &[base, u32::MAX as u64, length]);
+        assert_eq!(status(&mut fw_mgmt, &overflow), ErrorCode::InvalidParameters as i64);
+
+        assert_eq!(status(&mut fw_mgmt, &write_shared), 0);
+        assert_eq!(status(&mut fw_mgmt, &[EC_CAP_FW_UPDATE_END]), 0);
+    }
+
+    #[test]
+    fn test_process_indirect() {
+        static SPACE: SharedAddressSpace<NoTable, 2> = SharedAddressSpace::new();
+        static IDENTITY: FirmwareIdentity = FirmwareIdentity {
+            name: "ec",
+            version: Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            },
+            git_revision: "0123456789ab",
+            build_time: "2025-01-01T00:00:00+00:00",
+            features: "",
+        };
+        const BUILD_INFO: &str = "ec 1.2.3 (0123456789ab) built 2025-01-01T00:00:00+00:00";
+
+        SPACE.set(AddressSpace::new(NoTable));
+        let (rx, rx_base) = shared_page(&SPACE, 0);
+        let (tx, tx_base) = shared_page(&SPACE, 0xff);
+        let indirect = |seq: u8| {
+            let mut bytes = registers(EC_CAP_INDIRECT_MSG, &[0, 0, 0, rx_base, tx_base]);
+            bytes[1] = seq;
+            bytes
+        };
+
+        rx[..4].copy_from_slice(&[7, 0, EC_INDIRECT_GET_BUILD_INFO as u8, 0]);
+        let mut fw_mgmt = FwMgmt::new().with_memory_mapper(&SPACE);
+        assert_eq!(status(&mut fw_mgmt, &indirect(7)), ErrorCode::NotSupported as i64);
+
+        let mut fw_mgmt = fw_mgmt.with_identity(&IDENTITY);
+        assert_eq!(status(&mut fw_mgmt, &indirect(7)), 0);
+        assert_eq!(tx[..4], [7, 0, BUILD_INFO.len() as u8, 0]);
+        assert_eq!(&tx[8..8 + BUILD_INFO.len()], BUILD_INFO.as_bytes());
+
+        // The sequence number must match the one of the message in the buffer
+        assert_eq!(status(&mut fw_mgmt, &indirect(8)), ErrorCode::InvalidParameters as i64);
+
+        rx[2] = 0x7f;
+        assert_eq!(status(&mut fw_mgmt, &indirect(7)), ErrorCode::NotSupported as i64);
+    }
+
+    #[test]
+    fn test_build_info_truncated() {
+        let features = "x".repeat(INDIRECT_DATA_SIZE);
+        let identity = Box::leak(Box::new(FirmwareIdentity {
+            name: "ec",
+            version: Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            },
+            git_revision: "0123456789ab",
+            build_time: "2025-01-01T00:00:00+00:00",
+            features: features.leak(),
+        }));
+        let fw_mgmt = FwMgmt::new().with_identity(identity);
+
+        let mut data = [0; INDIRECT_DATA_SIZE];
+        assert_eq!(fw_mgmt.build_info(&mut data), Ok(INDIRECT_DATA_SIZE));
+        let prefix = "ec 1.2.3 (0123456789ab) built 2025-01-01T00:00:00+00:00 features ";
+        assert_eq!(&data[..prefix.len()], prefix.as_bytes());
+        assert!(data[prefix.len()..].iter().all(|&b| b == b'x'));
+    }
+}
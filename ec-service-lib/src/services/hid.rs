@@ -0,0 +1,604 @@
+use core::cell::RefCell;
+
+use super::notification::{self, Notification};
+use crate::{Result, Service};
+use critical_section::Mutex;
+use heapless::Deque;
+use log::{debug, error};
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::{uuid, Uuid};
+
+// Protocol CMD definitions for Hid
+const EC_HID_GET_ATTRIBUTES: u8 = 0x1;
+const EC_HID_GET_DESCRIPTOR: u8 = 0x2;
+const EC_HID_GET_INPUT_REPORT: u8 = 0x3;
+
+/// Bytes of report descriptor returned per EC_HID_GET_DESCRIPTOR request.
+const DESCRIPTOR_CHUNK_SIZE: usize = 96;
+
+/// Largest input report, reports fit a single response.
+pub const MAX_REPORT_SIZE: usize = 64;
+
+/// Input reports queued for the OS, the oldest are dropped beyond.
+pub const INPUT_QUEUE_DEPTH: usize = 16;
+
+/// Rows a [`KeyboardMatrix`] can have.
+pub const MAX_MATRIX_ROWS: usize = 16;
+
+/// Report descriptor of a boot protocol keyboard, as in appendix B.1 of the HID specification.
+pub const BOOT_KEYBOARD_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute), modifier byte
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant), reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute), LED report
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant), LED report padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array), key array
+    0xc0, // End Collection
+];
+
+/// Size of a boot protocol keyboard input report.
+pub const BOOT_KEYBOARD_REPORT_SIZE: usize = 8;
+
+// Usages of the modifier keys, reported as bits of the first byte
+const USAGE_LEFT_CONTROL: u8 = 0xe0;
+const USAGE_RIGHT_GUI: u8 = 0xe7;
+/// Usage filling the key array when more keys are down than it holds.
+const USAGE_ERROR_ROLLOVER: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputReport {
+    len: u8,
+    data: [u8; MAX_REPORT_SIZE],
+}
+
+impl InputReport {
+    /// A report holding `data`, or `None` if it is longer than [`MAX_REPORT_SIZE`].
+    pub fn new(data: &[u8]) -> Option<Self> {
+        let mut report = InputReport {
+            len: data.len() as u8,
+            data: [0; MAX_REPORT_SIZE],
+        };
+        report.data.get_mut(..data.len())?.copy_from_slice(data);
+        Some(report)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+/// Identification of the HID device, as in the HID descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HidAttributes {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub version: u16,
+}
+
+/// An HID device exposed to the OS by the [`Hid`] service.
+pub trait HidSource {
+    fn attributes(&self) -> HidAttributes;
+    fn report_descriptor(&self) -> &[u8];
+
+    /// The next input report, polled when the OS drains an empty queue.
+    ///
+    /// Events signalled by interrupts, such as hotkeys, are rather queued with
+    /// [`push_input_report`].
+    fn poll(&mut self) -> Option<InputReport> {
+        None
+    }
+}
+
+/// A keyboard matrix, scanned for the keys currently down.
+pub trait KeyboardMatrix {
+    /// Number of rows, at most [`MAX_MATRIX_ROWS`].
+    fn rows(&self) -> usize;
+
+    /// Read the keys down, one bit per column for each row.
+    fn scan(&mut self, rows: &mut [u16]);
+
+    /// HID usage of the key at `row` and `column`, 0 if there is none.
+    fn usage(&self, row: usize, column: usize) -> u8;
+}
+
+/// A boot protocol keyboard reporting the keys of a [`KeyboardMatrix`].
+pub struct MatrixKeyboard<M: KeyboardMatrix> {
+    matrix: M,
+    attributes: HidAttributes,
+    last_scan: [u16; MAX_MATRIX_ROWS],
+}
+
+impl<M: KeyboardMatrix> MatrixKeyboard<M> {
+    pub fn new(matrix: M, attributes: HidAttributes) -> Self {
+        Self {
+            matrix,
+            attributes,
+            last_scan: [0; MAX_MATRIX_ROWS],
+        }
+    }
+
+    /// Scan the matrix and queue the keys down for the OS if they changed since the last scan.
+    ///
+    /// For a platform task scanning the keyboard, the OS being notified of the new report. The
+    /// [`Hid`] service then exposes a [`BootKeyboard`] rather than polling the matrix itself.
+    pub fn scan(&mut self) {
+        if let Some(report) = self.poll() {
+            push_input_report(report);
+        }
+    }
+
+    /// The boot keyboard report of the keys down in `scan`.
+    fn report(&self, scan: &[u16]) -> InputReport {
+        let mut data = [0u8; BOOT_KEYBOARD_REPORT_SIZE];
+        let mut keys = 0;
+        for (row, &columns) in scan.iter().enumerate() {
+            for column in (0..u16::BITS as usize).filter(|c| columns & (1 << c) != 0) {
+                match self.matrix.usage(row, column) {
+                    0 => {}
+                    usage @ USAGE_LEFT_CONTROL..=USAGE_RIGHT_GUI => data[0] |= 1 << (usage - USAGE_LEFT_CONTROL),
+                    usage => {
+                        if keys < 6 {
+                            data[2 + keys] = usage;
+                        } else {
+                            data[2..].fill(USAGE_ERROR_ROLLOVER);
+                        }
+                        keys += 1;
+                    }
+                }
+            }
+        }
+        InputReport::new(&data).unwrap()
+    }
+}
+
+impl<M: KeyboardMatrix> HidSource for MatrixKeyboard<M> {
+    fn attributes(&self) -> HidAttributes {
+        self.attributes
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        BOOT_KEYBOARD_DESCRIPTOR
+    }
+
+    /// Scan the matrix, reporting the keys down if they changed since the last scan.
+    fn poll(&mut self) -> Option<InputReport> {
+        let rows = self.matrix.rows().min(MAX_MATRIX_ROWS);
+        let mut scan = [0; MAX_MATRIX_ROWS];
+        self.matrix.scan(&mut scan[..rows]);
+        if scan == self.last_scan {
+            return None;
+        }
+        self.last_scan = scan;
+        Some(self.report(&scan[..rows]))
+    }
+}
+
+/// A boot protocol keyboard whose input reports are all queued with [`push_input_report`], such
+/// as by a task scanning a [`MatrixKeyboard`].
+pub struct BootKeyboard {
+    attributes: HidAttributes,
+}
+
+impl BootKeyboard {
+    pub const fn new(attributes: HidAttributes) -> Self {
+        Self { attributes }
+    }
+}
+
+impl HidSource for BootKeyboard {
+    fn attributes(&self) -> HidAttributes {
+        self.attributes
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        BOOT_KEYBOARD_DESCRIPTOR
+    }
+}
+
+/// A step of a [`SimulatedMatrix`] script, the keys down for one scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatrixStep {
+    pub rows: [u16; MAX_MATRIX_ROWS],
+}
+
+impl MatrixStep {
+    /// No key down.
+    pub const RELEASED: MatrixStep = MatrixStep {
+        rows: [0; MAX_MATRIX_ROWS],
+    };
+
+    /// `self` with the key at `row` and `column` down as well.
+    ///
+    /// Keys beyond the [`MAX_MATRIX_ROWS`] rows of 16 columns a step holds are ignored.
+    pub const fn press(mut self, row: usize, column: usize) -> Self {
+        if row < MAX_MATRIX_ROWS && column < u16::BITS as usize {
+            self.rows[row] |= 1 << column;
+        }
+        self
+    }
+}
+
+/// A keyboard matrix playing a script of scans, then reporting no key down.
+pub struct SimulatedMatrix {
+    keymap: &'static [&'static [u8]],
+    script: &'static [MatrixStep],
+    position: usize,
+}
+
+impl SimulatedMatrix {
+    /// A matrix with the usages of `keymap`, indexed by row then column, playing `script`.
+    pub const fn new(keymap: &'static [&'static [u8]], script: &'static [MatrixStep]) -> Self {
+        Self {
+            keymap,
+            script,
+            position: 0,
+        }
+    }
+}
+
+impl KeyboardMatrix for SimulatedMatrix {
+    fn rows(&self) -> usize {
+        self.keymap.len()
+    }
+
+    fn scan(&mut self, rows: &mut [u16]) {
+        let step = self.script.get(self.position).unwrap_or(&MatrixStep::RELEASED);
+        self.position += 1;
+        for (row, columns) in rows.iter_mut().zip(step.rows) {
+            *row = columns;
+        }
+    }
+
+    fn usage(&self, row: usize, column: usize) -> u8 {
+        self.keymap
+            .get(row)
+            .and_then(|columns| columns.get(column))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+struct InputQueue {
+    reports: Deque<InputReport, INPUT_QUEUE_DEPTH>,
+    dropped: u32,
+    notification: Option<Notification>,
+}
+
+impl InputQueue {
+    const fn new() -> Self {
+        Self {
+            reports: Deque::new(),
+            dropped: 0,
+            notification: None,
+        }
+    }
+
+    /// Queue `report`, returning the notification to send if the queue was empty.
+    fn push(&mut self, report: InputReport) -> Option<Notification> {
+        let was_empty = self.reports.is_empty();
+        if self.reports.is_full() {
+            self.reports.pop_front();
+            self.dropped += 1;
+        }
+        let _ = self.reports.push_back(report);
+        self.notification.filter(|_| was_empty)
+    }
+}
+
+static INPUT_QUEUE: Mutex<RefCell<InputQueue>> = Mutex::new(RefCell::new(InputQueue::new()));
+
+/// Queue an input report for the OS, for the platform to call on hotkeys and other events.
+pub fn push_input_report(report: InputReport) {
+    let notification = critical_section::with(|cs| INPUT_QUEUE.borrow_ref_mut(cs).push(report));
+
+    if let Some(n) = notification {
+        n.send("HID input");
+    }
+}
+
+#[derive(Default)]
+struct AttributesRsp {
+    status: i64,
+    attributes: HidAttributes,
+    descriptor_len: u16,
+}
+
+impl From<AttributesRsp> for RegisterPayload {
+    fn from(rsp: AttributesRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.attributes.vendor_id.to_le_bytes())
+            .chain(rsp.attributes.product_id.to_le_bytes())
+            .chain(rsp.attributes.version.to_le_bytes())
+            .chain(rsp.descriptor_len.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+struct DescriptorChunkRsp {
+    status: i64,
+    total_len: u32,
+    chunk_len: u32,
+    chunk: [u8; DESCRIPTOR_CHUNK_SIZE],
+}
+
+impl From<DescriptorChunkRsp> for RegisterPayload {
+    fn from(rsp: DescriptorChunkRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.total_len.to_le_bytes())
+            .chain(rsp.chunk_len.to_le_bytes())
+            .chain(rsp.chunk.into_iter().take(rsp.chunk_len as usize));
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+struct InputReportRsp {
+    status: i64,
+    /// Reports still queued after this one.
+    remaining: u16,
+    /// Reports dropped because the queue was full, since the last report returned.
+    dropped: u16,
+    report: Option<InputReport>,
+}
+
+impl From<InputReportRsp> for RegisterPayload {
+    fn from(rsp: InputReportRsp) -> Self {
+        let len = rsp.report.map_or(0, |r| r.len as u32);
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(len.to_le_bytes())
+            .chain(rsp.remaining.to_le_bytes())
+            .chain(rsp.dropped.to_le_bytes())
+            .chain(
+                rsp.report
+                    .into_iter()
+                    .flat_map(|r| r.data.into_iter().take(r.len as usize)),
+            );
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+/// HID device of the EC, such as the keyboard, with its input reports queued for the OS.
+pub struct Hid<S: HidSource> {
+    source: S,
+    notification_id: Option<u8>,
+}
+
+impl<S: HidSource> Hid<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            notification_id: None,
+        }
+    }
+
+    /// Notify the OS of new input with the global notification `id`.
+    pub fn with_notification_id(mut self, id: u8) -> Self {
+        self.notification_id = Some(id);
+        self
+    }
+
+    fn get_attributes(&self) -> AttributesRsp {
+        AttributesRsp {
+            status: 0x0,
+            attributes: self.source.attributes(),
+            descriptor_len: self.source.report_descriptor().len() as u16,
+        }
+    }
+
+    fn get_descriptor(&self, offset: u64) -> DescriptorChunkRsp {
+        let descriptor = self.source.report_descriptor();
+        let mut rsp = DescriptorChunkRsp {
+            status: 0x0,
+            total_len: descriptor.len() as u32,
+            chunk_len: 0,
+            chunk: [0; DESCRIPTOR_CHUNK_SIZE],
+        };
+
+        let Some(rest) = descriptor.get(offset as usize..) else {
+            rsp.status = ErrorCode::InvalidParameters as i64;
+            return rsp;
+        };
+        let len = rest.len().min(DESCRIPTOR_CHUNK_SIZE);
+        rsp.chunk[..len].copy_from_slice(&rest[..len]);
+        rsp.chunk_len = len as u32;
+        rsp
+    }
+
+    fn get_input_report(&mut self) -> InputReportRsp {
+        let (queued, remaining, dropped) = critical_section::with(|cs| {
+            let mut queue = INPUT_QUEUE.borrow_ref_mut(cs);
+            let report = queue.reports.pop_front();
+            let dropped = core::mem::take(&mut queue.dropped);
+            (report, queue.reports.len() as u16, dropped.min(u16::MAX as u32) as u16)
+        });
+
+        let report = queued.or_else(|| self.source.poll());
+        InputReportRsp {
+            status: if report.is_some() {
+                0x0
+            } else {
+                ErrorCode::NoData as i64
+            },
+            remaining,
+            dropped,
+            report,
+        }
+    }
+}
+
+const UUID: Uuid = uuid!("5fca29e9-00a5-43f8-a984-747dcf3da58e");
+
+impl<S: HidSource> Service for Hid<S> {
+    fn service_name(&self) -> &'static str {
+        "Hid"
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        UUID
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        let cmd = msg.u8_at(0);
+        debug!("Received Hid command 0x{:x}", cmd);
+        if let Some(notification) = notification::track_endpoints(self.notification_id, &msg) {
+            critical_section::with(|cs| INPUT_QUEUE.borrow_ref_mut(cs).notification = Some(notification));
+        }
+
+        let payload = match cmd {
+            EC_HID_GET_ATTRIBUTES => RegisterPayload::from(self.get_attributes()),
+            EC_HID_GET_DESCRIPTOR => RegisterPayload::from(self.get_descriptor(msg.register_at(1))),
+            EC_HID_GET_INPUT_REPORT => RegisterPayload::from(self.get_input_report()),
+            _ => {
+                error!("Unknown Hid Command: {}", cmd);
+                return Err(odp_ffa::Error::Other("Unknown Hid Command"));
+            }
+        };
+
+        Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Usages of the keys of the test keymap
+    const A: u8 = 0x04;
+    const B: u8 = 0x05;
+    const LEFT_SHIFT: u8 = 0xe1;
+
+    static KEYMAP: [&[u8]; 2] = [&[A, B, 0, 0], &[LEFT_SHIFT, 0x06, 0x07, 0x08]];
+
+    fn keyboard(script: &'static [MatrixStep]) -> MatrixKeyboard<SimulatedMatrix> {
+        MatrixKeyboard::new(SimulatedMatrix::new(&KEYMAP, script), HidAttributes::default())
+    }
+
+    #[test]
+    fn test_matrix_reports_changes() {
+        static SCRIPT: [MatrixStep; 3] = [
+            MatrixStep::RELEASED.press(0, 0),
+            MatrixStep::RELEASED.press(0, 0),
+            MatrixStep::RELEASED.press(0, 1).press(1, 0),
+        ];
+        let mut keyboard = keyboard(&SCRIPT);
+
+        assert_eq!(keyboard.poll().unwrap().as_bytes(), [0, 0, A, 0, 0, 0, 0, 0]);
+        assert_eq!(keyboard.poll(), None);
+        assert_eq!(keyboard.poll().unwrap().as_bytes(), [0b10, 0, B, 0, 0, 0, 0, 0]);
+        assert_eq!(keyboard.poll().unwrap().as_bytes(), [0; BOOT_KEYBOARD_REPORT_SIZE]);
+        assert_eq!(keyboard.poll(), None);
+    }
+
+    #[test]
+    fn test_matrix_rollover() {
+        static SCRIPT: [MatrixStep; 1] = [MatrixStep::RELEASED
+            .press(0, 0)
+            .press(0, 1)
+            .press(1, 1)
+            .press(1, 2)
+            .press(1, 3)
+            .press(1, 0)
+            .press(0, 2)];
+        let mut keyboard = keyboard(&SCRIPT);
+        assert_eq!(
+            keyboard.poll().unwrap().as_bytes(),
+            [0b10, 0, A, B, 0x06, 0x07, 0x08, 0]
+        );
+
+        static KEYMAP7: [&[u8]; 1] = [&[0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a]];
+        static SEVEN_KEYS: [MatrixStep; 1] = [MatrixStep {
+            rows: [0x7f, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        }];
+        let mut keyboard = MatrixKeyboard::new(SimulatedMatrix::new(&KEYMAP7, &SEVEN_KEYS), HidAttributes::default());
+        assert_eq!(keyboard.poll().unwrap().as_bytes()[2..], [USAGE_ERROR_ROLLOVER; 6]);
+    }
+
+    #[test]
+    fn test_scan_queues_reports() {
+        static SCRIPT: [MatrixStep; 1] = [MatrixStep::RELEASED.press(0, 1)];
+        let mut keyboard = keyboard(&SCRIPT);
+        keyboard.scan();
+        keyboard.scan();
+
+        let mut hid = Hid::new(BootKeyboard::new(HidAttributes::default()));
+        let rsp = hid.get_input_report();
+        assert_eq!((rsp.status, rsp.remaining), (0, 1));
+        assert_eq!(rsp.report.unwrap().as_bytes(), [0, 0, B, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            hid.get_input_report().report.unwrap().as_bytes(),
+            [0; BOOT_KEYBOARD_REPORT_SIZE]
+        );
+        assert_eq!(hid.get_input_report().status, ErrorCode::NoData as i64);
+    }
+
+    #[test]
+    fn test_press_outside_matrix_ignored() {
+        let step = MatrixStep::RELEASED.press(0, 15).press(0, 16).press(MAX_MATRIX_ROWS, 0);
+        assert_eq!(step.rows[0], 1 << 15);
+        assert_eq!(step.rows[1..], [0; MAX_MATRIX_ROWS - 1]);
+    }
+
+    #[test]
+    fn test_queue_drops_oldest_and_notifies_when_empty() {
+        let notification = Notification {
+            sender_id: 0x8002,
+            receiver_id: 0,
+            bitmap: 1 << 6,
+        };
+        let mut queue = InputQueue::new();
+        queue.notification = Some(notification);
+
+        assert_eq!(queue.push(InputReport::new(&[0]).unwrap()), Some(notification));
+        for i in 1..=INPUT_QUEUE_DEPTH as u8 {
+            assert_eq!(queue.push(InputReport::new(&[i]).unwrap()), None);
+        }
+        assert_eq!(queue.dropped, 1);
+        assert_eq!(queue.reports.front().unwrap().as_bytes(), [1]);
+        assert!(InputReport::new(&[0; MAX_REPORT_SIZE + 1]).is_none());
+    }
+
+    #[test]
+    fn test_descriptor_chunks() {
+        let hid = Hid::new(keyboard(&[]));
+        let rsp = hid.get_descriptor(0);
+        assert_eq!(
+            (rsp.total_len as usize, rsp.chunk_len as usize),
+            (BOOT_KEYBOARD_DESCRIPTOR.len(), BOOT_KEYBOARD_DESCRIPTOR.len())
+        );
+        assert_eq!(hid.get_descriptor(60).chunk[..3], BOOT_KEYBOARD_DESCRIPTOR[60..]);
+        assert_eq!(hid.get_descriptor(64).status, ErrorCode::InvalidParameters as i64);
+    }
+}
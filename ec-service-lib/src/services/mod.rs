@@ -1,7 +1,32 @@
+mod debug;
 mod fw_mgmt;
+mod hid;
+mod notification;
 mod notify;
+mod oem;
+mod power;
 mod thermal;
+mod time_alarm;
+mod ucsi;
 
+pub use debug::Debug;
 pub use fw_mgmt::FwMgmt;
+pub use hid::{
+    push_input_report, BootKeyboard, Hid, HidAttributes, HidSource, InputReport, KeyboardMatrix, MatrixKeyboard,
+    MatrixStep, SimulatedMatrix, BOOT_KEYBOARD_DESCRIPTOR, BOOT_KEYBOARD_REPORT_SIZE, INPUT_QUEUE_DEPTH,
+    MAX_MATRIX_ROWS, MAX_REPORT_SIZE,
+};
 pub use notify::Notify;
+pub use oem::{
+    Oem, OemAccess, OemCommand, OemError, OemHandler, MAX_OEM_COMMANDS, OEM_REQUEST_SIZE, OEM_RESPONSE_SIZE,
+};
+pub use power::{
+    check_power_source, raise_power_events, ChargerControl, ChargerError, ChargerState, Power, PowerEvents,
+    PowerPlatform, SourceCapabilities, SourceType,
+};
 pub use thermal::Thermal;
+pub use time_alarm::{check_wake_alarms, DateTime, RtcError, RtcSource, SoftRtc, TimeAlarm};
+pub use ucsi::{
+    raise_connector_change, PdController, SimulatedPdController, Ucsi, UcsiCommand, UcsiError, MAX_CONNECTORS,
+    MESSAGE_SIZE, UCSI_DATA_SIZE,
+};
@@ -0,0 +1,56 @@
+//! Global notifications the services send the OS on events.
+
+use log::warn;
+use odp_ffa::{Function, MsgSendDirectReq2, NotificationSet};
+
+/// Notification flags for a global notification, as used by FwMgmt's test notification.
+const NOTIFICATION_FLAGS: u32 = 0b10;
+
+/// Where to send a service's notification, learned from the OS's requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Notification {
+    pub(super) sender_id: u16,
+    pub(super) receiver_id: u16,
+    pub(super) bitmap: u64,
+}
+
+impl Notification {
+    /// Send the notification, `event` naming what it is for in the log of a failure.
+    pub(super) fn send(self, event: &str) {
+        if let Err(e) = NotificationSet::new(self.sender_id, self.receiver_id, NOTIFICATION_FLAGS, self.bitmap).exec() {
+            warn!("Failed to notify {}: {:?}", event, e);
+        }
+    }
+}
+
+/// Who to notify with the global notification `id`, the OS being the sender of `msg`.
+///
+/// None when the service was given no notification id.
+pub(super) fn track_endpoints(id: Option<u8>, msg: &MsgSendDirectReq2) -> Option<Notification> {
+    id.map(|id| Notification {
+        sender_id: msg.destination_id(),
+        receiver_id: msg.source_id(),
+        bitmap: 1 << id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::request;
+    use uuid::uuid;
+
+    #[test]
+    fn test_track_endpoints() {
+        let msg = request(uuid!("7157addf-2fbe-4c63-ae95-efac16e3b01c"), &[0x1]);
+        assert_eq!(track_endpoints(None, &msg), None);
+        assert_eq!(
+            track_endpoints(Some(5), &msg),
+            Some(Notification {
+                sender_id: 2,
+                receiver_id: 1,
+                bitmap: 1 << 5,
+            })
+        );
+    }
+}
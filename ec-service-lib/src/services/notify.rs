@@ -1,3 +1,4 @@
+use crate::persist::{self, KeyValueStore, Persist};
 use crate::{Result, Service};
 use log::{debug, error, info};
 use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
@@ -12,6 +13,10 @@ const NOTIFY_MAX_MAPPINGS: usize = 64;
 // by the number of registers available.
 const NOTIFY_MAX_MAPPINGS_PER_REQ: usize = 8;
 
+// Bytes of a service and of a mapping in the persisted record
+const ENTRY_RECORD_SIZE: usize = 17;
+const MAPPING_RECORD_SIZE: usize = 9;
+
 const MESSAGE_INFO_DIR_RESP: u64 = 0x100; // Base for direct response messages
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
@@ -157,7 +162,7 @@ impl Default for NfyEntry {
     }
 }
 
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Copy, Clone)]
 pub struct Notify {
     // We will carry the registered notifications in this struct.
     // which will be an array of NfyEntry with size of NOTIFY_MAX_SERVICES.
@@ -166,6 +171,9 @@ pub struct Notify {
     // Here we also keep track of the global bitmap to the best of our knowledge.
     // So that the multiple mappings will not conflict on the same bit.
     global_bitmap: u64,
+
+    // Where the registrations are kept across restarts, if anywhere.
+    store: Option<&'static dyn KeyValueStore>,
 }
 
 impl Notify {
@@ -173,10 +181,17 @@ impl Notify {
         Self::default()
     }
 
+    /// Keep the registrations in `store`, restoring those it holds.
+    pub fn with_store(mut self, store: &'static dyn KeyValueStore) -> Self {
+        persist::restore(store, &mut self);
+        self.store = Some(store);
+        self
+    }
+
     fn nfy_find_entry(&self, uuid: Uuid) -> Option<usize> {
         self.entries
             .iter()
-            .position(|entry| (entry.service_uuid == uuid && entry.in_use))
+            .position(|entry| entry.service_uuid == uuid && entry.in_use)
     }
 
     fn nfy_find_empty_slot(&self) -> Option<usize> {
@@ -425,6 +440,88 @@ impl Notify {
     }
 }
 
+/// The registered services, each as its UUID and count of mappings followed by the mappings as
+/// cookie, id, type and source id.
+impl Persist for Notify {
+    const KEY: u16 = 0x1;
+    const VERSION: u8 = 1;
+
+    fn save(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut len = 0;
+        for entry in self.entries.iter().filter(|entry| entry.in_use) {
+            let mappings = entry.mappings.iter().filter(|mapping| mapping.in_use);
+            let bytes = buf.get_mut(len..len + ENTRY_RECORD_SIZE)?;
+            bytes[..16].copy_from_slice(entry.service_uuid.as_bytes());
+            bytes[16] = mappings.clone().count() as u8;
+            len += ENTRY_RECORD_SIZE;
+
+            for mapping in mappings {
+                let iter = mapping
+                    .cookie
+                    .to_le_bytes()
+                    .into_iter()
+                    .chain(mapping.id.to_le_bytes())
+                    .chain([mapping.ntype as u8])
+                    .chain(mapping.src_id.to_le_bytes());
+                for (byte, value) in buf.get_mut(len..len + MAPPING_RECORD_SIZE)?.iter_mut().zip(iter) {
+                    *byte = value;
+                }
+                len += MAPPING_RECORD_SIZE;
+            }
+        }
+        Some(len)
+    }
+
+    fn restore(&mut self, version: u8, mut record: &[u8]) -> bool {
+        if version != Self::VERSION {
+            return false;
+        }
+
+        let mut entries = [NfyEntry::default(); NOTIFY_MAX_SERVICES];
+        let mut global_bitmap = 0u64;
+        for entry in entries.iter_mut() {
+            if record.is_empty() {
+                break;
+            }
+            let Some((header, rest)) = record.split_at_checked(ENTRY_RECORD_SIZE) else {
+                return false;
+            };
+            let count = header[16] as usize;
+            let Some((mappings, rest)) = rest.split_at_checked(count * MAPPING_RECORD_SIZE) else {
+                return false;
+            };
+            if count > NOTIFY_MAX_MAPPINGS {
+                return false;
+            }
+
+            entry.service_uuid = Uuid::from_slice(&header[..16]).unwrap();
+            entry.in_use = true;
+            for (mapping, bytes) in entry.mappings.iter_mut().zip(mappings.chunks(MAPPING_RECORD_SIZE)) {
+                *mapping = NfyMapping {
+                    cookie: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+                    id: u16::from_le_bytes([bytes[4], bytes[5]]),
+                    ntype: if bytes[6] == NotifyType::PerVcpu as u8 {
+                        NotifyType::PerVcpu
+                    } else {
+                        NotifyType::Global
+                    },
+                    src_id: u16::from_le_bytes([bytes[7], bytes[8]]),
+                    in_use: true,
+                };
+                global_bitmap |= 1u64.checked_shl(mapping.id as u32).unwrap_or(0);
+            }
+            record = rest;
+        }
+        if !record.is_empty() {
+            return false;
+        }
+
+        self.entries = entries;
+        self.global_bitmap = global_bitmap;
+        true
+    }
+}
+
 const UUID: Uuid = uuid!("e474d87e-5731-4044-a727-cb3e8cf3c8df");
 
 impl Service for Notify {
@@ -452,6 +549,52 @@ impl Service for Notify {
             }
         };
 
+        if let (MessageID::Setup | MessageID::Destroy, Some(store)) = (req.msg_info.message_id(), self.store) {
+            persist::save(store, self);
+        }
+
         Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registrations_persist() {
+        let receiver = uuid!("25cb5207-ac36-427d-aaef-3aa78877d27e");
+        let mut notify = Notify::new();
+        let mut notifications = [(0, 0, NotifyType::Global); 7];
+        notifications[0] = (0x1234, 3, NotifyType::Global);
+        notifications[1] = (0x5678, 9, NotifyType::PerVcpu);
+        let req = NotifyReq {
+            src_id: 0x8001,
+            sender_uuid: Uuid::nil(),
+            receiver_uuid: receiver,
+            msg_info: MessageInfo::from_raw(MessageID::Setup as u64),
+            count: 2,
+            notifications,
+        };
+        assert_eq!(notify.nfy_setup(req).status, ErrorCode::Ok);
+
+        let mut buf = [0; 64];
+        let len = notify.save(&mut buf).unwrap();
+        assert_eq!(len, ENTRY_RECORD_SIZE + 2 * MAPPING_RECORD_SIZE);
+        assert_eq!(notify.save(&mut buf[..len - 1]), None);
+
+        let mut restored = Notify::new();
+        assert!(restored.restore(Notify::VERSION, &buf[..len]));
+        assert_eq!(restored.global_bitmap, (1 << 3) | (1 << 9));
+        let entry = restored.nfy_find_entry(receiver).unwrap();
+        let mapping = restored.entries[entry].mappings[1];
+        assert_eq!(
+            (mapping.cookie, mapping.id, mapping.ntype, mapping.src_id),
+            (0x5678, 9, NotifyType::PerVcpu, 0x8001)
+        );
+
+        // Setting up the same cookie again fails as it would have before the restart
+        assert_eq!(restored.nfy_setup(req).status, ErrorCode::InvalidParameters);
+        assert!(!restored.restore(Notify::VERSION, &buf[..len - 1]));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::access::{self, AccessRule, Caller};
+use crate::{Result, Service};
+use log::{debug, error, warn};
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::{uuid, Uuid};
+
+// Protocol CMD definitions for Oem
+const EC_OEM_GET_VERSION: u8 = 0x0;
+const EC_OEM_LIST_COMMANDS: u8 = 0x1;
+const EC_OEM_EXECUTE: u8 = 0x2;
+
+/// Version of the OEM service protocol itself, independent of the commands it hosts.
+const OEM_PROTOCOL_VERSION: u16 = 0x0100;
+
+/// Commands that can be registered with one [`Oem`] service.
+pub const MAX_OEM_COMMANDS: usize = 32;
+
+const PAYLOAD_SIZE: usize = 14 * 8;
+
+/// Offset of the command data in EC_OEM_EXECUTE requests.
+const REQUEST_DATA_OFFSET: usize = 8;
+/// Offset of the command data in EC_OEM_EXECUTE responses.
+const RESPONSE_DATA_OFFSET: usize = 16;
+
+/// Bytes of data an OEM command receives.
+pub const OEM_REQUEST_SIZE: usize = PAYLOAD_SIZE - REQUEST_DATA_OFFSET;
+/// Bytes of data an OEM command can return.
+pub const OEM_RESPONSE_SIZE: usize = PAYLOAD_SIZE - RESPONSE_DATA_OFFSET;
+
+/// Size of a command descriptor in EC_OEM_LIST_COMMANDS responses.
+const DESCRIPTOR_SIZE: usize = 8;
+const DESCRIPTORS_PER_RESPONSE: usize = (PAYLOAD_SIZE - 16) / DESCRIPTOR_SIZE;
+
+/// Handler of a vendor command, returning the length of the data written to the response.
+pub type OemHandler = fn(
+    request: &[u8; OEM_REQUEST_SIZE],
+    response: &mut [u8; OEM_RESPONSE_SIZE],
+) -> core::result::Result<usize, ErrorCode>;
+
+/// Which FF-A endpoints may run a vendor command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OemAccess {
+    Any,
+    /// The callers named, denied to the others as by an [`AccessRule`] of the service.
+    Callers(&'static [Caller]),
+}
+
+impl OemAccess {
+    /// The rule the command's EC_OEM_EXECUTE requests are checked against.
+    fn rule(&self) -> Option<AccessRule> {
+        match *self {
+            OemAccess::Any => None,
+            OemAccess::Callers(callers) => Some(AccessRule { opcodes: &[], callers }),
+        }
+    }
+
+    /// Flags reported by EC_OEM_LIST_COMMANDS, bit 0 set for restricted commands.
+    fn flags(&self) -> u8 {
+        match self {
+            OemAccess::Any => 0,
+            OemAccess::Callers(_) => 1,
+        }
+    }
+}
+
+/// A vendor command, identified by the vendor's id and its command number.
+#[derive(Debug, Clone, Copy)]
+pub struct OemCommand {
+    pub vendor_id: u16,
+    pub command: u8,
+    /// Version of the command, the OS can require a minimum.
+    pub version: u8,
+    pub access: OemAccess,
+    pub handler: OemHandler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OemError {
+    /// A command with the same vendor id and number is already registered.
+    Duplicate,
+    /// All [`MAX_OEM_COMMANDS`] slots are used.
+    Full,
+}
+
+#[derive(Default)]
+struct VersionRsp {
+    status: i64,
+    version: u16,
+    count: u16,
+}
+
+impl From<VersionRsp> for RegisterPayload {
+    fn from(rsp: VersionRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.version.to_le_bytes())
+            .chain(rsp.count.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+struct ListRsp {
+    status: i64,
+    total: u16,
+    count: u16,
+    descriptors: [[u8; DESCRIPTOR_SIZE]; DESCRIPTORS_PER_RESPONSE],
+}
+
+impl From<ListRsp> for RegisterPayload {
+    fn from(rsp: ListRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.total.to_le_bytes())
+            .chain(rsp.count.to_le_bytes())
+            .chain([0; 4])
+            .chain(rsp.descriptors.into_iter().take(rsp.count as usize).flatten());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+struct ExecuteRsp {
+    status: i64,
+    len: u32,
+    data: [u8; OEM_RESPONSE_SIZE],
+}
+
+impl From<ExecuteRsp> for RegisterPayload {
+    fn from(rsp: ExecuteRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(rsp.len.to_le_bytes())
+            .chain([0; 4])
+            .chain(rsp.data.into_iter().take(rsp.len as usize));
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+/// Host of board specific commands, registered by the platform crate.
+///
+/// Commands are keyed by vendor id and command number. The OS enumerates them with
+/// EC_OEM_LIST_COMMANDS and runs them with EC_OEM_EXECUTE, which carries the vendor id at byte 2,
+/// the command at byte 4, the minimum version at byte 5 and the command data from byte 8.
+#[derive(Default)]
+pub struct Oem {
+    commands: heapless::Vec<OemCommand, MAX_OEM_COMMANDS>,
+}
+
+impl Oem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `command`, logging and skipping it if it cannot be.
+    pub fn with_command(mut self, command: OemCommand) -> Self {
+        if let Err(e) = self.register(command) {
+            error!(
+                "Failed to register OEM command {:#x}:{:#x}: {:?}",
+                command.vendor_id, command.command, e
+            );
+        }
+        self
+    }
+
+    pub fn register(&mut self, command: OemCommand) -> core::result::Result<(), OemError> {
+        if self.find(command.vendor_id, command.command).is_some() {
+            return Err(OemError::Duplicate);
+        }
+        self.commands.push(command).map_err(|_| OemError::Full)
+    }
+
+    fn find(&self, vendor_id: u16, command: u8) -> Option<&OemCommand> {
+        self.commands
+            .iter()
+            .find(|c| c.vendor_id == vendor_id && c.command == command)
+    }
+
+    fn get_version(&self) -> VersionRsp {
+        VersionRsp {
+            status: 0x0,
+            version: OEM_PROTOCOL_VERSION,
+            count: self.commands.len() as u16,
+        }
+    }
+
+    /// Describe the commands from `index` on, as many as fit in a response.
+    fn list_commands(&self, index: u64) -> ListRsp {
+        let mut rsp = ListRsp {
+            status: 0x0,
+            total: self.commands.len() as u16,
+            count: 0,
+            descriptors: [[0; DESCRIPTOR_SIZE]; DESCRIPTORS_PER_RESPONSE],
+        };
+
+        let commands = self.commands.iter().skip(index as usize);
+        for (descriptor, command) in rsp.descriptors.iter_mut().zip(commands) {
+            let [vendor_lo, vendor_hi] = command.vendor_id.to_le_bytes();
+            *descriptor = [
+                vendor_lo,
+                vendor_hi,
+                command.command,
+                command.version,
+                command.access.flags(),
+                0,
+                0,
+                0,
+            ];
+            rsp.count += 1;
+        }
+        rsp
+    }
+
+    /// The `Denied` response to the EC_OEM_EXECUTE request `msg`, if its command is not open to
+    /// the caller.
+    fn deny(&self, msg: &MsgSendDirectReq2) -> Option<MsgSendDirectResp2> {
+        let rule = self.find(msg.u16_at(2), msg.u8_at(4))?.access.rule()?;
+        access::deny(&[rule], UUID, msg)
+    }
+
+    fn execute(&self, msg: &MsgSendDirectReq2) -> ExecuteRsp {
+        let mut rsp = ExecuteRsp {
+            status: 0x0,
+            len: 0,
+            data: [0; OEM_RESPONSE_SIZE],
+        };
+
+        let (vendor_id, command, min_version) = (msg.u16_at(2), msg.u8_at(4), msg.u8_at(5));
+        let Some(entry) = self.find(vendor_id, command) else {
+            warn!("Unknown OEM command {:#x}:{:#x}", vendor_id, command);
+            rsp.status = ErrorCode::NotSupported as i64;
+            return rsp;
+        };
+        if entry.version < min_version {
+            rsp.status = ErrorCode::NotSupported as i64;
+            return rsp;
+        }
+        let mut request = [0; OEM_REQUEST_SIZE];
+        request.copy_from_slice(msg.slice(REQUEST_DATA_OFFSET..PAYLOAD_SIZE));
+        match (entry.handler)(&request, &mut rsp.data) {
+            Ok(len) => rsp.len = len.min(OEM_RESPONSE_SIZE) as u32,
+            Err(e) => rsp.status = e as i64,
+        }
+        rsp
+    }
+}
+
+const UUID: Uuid = uuid!("9a8a1e88-a880-447c-830d-6d764e9172bb");
+
+impl Service for Oem {
+    fn service_name(&self) -> &'static str {
+        "Oem"
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        UUID
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        let cmd = msg.u8_at(0);
+        debug!("Received Oem command 0x{:x}", cmd);
+
+        let payload = match cmd {
+            EC_OEM_GET_VERSION => RegisterPayload::from(self.get_version()),
+            EC_OEM_LIST_COMMANDS => RegisterPayload::from(self.list_commands(msg.register_at(1))),
+            EC_OEM_EXECUTE => {
+                if let Some(denied) = self.deny(&msg) {
+                    return Ok(denied);
+                }
+                RegisterPayload::from(self.execute(&msg))
+            }
+            _ => {
+                error!("Unknown Oem Command: {}", cmd);
+                return Err(odp_ffa::Error::Other("Unknown Oem Command"));
+            }
+        };
+
+        Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VENDOR: u16 = 0x1414;
+    const OS_ID: u16 = 0x1;
+
+    fn echo(
+        request: &[u8; OEM_REQUEST_SIZE],
+        response: &mut [u8; OEM_RESPONSE_SIZE],
+    ) -> core::result::Result<usize, ErrorCode> {
+        response[..4].copy_from_slice(&request[..4]);
+        Ok(4)
+    }
+
+    fn failing(_: &[u8; OEM_REQUEST_SIZE], _: &mut [u8; OEM_RESPONSE_SIZE]) -> core::result::Result<usize, ErrorCode> {
+        Err(ErrorCode::Busy)
+    }
+
+    fn command(command: u8, version: u8, access: OemAccess, handler: OemHandler) -> OemCommand {
+        OemCommand {
+            vendor_id: VENDOR,
+            command,
+            version,
+            access,
+            handler,
+        }
+    }
+
+    fn execute_request(source_id: u16, command: u8, min_version: u8) -> MsgSendDirectReq2 {
+        let mut bytes = [0u8; 12];
+        bytes[0] = EC_OEM_EXECUTE;
+        bytes[2..4].copy_from_slice(&VENDOR.to_le_bytes());
+        bytes[4] = command;
+        bytes[5] = min_version;
+        bytes[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        MsgSendDirectReq2::new(source_id, 0x8002, UUID, RegisterPayload::from_iter(bytes))
+    }
+
+    #[test]
+    fn test_register_rejects_duplicates_and_overflow() {
+        let mut oem = Oem::new();
+        assert_eq!(oem.register(command(0, 1, OemAccess::Any, echo)), Ok(()));
+        assert_eq!(
+            oem.register(command(0, 2, OemAccess::Any, echo)),
+            Err(OemError::Duplicate)
+        );
+
+        for cmd in 1..MAX_OEM_COMMANDS as u8 {
+            oem.register(command(cmd, 1, OemAccess::Any, echo)).unwrap();
+        }
+        assert_eq!(
+            oem.register(command(0xff, 1, OemAccess::Any, echo)),
+            Err(OemError::Full)
+        );
+
+        let rsp = oem.list_commands(MAX_OEM_COMMANDS as u64 - 2);
+        assert_eq!((rsp.total as usize, rsp.count), (MAX_OEM_COMMANDS, 2));
+        assert_eq!(rsp.descriptors[0][..4], [0x14, 0x14, MAX_OEM_COMMANDS as u8 - 2, 1]);
+        assert_eq!(oem.list_commands(0).count as usize, DESCRIPTORS_PER_RESPONSE);
+    }
+
+    #[test]
+    fn test_execute_checks_version_and_access() {
+        static TRUSTED: [Caller; 1] = [Caller::Endpoint(OS_ID)];
+        let oem = Oem::new()
+            .with_command(command(1, 2, OemAccess::Any, echo))
+            .with_command(command(2, 1, OemAccess::Callers(&TRUSTED), echo))
+            .with_command(command(3, 1, OemAccess::Any, failing));
+
+        let rsp = oem.execute(&execute_request(OS_ID, 1, 2));
+        assert_eq!((rsp.status, rsp.len), (0, 4));
+        assert_eq!(rsp.data[..4], [1, 2, 3, 4]);
+
+        assert_eq!(
+            oem.execute(&execute_request(OS_ID, 1, 3)).status,
+            ErrorCode::NotSupported as i64
+        );
+        assert!(oem.deny(&execute_request(OS_ID, 2, 0)).is_none());
+        assert_eq!(oem.execute(&execute_request(OS_ID, 2, 0)).status, 0);
+        assert_eq!(
+            oem.execute(&execute_request(OS_ID, 3, 0)).status,
+            ErrorCode::Busy as i64
+        );
+        assert_eq!(
+            oem.execute(&execute_request(OS_ID, 4, 0)).status,
+            ErrorCode::NotSupported as i64
+        );
+    }
+
+    #[test]
+    fn test_denied_command_audited() {
+        static TRUSTED: [Caller; 1] = [Caller::Endpoint(OS_ID)];
+        let _lock = crate::test_support::AUDIT_LOG_LOCK.lock();
+        let mut oem = Oem::new().with_command(command(2, 1, OemAccess::Callers(&TRUSTED), echo));
+
+        let denied = access::denied_count();
+        let rsp = embassy_futures::block_on(oem.ffa_msg_send_direct_req2(execute_request(0x2, 2, 0))).unwrap();
+        assert_eq!(rsp.u64_at(0) as i64, ErrorCode::Denied as i64);
+        assert_eq!(access::denied_count(), denied + 1);
+        assert_eq!(
+            access::audit_entry(0).map(|entry| (entry.source_id, entry.service, entry.opcode)),
+            Some((0x2, UUID, EC_OEM_EXECUTE))
+        );
+    }
+}
@@ -0,0 +1,486 @@
+use core::cell::RefCell;
+
+use super::notification::{self, Notification};
+use crate::persist::KeyValueStore;
+use crate::{Result, Service};
+use critical_section::Mutex;
+use log::{debug, error};
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::{uuid, Uuid};
+
+// Protocol CMD definitions for Power
+const EC_PWR_GET_PSR: u8 = 0x1;
+const EC_PWR_GET_SOURCE_CAPS: u8 = 0x2;
+const EC_PWR_GET_CHARGER: u8 = 0x3;
+const EC_PWR_SET_CHARGER: u8 = 0x4;
+const EC_PWR_GET_EVENTS: u8 = 0x5;
+
+/// Kind of the power source currently supplying the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum SourceType {
+    #[default]
+    None = 0,
+    Ac = 1,
+    UsbC = 2,
+    UsbPd = 3,
+}
+
+/// What the current power source can deliver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourceCapabilities {
+    pub source: SourceType,
+    pub voltage_mv: u32,
+    pub current_ma: u32,
+    pub power_mw: u32,
+}
+
+/// Charger settings requested by the OS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChargerControl {
+    pub enabled: bool,
+    /// Battery charge current limit, 0 leaves the platform default.
+    pub charge_current_ma: u32,
+    /// Input current limit, 0 leaves the platform default.
+    pub input_current_ma: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChargerState {
+    pub control: ChargerControl,
+    /// Whether the battery is charging right now.
+    pub charging: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargerError {
+    /// The platform has no controllable charger.
+    Unsupported,
+    /// A current limit is beyond what the charger supports.
+    InvalidLimit,
+}
+
+/// Power hardware of the platform, queried and controlled by the [`Power`] service.
+pub trait PowerPlatform {
+    /// Whether the AC adapter is online, as reported by _PSR.
+    fn ac_online(&self) -> bool;
+    fn source_capabilities(&self) -> SourceCapabilities;
+    fn charger(&self) -> ChargerState;
+    fn set_charger(&mut self, control: ChargerControl) -> core::result::Result<(), ChargerError>;
+}
+
+/// Power events latched until the OS reads them with EC_PWR_GET_EVENTS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PowerEvents(u32);
+
+impl PowerEvents {
+    pub const SOURCE_CHANGED: Self = Self(1 << 0);
+    pub const CAPABILITIES_CHANGED: Self = Self(1 << 1);
+    pub const POWER_BUTTON: Self = Self(1 << 2);
+    pub const LID_OPEN: Self = Self(1 << 3);
+    pub const LID_CLOSED: Self = Self(1 << 4);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+struct Pending {
+    events: PowerEvents,
+    notification: Option<Notification>,
+    /// AC adapter state and source capabilities seen by the last [`check_power_source`].
+    source: Option<(bool, SourceCapabilities)>,
+    /// Store to flush on power events, which may precede a loss of power.
+    store: Option<&'static dyn KeyValueStore>,
+}
+
+impl Pending {
+    const fn new() -> Self {
+        Self {
+            events: PowerEvents::empty(),
+            notification: None,
+            source: None,
+            store: None,
+        }
+    }
+
+    /// The events of the changes of the power source since it was last seen, none the first time.
+    fn source_changes(&mut self, online: bool, caps: SourceCapabilities) -> PowerEvents {
+        let Some((last_online, last_caps)) = self.source.replace((online, caps)) else {
+            return PowerEvents::empty();
+        };
+
+        let mut events = PowerEvents::empty();
+        if online != last_online || caps.source != last_caps.source {
+            events = events.union(PowerEvents::SOURCE_CHANGED);
+        }
+        if caps != last_caps {
+            events = events.union(PowerEvents::CAPABILITIES_CHANGED);
+        }
+        events
+    }
+
+    /// Latch `events`, returning the notification to send if they are new.
+    fn raise(&mut self, events: PowerEvents) -> Option<Notification> {
+        let new = !self.events.contains(events);
+        self.events = self.events.union(events);
+        self.notification.filter(|_| new)
+    }
+
+    fn take(&mut self) -> PowerEvents {
+        core::mem::take(&mut self.events)
+    }
+}
+
+/// Shared by the platform raising events and the [`Power`] service, of which a partition has one.
+static PENDING: Mutex<RefCell<Pending>> = Mutex::new(RefCell::new(Pending::new()));
+
+/// Report power events, for the platform to call from its interrupt handlers or tasks.
+///
+/// The OS is notified once it has talked to the [`Power`] service and the events were not
+/// already pending. The store given to [`Power::with_store`] is flushed first.
+pub fn raise_power_events(events: PowerEvents) {
+    debug!("Power events raised: {:#x}", events.bits());
+    let (notification, store) = critical_section::with(|cs| {
+        let mut pending = PENDING.borrow_ref_mut(cs);
+        (pending.raise(events), pending.store)
+    });
+
+    if let Some(Err(e)) = store.map(|store| store.flush()) {
+        error!("Failed to flush the service state: {:?}", e);
+    }
+
+    if let Some(n) = notification {
+        n.send("power events");
+    }
+}
+
+/// Compare the power source of `platform` with the last check and raise the events of its changes,
+/// for platforms polling the source rather than being interrupted on changes.
+pub fn check_power_source(platform: &impl PowerPlatform) {
+    let (online, caps) = (platform.ac_online(), platform.source_capabilities());
+    let events = critical_section::with(|cs| PENDING.borrow_ref_mut(cs).source_changes(online, caps));
+    if !events.is_empty() {
+        raise_power_events(events);
+    }
+}
+
+#[derive(Default)]
+struct GenericRsp {
+    status: i64,
+}
+
+impl From<GenericRsp> for RegisterPayload {
+    fn from(rsp: GenericRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes())
+    }
+}
+
+struct PsrRsp {
+    status: i64,
+    online: u32,
+}
+
+impl From<PsrRsp> for RegisterPayload {
+    fn from(rsp: PsrRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes().into_iter().chain(rsp.online.to_le_bytes()))
+    }
+}
+
+struct SourceCapsRsp {
+    status: i64,
+    caps: SourceCapabilities,
+}
+
+impl From<SourceCapsRsp> for RegisterPayload {
+    fn from(rsp: SourceCapsRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain((rsp.caps.source as u32).to_le_bytes())
+            .chain(rsp.caps.voltage_mv.to_le_bytes())
+            .chain(rsp.caps.current_ma.to_le_bytes())
+            .chain(rsp.caps.power_mw.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+struct ChargerRsp {
+    status: i64,
+    state: ChargerState,
+}
+
+impl From<ChargerRsp> for RegisterPayload {
+    fn from(rsp: ChargerRsp) -> Self {
+        let iter = rsp
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain([rsp.state.control.enabled as u8, rsp.state.charging as u8, 0, 0])
+            .chain(rsp.state.control.charge_current_ma.to_le_bytes())
+            .chain(rsp.state.control.input_current_ma.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+impl<P: Payload> From<&P> for ChargerControl {
+    fn from(msg: &P) -> ChargerControl {
+        ChargerControl {
+            enabled: msg.u8_at(1) != 0,
+            charge_current_ma: msg.u32_at(4),
+            input_current_ma: msg.u32_at(8),
+        }
+    }
+}
+
+struct EventsRsp {
+    status: i64,
+    events: u32,
+}
+
+impl From<EventsRsp> for RegisterPayload {
+    fn from(rsp: EventsRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes().into_iter().chain(rsp.events.to_le_bytes()))
+    }
+}
+
+/// AC adapter state, power source capabilities, charger control and power button and lid events.
+///
+/// A partition has a single Power service: the events raised with [`raise_power_events`], the
+/// notification sent for them and the store flushed are kept for the whole partition, not per
+/// instance.
+pub struct Power<P: PowerPlatform> {
+    platform: P,
+    notification_id: Option<u8>,
+}
+
+impl<P: PowerPlatform> Power<P> {
+    pub fn new(platform: P) -> Self {
+        Self {
+            platform,
+            notification_id: None,
+        }
+    }
+
+    /// Notify the OS of new power events with the global notification `id`.
+    pub fn with_notification_id(mut self, id: u8) -> Self {
+        self.notification_id = Some(id);
+        self
+    }
+
+    /// Flush `store` on power events, when it defers writing the state of the services. Panics if
+    /// a store was already given, as only one Power service may be built per partition.
+    pub fn with_store(self, store: &'static dyn KeyValueStore) -> Self {
+        let previous = critical_section::with(|cs| PENDING.borrow_ref_mut(cs).store.replace(store));
+        assert!(previous.is_none(), "A partition has a single Power service");
+        self
+    }
+
+    fn get_psr(&self) -> PsrRsp {
+        PsrRsp {
+            status: 0x0,
+            online: self.platform.ac_online() as u32,
+        }
+    }
+
+    fn get_source_caps(&self) -> SourceCapsRsp {
+        SourceCapsRsp {
+            status: 0x0,
+            caps: self.platform.source_capabilities(),
+        }
+    }
+
+    fn get_charger(&self) -> ChargerRsp {
+        ChargerRsp {
+            status: 0x0,
+            state: self.platform.charger(),
+        }
+    }
+
+    fn set_charger(&mut self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        let control: ChargerControl = msg.into();
+        debug!("set_charger {:?}", control);
+
+        let status = match self.platform.set_charger(control) {
+            Ok(()) => 0x0,
+            Err(ChargerError::Unsupported) => ErrorCode::NotSupported as i64,
+            Err(ChargerError::InvalidLimit) => ErrorCode::InvalidParameters as i64,
+        };
+        GenericRsp { status }
+    }
+
+    fn get_events(&self) -> EventsRsp {
+        let events = critical_section::with(|cs| PENDING.borrow_ref_mut(cs).take());
+        EventsRsp {
+            status: 0x0,
+            events: events.bits(),
+        }
+    }
+}
+
+const UUID: Uuid = uuid!("7157addf-2fbe-4c63-ae95-efac16e3b01c");
+
+impl<P: PowerPlatform> Service for Power<P> {
+    fn service_name(&self) -> &'static str {
+        "Power"
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        UUID
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        let cmd = msg.u8_at(0);
+        debug!("Received Power command 0x{:x}", cmd);
+        if let Some(notification) = notification::track_endpoints(self.notification_id, &msg) {
+            critical_section::with(|cs| PENDING.borrow_ref_mut(cs).notification = Some(notification));
+        }
+
+        let payload = match cmd {
+            EC_PWR_GET_PSR => RegisterPayload::from(self.get_psr()),
+            EC_PWR_GET_SOURCE_CAPS => RegisterPayload::from(self.get_source_caps()),
+            EC_PWR_GET_CHARGER => RegisterPayload::from(self.get_charger()),
+            EC_PWR_SET_CHARGER => RegisterPayload::from(self.set_charger(&msg)),
+            EC_PWR_GET_EVENTS => RegisterPayload::from(self.get_events()),
+            _ => {
+                error!("Unknown Power Command: {}", cmd);
+                return Err(odp_ffa::Error::Other("Unknown Power Command"));
+            }
+        };
+
+        Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::request;
+
+    const MAX_CURRENT_MA: u32 = 3000;
+
+    #[derive(Default)]
+    struct TestPlatform {
+        charger: ChargerState,
+    }
+
+    impl PowerPlatform for TestPlatform {
+        fn ac_online(&self) -> bool {
+            true
+        }
+
+        fn source_capabilities(&self) -> SourceCapabilities {
+            SourceCapabilities {
+                source: SourceType::UsbPd,
+                voltage_mv: 20000,
+                current_ma: MAX_CURRENT_MA,
+                power_mw: 60000,
+            }
+        }
+
+        fn charger(&self) -> ChargerState {
+            self.charger
+        }
+
+        fn set_charger(&mut self, control: ChargerControl) -> core::result::Result<(), ChargerError> {
+            if control.charge_current_ma > MAX_CURRENT_MA {
+                return Err(ChargerError::InvalidLimit);
+            }
+            self.charger.control = control;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reports_platform_state() {
+        let power = Power::new(TestPlatform::default());
+        let payload = RegisterPayload::from(power.get_psr());
+        assert_eq!((payload.u64_at(0), payload.u32_at(8)), (0, 1));
+
+        let payload = RegisterPayload::from(power.get_source_caps());
+        assert_eq!(payload.u32_at(8), SourceType::UsbPd as u32);
+        assert_eq!((payload.u32_at(12), payload.u32_at(16)), (20000, MAX_CURRENT_MA));
+    }
+
+    #[test]
+    fn test_set_charger() {
+        let mut power = Power::new(TestPlatform::default());
+        let mut bytes = [0u8; 12];
+        bytes[0] = EC_PWR_SET_CHARGER;
+        bytes[1] = 1;
+        bytes[4..8].copy_from_slice(&1500u32.to_le_bytes());
+        assert_eq!(power.set_charger(&request(UUID, &bytes)).status, 0);
+
+        let state = power.get_charger().state;
+        assert!(state.control.enabled);
+        assert_eq!(state.control.charge_current_ma, 1500);
+
+        bytes[4..8].copy_from_slice(&(MAX_CURRENT_MA + 1).to_le_bytes());
+        assert_eq!(
+            power.set_charger(&request(UUID, &bytes)).status,
+            ErrorCode::InvalidParameters as i64
+        );
+        assert_eq!(power.get_charger().state.control.charge_current_ma, 1500);
+    }
+
+    #[test]
+    fn test_events_latch_and_notify_once() {
+        let mut pending = Pending::new();
+        assert_eq!(pending.raise(PowerEvents::POWER_BUTTON), None);
+
+        let notification = Notification {
+            sender_id: 0x8002,
+            receiver_id: 0,
+            bitmap: 1 << 3,
+        };
+        pending.notification = Some(notification);
+        assert_eq!(pending.raise(PowerEvents::POWER_BUTTON), None);
+        assert_eq!(pending.raise(PowerEvents::SOURCE_CHANGED), Some(notification));
+
+        let events = pending.take();
+        assert!(events.contains(PowerEvents::POWER_BUTTON.union(PowerEvents::SOURCE_CHANGED)));
+        assert!(pending.take().is_empty());
+        assert_eq!(pending.raise(PowerEvents::POWER_BUTTON), Some(notification));
+    }
+
+    #[test]
+    fn test_source_changes() {
+        let mut pending = Pending::new();
+        let caps = TestPlatform::default().source_capabilities();
+        assert!(pending.source_changes(true, caps).is_empty());
+        assert!(pending.source_changes(true, caps).is_empty());
+
+        let lower = SourceCapabilities {
+            power_mw: 45000,
+            ..caps
+        };
+        assert_eq!(pending.source_changes(true, lower), PowerEvents::CAPABILITIES_CHANGED);
+
+        let events = pending.source_changes(false, SourceCapabilities::default());
+        assert_eq!(
+            events,
+            PowerEvents::SOURCE_CHANGED.union(PowerEvents::CAPABILITIES_CHANGED)
+        );
+        assert_eq!(
+            pending.source_changes(true, SourceCapabilities::default()),
+            PowerEvents::SOURCE_CHANGED
+        );
+    }
+}
@@ -1,6 +1,8 @@
+use crate::fan::{FanControl, FanPolicy, FanStatus};
+use crate::persist::{self, KeyValueStore, Persist};
 use crate::service::{Result, Service};
 use log::{debug, error};
-use odp_ffa::{Function, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload, Yield};
+use odp_ffa::{ErrorCode, Function, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload, Yield};
 use uuid::{uuid, Builder, Uuid};
 
 // Protocol CMD definitions for Thermal
@@ -10,6 +12,20 @@ const EC_THM_GET_THRS: u8 = 0x3;
 const EC_THM_SET_SCP: u8 = 0x4;
 const EC_THM_GET_VAR: u8 = 0x5;
 const EC_THM_SET_VAR: u8 = 0x6;
+const EC_THM_GET_FAN: u8 = 0x7;
+const EC_THM_SET_FAN_POLICY: u8 = 0x8;
+
+/// Fans the Thermal service can report and control.
+pub const MAX_FANS: usize = 4;
+/// Temperature sensors the Thermal service keeps thresholds for.
+pub const MAX_SENSORS: usize = 8;
+
+/// Bytes of a threshold in the persisted record: sensor id, timeout, low and high.
+const THRESHOLD_RECORD_SIZE: usize = 11;
+
+// Fan policy modes of EC_THM_GET_FAN and EC_THM_SET_FAN_POLICY
+const FAN_POLICY_AUTO: u8 = 0x0;
+const FAN_POLICY_MANUAL: u8 = 0x1;
 
 #[derive(Default)]
 struct GenericRsp {
@@ -52,6 +68,35 @@ impl<P: Payload> From<&P> for ThresholdReq {
     }
 }
 
+/// Thresholds of a sensor, set by EC_THM_SET_THRS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Threshold {
+    timeout: u16,
+    low_temp: u32,
+    high_temp: u32,
+}
+
+#[derive(Default)]
+struct ThresholdRsp {
+    status: i64,
+    timeout: u32,
+    low_temp: u32,
+    high_temp: u32,
+}
+
+impl From<ThresholdRsp> for RegisterPayload {
+    fn from(value: ThresholdRsp) -> Self {
+        let iter = value
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(value.timeout.to_le_bytes())
+            .chain(value.low_temp.to_le_bytes())
+            .chain(value.high_temp.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
 #[derive(Default)]
 struct ReadVarReq {
     id: u8,
@@ -114,13 +159,72 @@ impl<P: Payload> From<&P> for SetVarReq {
 }
 
 #[derive(Default)]
-pub struct Thermal {}
+struct FanRsp {
+    status: i64,
+    rpm: u32,
+    temperature: u32,
+    duty: u8,
+    policy: u8,
+    stalled: u8,
+}
+
+impl From<FanRsp> for RegisterPayload {
+    fn from(value: FanRsp) -> Self {
+        let iter = value
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(value.rpm.to_le_bytes())
+            .chain(value.temperature.to_le_bytes())
+            .chain([value.duty, value.policy, value.stalled]);
+        RegisterPayload::from_iter(iter)
+    }
+}
+
+impl From<FanStatus> for FanRsp {
+    fn from(status: FanStatus) -> Self {
+        let policy = match status.policy {
+            FanPolicy::Auto => FAN_POLICY_AUTO,
+            FanPolicy::Manual(_) => FAN_POLICY_MANUAL,
+        };
+        FanRsp {
+            status: 0x0,
+            rpm: status.rpm,
+            temperature: status.temperature_dk,
+            duty: status.duty,
+            policy,
+            stalled: status.stalled as u8,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Thermal {
+    fans: heapless::Vec<&'static dyn FanControl, MAX_FANS>,
+    thresholds: [Option<Threshold>; MAX_SENSORS],
+    store: Option<&'static dyn KeyValueStore>,
+}
 
 impl Thermal {
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Report and control `fan` as the next fan index, from 0.
+    pub fn with_fan(mut self, fan: &'static dyn FanControl) -> Self {
+        if self.fans.push(fan).is_err() {
+            error!("Thermal supports at most {} fans", MAX_FANS);
+        }
+        self
+    }
+
+    /// Keep the thresholds in `store`, restoring those it holds.
+    pub fn with_store(mut self, store: &'static dyn KeyValueStore) -> Self {
+        persist::restore(store, &mut self);
+        self.store = Some(store);
+        self
+    }
+
     fn get_temperature(&self, msg: &MsgSendDirectReq2) -> TempRsp {
         debug!("get_temperature sensor 0x{:x}", msg.u8_at(1));
 
@@ -133,7 +237,7 @@ impl Thermal {
         }
     }
 
-    fn set_threshold(&self, msg: &MsgSendDirectReq2) -> GenericRsp {
+    fn set_threshold(&mut self, msg: &MsgSendDirectReq2) -> GenericRsp {
         let req: ThresholdReq = msg.into();
         debug!(
             "set_threshold temperature sensor 0x{:x}
@@ -143,11 +247,37 @@ impl Thermal {
             req.id, req.timeout, req.low_temp, req.high_temp
         );
 
+        let Some(threshold) = self.thresholds.get_mut(req.id as usize) else {
+            return GenericRsp {
+                status: ErrorCode::InvalidParameters as i64,
+            };
+        };
+        *threshold = Some(Threshold {
+            timeout: req.timeout as u16,
+            low_temp: req.low_temp,
+            high_temp: req.high_temp,
+        });
+        if let Some(store) = self.store {
+            persist::save(store, self);
+        }
+
         GenericRsp { status: 0x0 }
     }
 
-    fn get_threshold(&self, _msg: &MsgSendDirectReq2) -> GenericRsp {
-        GenericRsp { status: 0x0 }
+    fn get_threshold(&self, msg: &MsgSendDirectReq2) -> ThresholdRsp {
+        match self.thresholds.get(msg.u8_at(1) as usize) {
+            Some(Some(threshold)) => ThresholdRsp {
+                status: 0x0,
+                timeout: threshold.timeout as u32,
+                low_temp: threshold.low_temp,
+                high_temp: threshold.high_temp,
+            },
+            Some(None) => ThresholdRsp::default(),
+            None => ThresholdRsp {
+                status: ErrorCode::InvalidParameters as i64,
+                ..Default::default()
+            },
+        }
     }
 
     fn set_cooling_policy(&self, _msg: &MsgSendDirectReq2) -> GenericRsp {
@@ -186,6 +316,79 @@ impl Thermal {
 
         GenericRsp { status: 0x0 }
     }
+
+    fn get_fan(&self, msg: &MsgSendDirectReq2) -> FanRsp {
+        let status = self.fans.get(msg.u8_at(1) as usize).and_then(|fan| fan.status());
+        match status {
+            Some(status) => status.into(),
+            None => FanRsp {
+                status: ErrorCode::InvalidParameters as i64,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn set_fan_policy(&self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        let policy = match msg.u8_at(2) {
+            FAN_POLICY_AUTO => FanPolicy::Auto,
+            FAN_POLICY_MANUAL if msg.u8_at(3) <= 100 => FanPolicy::Manual(msg.u8_at(3)),
+            _ => {
+                return GenericRsp {
+                    status: ErrorCode::InvalidParameters as i64,
+                }
+            }
+        };
+        debug!("set_fan_policy fan 0x{:x}: {:?}", msg.u8_at(1), policy);
+
+        match self.fans.get(msg.u8_at(1) as usize) {
+            Some(fan) if fan.set_policy(policy) => GenericRsp { status: 0x0 },
+            _ => GenericRsp {
+                status: ErrorCode::InvalidParameters as i64,
+            },
+        }
+    }
+}
+
+/// The thresholds set, each as sensor id, timeout, low and high temperature.
+impl Persist for Thermal {
+    const KEY: u16 = 0x0005;
+    const VERSION: u8 = 1;
+
+    fn save(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut len = 0;
+        for (id, threshold) in self.thresholds.iter().enumerate() {
+            let Some(threshold) = threshold else {
+                continue;
+            };
+            let iter = [id as u8]
+                .into_iter()
+                .chain(threshold.timeout.to_le_bytes())
+                .chain(threshold.low_temp.to_le_bytes())
+                .chain(threshold.high_temp.to_le_bytes());
+            for (byte, value) in buf.get_mut(len..len + THRESHOLD_RECORD_SIZE)?.iter_mut().zip(iter) {
+                *byte = value;
+            }
+            len += THRESHOLD_RECORD_SIZE;
+        }
+        Some(len)
+    }
+
+    fn restore(&mut self, version: u8, record: &[u8]) -> bool {
+        if version != Self::VERSION || !record.len().is_multiple_of(THRESHOLD_RECORD_SIZE) {
+            return false;
+        }
+        for bytes in record.chunks(THRESHOLD_RECORD_SIZE) {
+            let field = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            if let Some(threshold) = self.thresholds.get_mut(bytes[0] as usize) {
+                *threshold = Some(Threshold {
+                    timeout: u16::from_le_bytes([bytes[1], bytes[2]]),
+                    low_temp: field(3),
+                    high_temp: field(7),
+                });
+            }
+        }
+        true
+    }
 }
 
 const UUID: Uuid = uuid!("31f56da7-593c-4d72-a4b3-8fc7171ac073");
@@ -210,6 +413,8 @@ impl Service for Thermal {
             EC_THM_SET_SCP => RegisterPayload::from(self.set_cooling_policy(&msg)),
             EC_THM_GET_VAR => RegisterPayload::from(self.get_variable(&msg)),
             EC_THM_SET_VAR => RegisterPayload::from(self.set_variable(&msg)),
+            EC_THM_GET_FAN => RegisterPayload::from(self.get_fan(&msg)),
+            EC_THM_SET_FAN_POLICY => RegisterPayload::from(self.set_fan_policy(&msg)),
             _ => {
                 error!("Unknown Thermal Command: {}", cmd);
                 return Err(odp_ffa::Error::Other("Unknown Thermal Command"));
@@ -219,3 +424,91 @@ impl Service for Thermal {
         Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::request;
+    use core::cell::Cell;
+    use critical_section::Mutex;
+
+    struct TestFan(Mutex<Cell<FanPolicy>>);
+
+    impl FanControl for TestFan {
+        fn update(&self, _now_ms: u64) {}
+
+        fn status(&self) -> Option<FanStatus> {
+            Some(FanStatus {
+                rpm: 2400,
+                duty: 48,
+                temperature_dk: 3231,
+                policy: critical_section::with(|cs| self.0.borrow(cs).get()),
+                stalled: false,
+            })
+        }
+
+        fn set_policy(&self, policy: FanPolicy) -> bool {
+            critical_section::with(|cs| self.0.borrow(cs).set(policy));
+            true
+        }
+    }
+
+    static FAN: TestFan = TestFan(Mutex::new(Cell::new(FanPolicy::Auto)));
+
+    #[test]
+    fn test_fan_commands() {
+        let thermal = Thermal::new().with_fan(&FAN);
+        let payload = RegisterPayload::from(thermal.get_fan(&request(UUID, &[EC_THM_GET_FAN, 0])));
+        assert_eq!(
+            (payload.u64_at(0), payload.u32_at(8), payload.u32_at(12)),
+            (0, 2400, 3231)
+        );
+        assert_eq!((payload.u8_at(16), payload.u8_at(17)), (48, FAN_POLICY_AUTO));
+
+        let rsp = thermal.set_fan_policy(&request(UUID, &[EC_THM_SET_FAN_POLICY, 0, FAN_POLICY_MANUAL, 101]));
+        assert_eq!(rsp.status, ErrorCode::InvalidParameters as i64);
+        let rsp = thermal.set_fan_policy(&request(UUID, &[EC_THM_SET_FAN_POLICY, 0, FAN_POLICY_MANUAL, 80]));
+        assert_eq!(rsp.status, 0);
+        let payload = RegisterPayload::from(thermal.get_fan(&request(UUID, &[EC_THM_GET_FAN, 0])));
+        assert_eq!(payload.u8_at(17), FAN_POLICY_MANUAL);
+
+        let payload = RegisterPayload::from(thermal.get_fan(&request(UUID, &[EC_THM_GET_FAN, 1])));
+        assert_eq!(payload.u64_at(0), ErrorCode::InvalidParameters as u64);
+    }
+
+    #[test]
+    fn test_thresholds_persist() {
+        use crate::persist::{PersistentStore, RamStorage, SavePolicy};
+
+        static STORE: PersistentStore<RamStorage<1024>, 256> = PersistentStore::new();
+        STORE.init(RamStorage::new(256), SavePolicy::OnChange);
+
+        let mut thermal = Thermal::new().with_store(&STORE);
+        let mut req = [0; 13];
+        req[..3].copy_from_slice(&[EC_THM_SET_THRS, 2, 0]);
+        req[3..5].copy_from_slice(&30u16.to_le_bytes());
+        req[5..9].copy_from_slice(&2731u32.to_le_bytes());
+        req[9..].copy_from_slice(&3531u32.to_le_bytes());
+        assert_eq!(thermal.set_threshold(&request(UUID, &req)).status, 0);
+        req[1] = MAX_SENSORS as u8;
+        assert_eq!(
+            thermal.set_threshold(&request(UUID, &req)).status,
+            ErrorCode::InvalidParameters as i64
+        );
+
+        // A new instance, as after a restart, gets the thresholds back
+        let thermal = Thermal::new().with_store(&STORE);
+        let payload = RegisterPayload::from(thermal.get_threshold(&request(UUID, &[EC_THM_GET_THRS, 2])));
+        assert_eq!(
+            (
+                payload.u64_at(0),
+                payload.u32_at(8),
+                payload.u32_at(12),
+                payload.u32_at(16)
+            ),
+            (0, 30, 2731, 3531)
+        );
+        let payload = RegisterPayload::from(thermal.get_threshold(&request(UUID, &[EC_THM_GET_THRS, 1])));
+        assert_eq!((payload.u64_at(0), payload.u32_at(12)), (0, 0));
+    }
+}
@@ -0,0 +1,591 @@
+use core::cell::RefCell;
+
+use super::notification::{self, Notification};
+use crate::{Result, Service};
+use critical_section::Mutex;
+use log::{debug, error, info};
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::{uuid, Uuid};
+
+// Protocol CMD definitions for TimeAlarm, named after the ACPI Time and Alarm Device methods
+const EC_TAS_GET_GCP: u8 = 0x1;
+const EC_TAS_GET_GRT: u8 = 0x2;
+const EC_TAS_SET_SRT: u8 = 0x3;
+const EC_TAS_SET_STV: u8 = 0x4;
+const EC_TAS_GET_TIV: u8 = 0x5;
+const EC_TAS_GET_GWS: u8 = 0x6;
+const EC_TAS_SET_CWS: u8 = 0x7;
+
+/// Offset of the ACPI time buffer in _GRT responses and _SRT requests.
+const TIME_OFFSET: usize = 8;
+const TIME_SIZE: usize = 16;
+
+// _GCP capability bits
+const GCP_AC_WAKE: u32 = 1 << 0;
+const GCP_DC_WAKE: u32 = 1 << 1;
+const GCP_REAL_TIME: u32 = 1 << 2;
+const GCP_MILLISECONDS: u32 = 1 << 3;
+const GCP_WAKE_STATUS: u32 = 1 << 4;
+
+// _GWS status bits
+const GWS_EXPIRED: u32 = 1 << 0;
+
+/// _STV value disabling a timer, and _TIV value of a disabled timer.
+const TIMER_DISABLED: u32 = u32::MAX;
+
+/// _SRT/_GRT time zone value for a local time without a known zone.
+const TIME_ZONE_UNSPECIFIED: i16 = 2047;
+
+const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Monotonic milliseconds, such as the uptime from the arch timer.
+pub type Clock = fn() -> u64;
+
+/// Calendar date and local time, as kept by the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub milli: u16,
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Whether the fields are in the ranges the ACPI time buffer allows.
+    pub fn is_valid(&self) -> bool {
+        (1900..=9999).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && self.milli < 1000
+    }
+
+    /// Milliseconds since 1970-01-01 00:00:00.
+    pub fn to_epoch_ms(&self) -> i64 {
+        // Days from the civil date, counting years from March so leap days come last
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        let time = ((self.hour as i64 * 60 + self.minute as i64) * 60 + self.second as i64) * 1000 + self.milli as i64;
+        days * MS_PER_DAY + time
+    }
+
+    pub fn from_epoch_ms(ms: i64) -> Self {
+        let days = ms.div_euclid(MS_PER_DAY) + 719468;
+        let time = ms.rem_euclid(MS_PER_DAY);
+
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3_600_000) as u8,
+            minute: (time / 60_000 % 60) as u8,
+            second: (time / 1000 % 60) as u8,
+            milli: (time % 1000) as u16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The RTC cannot be set.
+    ReadOnly,
+    /// The RTC cannot hold the time, e.g. a year outside its range.
+    OutOfRange,
+    Hardware,
+}
+
+/// Real time clock backing the ACPI Time and Alarm Device.
+pub trait RtcSource {
+    /// The current time, or `None` if the RTC has not been set since it lost power.
+    fn read(&self) -> Option<DateTime>;
+    fn write(&mut self, time: DateTime) -> core::result::Result<(), RtcError>;
+
+    /// Whether the RTC keeps milliseconds rather than whole seconds.
+    fn has_milliseconds(&self) -> bool {
+        false
+    }
+}
+
+/// RTC for platforms without one, counting from the last time set with the arch timer.
+///
+/// The time is lost whenever the partition restarts, until the OS sets it again.
+pub struct SoftRtc {
+    clock: Clock,
+    /// Epoch milliseconds at clock 0.
+    offset: Option<i64>,
+}
+
+impl SoftRtc {
+    pub const fn new(clock: Clock) -> Self {
+        Self { clock, offset: None }
+    }
+}
+
+impl RtcSource for SoftRtc {
+    fn read(&self) -> Option<DateTime> {
+        let offset = self.offset?;
+        Some(DateTime::from_epoch_ms(offset + (self.clock)() as i64))
+    }
+
+    fn write(&mut self, time: DateTime) -> core::result::Result<(), RtcError> {
+        self.offset = Some(time.to_epoch_ms() - (self.clock)() as i64);
+        Ok(())
+    }
+
+    fn has_milliseconds(&self) -> bool {
+        true
+    }
+}
+
+/// The two wake timers of the ACPI Time and Alarm Device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive)]
+#[repr(u8)]
+enum Timer {
+    Ac = 0,
+    Dc = 1,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TimerState {
+    /// Clock value the timer expires at.
+    deadline_ms: Option<u64>,
+    expired: bool,
+}
+
+struct Alarms {
+    timers: [TimerState; 2],
+    notification: Option<Notification>,
+}
+
+impl Alarms {
+    const fn new() -> Self {
+        Self {
+            timers: [TimerState {
+                deadline_ms: None,
+                expired: false,
+            }; 2],
+            notification: None,
+        }
+    }
+
+    /// Expire the timers due at `now_ms`, returning the notification to send if any did.
+    fn expire(&mut self, now_ms: u64) -> Option<Notification> {
+        let mut expired = false;
+        for timer in self.timers.iter_mut() {
+            if timer.deadline_ms.is_some_and(|deadline| deadline <= now_ms) {
+                timer.deadline_ms = None;
+                timer.expired = true;
+                expired = true;
+            }
+        }
+        self.notification.filter(|_| expired)
+    }
+
+    fn set(&mut self, timer: Timer, now_ms: u64, seconds: u32) {
+        self.timers[timer as usize].deadline_ms = match seconds {
+            TIMER_DISABLED => None,
+            _ => Some(now_ms + seconds as u64 * 1000),
+        };
+    }
+
+    fn remaining(&self, timer: Timer, now_ms: u64) -> u32 {
+        match self.timers[timer as usize].deadline_ms {
+            // Round up so an armed timer never reads as 0 before it expires
+            Some(deadline) => deadline.saturating_sub(now_ms).div_ceil(1000) as u32,
+            None => TIMER_DISABLED,
+        }
+    }
+}
+
+static ALARMS: Mutex<RefCell<Alarms>> = Mutex::new(RefCell::new(Alarms::new()));
+
+/// Expire the wake timers due at `now_ms` of the clock given to [`TimeAlarm`], and notify the OS.
+///
+/// For the platform to call periodically or from a timer interrupt. The timers are also checked
+/// whenever the OS talks to the service.
+pub fn check_wake_alarms(now_ms: u64) {
+    let notification = critical_section::with(|cs| ALARMS.borrow_ref_mut(cs).expire(now_ms));
+
+    if let Some(n) = notification {
+        info!("Wake alarm expired");
+        n.send("the wake alarm");
+    }
+}
+
+#[derive(Default)]
+struct GenericRsp {
+    status: i64,
+}
+
+impl From<GenericRsp> for RegisterPayload {
+    fn from(rsp: GenericRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes())
+    }
+}
+
+struct ValueRsp {
+    status: i64,
+    value: u32,
+}
+
+impl From<ValueRsp> for RegisterPayload {
+    fn from(rsp: ValueRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes().into_iter().chain(rsp.value.to_le_bytes()))
+    }
+}
+
+/// The time buffer of _GRT and _SRT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct AcpiTime {
+    time: DateTime,
+    /// Whether _GRT could read the time, unused by _SRT.
+    valid: bool,
+    time_zone: i16,
+    daylight: u8,
+}
+
+impl AcpiTime {
+    fn to_bytes(self) -> [u8; TIME_SIZE] {
+        let mut bytes = [0; TIME_SIZE];
+        bytes[0..2].copy_from_slice(&self.time.year.to_le_bytes());
+        bytes[2..7].copy_from_slice(&[
+            self.time.month,
+            self.time.day,
+            self.time.hour,
+            self.time.minute,
+            self.time.second,
+        ]);
+        bytes[7] = self.valid as u8;
+        bytes[8..10].copy_from_slice(&self.time.milli.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.time_zone.to_le_bytes());
+        bytes[12] = self.daylight;
+        bytes
+    }
+}
+
+impl<P: Payload> From<&P> for AcpiTime {
+    fn from(msg: &P) -> AcpiTime {
+        AcpiTime {
+            time: DateTime {
+                year: msg.u16_at(TIME_OFFSET),
+                month: msg.u8_at(TIME_OFFSET + 2),
+                day: msg.u8_at(TIME_OFFSET + 3),
+                hour: msg.u8_at(TIME_OFFSET + 4),
+                minute: msg.u8_at(TIME_OFFSET + 5),
+                second: msg.u8_at(TIME_OFFSET + 6),
+                milli: msg.u16_at(TIME_OFFSET + 8),
+            },
+            valid: false,
+            time_zone: msg.u16_at(TIME_OFFSET + 10) as i16,
+            daylight: msg.u8_at(TIME_OFFSET + 12),
+        }
+    }
+}
+
+struct TimeRsp {
+    status: i64,
+    time: AcpiTime,
+}
+
+impl From<TimeRsp> for RegisterPayload {
+    fn from(rsp: TimeRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes().into_iter().chain(rsp.time.to_bytes()))
+    }
+}
+
+/// The ACPI Time and Alarm Device: real time, AC and DC wake timers and their wake status.
+pub struct TimeAlarm<R: RtcSource> {
+    rtc: R,
+    clock: Clock,
+    time_zone: i16,
+    daylight: u8,
+    notification_id: Option<u8>,
+}
+
+impl<R: RtcSource> TimeAlarm<R> {
+    /// Keep the real time in `rtc` and run the wake timers on `clock`.
+    pub fn new(rtc: R, clock: Clock) -> Self {
+        Self {
+            rtc,
+            clock,
+            time_zone: TIME_ZONE_UNSPECIFIED,
+            daylight: 0,
+            notification_id: None,
+        }
+    }
+
+    /// Notify the OS of expired wake timers with the global notification `id`.
+    pub fn with_notification_id(mut self, id: u8) -> Self {
+        self.notification_id = Some(id);
+        self
+    }
+
+    fn get_capabilities(&self) -> ValueRsp {
+        let mut caps = GCP_AC_WAKE | GCP_DC_WAKE | GCP_REAL_TIME | GCP_WAKE_STATUS;
+        if self.rtc.has_milliseconds() {
+            caps |= GCP_MILLISECONDS;
+        }
+        ValueRsp {
+            status: 0x0,
+            value: caps,
+        }
+    }
+
+    fn get_real_time(&self) -> TimeRsp {
+        let time = self.rtc.read();
+        TimeRsp {
+            status: 0x0,
+            time: AcpiTime {
+                time: time.unwrap_or_default(),
+                valid: time.is_some(),
+                time_zone: self.time_zone,
+                daylight: self.daylight,
+            },
+        }
+    }
+
+    fn set_real_time(&mut self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        let req: AcpiTime = msg.into();
+        debug!("set_real_time {:?}", req);
+
+        let time_zone_valid = (-1440..=1440).contains(&req.time_zone) || req.time_zone == TIME_ZONE_UNSPECIFIED;
+        if !req.time.is_valid() || !time_zone_valid {
+            return GenericRsp {
+                status: ErrorCode::InvalidParameters as i64,
+            };
+        }
+
+        let status = match self.rtc.write(req.time) {
+            Ok(()) => {
+                self.time_zone = req.time_zone;
+                self.daylight = req.daylight;
+                0x0
+            }
+            Err(RtcError::ReadOnly) => ErrorCode::NotSupported as i64,
+            Err(RtcError::OutOfRange) => ErrorCode::InvalidParameters as i64,
+            Err(RtcError::Hardware) => ErrorCode::Aborted as i64,
+        };
+        GenericRsp { status }
+    }
+
+    fn set_timer(&self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        let Ok(timer) = Timer::try_from(msg.u8_at(1)) else {
+            return GenericRsp {
+                status: ErrorCode::InvalidParameters as i64,
+            };
+        };
+        let seconds = msg.u32_at(4);
+        debug!("set_timer {:?} to {} s", timer, seconds);
+
+        let now = (self.clock)();
+        critical_section::with(|cs| ALARMS.borrow_ref_mut(cs).set(timer, now, seconds));
+        GenericRsp { status: 0x0 }
+    }
+
+    fn get_timer(&self, msg: &MsgSendDirectReq2) -> ValueRsp {
+        let Ok(timer) = Timer::try_from(msg.u8_at(1)) else {
+            return ValueRsp {
+                status: ErrorCode::InvalidParameters as i64,
+                value: TIMER_DISABLED,
+            };
+        };
+
+        let now = (self.clock)();
+        ValueRsp {
+            status: 0x0,
+            value: critical_section::with(|cs| ALARMS.borrow_ref(cs).remaining(timer, now)),
+        }
+    }
+
+    fn get_wake_status(&self, msg: &MsgSendDirectReq2) -> ValueRsp {
+        let Ok(timer) = Timer::try_from(msg.u8_at(1)) else {
+            return ValueRsp {
+                status: ErrorCode::InvalidParameters as i64,
+                value: 0,
+            };
+        };
+
+        let expired = critical_section::with(|cs| ALARMS.borrow_ref(cs).timers[timer as usize].expired);
+        ValueRsp {
+            status: 0x0,
+            value: if expired { GWS_EXPIRED } else { 0 },
+        }
+    }
+
+    fn clear_wake_status(&self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        let Ok(timer) = Timer::try_from(msg.u8_at(1)) else {
+            return GenericRsp {
+                status: ErrorCode::InvalidParameters as i64,
+            };
+        };
+
+        critical_section::with(|cs| ALARMS.borrow_ref_mut(cs).timers[timer as usize].expired = false);
+        GenericRsp { status: 0x0 }
+    }
+}
+
+const UUID: Uuid = uuid!("23ea63ed-b593-46ea-b027-8924df88e92f");
+
+impl<R: RtcSource> Service for TimeAlarm<R> {
+    fn service_name(&self) -> &'static str {
+        "TimeAlarm"
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        UUID
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        let cmd = msg.u8_at(0);
+        debug!("Received TimeAlarm command 0x{:x}", cmd);
+        if let Some(notification) = notification::track_endpoints(self.notification_id, &msg) {
+            critical_section::with(|cs| ALARMS.borrow_ref_mut(cs).notification = Some(notification));
+        }
+        check_wake_alarms((self.clock)());
+
+        let payload = match cmd {
+            EC_TAS_GET_GCP => RegisterPayload::from(self.get_capabilities()),
+            EC_TAS_GET_GRT => RegisterPayload::from(self.get_real_time()),
+            EC_TAS_SET_SRT => RegisterPayload::from(self.set_real_time(&msg)),
+            EC_TAS_SET_STV => RegisterPayload::from(self.set_timer(&msg)),
+            EC_TAS_GET_TIV => RegisterPayload::from(self.get_timer(&msg)),
+            EC_TAS_GET_GWS => RegisterPayload::from(self.get_wake_status(&msg)),
+            EC_TAS_SET_CWS => RegisterPayload::from(self.clear_wake_status(&msg)),
+            _ => {
+                error!("Unknown TimeAlarm Command: {}", cmd);
+                return Err(odp_ffa::Error::Other("Unknown TimeAlarm Command"));
+            }
+        };
+
+        Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::request;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static NOW: AtomicU64 = AtomicU64::new(5000);
+
+    fn now() -> u64 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_epoch_conversion() {
+        let time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 45,
+            second: 30,
+            milli: 250,
+        };
+        assert_eq!(time.to_epoch_ms(), 1_709_214_330_250);
+        assert_eq!(DateTime::from_epoch_ms(time.to_epoch_ms()), time);
+
+        let before_epoch = DateTime {
+            year: 1900,
+            month: 1,
+            day: 1,
+            ..Default::default()
+        };
+        assert_eq!(DateTime::from_epoch_ms(before_epoch.to_epoch_ms()), before_epoch);
+        assert!(!DateTime { year: 2023, ..time }.is_valid());
+    }
+
+    #[test]
+    fn test_soft_rtc_set_and_read() {
+        let mut service = TimeAlarm::new(SoftRtc::new(now), now);
+        assert!(!service.get_real_time().time.valid);
+
+        let time = AcpiTime {
+            time: DateTime {
+                year: 2025,
+                month: 12,
+                day: 31,
+                hour: 23,
+                minute: 59,
+                second: 59,
+                milli: 500,
+            },
+            valid: false,
+            time_zone: -480,
+            daylight: 1,
+        };
+        let mut bytes = [0u8; TIME_OFFSET + TIME_SIZE];
+        bytes[0] = EC_TAS_SET_SRT;
+        bytes[TIME_OFFSET..].copy_from_slice(&time.to_bytes());
+        assert_eq!(service.set_real_time(&request(UUID, &bytes)).status, 0);
+
+        let rtc = SoftRtc {
+            clock: || now() + 1000,
+            offset: service.rtc.offset,
+        };
+        let read = TimeAlarm::new(rtc, now).get_real_time().time.time;
+        assert_eq!((read.year, read.month, read.day, read.milli), (2026, 1, 1, 500));
+
+        bytes[TIME_OFFSET + 2] = 13;
+        assert_eq!(
+            service.set_real_time(&request(UUID, &bytes)).status,
+            ErrorCode::InvalidParameters as i64
+        );
+    }
+
+    #[test]
+    fn test_timer_expiry() {
+        let mut alarms = Alarms::new();
+        alarms.set(Timer::Ac, 1000, 10);
+        assert_eq!(alarms.remaining(Timer::Ac, 1500), 10);
+        assert_eq!(alarms.remaining(Timer::Dc, 1500), TIMER_DISABLED);
+        assert_eq!(alarms.expire(10_999), None);
+        assert!(!alarms.timers[Timer::Ac as usize].expired);
+
+        let notification = Notification {
+            sender_id: 0x8002,
+            receiver_id: 0,
+            bitmap: 1 << 4,
+        };
+        alarms.notification = Some(notification);
+        assert_eq!(alarms.expire(11_000), Some(notification));
+        assert!(alarms.timers[Timer::Ac as usize].expired);
+        assert_eq!(alarms.remaining(Timer::Ac, 11_000), TIMER_DISABLED);
+        assert_eq!(alarms.expire(20_000), None);
+
+        alarms.set(Timer::Dc, 0, 5);
+        alarms.set(Timer::Dc, 0, TIMER_DISABLED);
+        assert_eq!(alarms.expire(u64::MAX), None);
+    }
+}
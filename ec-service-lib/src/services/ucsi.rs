@@ -0,0 +1,616 @@
+use core::cell::RefCell;
+
+use super::notification::{self, Notification};
+use crate::address_space::{AddressSpaceError, MemoryMapper};
+use crate::{Result, Service};
+use critical_section::Mutex;
+use log::{debug, error, warn};
+use odp_ffa::{ErrorCode, MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::{uuid, Uuid};
+
+// Protocol CMD definitions for UCSI
+const EC_UCSI_SET_BUFFER: u8 = 0x1;
+const EC_UCSI_EXECUTE: u8 = 0x2;
+
+// Layout of the UCSI data structure in the buffer shared by the OS
+const VERSION_OFFSET: u64 = 0;
+const CCI_OFFSET: u64 = 4;
+const CONTROL_OFFSET: u64 = 8;
+const MESSAGE_IN_OFFSET: u64 = 16;
+const MESSAGE_OUT_OFFSET: u64 = 32;
+pub const UCSI_DATA_SIZE: u64 = 48;
+pub const MESSAGE_SIZE: usize = 16;
+
+/// UCSI revision 1.2, in BCD.
+const UCSI_VERSION: u16 = 0x0120;
+
+// Commands the PPM handles itself
+const PPM_RESET: u8 = 0x01;
+const CANCEL: u8 = 0x02;
+const ACK_CC_CI: u8 = 0x04;
+const SET_NOTIFICATION_ENABLE: u8 = 0x05;
+
+// Commands the simulated PD controller implements
+const CONNECTOR_RESET: u8 = 0x03;
+const GET_CAPABILITY: u8 = 0x06;
+const GET_CONNECTOR_CAPABILITY: u8 = 0x07;
+const GET_CONNECTOR_STATUS: u8 = 0x12;
+const GET_ERROR_STATUS: u8 = 0x13;
+
+// CCI bits
+const CCI_NOT_SUPPORTED: u32 = 1 << 25;
+const CCI_CANCEL_COMPLETED: u32 = 1 << 26;
+const CCI_RESET_COMPLETED: u32 = 1 << 27;
+const CCI_ACK_COMMAND_COMPLETE: u32 = 1 << 29;
+const CCI_ERROR: u32 = 1 << 30;
+const CCI_COMMAND_COMPLETE: u32 = 1 << 31;
+
+/// ACK_CC_CI acknowledgement of the connector change, in the command specific byte.
+///
+/// Bit 1 acknowledges the command completion, which needs nothing from the PPM.
+const ACK_CONNECTOR_CHANGE: u8 = 1 << 0;
+
+// SET_NOTIFICATION_ENABLE bits
+const NOTIFY_COMMAND_COMPLETE: u16 = 1 << 0;
+/// Every other bit enables notifications of a kind of connector change.
+const NOTIFY_CONNECTOR_CHANGE: u16 = !NOTIFY_COMMAND_COMPLETE;
+
+/// Highest connector number the CCI connector change indicator can hold.
+pub const MAX_CONNECTORS: u8 = 127;
+
+/// A command written by the OS to CONTROL and MESSAGE_OUT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UcsiCommand {
+    pub command: u8,
+    pub data_length: u8,
+    /// Bytes 2 to 7 of CONTROL.
+    pub specific: [u8; 6],
+    pub message_out: [u8; MESSAGE_SIZE],
+}
+
+impl UcsiCommand {
+    /// The connector number most commands carry in their first specific byte.
+    pub fn connector(&self) -> u8 {
+        self.specific[0] & 0x7f
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UcsiError {
+    /// The PD controller does not implement the command.
+    NotSupported,
+    /// The command failed, the reason being available through GET_ERROR_STATUS.
+    Failed,
+}
+
+/// USB Power Delivery controller executing the UCSI commands the PPM does not handle itself.
+pub trait PdController {
+    fn reset(&mut self);
+
+    /// Execute `command`, returning the length of the data written to `message_in`.
+    fn execute(
+        &mut self,
+        command: &UcsiCommand,
+        message_in: &mut [u8; MESSAGE_SIZE],
+    ) -> core::result::Result<usize, UcsiError>;
+}
+
+/// The PPM's side of the UCSI data structure.
+struct Ppm {
+    mapper: Option<&'static dyn MemoryMapper>,
+    address: u64,
+    notification_enable: u16,
+    /// Connectors with a change not yet acknowledged, bit n for connector n.
+    pending_changes: u128,
+    notification: Option<Notification>,
+}
+
+impl Ppm {
+    const fn new() -> Self {
+        Self {
+            mapper: None,
+            address: 0,
+            notification_enable: 0,
+            pending_changes: 0,
+            notification: None,
+        }
+    }
+
+    fn mapper(&self) -> core::result::Result<&'static dyn MemoryMapper, AddressSpaceError> {
+        self.mapper.ok_or(AddressSpaceError::Uninitialized)
+    }
+
+    fn set_buffer(
+        &mut self,
+        mapper: &'static dyn MemoryMapper,
+        address: u64,
+    ) -> core::result::Result<(), AddressSpaceError> {
+        mapper.check(address, UCSI_DATA_SIZE, true)?;
+        mapper.write(address, &[0; UCSI_DATA_SIZE as usize])?;
+        mapper.write(address + VERSION_OFFSET, &UCSI_VERSION.to_le_bytes())?;
+        self.mapper = Some(mapper);
+        self.address = address;
+        Ok(())
+    }
+
+    fn read_command(&self) -> core::result::Result<UcsiCommand, AddressSpaceError> {
+        let mapper = self.mapper()?;
+        let mut control = [0; 8];
+        let mut cmd = UcsiCommand::default();
+        mapper.read(self.address + CONTROL_OFFSET, &mut control)?;
+        mapper.read(self.address + MESSAGE_OUT_OFFSET, &mut cmd.message_out)?;
+        cmd.command = control[0];
+        cmd.data_length = control[1];
+        cmd.specific.copy_from_slice(&control[2..]);
+        Ok(cmd)
+    }
+
+    /// The CCI connector change indicator, reporting the lowest numbered pending connector.
+    fn change_indicator(&self) -> u32 {
+        match self.pending_changes {
+            0 => 0,
+            changes => changes.trailing_zeros() << 1,
+        }
+    }
+
+    fn write_cci(&self, cci: u32) -> core::result::Result<(), AddressSpaceError> {
+        self.mapper()?.write(self.address + CCI_OFFSET, &cci.to_le_bytes())
+    }
+
+    /// Handle the commands that are the PPM's own, returning the CCI.
+    fn execute_own(&mut self, cmd: &UcsiCommand) -> u32 {
+        match cmd.command {
+            PPM_RESET => {
+                self.notification_enable = 0;
+                self.pending_changes = 0;
+                CCI_RESET_COMPLETED
+            }
+            // Commands complete synchronously, there is never anything to cancel
+            CANCEL => CCI_CANCEL_COMPLETED | CCI_COMMAND_COMPLETE,
+            ACK_CC_CI => {
+                if cmd.specific[0] & ACK_CONNECTOR_CHANGE != 0 && self.pending_changes != 0 {
+                    self.pending_changes &= self.pending_changes - 1;
+                }
+                CCI_ACK_COMMAND_COMPLETE
+            }
+            SET_NOTIFICATION_ENABLE => {
+                self.notification_enable = u16::from_le_bytes([cmd.specific[0], cmd.specific[1]]);
+                CCI_COMMAND_COMPLETE
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Publish the outcome of a command, returning the CCI and the notification to send.
+    fn complete(
+        &mut self,
+        cci: u32,
+        message_in: &[u8],
+    ) -> core::result::Result<(u32, Option<Notification>), AddressSpaceError> {
+        let cci = cci | self.change_indicator() | ((message_in.len() as u32) << 8);
+        self.mapper()?.write(self.address + MESSAGE_IN_OFFSET, message_in)?;
+        self.write_cci(cci)?;
+
+        let notify = cci & CCI_COMMAND_COMPLETE != 0 && self.notification_enable & NOTIFY_COMMAND_COMPLETE != 0;
+        Ok((cci, self.notification.filter(|_| notify)))
+    }
+
+    /// Record a change on `connector`, returning the notification to send if it is reported now.
+    fn connector_change(&mut self, connector: u8) -> Option<Notification> {
+        let reported_now = self.pending_changes == 0;
+        self.pending_changes |= 1 << connector;
+        if !reported_now {
+            return None;
+        }
+
+        if let Err(e) = self.write_cci(self.change_indicator()) {
+            debug!("Connector change not published: {:?}", e);
+            return None;
+        }
+        self.notification
+            .filter(|_| self.notification_enable & NOTIFY_CONNECTOR_CHANGE != 0)
+    }
+}
+
+static PPM: Mutex<RefCell<Ppm>> = Mutex::new(RefCell::new(Ppm::new()));
+
+/// Report a change on `connector`, for the PD controller driver to call.
+///
+/// The change is published in the CCI and the OS notified once earlier changes are acknowledged.
+pub fn raise_connector_change(connector: u8) {
+    if connector == 0 || connector > MAX_CONNECTORS {
+        error!("Invalid UCSI connector {}", connector);
+        return;
+    }
+
+    debug!("UCSI connector {} changed", connector);
+    if let Some(notification) = critical_section::with(|cs| PPM.borrow_ref_mut(cs).connector_change(connector)) {
+        notification.send("UCSI event");
+    }
+}
+
+/// PD controller with simulated connectors, for testing the OS's UCSI driver.
+pub struct SimulatedPdController<const N: usize> {
+    connected: [bool; N],
+    /// Connector status change fields not yet read with GET_CONNECTOR_STATUS.
+    status_change: [u16; N],
+    last_error: u16,
+}
+
+// Connector status change bit and connector status flag of GET_CONNECTOR_STATUS
+const STATUS_CHANGE_CONNECT: u16 = 1 << 14;
+const STATUS_CONNECTED: u16 = 1 << 3;
+
+// GET_ERROR_STATUS bits
+const ERROR_UNRECOGNIZED_COMMAND: u16 = 1 << 0;
+const ERROR_NON_EXISTENT_CONNECTOR: u16 = 1 << 1;
+
+impl<const N: usize> Default for SimulatedPdController<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SimulatedPdController<N> {
+    pub const fn new() -> Self {
+        Self {
+            connected: [false; N],
+            status_change: [0; N],
+            last_error: 0,
+        }
+    }
+
+    /// Plug or unplug a partner on `connector`, numbered from 1.
+    pub fn set_connected(&mut self, connector: u8, connected: bool) {
+        let Some(index) = self.index(connector) else {
+            return;
+        };
+        if self.connected[index] != connected {
+            self.connected[index] = connected;
+            self.status_change[index] |= STATUS_CHANGE_CONNECT;
+            raise_connector_change(connector);
+        }
+    }
+
+    fn index(&self, connector: u8) -> Option<usize> {
+        (1..=N).contains(&(connector as usize)).then(|| connector as usize - 1)
+    }
+
+    fn fail(&mut self, error: u16) -> core::result::Result<usize, UcsiError> {
+        self.last_error = error;
+        Err(UcsiError::Failed)
+    }
+}
+
+impl<const N: usize> PdController for SimulatedPdController<N> {
+    fn reset(&mut self) {
+        self.status_change = [0; N];
+        self.last_error = 0;
+    }
+
+    fn execute(
+        &mut self,
+        command: &UcsiCommand,
+        message_in: &mut [u8; MESSAGE_SIZE],
+    ) -> core::result::Result<usize, UcsiError> {
+        match command.command {
+            GET_CAPABILITY => {
+                // Power delivery supported, N connectors, no optional features or alternate modes
+                message_in[0] = 1 << 2;
+                message_in[4] = N as u8;
+                // USB PD 3.0 and Type-C 2.0
+                message_in[12..14].copy_from_slice(&0x0300u16.to_le_bytes());
+                message_in[14..16].copy_from_slice(&0x0200u16.to_le_bytes());
+                Ok(16)
+            }
+            GET_CONNECTOR_CAPABILITY | GET_CONNECTOR_STATUS | CONNECTOR_RESET => {
+                let Some(index) = self.index(command.connector()) else {
+                    return self.fail(ERROR_NON_EXISTENT_CONNECTOR);
+                };
+                match command.command {
+                    GET_CONNECTOR_CAPABILITY => {
+                        // Dual role port, both provider and consumer
+                        message_in[0] = 1 << 2;
+                        message_in[1] = 0b11;
+                        Ok(2)
+                    }
+                    GET_CONNECTOR_STATUS => {
+                        let status = if self.connected[index] { STATUS_CONNECTED } else { 0 };
+                        message_in[0..2]
+                            .copy_from_slice(&core::mem::take(&mut self.status_change[index]).to_le_bytes());
+                        message_in[2..4].copy_from_slice(&status.to_le_bytes());
+                        Ok(9)
+                    }
+                    _ => Ok(0),
+                }
+            }
+            GET_ERROR_STATUS => {
+                message_in[0..2].copy_from_slice(&self.last_error.to_le_bytes());
+                Ok(2)
+            }
+            _ => {
+                self.last_error = ERROR_UNRECOGNIZED_COMMAND;
+                Err(UcsiError::NotSupported)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct GenericRsp {
+    status: i64,
+}
+
+impl From<GenericRsp> for RegisterPayload {
+    fn from(rsp: GenericRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes())
+    }
+}
+
+struct ExecuteRsp {
+    status: i64,
+    cci: u32,
+}
+
+impl From<ExecuteRsp> for RegisterPayload {
+    fn from(rsp: ExecuteRsp) -> Self {
+        RegisterPayload::from_iter(rsp.status.to_le_bytes().into_iter().chain(rsp.cci.to_le_bytes()))
+    }
+}
+
+fn address_space_status(e: AddressSpaceError) -> i64 {
+    let code = match e {
+        AddressSpaceError::Uninitialized => ErrorCode::NotSupported,
+        _ => ErrorCode::Denied,
+    };
+    code as i64
+}
+
+/// USB Type-C Connector System Software Interface, the PPM between the OS and a PD controller.
+///
+/// The OS shares the UCSI data structure with EC_UCSI_SET_BUFFER, writes CONTROL and
+/// MESSAGE_OUT there and sends EC_UCSI_EXECUTE. The CCI is both returned and written to the
+/// buffer, with a notification if enabled with SET_NOTIFICATION_ENABLE.
+pub struct Ucsi<C: PdController> {
+    controller: C,
+    memory_mapper: Option<&'static dyn MemoryMapper>,
+    notification_id: Option<u8>,
+}
+
+impl<C: PdController> Ucsi<C> {
+    pub fn new(controller: C) -> Self {
+        Self {
+            controller,
+            memory_mapper: None,
+            notification_id: None,
+        }
+    }
+
+    /// Access the UCSI data structure the OS shares through `memory_mapper`.
+    pub fn with_memory_mapper(mut self, memory_mapper: &'static dyn MemoryMapper) -> Self {
+        self.memory_mapper = Some(memory_mapper);
+        self
+    }
+
+    /// Signal command completion and connector changes with the global notification `id`.
+    pub fn with_notification_id(mut self, id: u8) -> Self {
+        self.notification_id = Some(id);
+        self
+    }
+
+    fn set_buffer(&self, address: u64) -> GenericRsp {
+        let Some(mapper) = self.memory_mapper else {
+            return GenericRsp {
+                status: ErrorCode::NotSupported as i64,
+            };
+        };
+
+        match critical_section::with(|cs| PPM.borrow_ref_mut(cs).set_buffer(mapper, address)) {
+            Ok(()) => GenericRsp { status: 0x0 },
+            Err(e) => {
+                warn!("UCSI buffer at {:#x} rejected: {:?}", address, e);
+                GenericRsp {
+                    status: address_space_status(e),
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self) -> ExecuteRsp {
+        match self.try_execute() {
+            Ok(cci) => ExecuteRsp { status: 0x0, cci },
+            Err(e) => {
+                warn!("UCSI command failed: {:?}", e);
+                ExecuteRsp {
+                    status: address_space_status(e),
+                    cci: 0,
+                }
+            }
+        }
+    }
+
+    fn try_execute(&mut self) -> core::result::Result<u32, AddressSpaceError> {
+        let cmd = critical_section::with(|cs| PPM.borrow_ref(cs).read_command())?;
+        debug!("UCSI command 0x{:x}", cmd.command);
+
+        let mut message_in = [0; MESSAGE_SIZE];
+        let (cci, len) = match cmd.command {
+            PPM_RESET | CANCEL | ACK_CC_CI | SET_NOTIFICATION_ENABLE => {
+                if cmd.command == PPM_RESET {
+                    self.controller.reset();
+                }
+                let cci = critical_section::with(|cs| PPM.borrow_ref_mut(cs).execute_own(&cmd));
+                (cci, 0)
+            }
+            // The controller may be slow, it runs without holding the PPM
+            _ => match self.controller.execute(&cmd, &mut message_in) {
+                Ok(len) => (CCI_COMMAND_COMPLETE, len.min(MESSAGE_SIZE)),
+                Err(UcsiError::NotSupported) => (CCI_COMMAND_COMPLETE | CCI_NOT_SUPPORTED, 0),
+                Err(UcsiError::Failed) => (CCI_COMMAND_COMPLETE | CCI_ERROR, 0),
+            },
+        };
+
+        let (cci, notification) =
+            critical_section::with(|cs| PPM.borrow_ref_mut(cs).complete(cci, &message_in[..len]))?;
+        if let Some(notification) = notification {
+            notification.send("UCSI event");
+        }
+        Ok(cci)
+    }
+}
+
+const UUID: Uuid = uuid!("65467f50-827f-4e4f-8770-dbf4c3f77f45");
+
+impl<C: PdController> Service for Ucsi<C> {
+    fn service_name(&self) -> &'static str {
+        "Ucsi"
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        UUID
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        let cmd = msg.u8_at(0);
+        debug!("Received Ucsi command 0x{:x}", cmd);
+        if let Some(notification) = notification::track_endpoints(self.notification_id, &msg) {
+            critical_section::with(|cs| PPM.borrow_ref_mut(cs).notification = Some(notification));
+        }
+
+        let payload = match cmd {
+            EC_UCSI_SET_BUFFER => RegisterPayload::from(self.set_buffer(msg.register_at(1))),
+            EC_UCSI_EXECUTE => RegisterPayload::from(self.execute()),
+            _ => {
+                error!("Unknown Ucsi Command: {}", cmd);
+                return Err(odp_ffa::Error::Other("Unknown Ucsi Command"));
+            }
+        };
+
+        Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::{AddressSpace, Attributes, SharedAddressSpace, PAGE_SIZE};
+    use crate::test_support::{leak_page, NoTable};
+
+    const ACK_COMMAND_COMPLETE: u8 = 1 << 1;
+
+    /// A PPM sharing a fresh page with the test, which plays the OS.
+    fn shared_ppm(space: &'static SharedAddressSpace<NoTable, 2>) -> (Ppm, &'static mut [u8; 4096]) {
+        let page = leak_page(0xff);
+        let base = page.as_mut_ptr() as u64;
+        space.set(AddressSpace::new(NoTable));
+        space.map(base, PAGE_SIZE, Attributes::DATA).unwrap();
+
+        let mut ppm = Ppm::new();
+        ppm.set_buffer(space, base).unwrap();
+        (ppm, page)
+    }
+
+    fn command(command: u8, specific: [u8; 6]) -> UcsiCommand {
+        UcsiCommand {
+            command,
+            specific,
+            ..Default::default()
+        }
+    }
+
+    fn cci(buffer: &[u8]) -> u32 {
+        u32::from_le_bytes(buffer[CCI_OFFSET as usize..][..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_buffer_layout_and_completion() {
+        static SPACE: SharedAddressSpace<NoTable, 2> = SharedAddressSpace::new();
+        let (mut ppm, buffer) = shared_ppm(&SPACE);
+        assert_eq!(buffer[..4], [0x20, 0x01, 0, 0]);
+        assert_eq!(cci(buffer), 0);
+
+        buffer[CONTROL_OFFSET as usize] = GET_CAPABILITY;
+        assert_eq!(ppm.read_command().unwrap().command, GET_CAPABILITY);
+
+        let mut message_in = [0; MESSAGE_SIZE];
+        let mut controller = SimulatedPdController::<2>::new();
+        let len = controller
+            .execute(&command(GET_CAPABILITY, [0; 6]), &mut message_in)
+            .unwrap();
+        let notification = Notification {
+            sender_id: 0x8002,
+            receiver_id: 0,
+            bitmap: 1 << 5,
+        };
+        ppm.notification = Some(notification);
+
+        let (cci_value, notify) = ppm.complete(CCI_COMMAND_COMPLETE, &message_in[..len]).unwrap();
+        assert_eq!(cci_value, CCI_COMMAND_COMPLETE | (16 << 8));
+        assert_eq!(cci(buffer), cci_value);
+        assert_eq!(buffer[MESSAGE_IN_OFFSET as usize + 4], 2);
+        assert_eq!(notify, None);
+
+        ppm.execute_own(&command(SET_NOTIFICATION_ENABLE, [0x01, 0, 0, 0, 0, 0]));
+        assert_eq!(ppm.complete(CCI_COMMAND_COMPLETE, &[]).unwrap().1, Some(notification));
+    }
+
+    #[test]
+    fn test_connector_changes_reported_in_order() {
+        static SPACE: SharedAddressSpace<NoTable, 2> = SharedAddressSpace::new();
+        let (mut ppm, buffer) = shared_ppm(&SPACE);
+        let notification = Notification {
+            sender_id: 0x8002,
+            receiver_id: 0,
+            bitmap: 1 << 5,
+        };
+        ppm.notification = Some(notification);
+        ppm.notification_enable = STATUS_CHANGE_CONNECT;
+
+        assert_eq!(ppm.connector_change(3), Some(notification));
+        assert_eq!(cci(buffer), 3 << 1);
+        assert_eq!(ppm.connector_change(1), None);
+        assert_eq!(ppm.change_indicator(), 1 << 1);
+
+        let ack = command(ACK_CC_CI, [ACK_CONNECTOR_CHANGE | ACK_COMMAND_COMPLETE, 0, 0, 0, 0, 0]);
+        let cci_value = ppm.execute_own(&ack);
+        assert_eq!(
+            ppm.complete(cci_value, &[]).unwrap().0,
+            CCI_ACK_COMMAND_COMPLETE | (3 << 1)
+        );
+        ppm.execute_own(&ack);
+        assert_eq!(ppm.change_indicator(), 0);
+
+        ppm.connector_change(2);
+        assert_eq!(ppm.execute_own(&command(PPM_RESET, [0; 6])), CCI_RESET_COMPLETED);
+        assert_eq!((ppm.pending_changes, ppm.notification_enable), (0, 0));
+    }
+
+    #[test]
+    fn test_simulated_controller() {
+        let mut controller = SimulatedPdController::<2>::new();
+        let mut message_in = [0; MESSAGE_SIZE];
+        controller.set_connected(2, true);
+
+        let status = command(GET_CONNECTOR_STATUS, [2, 0, 0, 0, 0, 0]);
+        assert_eq!(controller.execute(&status, &mut message_in), Ok(9));
+        assert_eq!(
+            u16::from_le_bytes([message_in[0], message_in[1]]),
+            STATUS_CHANGE_CONNECT
+        );
+        assert_eq!(u16::from_le_bytes([message_in[2], message_in[3]]), STATUS_CONNECTED);
+        controller.execute(&status, &mut message_in).unwrap();
+        assert_eq!(message_in[0..2], [0, 0]);
+
+        let missing = command(GET_CONNECTOR_STATUS, [3, 0, 0, 0, 0, 0]);
+        assert_eq!(controller.execute(&missing, &mut message_in), Err(UcsiError::Failed));
+        assert_eq!(
+            controller.execute(&command(GET_ERROR_STATUS, [0; 6]), &mut message_in),
+            Ok(2)
+        );
+        assert_eq!(message_in[0..2], ERROR_NON_EXISTENT_CONNECTOR.to_le_bytes());
+        assert_eq!(
+            controller.execute(&command(0x30, [0; 6]), &mut message_in),
+            Err(UcsiError::NotSupported)
+        );
+    }
+}
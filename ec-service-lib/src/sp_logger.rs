@@ -1,18 +1,481 @@
-use log::{Metadata, Record};
+//! Logger of the partition.
+//!
+//! Records are filtered by module, with levels that can be changed at runtime, and rate limited
+//! per call site so a message logged in a loop cannot flood the console. Records that pass are
+//! written to the FF-A console and to an in-memory history, which crash records and the OS can
+//! read back.
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+
+use critical_section::Mutex;
+use log::{LevelFilter, Metadata, Record};
+
+/// Number of bytes of recent log output kept in memory.
+pub const LOG_HISTORY_SIZE: usize = 4096;
+
+/// Records a call site may log per window before further records are suppressed.
+pub const RATE_LIMIT_BURST: u32 = 8;
+pub const RATE_LIMIT_WINDOW_MS: u64 = 1000;
+const RATE_LIMIT_SITES: usize = 16;
+
+const MAX_MODULE_LEVELS: usize = 8;
+const MODULE_NAME_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogConfigError {
+    NameTooLong,
+    TooManyModules,
+}
 
 pub struct SpLogger;
 
 impl log::Log for SpLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        critical_section::with(|cs| metadata.level() <= STATE.borrow_ref(cs).filters.level(metadata.target()))
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let module_path = record.module_path().unwrap_or("unknown");
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let suppressed = match (record.file_static(), record.line()) {
+            (Some(file), Some(line)) => {
+                let now = critical_section::with(|cs| CLOCK.borrow(cs).get()).map(|clock| clock());
+                let verdict = critical_section::with(|cs| {
+                    let mut state = STATE.borrow_ref_mut(cs);
+                    now.map_or(Verdict::Log { suppressed: 0 }, |now| {
+                        state.limiter.check(file, line, now)
+                    })
+                });
+                match verdict {
+                    Verdict::Log { suppressed } => suppressed,
+                    Verdict::Suppress => return,
+                }
+            }
+            _ => 0,
+        };
+
+        let module_path = record.module_path().unwrap_or("unknown");
+        let to_console = record.level() <= critical_section::with(|cs| STATE.borrow_ref(cs).console_level);
+        if suppressed > 0 {
+            let level = record.level();
+            if to_console {
+                odp_ffa::println!(
+                    "{:<5} - {} - {} similar messages suppressed",
+                    level,
+                    module_path,
+                    suppressed
+                );
+            }
+            write_history(format_args!(
+                "{:<5} - {} - {} similar messages suppressed\n",
+                level, module_path, suppressed
+            ));
+        }
+
+        if to_console {
             odp_ffa::println!("{:<5} - {} - {}", record.level(), module_path, record.args());
         }
+        write_history(format_args!(
+            "{:<5} - {} - {}\n",
+            record.level(),
+            module_path,
+            record.args()
+        ));
     }
 
     fn flush(&self) {}
 }
+
+fn write_history(args: core::fmt::Arguments) {
+    critical_section::with(|cs| {
+        // Logging from within a log statement's arguments must not panic
+        if let Ok(mut history) = HISTORY.borrow(cs).try_borrow_mut() {
+            let _ = history.write_fmt(args);
+        }
+    });
+}
+
+/// Levels by module path, the longest matching module wins.
+struct Filters {
+    default: LevelFilter,
+    modules: heapless::Vec<(heapless::String<MODULE_NAME_SIZE>, LevelFilter), MAX_MODULE_LEVELS>,
+}
+
+impl Filters {
+    const fn new() -> Self {
+        Self {
+            default: LevelFilter::Trace,
+            modules: heapless::Vec::new(),
+        }
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn set(&mut self, module: &str, level: LevelFilter) -> Result<(), LogConfigError> {
+        if let Some(entry) = self.modules.iter_mut().find(|(name, _)| name == module) {
+            entry.1 = level;
+            return Ok(());
+        }
+
+        let name = heapless::String::try_from(module).map_err(|_| LogConfigError::NameTooLong)?;
+        self.modules
+            .push((name, level))
+            .map_err(|_| LogConfigError::TooManyModules)
+    }
+
+    /// The most verbose level any module is logged at.
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    /// Log the record, after reporting the records suppressed since the previous one.
+    Log {
+        suppressed: u32,
+    },
+    Suppress,
+}
+
+#[derive(Clone, Copy)]
+struct Site {
+    file: &'static str,
+    line: u32,
+    window_start: u64,
+    last_seen: u64,
+    count: u32,
+    suppressed: u32,
+}
+
+/// Allows each call site [`RATE_LIMIT_BURST`] records per [`RATE_LIMIT_WINDOW_MS`].
+struct RateLimiter {
+    sites: [Option<Site>; RATE_LIMIT_SITES],
+}
+
+impl RateLimiter {
+    const fn new() -> Self {
+        Self {
+            sites: [None; RATE_LIMIT_SITES],
+        }
+    }
+
+    fn check(&mut self, file: &'static str, line: u32, now: u64) -> Verdict {
+        let found = self
+            .sites
+            .iter_mut()
+            .flatten()
+            .find(|site| site.line == line && site.file == file);
+
+        let Some(site) = found else {
+            // Track the new site in place of the one that has been quiet the longest
+            let slot = self
+                .sites
+                .iter_mut()
+                .min_by_key(|slot| slot.map_or(0, |site| site.last_seen + 1))
+                .unwrap();
+            *slot = Some(Site {
+                file,
+                line,
+                window_start: now,
+                last_seen: now,
+                count: 1,
+                suppressed: 0,
+            });
+            return Verdict::Log { suppressed: 0 };
+        };
+
+        site.last_seen = now;
+        if now.saturating_sub(site.window_start) >= RATE_LIMIT_WINDOW_MS {
+            let suppressed = site.suppressed;
+            site.window_start = now;
+            site.count = 1;
+            site.suppressed = 0;
+            Verdict::Log { suppressed }
+        } else if site.count < RATE_LIMIT_BURST {
+            site.count += 1;
+            Verdict::Log { suppressed: 0 }
+        } else {
+            site.suppressed += 1;
+            Verdict::Suppress
+        }
+    }
+}
+
+struct State {
+    filters: Filters,
+    /// Records above this level are kept in the history only.
+    console_level: LevelFilter,
+    limiter: RateLimiter,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    filters: Filters::new(),
+    console_level: LevelFilter::Trace,
+    limiter: RateLimiter::new(),
+}));
+
+/// Returns milliseconds since boot.
+type Clock = fn() -> u64;
+
+/// Rate limiting is disabled until a clock is set.
+static CLOCK: Mutex<Cell<Option<Clock>>> = Mutex::new(Cell::new(None));
+
+fn update_max_level(state: &State) {
+    log::set_max_level(state.filters.max());
+}
+
+/// Set the level of modules without a level of their own.
+pub fn set_default_level(level: LevelFilter) {
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        state.filters.default = level;
+        update_max_level(&state);
+    });
+}
+
+/// Set the level of `module` and the modules below it, overriding the default level.
+pub fn set_module_level(module: &str, level: LevelFilter) -> Result<(), LogConfigError> {
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        state.filters.set(module, level)?;
+        update_max_level(&state);
+        Ok(())
+    })
+}
+
+/// Remove all per-module levels.
+pub fn clear_module_levels() {
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        state.filters.modules.clear();
+        update_max_level(&state);
+    });
+}
+
+/// Only write records up to `level` to the console, the history still gets every record.
+pub fn set_console_level(level: LevelFilter) {
+    critical_section::with(|cs| STATE.borrow_ref_mut(cs).console_level = level);
+}
+
+/// Provide the time used for rate limiting, in milliseconds.
+pub fn set_clock(now_ms: Clock) {
+    critical_section::with(|cs| CLOCK.borrow(cs).set(Some(now_ms)));
+}
+
+/// The most recent log output, overwritten oldest first.
+///
+/// Bytes are addressed by their position in the stream of all output since boot, so that a
+/// reader can resume where it left off.
+struct History {
+    buf: [u8; LOG_HISTORY_SIZE],
+    end: usize,
+    len: usize,
+    written: u64,
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_HISTORY_SIZE],
+            end: 0,
+            len: 0,
+            written: 0,
+        }
+    }
+
+    /// Copy the output from stream `position` into `out`.
+    ///
+    /// Returns the position of the first byte copied, later than `position` if those bytes have
+    /// been overwritten, and the number of bytes copied.
+    fn read(&self, position: u64, out: &mut [u8]) -> (u64, usize) {
+        let start = position.clamp(self.written - self.len as u64, self.written);
+        let available = (self.written - start) as usize;
+        let count = available.min(out.len());
+        let first = (self.end + LOG_HISTORY_SIZE - available) % LOG_HISTORY_SIZE;
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.buf[(first + i) % LOG_HISTORY_SIZE];
+        }
+        (start, count)
+    }
+
+    /// Copy the newest bytes that fit into `out`, oldest first.
+    fn copy_recent(&self, out: &mut [u8]) -> usize {
+        let count = self.len.min(out.len());
+        self.read(self.written - count as u64, out).1
+    }
+}
+
+impl Write for History {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.end] = byte;
+            self.end = (self.end + 1) % LOG_HISTORY_SIZE;
+            self.len = (self.len + 1).min(LOG_HISTORY_SIZE);
+        }
+        self.written += s.len() as u64;
+        Ok(())
+    }
+}
+
+static HISTORY: Mutex<RefCell<History>> = Mutex::new(RefCell::new(History::new()));
+
+/// Copy the most recent log output into `out`, returning the number of bytes written.
+///
+/// Safe to call from a panic handler: if the history is being written to, nothing is copied.
+pub fn recent_log(out: &mut [u8]) -> usize {
+    critical_section::with(|cs| match HISTORY.borrow(cs).try_borrow() {
+        Ok(history) => history.copy_recent(out),
+        Err(_) => 0,
+    })
+}
+
+/// Copy the log output from stream `position` into `out`.
+///
+/// Returns the position of the first byte copied, which is past `position` if the history no
+/// longer holds it, and the number of bytes copied. Reading from the returned position plus the
+/// count continues the stream.
+pub fn read_log(position: u64, out: &mut [u8]) -> (u64, usize) {
+    critical_section::with(|cs| match HISTORY.borrow(cs).try_borrow() {
+        Ok(history) => history.read(position, out),
+        Err(_) => (position, 0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_keeps_newest_bytes() {
+        let mut history = History::new();
+        let mut out = [0u8; 8];
+
+        write!(history, "abc").unwrap();
+        assert_eq!(history.copy_recent(&mut out), 3);
+        assert_eq!(&out[..3], b"abc");
+
+        for _ in 0..LOG_HISTORY_SIZE / 4 {
+            write!(history, "0123").unwrap();
+        }
+        write!(history, "xyz").unwrap();
+        assert_eq!(history.copy_recent(&mut out), 8);
+        assert_eq!(&out, b"30123xyz");
+    }
+
+    #[test]
+    fn test_history_read_by_position() {
+        let mut history = History::new();
+        let mut out = [0u8; 4];
+
+        write!(history, "abcdef").unwrap();
+        assert_eq!(history.read(0, &mut out), (0, 4));
+        assert_eq!(&out, b"abcd");
+        assert_eq!(history.read(4, &mut out), (4, 2));
+        assert_eq!(&out[..2], b"ef");
+        assert_eq!(history.read(6, &mut out), (6, 0));
+        assert_eq!(history.read(100, &mut out), (6, 0));
+
+        // Overwritten output is skipped
+        for _ in 0..LOG_HISTORY_SIZE {
+            write!(history, "x").unwrap();
+        }
+        write!(history, "yz").unwrap();
+        assert_eq!(history.read(0, &mut out), (8, 4));
+        assert_eq!(history.read(history.written - 3, &mut out), (history.written - 3, 3));
+        assert_eq!(&out[..3], b"xyz");
+    }
+
+    #[test]
+    fn test_module_filters() {
+        let mut filters = Filters::new();
+        filters.default = LevelFilter::Info;
+        filters.set("ec_service_lib", LevelFilter::Warn).unwrap();
+        filters
+            .set("ec_service_lib::services::thermal", LevelFilter::Trace)
+            .unwrap();
+
+        assert_eq!(filters.level("qemu_ec_sp"), LevelFilter::Info);
+        assert_eq!(filters.level("ec_service_lib"), LevelFilter::Warn);
+        assert_eq!(filters.level("ec_service_lib::services::fw_mgmt"), LevelFilter::Warn);
+        assert_eq!(filters.level("ec_service_lib::services::thermal"), LevelFilter::Trace);
+        // Only whole path components match
+        assert_eq!(filters.level("ec_service_lib_extra"), LevelFilter::Info);
+        assert_eq!(filters.max(), LevelFilter::Trace);
+
+        filters
+            .set("ec_service_lib::services::thermal", LevelFilter::Error)
+            .unwrap();
+        assert_eq!(filters.level("ec_service_lib::services::thermal"), LevelFilter::Error);
+        assert_eq!(filters.max(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_module_filter_limits() {
+        let mut filters = Filters::new();
+        let long = "m".repeat(MODULE_NAME_SIZE + 1);
+        assert_eq!(filters.set(&long, LevelFilter::Off), Err(LogConfigError::NameTooLong));
+
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h", "i"];
+        for name in &names[..MAX_MODULE_LEVELS] {
+            filters.set(name, LevelFilter::Off).unwrap();
+        }
+        assert_eq!(
+            filters.set(names[MAX_MODULE_LEVELS], LevelFilter::Off),
+            Err(LogConfigError::TooManyModules)
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new();
+
+        for _ in 0..RATE_LIMIT_BURST {
+            assert_eq!(limiter.check("a.rs", 1, 0), Verdict::Log { suppressed: 0 });
+        }
+        assert_eq!(limiter.check("a.rs", 1, 10), Verdict::Suppress);
+        assert_eq!(limiter.check("a.rs", 1, 20), Verdict::Suppress);
+        // Other call sites are limited separately
+        assert_eq!(limiter.check("a.rs", 2, 20), Verdict::Log { suppressed: 0 });
+
+        assert_eq!(
+            limiter.check("a.rs", 1, RATE_LIMIT_WINDOW_MS),
+            Verdict::Log { suppressed: 2 }
+        );
+        assert_eq!(
+            limiter.check("a.rs", 1, RATE_LIMIT_WINDOW_MS + 1),
+            Verdict::Log { suppressed: 0 }
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_evicts_quietest_site() {
+        let mut limiter = RateLimiter::new();
+        for line in 0..RATE_LIMIT_SITES as u32 {
+            limiter.check("a.rs", line, 100 + line as u64);
+        }
+        for _ in 1..RATE_LIMIT_BURST {
+            limiter.check("a.rs", 0, 200);
+        }
+
+        // Line 1 is now the quietest site and makes room, line 0 keeps its count
+        limiter.check("b.rs", 1, 300);
+        assert_eq!(limiter.check("a.rs", 0, 300), Verdict::Suppress);
+    }
+}
@@ -0,0 +1,162 @@
+//! Counters of the requests handled by each service, per opcode.
+//!
+//! [`ServiceNode`](crate::ServiceNode) records every request it dispatches, the Debug service
+//! returns the counters to the OS.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use odp_ffa::{ErrorCode, MsgSendDirectResp2, Payload};
+use uuid::Uuid;
+
+use crate::Result;
+
+/// Distinct service and opcode pairs tracked, requests beyond are only counted as untracked.
+pub const MAX_STATS_ENTRIES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestStats {
+    pub service: Uuid,
+    pub opcode: u8,
+    pub requests: u64,
+    /// Requests the service failed, with an error or an error status, or that were denied.
+    pub errors: u64,
+}
+
+struct Table {
+    entries: heapless::Vec<RequestStats, MAX_STATS_ENTRIES>,
+    untracked: u64,
+}
+
+impl Table {
+    const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+            untracked: 0,
+        }
+    }
+
+    fn record(&mut self, service: Uuid, opcode: u8, ok: bool) {
+        let found = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.service == service && entry.opcode == opcode);
+
+        let entry = match found {
+            Some(entry) => entry,
+            None => {
+                let entry = RequestStats {
+                    service,
+                    opcode,
+                    requests: 0,
+                    errors: 0,
+                };
+                if self.entries.push(entry).is_err() {
+                    self.untracked += 1;
+                    return;
+                }
+                self.entries.last_mut().unwrap()
+            }
+        };
+
+        entry.requests += 1;
+        if !ok {
+            entry.errors += 1;
+        }
+    }
+}
+
+/// Whether `result` is a response reporting success.
+///
+/// Responses lead with their status, so one starting with an FF-A error code reports a failure
+/// as much as an error does. Payloads that start with data rather than a status are only
+/// mistaken for a failure if their first 8 bytes are exactly one of those codes.
+pub fn succeeded(result: &Result<MsgSendDirectResp2>) -> bool {
+    match result {
+        Ok(rsp) => matches!(ErrorCode::try_from(rsp.u64_at(0) as i64), Ok(ErrorCode::Ok) | Err(_)),
+        Err(_) => false,
+    }
+}
+
+static STATS: Mutex<RefCell<Table>> = Mutex::new(RefCell::new(Table::new()));
+
+/// Count a request to `opcode` of `service`, and whether it failed, see [`succeeded`].
+pub fn record(service: Uuid, opcode: u8, ok: bool) {
+    critical_section::with(|cs| STATS.borrow_ref_mut(cs).record(service, opcode, ok));
+}
+
+/// The counters of the `index`th service and opcode pair seen, in order of first request.
+pub fn get(index: usize) -> Option<RequestStats> {
+    critical_section::with(|cs| STATS.borrow_ref(cs).entries.get(index).copied())
+}
+
+/// Number of service and opcode pairs tracked.
+pub fn count() -> usize {
+    critical_section::with(|cs| STATS.borrow_ref(cs).entries.len())
+}
+
+/// Requests not tracked because the table was full.
+pub fn untracked() -> u64 {
+    critical_section::with(|cs| STATS.borrow_ref(cs).untracked)
+}
+
+pub fn reset() {
+    critical_section::with(|cs| *STATS.borrow_ref_mut(cs) = Table::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::uuid;
+
+    const THERMAL: Uuid = uuid!("31f56da7-593c-4d72-a4b3-8fc7171ac073");
+    const FW_MGMT: Uuid = uuid!("330c1273-fde5-4757-9819-5b6539037502");
+
+    #[test]
+    fn test_counts_per_service_and_opcode() {
+        let mut table = Table::new();
+        table.record(THERMAL, 1, true);
+        table.record(THERMAL, 1, false);
+        table.record(THERMAL, 2, true);
+        table.record(FW_MGMT, 1, true);
+
+        assert_eq!(table.entries.len(), 3);
+        assert_eq!(
+            table.entries[0],
+            RequestStats {
+                service: THERMAL,
+                opcode: 1,
+                requests: 2,
+                errors: 1,
+            }
+        );
+        assert_eq!((table.entries[2].service, table.entries[2].requests), (FW_MGMT, 1));
+    }
+
+    #[test]
+    fn test_full_table_counts_untracked() {
+        let mut table = Table::new();
+        for opcode in 0..=MAX_STATS_ENTRIES as u8 {
+            table.record(THERMAL, opcode, true);
+        }
+        table.record(THERMAL, 0, true);
+
+        assert_eq!(table.entries.len(), MAX_STATS_ENTRIES);
+        assert_eq!(table.entries[0].requests, 2);
+        assert_eq!(table.untracked, 1);
+    }
+
+    #[test]
+    fn test_error_status_counts_as_failure() {
+        let response = |status: i64| {
+            let payload = odp_ffa::RegisterPayload::from_iter(status.to_le_bytes().into_iter().chain([0xaa; 8]));
+            Ok(MsgSendDirectResp2::new(2, 1, THERMAL, payload))
+        };
+
+        assert!(succeeded(&response(0)));
+        assert!(succeeded(&response(0x1234)));
+        assert!(!succeeded(&response(ErrorCode::Denied as i64)));
+        assert!(!succeeded(&response(ErrorCode::NotReady as i64)));
+        assert!(!succeeded(&Err(odp_ffa::Error::Other("failed"))));
+    }
+}
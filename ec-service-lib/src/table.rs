@@ -0,0 +1,197 @@
+//! Services registered at runtime, as an alternative to [`service_list!`](crate::service_list).
+//!
+//! [`service_list!`](crate::service_list) nests the services in a type fixed at build time and
+//! matches the UUID of each request against each service in turn. A [`ServiceTable`] holds up to
+//! `N` services as trait objects, sorted by UUID, so that services can be registered once the
+//! platform has probed which ones it supports, and a request finds its service by binary search.
+//!
+//! Trait objects cannot return their futures unboxed, so each request to a service in the table
+//! allocates its future on the heap: the table needs a global allocator and is only built with the
+//! `alloc` feature.
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+
+use log::error;
+use odp_ffa::{MsgSendDirectReq2, MsgSendDirectResp2, Payload};
+use uuid::Uuid;
+
+use crate::access::{self, AccessRule};
+use crate::middleware::{Layered, Middleware};
+use crate::{stats, Result, Service, ServiceNodeHandler};
+
+/// The future of a request to a [`DynService`].
+pub type ServiceFuture<'a> = Pin<Box<dyn Future<Output = Result<MsgSendDirectResp2>> + 'a>>;
+
+/// A [`Service`] usable as a trait object, implemented for every service.
+pub trait DynService {
+    fn service_name(&self) -> &'static str;
+    fn service_uuid(&self) -> Uuid;
+    fn access_rules(&self) -> &[AccessRule];
+    fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> ServiceFuture<'_>;
+}
+
+impl<S: Service> DynService for S {
+    fn service_name(&self) -> &'static str {
+        Service::service_name(self)
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        Service::service_uuid(self)
+    }
+
+    fn access_rules(&self) -> &[AccessRule] {
+        Service::access_rules(self)
+    }
+
+    fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> ServiceFuture<'_> {
+        Box::pin(Service::ffa_msg_send_direct_req2(self, msg))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The table already holds `N` services.
+    Full,
+    /// A service with the same UUID is registered.
+    Duplicate(Uuid),
+}
+
+/// Up to `N` services, dispatched to by UUID.
+pub struct ServiceTable<'a, const N: usize> {
+    /// Sorted by UUID.
+    services: heapless::Vec<(Uuid, &'a mut dyn DynService), N>,
+}
+
+impl<const N: usize> Default for ServiceTable<'_, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> ServiceTable<'a, N> {
+    pub const fn new() -> Self {
+        Self {
+            services: heapless::Vec::new(),
+        }
+    }
+
+    /// Dispatch the requests for the UUID of `service` to it.
+    pub fn register(&mut self, service: &'a mut dyn DynService) -> core::result::Result<(), RegisterError> {
+        let uuid = service.service_uuid();
+        let index = match self.services.binary_search_by_key(&uuid, |(uuid, _)| *uuid) {
+            Ok(_) => return Err(RegisterError::Duplicate(uuid)),
+            Err(index) => index,
+        };
+        self.services
+            .insert(index, (uuid, service))
+            .map_err(|_| RegisterError::Full)
+    }
+
+    /// Stop dispatching the requests for `uuid`, returning the service that handled them.
+    pub fn unregister(&mut self, uuid: Uuid) -> Option<&'a mut dyn DynService> {
+        let index = self.services.binary_search_by_key(&uuid, |(uuid, _)| *uuid).ok()?;
+        Some(self.services.remove(index).1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// Run the hooks of `middleware` around the dispatch of every request.
+    pub fn with_middleware<M: Middleware>(self, middleware: M) -> Layered<M, Self> {
+        Layered::new(middleware, self)
+    }
+}
+
+impl<const N: usize> ServiceNodeHandler for ServiceTable<'_, N> {
+    async fn handle(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        let uuid = msg.uuid();
+        let Ok(index) = self.services.binary_search_by_key(&uuid, |(uuid, _)| *uuid) else {
+            error!("Unknown UUID {}", uuid);
+            return Err(odp_ffa::Error::Other("Unknown UUID"));
+        };
+
+        let service = &mut *self.services[index].1;
+        let opcode = msg.u8_at(0);
+        let result = match access::deny(service.access_rules(), uuid, &msg) {
+            Some(denied) => Ok(denied),
+            None => service.ffa_msg_send_direct_req2(msg).await,
+        };
+        stats::record(uuid, opcode, stats::succeeded(&result));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use odp_ffa::RegisterPayload;
+    use uuid::uuid;
+
+    /// Answers with its `tag`.
+    struct Tagged {
+        uuid: Uuid,
+        tag: u8,
+    }
+
+    impl Service for Tagged {
+        fn service_name(&self) -> &'static str {
+            "Tagged"
+        }
+
+        fn service_uuid(&self) -> Uuid {
+            self.uuid
+        }
+
+        async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+            Ok(MsgSendDirectResp2::from_req_with_payload(
+                &msg,
+                RegisterPayload::from_iter([self.tag]),
+            ))
+        }
+    }
+
+    const UUIDS: [Uuid; 3] = [
+        uuid!("9a8a1e88-a880-447c-830d-6d764e9172bb"),
+        uuid!("25cb5207-ac36-427d-aaef-3aa78877d27e"),
+        uuid!("7157addf-2fbe-4c63-ae95-efac16e3b01c"),
+    ];
+
+    fn request(uuid: Uuid) -> MsgSendDirectReq2 {
+        MsgSendDirectReq2::new(1, 2, uuid, RegisterPayload::from_iter([0x1]))
+    }
+
+    #[test]
+    fn test_register_and_dispatch() {
+        let mut services = UUIDS.map(|uuid| Tagged {
+            uuid,
+            tag: uuid.as_bytes()[0],
+        });
+        let mut duplicate = Tagged { uuid: UUIDS[1], tag: 0 };
+        let [a, b, c] = &mut services;
+
+        let mut table = ServiceTable::<2>::new();
+        table.register(a).unwrap();
+        table.register(b).unwrap();
+        assert_eq!(table.register(&mut duplicate), Err(RegisterError::Duplicate(UUIDS[1])));
+        assert_eq!(table.register(c), Err(RegisterError::Full));
+        assert!(table.services.is_sorted_by_key(|(uuid, _)| *uuid));
+
+        for uuid in &UUIDS[..2] {
+            let rsp = embassy_futures::block_on(table.handle(request(*uuid))).unwrap();
+            assert_eq!(rsp.u8_at(0), uuid.as_bytes()[0]);
+        }
+        assert!(embassy_futures::block_on(table.handle(request(UUIDS[2]))).is_err());
+
+        let removed = table.unregister(UUIDS[0]).unwrap();
+        assert_eq!(removed.service_uuid(), UUIDS[0]);
+        assert!(embassy_futures::block_on(table.handle(request(UUIDS[0]))).is_err());
+        assert_eq!(table.len(), 1);
+    }
+}
@@ -0,0 +1,65 @@
+//! Fixtures shared by the tests of the crate.
+
+use odp_ffa::{MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
+use uuid::{uuid, Uuid};
+
+use crate::address_space::{Attributes, TranslationTable};
+use crate::{Result, Service};
+
+/// A request to the service `uuid` from endpoint 1 to endpoint 2, with `bytes` as payload.
+pub(crate) fn request(uuid: Uuid, bytes: &[u8]) -> MsgSendDirectReq2 {
+    MsgSendDirectReq2::new(1, 2, uuid, RegisterPayload::from_iter(bytes.iter().copied()))
+}
+
+/// Held by the tests that check the global audit log, which the others only append to.
+pub(crate) static AUDIT_LOG_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Echoes the opcode, failing opcode 0xff.
+pub(crate) struct Echo;
+
+impl Echo {
+    pub(crate) const UUID: Uuid = uuid!("9a8a1e88-a880-447c-830d-6d764e9172bb");
+}
+
+impl Service for Echo {
+    fn service_name(&self) -> &'static str {
+        "Echo"
+    }
+
+    fn service_uuid(&self) -> Uuid {
+        Self::UUID
+    }
+
+    async fn ffa_msg_send_direct_req2(&mut self, msg: MsgSendDirectReq2) -> Result<MsgSendDirectResp2> {
+        match msg.u8_at(0) {
+            0xff => Err(odp_ffa::Error::Other("Unknown Echo Command")),
+            opcode => Ok(MsgSendDirectResp2::from_req_with_payload(
+                &msg,
+                RegisterPayload::from_iter([opcode]),
+            )),
+        }
+    }
+}
+
+/// Translation table with nothing to program, for address spaces that only track the mappings.
+pub(crate) struct NoTable;
+
+impl TranslationTable for NoTable {
+    type Error = ();
+
+    fn map(&mut self, _base: u64, _size: u64, _attributes: Attributes) -> core::result::Result<(), ()> {
+        Ok(())
+    }
+
+    fn unmap(&mut self, _base: u64, _size: u64) -> core::result::Result<(), ()> {
+        Ok(())
+    }
+}
+
+/// A page of memory for the tests to map, filled with `fill` and never freed.
+pub(crate) fn leak_page(fill: u8) -> &'static mut [u8; 4096] {
+    #[repr(align(4096))]
+    struct Page([u8; 4096]);
+
+    &mut std::boxed::Box::leak(std::boxed::Box::new(Page([fill; 4096]))).0
+}
@@ -0,0 +1,495 @@
+//! Reporting of exceptions the partition cannot recover from.
+//!
+//! The vectors that have no handler capture an [`ExceptionFrame`], decode the syndrome register
+//! and walk the frame-pointer chain before panicking, so a log from the field identifies the
+//! faulting instruction, the kind of fault and the call path that led to it.
+
+use core::cell::Cell;
+use core::fmt;
+use core::ops::Range;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use log::error;
+
+/// Maximum number of return addresses collected by [`Backtrace::walk`].
+pub const MAX_BACKTRACE_DEPTH: usize = 16;
+
+/// Exception vector that was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vector {
+    SyncCurrent,
+    SErrorCurrent,
+    SyncLower,
+    SErrorLower,
+}
+
+impl Vector {
+    pub fn name(self) -> &'static str {
+        match self {
+            Vector::SyncCurrent => "sync_exception_current",
+            Vector::SErrorCurrent => "serr_current",
+            Vector::SyncLower => "sync_lower",
+            Vector::SErrorLower => "serr_lower",
+        }
+    }
+}
+
+/// Exception class, ESR_ELx bits [31:26].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionClass {
+    Unknown,
+    WfiWfe,
+    FpAccess,
+    IllegalExecutionState,
+    Svc64,
+    Hvc64,
+    Smc64,
+    SysRegTrap,
+    InstructionAbortLower,
+    InstructionAbortCurrent,
+    PcAlignment,
+    DataAbortLower,
+    DataAbortCurrent,
+    SpAlignment,
+    FpException,
+    SError,
+    BreakpointLower,
+    BreakpointCurrent,
+    SoftwareStepLower,
+    SoftwareStepCurrent,
+    WatchpointLower,
+    WatchpointCurrent,
+    Brk64,
+    Other(u8),
+}
+
+impl ExceptionClass {
+    pub fn from_ec(ec: u8) -> Self {
+        match ec {
+            0x00 => ExceptionClass::Unknown,
+            0x01 => ExceptionClass::WfiWfe,
+            0x07 => ExceptionClass::FpAccess,
+            0x0e => ExceptionClass::IllegalExecutionState,
+            0x15 => ExceptionClass::Svc64,
+            0x16 => ExceptionClass::Hvc64,
+            0x17 => ExceptionClass::Smc64,
+            0x18 => ExceptionClass::SysRegTrap,
+            0x20 => ExceptionClass::InstructionAbortLower,
+            0x21 => ExceptionClass::InstructionAbortCurrent,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLower,
+            0x25 => ExceptionClass::DataAbortCurrent,
+            0x26 => ExceptionClass::SpAlignment,
+            0x2c => ExceptionClass::FpException,
+            0x2f => ExceptionClass::SError,
+            0x30 => ExceptionClass::BreakpointLower,
+            0x31 => ExceptionClass::BreakpointCurrent,
+            0x32 => ExceptionClass::SoftwareStepLower,
+            0x33 => ExceptionClass::SoftwareStepCurrent,
+            0x34 => ExceptionClass::WatchpointLower,
+            0x35 => ExceptionClass::WatchpointCurrent,
+            0x3c => ExceptionClass::Brk64,
+            ec => ExceptionClass::Other(ec),
+        }
+    }
+}
+
+impl fmt::Display for ExceptionClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ExceptionClass::Unknown => "unknown reason",
+            ExceptionClass::WfiWfe => "trapped WFI/WFE",
+            ExceptionClass::FpAccess => "trapped FP/SIMD access",
+            ExceptionClass::IllegalExecutionState => "illegal execution state",
+            ExceptionClass::Svc64 => "svc",
+            ExceptionClass::Hvc64 => "hvc",
+            ExceptionClass::Smc64 => "smc",
+            ExceptionClass::SysRegTrap => "trapped system register access",
+            ExceptionClass::InstructionAbortLower => "instruction abort (lower EL)",
+            ExceptionClass::InstructionAbortCurrent => "instruction abort (current EL)",
+            ExceptionClass::PcAlignment => "PC alignment fault",
+            ExceptionClass::DataAbortLower => "data abort (lower EL)",
+            ExceptionClass::DataAbortCurrent => "data abort (current EL)",
+            ExceptionClass::SpAlignment => "SP alignment fault",
+            ExceptionClass::FpException => "floating point exception",
+            ExceptionClass::SError => "SError",
+            ExceptionClass::BreakpointLower => "breakpoint (lower EL)",
+            ExceptionClass::BreakpointCurrent => "breakpoint (current EL)",
+            ExceptionClass::SoftwareStepLower => "software step (lower EL)",
+            ExceptionClass::SoftwareStepCurrent => "software step (current EL)",
+            ExceptionClass::WatchpointLower => "watchpoint (lower EL)",
+            ExceptionClass::WatchpointCurrent => "watchpoint (current EL)",
+            ExceptionClass::Brk64 => "brk",
+            ExceptionClass::Other(ec) => return write!(f, "exception class {:#x}", ec),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Value of the exception syndrome register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Esr(pub u64);
+
+impl Esr {
+    pub fn class(self) -> ExceptionClass {
+        ExceptionClass::from_ec(((self.0 >> 26) & 0x3f) as u8)
+    }
+
+    /// Instruction specific syndrome, bits [24:0].
+    pub fn iss(self) -> u32 {
+        (self.0 & 0x1ff_ffff) as u32
+    }
+
+    /// True if the trapped instruction was 32 bits wide.
+    pub fn il(self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+
+    /// True if FAR_ELx holds the faulting address, only meaningful for aborts.
+    pub fn far_valid(self) -> bool {
+        self.iss() & (1 << 10) == 0
+    }
+
+    fn is_abort(self) -> bool {
+        matches!(
+            self.class(),
+            ExceptionClass::DataAbortLower
+                | ExceptionClass::DataAbortCurrent
+                | ExceptionClass::InstructionAbortLower
+                | ExceptionClass::InstructionAbortCurrent
+        )
+    }
+}
+
+/// Describe a data or instruction fault status code, ISS bits [5:0].
+fn fault_status(fsc: u32) -> (&'static str, Option<u32>) {
+    let level = Some(fsc & 0b11);
+    match fsc {
+        0b000000..=0b000011 => ("address size fault", level),
+        0b000100..=0b000111 => ("translation fault", level),
+        0b001001..=0b001011 => ("access flag fault", level),
+        0b001101..=0b001111 => ("permission fault", level),
+        0b010000 => ("synchronous external abort", None),
+        0b010100..=0b010111 => ("synchronous external abort on table walk", level),
+        0b100001 => ("alignment fault", None),
+        0b110000 => ("TLB conflict abort", None),
+        _ => ("unrecognized fault status", None),
+    }
+}
+
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = self.class();
+        let iss = self.iss();
+        match class {
+            ExceptionClass::Svc64 | ExceptionClass::Hvc64 | ExceptionClass::Smc64 | ExceptionClass::Brk64 => {
+                write!(f, "{} #{:#x}", class, iss & 0xffff)
+            }
+            _ if self.is_abort() => {
+                let (status, level) = fault_status(iss & 0x3f);
+                write!(f, "{}: {}", class, status)?;
+                if let Some(level) = level {
+                    write!(f, ", level {}", level)?;
+                }
+                if matches!(class, ExceptionClass::DataAbortLower | ExceptionClass::DataAbortCurrent) {
+                    let access = if iss & (1 << 6) != 0 { "write" } else { "read" };
+                    write!(f, " on {}", access)?;
+                    // With ISV set the access size and target register are known
+                    if iss & (1 << 24) != 0 {
+                        let size = 1 << ((iss >> 22) & 0b11);
+                        write!(f, " of {} bytes to x{}", size, (iss >> 16) & 0x1f)?;
+                    }
+                }
+                if !self.far_valid() {
+                    f.write_str(", far not valid")?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "{}, iss={:#x}", class, iss),
+        }
+    }
+}
+
+/// Registers saved on the stack by the vector entry code, in this layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct SavedRegisters {
+    /// x0 to x30.
+    pub x: [u64; 31],
+    /// Stack pointer of the interrupted code.
+    pub sp: u64,
+    pub elr: u64,
+    pub spsr: u64,
+}
+
+/// Register state captured when an exception is taken.
+///
+/// All the registers are those of the interrupted code, as saved by the vector entry code
+/// before anything else runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionFrame {
+    pub vector: Vector,
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: Esr,
+    pub far: u64,
+    pub sp: u64,
+    /// x0 to x30.
+    pub regs: [u64; 31],
+}
+
+impl ExceptionFrame {
+    /// The frame pointer, x29, where the frame-pointer chain into the interrupted code starts.
+    pub fn fp(&self) -> u64 {
+        self.regs[29]
+    }
+
+    /// The link register, x30.
+    pub fn lr(&self) -> u64 {
+        self.regs[30]
+    }
+}
+
+/// Return addresses recovered by following frame records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backtrace {
+    frames: [u64; MAX_BACKTRACE_DEPTH],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Follow the AAPCS64 frame record chain starting at `fp`.
+    ///
+    /// Each record is two words, the caller's frame pointer followed by the return address.
+    /// The walk stops at the first record that lies outside `stack`, is misaligned or does not
+    /// move towards the base of the stack, so a corrupted chain cannot send it astray.
+    /// `read` is only called with addresses inside `stack`.
+    pub fn walk(mut fp: u64, stack: Range<u64>, read: impl Fn(u64) -> u64) -> Self {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_BACKTRACE_DEPTH],
+            len: 0,
+        };
+
+        while backtrace.len < MAX_BACKTRACE_DEPTH {
+            let in_stack = fp >= stack.start && fp.checked_add(16).is_some_and(|end| end <= stack.end);
+            if !in_stack || fp & 0x7 != 0 {
+                break;
+            }
+
+            let next_fp = read(fp);
+            let lr = read(fp + 8);
+            if lr == 0 {
+                break;
+            }
+            backtrace.frames[backtrace.len] = lr;
+            backtrace.len += 1;
+
+            if next_fp <= fp {
+                break;
+            }
+            fp = next_fp;
+        }
+
+        backtrace
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+static LAST_EXCEPTION: Mutex<CriticalSectionRawMutex, Cell<Option<ExceptionFrame>>> = Mutex::new(Cell::new(None));
+
+/// The most recent exception reported by [`report`], if any.
+pub fn last_exception() -> Option<ExceptionFrame> {
+    LAST_EXCEPTION.lock(|last| last.get())
+}
+
+/// Record `frame` and log it with its decoded syndrome and backtrace.
+pub fn report(frame: &ExceptionFrame, backtrace: &Backtrace) {
+    LAST_EXCEPTION.lock(|last| last.set(Some(*frame)));
+
+    error!("Unhandled exception in {} at elr={:#x}", frame.vector.name(), frame.elr);
+    error!("  {}", frame.esr);
+    error!("  esr={:#x} far={:#x} spsr={:#x}", frame.esr.0, frame.far, frame.spsr);
+    error!("  sp={:#x} fp={:#x} lr={:#x}", frame.sp, frame.fp(), frame.lr());
+    for (row, regs) in frame.regs.chunks(4).enumerate() {
+        error!("  {}", RegisterRow { first: row * 4, regs });
+    }
+    error!("  backtrace:");
+    error!("    #0 {:#x}", frame.elr);
+    for (i, lr) in backtrace.frames().iter().enumerate() {
+        error!("    #{} {:#x}", i + 1, lr);
+    }
+}
+
+/// Registers `x<first>` onwards, on one line.
+struct RegisterRow<'a> {
+    first: usize,
+    regs: &'a [u64],
+}
+
+impl fmt::Display for RegisterRow<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, value) in self.regs.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "x{:<2}={:#018x}", self.first + i, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "none")]
+unsafe extern "C" {
+    // Bounds of the boot stack, defined by the platform's image.ld
+    static boot_stack_begin: u8;
+    static boot_stack_end: u8;
+}
+
+/// Record and log the state of an exception the partition cannot handle, then panic.
+///
+/// `saved` holds the registers of the interrupted code, saved by the vector entry code.
+#[cfg(target_os = "none")]
+pub fn fatal(vector: Vector, saved: &SavedRegisters) -> ! {
+    use aarch64_cpu::registers::{ESR_EL1, FAR_EL1, Readable};
+
+    let frame = ExceptionFrame {
+        vector,
+        elr: saved.elr,
+        spsr: saved.spsr,
+        esr: Esr(ESR_EL1.get()),
+        far: FAR_EL1.get(),
+        sp: saved.sp,
+        regs: saved.x,
+    };
+
+    // Only the addresses of the linker symbols are taken
+    let stack = (&raw const boot_stack_begin) as u64..(&raw const boot_stack_end) as u64;
+    // SAFETY: `walk` only reads aligned words inside the boot stack, which is always mapped.
+    let backtrace = Backtrace::walk(frame.fp(), stack, |addr| unsafe {
+        core::ptr::read_volatile(addr as *const u64)
+    });
+
+    report(&frame, &backtrace);
+    panic!("{} in {}", frame.esr.class(), vector.name());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_data_abort() {
+        // Write to an unmapped page, translation fault at level 1
+        let esr = Esr(0x9600_0045);
+        assert_eq!(esr.class(), ExceptionClass::DataAbortCurrent);
+        assert!(esr.il());
+        assert_eq!(
+            format!("{}", esr),
+            "data abort (current EL): translation fault, level 1 on write"
+        );
+
+        // Read with a valid syndrome: 4 bytes into x3, permission fault at level 3, FAR not valid
+        let esr = Esr(0x9783_040f);
+        assert_eq!(
+            format!("{}", esr),
+            "data abort (current EL): permission fault, level 3 on read of 4 bytes to x3, far not valid"
+        );
+    }
+
+    #[test]
+    fn test_decode_other_classes() {
+        assert_eq!(format!("{}", Esr(0x5600_0012)), "svc #0x12");
+        assert_eq!(format!("{}", Esr(0xf200_0001)), "brk #0x1");
+        assert_eq!(
+            format!("{}", Esr(0x8600_0021)),
+            "instruction abort (current EL): alignment fault"
+        );
+        assert_eq!(format!("{}", Esr(0x0200_0000)), "unknown reason, iss=0x0");
+        assert_eq!(ExceptionClass::from_ec(0x3f), ExceptionClass::Other(0x3f));
+    }
+
+    fn memory(words: &[(u64, u64)]) -> impl Fn(u64) -> u64 + '_ {
+        move |addr| {
+            words
+                .iter()
+                .find(|(a, _)| *a == addr)
+                .map(|(_, value)| *value)
+                .unwrap_or_else(|| panic!("read outside of the frame records: {:#x}", addr))
+        }
+    }
+
+    #[test]
+    fn test_walk_follows_chain() {
+        let words = [
+            (0x1000, 0x1040),
+            (0x1008, 0x4000_0100),
+            (0x1040, 0x1080),
+            (0x1048, 0x4000_0200),
+            (0x1080, 0),
+            (0x1088, 0x4000_0300),
+        ];
+        let backtrace = Backtrace::walk(0x1000, 0x1000..0x2000, memory(&words));
+        assert_eq!(backtrace.frames(), [0x4000_0100, 0x4000_0200, 0x4000_0300]);
+    }
+
+    #[test]
+    fn test_walk_stops_on_bad_records() {
+        // Chain pointing backwards
+        let words = [(0x1040, 0x1000), (0x1048, 0x4000_0100)];
+        let backtrace = Backtrace::walk(0x1040, 0x1000..0x2000, memory(&words));
+        assert_eq!(backtrace.frames(), [0x4000_0100]);
+
+        // Next record outside of the stack is never read
+        let words = [(0x1ff0, 0x3000), (0x1ff8, 0x4000_0100)];
+        let backtrace = Backtrace::walk(0x1ff0, 0x1000..0x2000, memory(&words));
+        assert_eq!(backtrace.frames(), [0x4000_0100]);
+
+        // Misaligned or out of range start
+        assert!(Backtrace::walk(0x1004, 0x1000..0x2000, memory(&[])).frames().is_empty());
+        assert!(Backtrace::walk(0x1ff8, 0x1000..0x2000, memory(&[])).frames().is_empty());
+        assert!(Backtrace::walk(0, 0x1000..0x2000, memory(&[])).frames().is_empty());
+    }
+
+    #[test]
+    fn test_walk_is_bounded() {
+        // Records chained 16 bytes apart for longer than the maximum depth
+        let walk = Backtrace::walk(0x1000, 0x1000..0x2000, |addr| {
+            if addr & 0xf == 0 { addr + 16 } else { 0x4000_0000 }
+        });
+        assert_eq!(walk.frames().len(), MAX_BACKTRACE_DEPTH);
+    }
+
+    #[test]
+    fn test_report_records_last_exception() {
+        let frame = ExceptionFrame {
+            vector: Vector::SyncCurrent,
+            elr: 0x4000_1234,
+            spsr: 0x3c5,
+            esr: Esr(0x9600_0045),
+            far: 0xdead_0000,
+            sp: 0x1f00,
+            regs: core::array::from_fn(|i| i as u64),
+        };
+        report(&frame, &Backtrace::walk(0, 0..0, |_| 0));
+        assert_eq!(last_exception(), Some(frame));
+        assert_eq!((frame.fp(), frame.lr()), (29, 30));
+    }
+
+    #[test]
+    fn test_register_rows() {
+        let regs: [u64; 31] = core::array::from_fn(|i| i as u64);
+        let row = RegisterRow {
+            first: 28,
+            regs: &regs[28..],
+        };
+        assert_eq!(
+            format!("{}", row),
+            "x28=0x000000000000001c x29=0x000000000000001d x30=0x000000000000001e"
+        );
+        assert_eq!(core::mem::size_of::<SavedRegisters>(), 34 * 8);
+    }
+}
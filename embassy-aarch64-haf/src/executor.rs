@@ -5,7 +5,7 @@ use odp_ffa::Function;
 #[unsafe(export_name = "__pender")]
 fn pender(context: *mut ()) {
     let context = context as usize;
-    log::info!("pender called with context: {:<08X}", context);
+    log::trace!("pender called with context: {:<08X}", context);
     // do we need to execute an FFA_RUN or FFA_INTERRUPT here?
 }
 
@@ -35,6 +35,16 @@ impl Executor {
         let version = odp_ffa::Version::new().exec().unwrap();
         log::info!("FFA version: {}.{}", version.major(), version.minor());
 
+        // SAFETY: this is the single call made at boot, before any task can schedule a timer.
+        #[cfg(feature = "time-driver")]
+        unsafe {
+            crate::time_driver::init();
+        }
+
+        // The timer interrupt can only be taken once IRQs are unmasked at the PE
+        #[cfg(feature = "time-driver")]
+        crate::interrupt::enable_arch_interrupts();
+
         init(self.inner.spawner());
 
         loop {
@@ -9,13 +9,22 @@ pub mod interrupt;
 #[cfg(feature = "time-driver")]
 pub mod time_driver;
 
+#[cfg(any(feature = "time-driver", test))]
+mod tick;
+
+pub mod exception;
+
+pub mod managed_exit;
+
+pub mod registry;
+
+#[cfg(target_os = "none")]
 mod critical_section;
 
 #[cfg(target_os = "none")]
 pub use executor::*;
 
-#[cfg(target_os = "none")]
-pub use interrupt::HafInterruptHandler;
+pub use registry::{HafInterruptHandler, InterruptSignal};
 
 #[cfg(target_os = "none")]
 pub use interrupt::{disable_arch_interrupts, enable_arch_interrupts};
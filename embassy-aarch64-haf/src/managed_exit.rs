@@ -0,0 +1,25 @@
+//! Delivery of the managed-exit virtual interrupt.
+//!
+//! Partitions whose manifest sets `ns-interrupts-action = <1>` receive [`InterruptId::MANAGED_EXIT`]
+//! when a non-secure interrupt needs the CPU back. The interrupt is routed to [`MANAGED_EXIT`] so
+//! the message loop can await it alongside the request it is handling.
+
+use hafnium::{InterruptId, InterruptType};
+
+use crate::registry::{self, InterruptSignal, RegistryError};
+
+/// Signaled whenever the SPMC requests a managed exit.
+pub static MANAGED_EXIT: InterruptSignal = InterruptSignal::new();
+
+/// Route the managed-exit interrupt to [`MANAGED_EXIT`] and enable it, unmasking interrupts at
+/// the PE so it is taken even without the time driver.
+///
+/// `int_type` must match how the manifest asks for managed exit to be signaled:
+/// [`InterruptType::Irq`] with `managed-exit-virq`, [`InterruptType::Fiq`] otherwise.
+pub fn enable(int_type: InterruptType) -> Result<(), RegistryError> {
+    registry::register(InterruptId::MANAGED_EXIT, &MANAGED_EXIT)?;
+    registry::enable(InterruptId::MANAGED_EXIT, int_type)?;
+    #[cfg(target_os = "none")]
+    crate::interrupt::enable_arch_interrupts();
+    Ok(())
+}
@@ -0,0 +1,506 @@
+//! Routing of Hafnium virtual interrupts to the subsystems that own them.
+//!
+//! Drivers register a [`HafInterruptHandler`] per [`InterruptId`]. Handlers run in IRQ
+//! context, so anything that needs to await should register an [`InterruptSignal`] and
+//! service the interrupt from a task.
+//!
+//! Hafnium has no runtime control over the priority of virtual interrupts, so priorities are
+//! applied by the registry: every interrupt pending when the vector is taken is acknowledged,
+//! then dispatched in priority order.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use hafnium::{
+    HfError, InterruptId, InterruptReconfigureCommand, InterruptType, hf_interrupt_deactivate, hf_interrupt_get,
+    hf_interrupt_reconfigure, hf_interrupt_set,
+};
+use log::{debug, warn};
+
+/// Maximum number of interrupts that can be routed at the same time.
+pub const MAX_INTERRUPT_HANDLERS: usize = 16;
+
+/// Priority of an interrupt until [`set_priority`] is called. Lower values are more urgent,
+/// as with the GIC.
+pub const DEFAULT_PRIORITY: u8 = 0x80;
+
+/// Handler invoked in IRQ context when its interrupt is pending.
+pub trait HafInterruptHandler: Sync {
+    fn handle(&self, haf_interrupt_id: InterruptId);
+}
+
+/// An interrupt that tasks can await.
+///
+/// Registering a signal turns an interrupt into an async event: the IRQ handler only records
+/// that it fired, and the task awaiting [`InterruptSignal::wait`] does the actual work.
+pub struct InterruptSignal {
+    signal: Signal<CriticalSectionRawMutex, InterruptId>,
+}
+
+impl Default for InterruptSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterruptSignal {
+    pub const fn new() -> Self {
+        Self { signal: Signal::new() }
+    }
+
+    /// Wait for the interrupt to fire, returning the ID that raised it.
+    ///
+    /// Interrupts raised while nobody is waiting are coalesced into a single wake-up.
+    pub async fn wait(&self) -> InterruptId {
+        self.signal.wait().await
+    }
+
+    /// Returns true if the interrupt fired and has not been waited for yet.
+    pub fn signaled(&self) -> bool {
+        self.signal.signaled()
+    }
+
+    /// Discard a pending interrupt.
+    pub fn reset(&self) {
+        self.signal.reset();
+    }
+}
+
+impl HafInterruptHandler for InterruptSignal {
+    fn handle(&self, haf_interrupt_id: InterruptId) {
+        self.signal.signal(haf_interrupt_id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryError {
+    /// A handler is already registered for this interrupt.
+    AlreadyRegistered(InterruptId),
+    /// All handler slots are in use.
+    Full,
+    /// No handler is registered for this interrupt.
+    NotRegistered(InterruptId),
+    /// Hafnium rejected the configuration request.
+    Hafnium(HfError),
+}
+
+#[derive(Clone, Copy)]
+struct Route {
+    id: InterruptId,
+    handler: &'static dyn HafInterruptHandler,
+    priority: u8,
+    /// How the interrupt was last enabled, if it was.
+    int_type: Option<InterruptType>,
+}
+
+/// Fixed capacity table of interrupt routes.
+pub struct InterruptRegistry<const N: usize> {
+    routes: Mutex<CriticalSectionRawMutex, RefCell<[Option<Route>; N]>>,
+}
+
+impl<const N: usize> Default for InterruptRegistry<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> InterruptRegistry<N> {
+    pub const fn new() -> Self {
+        Self {
+            routes: Mutex::new(RefCell::new([None; N])),
+        }
+    }
+
+    /// Route `id` to `handler`.
+    pub fn register(&self, id: InterruptId, handler: &'static dyn HafInterruptHandler) -> Result<(), RegistryError> {
+        self.routes.lock(|routes| {
+            let mut routes = routes.borrow_mut();
+            if routes.iter().flatten().any(|route| route.id == id) {
+                return Err(RegistryError::AlreadyRegistered(id));
+            }
+
+            let slot = routes
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(RegistryError::Full)?;
+            *slot = Some(Route {
+                id,
+                handler,
+                priority: DEFAULT_PRIORITY,
+                int_type: None,
+            });
+            Ok(())
+        })
+    }
+
+    /// Run `f` on the route for `id`.
+    fn with_route<R>(&self, id: InterruptId, f: impl FnOnce(&mut Route) -> R) -> Result<R, RegistryError> {
+        self.routes.lock(|routes| {
+            let mut routes = routes.borrow_mut();
+            routes
+                .iter_mut()
+                .flatten()
+                .find(|route| route.id == id)
+                .map(f)
+                .ok_or(RegistryError::NotRegistered(id))
+        })
+    }
+
+    /// Dispatch `id` before the pending interrupts of a higher `priority` value.
+    pub fn set_priority(&self, id: InterruptId, priority: u8) -> Result<(), RegistryError> {
+        self.with_route(id, |route| route.priority = priority)
+    }
+
+    pub fn priority(&self, id: InterruptId) -> Option<u8> {
+        self.with_route(id, |route| route.priority).ok()
+    }
+
+    /// Remove the route for `id`.
+    pub fn unregister(&self, id: InterruptId) -> Result<(), RegistryError> {
+        self.routes.lock(|routes| {
+            let mut routes = routes.borrow_mut();
+            let slot = routes
+                .iter_mut()
+                .find(|slot| slot.is_some_and(|route| route.id == id))
+                .ok_or(RegistryError::NotRegistered(id))?;
+            *slot = None;
+            Ok(())
+        })
+    }
+
+    pub fn is_registered(&self, id: InterruptId) -> bool {
+        self.handler(id).is_some()
+    }
+
+    fn handler(&self, id: InterruptId) -> Option<&'static dyn HafInterruptHandler> {
+        self.routes.lock(|routes| {
+            routes
+                .borrow()
+                .iter()
+                .flatten()
+                .find(|route| route.id == id)
+                .map(|route| route.handler)
+        })
+    }
+
+    /// Invoke the handlers of the interrupts in `pending`, most urgent first.
+    ///
+    /// Interrupts of equal priority are dispatched in the order they were acknowledged. Returns
+    /// false if none of them has a handler.
+    pub fn dispatch_all(&self, pending: &mut [InterruptId]) -> bool {
+        // Insertion sort: stable, and there are never more than a few pending
+        let priority = |id: InterruptId| self.priority(id).unwrap_or(DEFAULT_PRIORITY);
+        for i in 1..pending.len() {
+            let mut j = i;
+            while j > 0 && priority(pending[j - 1]) > priority(pending[j]) {
+                pending.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+        pending
+            .iter()
+            .fold(false, |dispatched, id| self.dispatch(*id) | dispatched)
+    }
+
+    /// Invoke the handler registered for `id`.
+    ///
+    /// The handler runs outside of the registry lock so it may itself (un)register routes.
+    /// Returns false if no handler is registered.
+    pub fn dispatch(&self, id: InterruptId) -> bool {
+        match self.handler(id) {
+            Some(handler) => {
+                handler.handle(id);
+                true
+            }
+            None => {
+                warn!("No handler registered for interrupt {:?}", id);
+                false
+            }
+        }
+    }
+}
+
+static REGISTRY: InterruptRegistry<MAX_INTERRUPT_HANDLERS> = InterruptRegistry::new();
+
+/// Route `id` to `handler` in the global registry used by the IRQ vector.
+///
+/// This only installs the route; call [`enable`] to unmask the interrupt at Hafnium.
+pub fn register(id: InterruptId, handler: &'static dyn HafInterruptHandler) -> Result<(), RegistryError> {
+    REGISTRY.register(id, handler)
+}
+
+/// Disable `id` and remove its route from the global registry.
+pub fn unregister(id: InterruptId) -> Result<(), RegistryError> {
+    if !REGISTRY.is_registered(id) {
+        return Err(RegistryError::NotRegistered(id));
+    }
+
+    disable(id)?;
+    REGISTRY.unregister(id)
+}
+
+/// Dispatch `id` before the pending interrupts of a higher `priority` value.
+pub fn set_priority(id: InterruptId, priority: u8) -> Result<(), RegistryError> {
+    REGISTRY.set_priority(id, priority)
+}
+
+/// Dispatch a pending interrupt to its registered handler.
+pub fn dispatch(id: InterruptId) -> bool {
+    REGISTRY.dispatch(id)
+}
+
+/// Acknowledge the pending virtual interrupts and hand them to their registered handlers, in
+/// priority order.
+///
+/// IRQs and FIQs share this path: Hafnium reports the pending interrupt ID the same way for
+/// both, and the managed-exit interrupt may be signaled as either depending on the manifest.
+/// Returns false if nothing was pending or no handler is registered.
+pub fn handle_pending() -> bool {
+    let mut pending = [InterruptId(0); MAX_INTERRUPT_HANDLERS];
+    let mut count = 0;
+    while count < pending.len() {
+        let Some(interrupt_id) = hf_interrupt_get() else {
+            break;
+        };
+        if let Err(e) = hf_interrupt_deactivate(interrupt_id) {
+            panic!("Failed to deactivate interrupt {:?}: {:?}", interrupt_id, e);
+        }
+        pending[count] = interrupt_id;
+        count += 1;
+    }
+
+    if count == 0 {
+        // The interrupt may have been withdrawn or already serviced by the time we got here
+        debug!("Spurious interrupt, nothing pending");
+        return false;
+    }
+
+    REGISTRY.dispatch_all(&mut pending[..count])
+}
+
+/// Enable delivery of `id` as an IRQ or FIQ.
+///
+/// Fails if no handler is registered, so an interrupt can never be enabled without a route.
+pub fn enable(id: InterruptId, int_type: InterruptType) -> Result<(), RegistryError> {
+    if !REGISTRY.is_registered(id) {
+        return Err(RegistryError::NotRegistered(id));
+    }
+
+    debug!("Enabling interrupt {:?} as {:?}", id, int_type);
+    hf_interrupt_set(id, int_type, true).map_err(RegistryError::Hafnium)?;
+    REGISTRY.with_route(id, |route| route.int_type = Some(int_type))
+}
+
+/// Stop delivery of `id`, as the type it was enabled with.
+pub fn disable(id: InterruptId) -> Result<(), RegistryError> {
+    let int_type = REGISTRY
+        .with_route(id, |route| route.int_type)
+        .ok()
+        .flatten()
+        .unwrap_or(InterruptType::Irq);
+    debug!("Disabling interrupt {:?}", id);
+    hf_interrupt_set(id, int_type, false).map_err(RegistryError::Hafnium)
+}
+
+/// Route a physical interrupt to the given PE.
+///
+/// The priority Hafnium gives a physical interrupt is fixed by the `interrupts` property of the
+/// device region in the partition manifest, [`set_priority`] only orders the dispatch.
+pub fn set_target_pe(id: InterruptId, pe: u64) -> Result<(), RegistryError> {
+    hf_interrupt_reconfigure(id, InterruptReconfigureCommand::TargetPe, pe).map_err(RegistryError::Hafnium)
+}
+
+/// Configure whether a physical interrupt is secure (`true`) or non-secure.
+pub fn set_secure(id: InterruptId, secure: bool) -> Result<(), RegistryError> {
+    hf_interrupt_reconfigure(id, InterruptReconfigureCommand::SecState, secure as u64).map_err(RegistryError::Hafnium)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use hafnium::HfCall;
+    use hafnium::mock::{self, Call};
+
+    struct CountingHandler(AtomicU32);
+
+    impl HafInterruptHandler for CountingHandler {
+        fn handle(&self, haf_interrupt_id: InterruptId) {
+            self.0.fetch_add(haf_interrupt_id.0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_dispatch_routes_by_id() {
+        static HANDLER_A: CountingHandler = CountingHandler(AtomicU32::new(0));
+        static HANDLER_B: CountingHandler = CountingHandler(AtomicU32::new(0));
+        let registry = InterruptRegistry::<4>::new();
+
+        registry.register(InterruptId(3), &HANDLER_A).unwrap();
+        registry.register(InterruptId(40), &HANDLER_B).unwrap();
+
+        assert!(registry.dispatch(InterruptId(3)));
+        assert!(registry.dispatch(InterruptId(40)));
+        assert!(registry.dispatch(InterruptId(40)));
+        assert!(!registry.dispatch(InterruptId(5)));
+
+        assert_eq!(HANDLER_A.0.load(Ordering::Relaxed), 3);
+        assert_eq!(HANDLER_B.0.load(Ordering::Relaxed), 80);
+    }
+
+    #[test]
+    fn test_register_rejects_duplicates_and_overflow() {
+        static HANDLER: CountingHandler = CountingHandler(AtomicU32::new(0));
+        let registry = InterruptRegistry::<2>::new();
+
+        registry.register(InterruptId(1), &HANDLER).unwrap();
+        assert_eq!(
+            registry.register(InterruptId(1), &HANDLER),
+            Err(RegistryError::AlreadyRegistered(InterruptId(1)))
+        );
+        registry.register(InterruptId(2), &HANDLER).unwrap();
+        assert_eq!(registry.register(InterruptId(3), &HANDLER), Err(RegistryError::Full));
+
+        registry.unregister(InterruptId(1)).unwrap();
+        assert_eq!(
+            registry.unregister(InterruptId(1)),
+            Err(RegistryError::NotRegistered(InterruptId(1)))
+        );
+        registry.register(InterruptId(3), &HANDLER).unwrap();
+    }
+
+    #[test]
+    fn test_signal_records_interrupt() {
+        static SIGNAL: InterruptSignal = InterruptSignal::new();
+        let registry = InterruptRegistry::<1>::new();
+        registry.register(InterruptId(7), &SIGNAL).unwrap();
+
+        assert!(!SIGNAL.signaled());
+        registry.dispatch(InterruptId(7));
+        assert!(SIGNAL.signaled());
+        assert_eq!(embassy_futures::block_on(SIGNAL.wait()), InterruptId(7));
+        assert!(!SIGNAL.signaled());
+    }
+
+    #[test]
+    fn test_handle_pending_acknowledges_and_dispatches() {
+        static HANDLER: CountingHandler = CountingHandler(AtomicU32::new(0));
+        register(InterruptId(100), &HANDLER).unwrap();
+
+        mock::push_result(HfCall::InterruptGet, 100);
+        assert!(handle_pending());
+        assert_eq!(HANDLER.0.load(Ordering::Relaxed), 100);
+        assert_eq!(
+            mock::calls(),
+            [
+                Call {
+                    function: HfCall::InterruptGet,
+                    args: [0, 0, 0]
+                },
+                Call {
+                    function: HfCall::InterruptDeactivate,
+                    args: [100, 100, 0]
+                },
+                Call {
+                    function: HfCall::InterruptGet,
+                    args: [0, 0, 0]
+                },
+            ]
+        );
+
+        // Nothing pending: no deactivation, no dispatch
+        mock::reset();
+        assert!(!handle_pending());
+        assert_eq!(mock::calls().len(), 1);
+        assert_eq!(HANDLER.0.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to deactivate interrupt")]
+    fn test_handle_pending_panics_when_deactivate_fails() {
+        mock::push_result(HfCall::InterruptGet, 101);
+        mock::push_result(HfCall::InterruptDeactivate, -1);
+        handle_pending();
+    }
+
+    #[test]
+    fn test_enable_requires_route() {
+        static HANDLER: CountingHandler = CountingHandler(AtomicU32::new(0));
+
+        assert_eq!(
+            enable(InterruptId(102), InterruptType::Irq),
+            Err(RegistryError::NotRegistered(InterruptId(102)))
+        );
+        assert!(mock::calls().is_empty());
+
+        register(InterruptId(102), &HANDLER).unwrap();
+        enable(InterruptId(102), InterruptType::Fiq).unwrap();
+        assert_eq!(
+            mock::calls(),
+            [Call {
+                function: HfCall::InterruptEnable,
+                args: [102, 1, 1]
+            }]
+        );
+
+        mock::push_result(HfCall::InterruptEnable, -1);
+        assert_eq!(
+            unregister(InterruptId(102)),
+            Err(RegistryError::Hafnium(HfError::Rejected))
+        );
+        assert!(REGISTRY.is_registered(InterruptId(102)));
+
+        // Disabled as the FIQ it was enabled as
+        mock::reset();
+        unregister(InterruptId(102)).unwrap();
+        assert_eq!(
+            mock::calls(),
+            [Call {
+                function: HfCall::InterruptEnable,
+                args: [102, 0, 1]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unregister_requires_route() {
+        assert_eq!(
+            unregister(InterruptId(103)),
+            Err(RegistryError::NotRegistered(InterruptId(103)))
+        );
+        assert!(mock::calls().is_empty());
+    }
+
+    #[test]
+    fn test_pending_dispatched_by_priority() {
+        struct OrderHandler;
+
+        static ORDER: AtomicU32 = AtomicU32::new(0);
+
+        impl HafInterruptHandler for OrderHandler {
+            fn handle(&self, haf_interrupt_id: InterruptId) {
+                let order = ORDER.load(Ordering::Relaxed);
+                ORDER.store(order * 1000 + haf_interrupt_id.0, Ordering::Relaxed);
+            }
+        }
+
+        static HANDLER: OrderHandler = OrderHandler;
+        for id in [104, 105, 106] {
+            register(InterruptId(id), &HANDLER).unwrap();
+        }
+        set_priority(InterruptId(106), 0x10).unwrap();
+        set_priority(InterruptId(104), 0xf0).unwrap();
+        assert_eq!(
+            set_priority(InterruptId(107), 0),
+            Err(RegistryError::NotRegistered(InterruptId(107)))
+        );
+
+        for id in [104, 105, 106] {
+            mock::push_result(HfCall::InterruptGet, id);
+        }
+        assert!(handle_pending());
+        assert_eq!(ORDER.load(Ordering::Relaxed), 106_105_104);
+    }
+}
@@ -0,0 +1,81 @@
+//! Conversions between the AArch64 generic timer counter and embassy ticks.
+//!
+//! The system counter runs at `CNTFRQ_EL0` Hz while embassy works in units of
+//! `embassy_time::TICK_HZ`. These helpers are kept free of register accesses so they
+//! can be tested on the host.
+
+/// Convert a raw counter value into embassy ticks, rounding down.
+pub const fn counter_to_ticks(counter: u64, counter_hz: u64, tick_hz: u64) -> u64 {
+    if counter_hz == tick_hz {
+        return counter;
+    }
+
+    let ticks = (counter as u128 * tick_hz as u128) / counter_hz as u128;
+    saturate(ticks)
+}
+
+/// Convert embassy ticks into a counter value, rounding up.
+///
+/// Rounding up guarantees that an alarm programmed with the result never fires before
+/// the requested tick. Values that do not fit in the counter saturate to `u64::MAX`,
+/// which the timer will never reach.
+pub const fn ticks_to_counter(ticks: u64, counter_hz: u64, tick_hz: u64) -> u64 {
+    if counter_hz == tick_hz {
+        return ticks;
+    }
+
+    let counter = (ticks as u128 * counter_hz as u128).div_ceil(tick_hz as u128);
+    saturate(counter)
+}
+
+const fn saturate(value: u128) -> u64 {
+    if value > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        value as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QEMU_COUNTER_HZ: u64 = 62_500_000;
+    const TICK_HZ: u64 = 1_000_000;
+
+    #[test]
+    fn test_counter_to_ticks() {
+        assert_eq!(counter_to_ticks(0, QEMU_COUNTER_HZ, TICK_HZ), 0);
+        assert_eq!(counter_to_ticks(QEMU_COUNTER_HZ, QEMU_COUNTER_HZ, TICK_HZ), TICK_HZ);
+        // 62.5 counts per tick, partial ticks round down
+        assert_eq!(counter_to_ticks(124, QEMU_COUNTER_HZ, TICK_HZ), 1);
+        assert_eq!(counter_to_ticks(125, QEMU_COUNTER_HZ, TICK_HZ), 2);
+    }
+
+    #[test]
+    fn test_ticks_to_counter_rounds_up() {
+        assert_eq!(ticks_to_counter(1, QEMU_COUNTER_HZ, TICK_HZ), 63);
+        assert_eq!(ticks_to_counter(2, QEMU_COUNTER_HZ, TICK_HZ), 125);
+        assert_eq!(ticks_to_counter(TICK_HZ, QEMU_COUNTER_HZ, TICK_HZ), QEMU_COUNTER_HZ);
+    }
+
+    #[test]
+    fn test_alarm_never_fires_early() {
+        for ticks in 0..10_000 {
+            let counter = ticks_to_counter(ticks, QEMU_COUNTER_HZ, TICK_HZ);
+            assert!(counter_to_ticks(counter, QEMU_COUNTER_HZ, TICK_HZ) >= ticks);
+        }
+    }
+
+    #[test]
+    fn test_same_frequency_is_identity() {
+        assert_eq!(counter_to_ticks(u64::MAX, TICK_HZ, TICK_HZ), u64::MAX);
+        assert_eq!(ticks_to_counter(u64::MAX, TICK_HZ, TICK_HZ), u64::MAX);
+    }
+
+    #[test]
+    fn test_saturates() {
+        assert_eq!(ticks_to_counter(u64::MAX, QEMU_COUNTER_HZ, TICK_HZ), u64::MAX);
+        assert_eq!(counter_to_ticks(u64::MAX, TICK_HZ, QEMU_COUNTER_HZ), u64::MAX);
+    }
+}
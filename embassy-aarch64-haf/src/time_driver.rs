@@ -1,38 +1,37 @@
-use aarch64_cpu::registers::{CNTFRQ_EL0, CNTP_CTL_EL0, CNTP_CVAL_EL0, CNTP_TVAL_EL0, Readable, Writeable};
+use crate::registry::{self, HafInterruptHandler};
+use crate::tick::{counter_to_ticks, ticks_to_counter};
+use aarch64_cpu::registers::{CNTFRQ_EL0, CNTP_CTL_EL0, CNTP_CVAL_EL0, CNTPCT_EL0, Readable, Writeable};
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, Ordering};
 use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_time_driver::Driver;
+use embassy_time_driver::{Driver, TICK_HZ};
 use embassy_time_queue_utils::Queue;
-use hafnium::{InterruptId, InterruptType, hf_interrupt_set};
-use log::info;
+use hafnium::{InterruptId, InterruptType};
+use log::{debug, info};
+
+/// Interrupt the SPMC uses to signal the partition's EL1 physical timer.
+pub const TIMER_INTERRUPT_ID: InterruptId = InterruptId::VIRTUAL_TIMER;
 
 struct AArch64HafniumDriver {
+    /// Frequency of the system counter in Hz, 0 until read from `CNTFRQ_EL0`.
+    counter_hz: AtomicU64,
     queue: Mutex<CriticalSectionRawMutex, RefCell<Queue>>,
 }
 
 impl Driver for AArch64HafniumDriver {
     fn now(&self) -> u64 {
-        // Flush the instruction pipeline so that instructions are fetched from memory
-        unsafe {
-            core::arch::asm!("isb", options(nomem, nostack));
-        }
-        log::info!("now() - reading CNTP_TVAL_EL0");
-        let value = CNTP_TVAL_EL0.get();
-        log::info!("now() - CNTP_TVAL_EL0: {}", value);
-        value
+        counter_to_ticks(self.counter(), self.counter_hz(), TICK_HZ)
     }
 
     fn schedule_wake(&self, at: u64, waker: &core::task::Waker) {
-        log::info!("schedule_wake() - at: {}", at);
         critical_section::with(|cs| {
             let mut queue = self.queue.borrow(cs).borrow_mut();
 
             if queue.schedule_wake(at, waker) {
-                loop {
-                    if self.set_alarm(queue.next_expiration(self.now())) {
-                        break;
-                    }
+                let mut next = queue.next_expiration(self.now());
+                while !self.set_alarm(next) {
+                    next = queue.next_expiration(self.now());
                 }
             }
         })
@@ -41,55 +40,97 @@ impl Driver for AArch64HafniumDriver {
 
 // https://hafnium.readthedocs.io/en/latest/secure-partition-manager/secure-partition-manager.html#support-for-arch-timer-and-system-counter
 impl AArch64HafniumDriver {
-    fn set_alarm(&self, next: u64) -> bool {
-        let now = self.now();
-        log::info!("set_alarm() - next: {}, now: {}", next, now);
+    /// Frequency of the system counter, read from `CNTFRQ_EL0` on first use as the firmware sets
+    /// it before the partition runs.
+    fn counter_hz(&self) -> u64 {
+        match self.counter_hz.load(Ordering::Relaxed) {
+            0 => {
+                let frequency = CNTFRQ_EL0.get();
+                self.counter_hz.store(frequency, Ordering::Relaxed);
+                frequency
+            }
+            frequency => frequency,
+        }
+    }
 
-        // If the requested expiration time has already passed, return false
-        if next <= now {
+    fn counter(&self) -> u64 {
+        // Prevent the counter read from being speculated ahead of earlier instructions
+        // SAFETY: `isb` only synchronizes the instruction stream, it has no memory side effects.
+        unsafe {
+            core::arch::asm!("isb", options(nomem, nostack));
+        }
+        CNTPCT_EL0.get()
+    }
+
+    /// Program the comparator for tick `next`.
+    ///
+    /// Returns false if `next` has already passed, in which case the caller must process
+    /// the queue again rather than wait for an interrupt.
+    fn set_alarm(&self, next: u64) -> bool {
+        if next <= self.now() {
             return false;
         }
 
-        log::info!("set_alarm() - setting CNTP_CVAL_EL0 to {}", next);
-        CNTP_CVAL_EL0.set(next);
+        // u64::MAX means the queue is empty; saturating to the maximum comparator value
+        // also clears any pending timer condition from a previous alarm.
+        CNTP_CVAL_EL0.set(ticks_to_counter(next, self.counter_hz(), TICK_HZ));
 
         true
     }
 
-    fn on_interupt(&self) {
+    fn on_interrupt(&self) {
         critical_section::with(|cs| {
-            let mut next = self.queue.borrow(cs).borrow_mut().next_expiration(self.now());
+            let mut queue = self.queue.borrow(cs).borrow_mut();
+            let mut next = queue.next_expiration(self.now());
             while !self.set_alarm(next) {
-                next = self.queue.borrow(cs).borrow_mut().next_expiration(self.now());
+                next = queue.next_expiration(self.now());
             }
         });
     }
 }
 
 embassy_time_driver::time_driver_impl!(static DRIVER: AArch64HafniumDriver = AArch64HafniumDriver {
+    counter_hz: AtomicU64::new(0),
     queue: Mutex::new(RefCell::new(Queue::new())),
 });
 
-pub fn on_interupt() {
-    DRIVER.on_interupt();
+/// Service the timer interrupt: wake expired timers and program the next alarm.
+///
+/// This is invoked through the interrupt registry whenever [`TIMER_INTERRUPT_ID`] is pending.
+pub fn on_interrupt() {
+    DRIVER.on_interrupt();
 }
 
-pub unsafe fn init() {
-    info!("init() - reading CNTFRQ_EL0");
-    let frequency = CNTFRQ_EL0.get();
+struct TimerInterruptHandler;
 
+impl HafInterruptHandler for TimerInterruptHandler {
+    fn handle(&self, _haf_interrupt_id: InterruptId) {
+        on_interrupt();
+    }
+}
+
+static TIMER_INTERRUPT_HANDLER: TimerInterruptHandler = TimerInterruptHandler;
+
+/// Start the timer and enable its interrupt.
+///
+/// # Safety
+///
+/// Must be called once, before any timer is scheduled, and before interrupts are unmasked.
+pub unsafe fn init() {
     info!(
-        "init() - frequency: {}, embassy tick hz: {}",
-        frequency,
-        embassy_time::TICK_HZ
+        "time driver: counter frequency: {} Hz, embassy tick: {} Hz",
+        DRIVER.counter_hz(),
+        TICK_HZ
     );
 
-    info!("init() - setting CNTP_CTL_EL0");
+    // Park the comparator in the far future until the first alarm is scheduled
+    CNTP_CVAL_EL0.set(u64::MAX);
     CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
 
-    info!("init() - enabling virtual timer interrupt");
-    hf_interrupt_set(InterruptId(3), InterruptType::Irq, true)
-        .expect("init() - failed to enable virtual timer interrupt");
+    registry::register(TIMER_INTERRUPT_ID, &TIMER_INTERRUPT_HANDLER)
+        .expect("time driver: failed to route the timer interrupt");
+    registry::enable(TIMER_INTERRUPT_ID, InterruptType::Irq)
+        .expect("time driver: failed to enable the timer interrupt");
 
-    info!("init() - done");
+    debug!("time driver: initialized");
 }
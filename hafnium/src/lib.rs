@@ -1,10 +1,12 @@
 #![cfg_attr(target_os = "none", no_std)]
 
+#[cfg(not(all(target_os = "none", target_arch = "aarch64")))]
+pub mod mock;
+
 /// Hypervisor call function codes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u64)]
-#[allow(unused)]
-enum HfCall {
+pub enum HfCall {
     MailboxWritableGet = 0xff01,
     MailboxWaiterGet = 0xff02,
     InterruptEnable = 0xff03,
@@ -14,10 +16,52 @@ enum HfCall {
     InterruptReconfigure = 0xff09,
 }
 
+/// Errors returned by the Hafnium paravirtual interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HfError {
+    /// Hafnium rejected the call, e.g. an invalid interrupt ID or a request the caller may not make.
+    Rejected,
+    /// Hafnium returned a value that is not valid for the call.
+    Unexpected(i64),
+}
+
+impl HfError {
+    fn from_code(code: i64) -> Self {
+        match code {
+            -1 => HfError::Rejected,
+            code => HfError::Unexpected(code),
+        }
+    }
+}
+
+/// Map the 0 / -1 convention used by most Hafnium calls.
+fn status(result: i64) -> Result<(), HfError> {
+    match result {
+        0 => Ok(()),
+        _ => Err(HfError::from_code(result)),
+    }
+}
+
+/// Map a call returning a VM ID, or -1 if there is none.
+fn vm_id(result: i64) -> Result<Option<u16>, HfError> {
+    match result {
+        -1 => Ok(None),
+        0..=0xffff => Ok(Some(result as u16)),
+        _ => Err(HfError::Unexpected(result)),
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct InterruptId(pub u32);
 
+impl InterruptId {
+    /// Virtual interrupt raised by the SPMC when the EL1 physical timer of the partition fires.
+    pub const VIRTUAL_TIMER: InterruptId = InterruptId(3);
+    /// Virtual interrupt raised by the SPMC to request a managed exit.
+    pub const MANAGED_EXIT: InterruptId = InterruptId(4);
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(unused)]
 pub enum InterruptType {
@@ -74,19 +118,28 @@ fn hf_call(arg0: HfCall, arg1: u64, arg2: u64, arg3: u64) -> i64 {
 
 #[cfg(not(all(target_os = "none", target_arch = "aarch64")))]
 fn hf_call(arg0: HfCall, arg1: u64, arg2: u64, arg3: u64) -> i64 {
-    println!("hf_call: {:?} - {} - {} - {}", arg0, arg1, arg2, arg3);
-    0
+    mock::call(arg0, arg1, arg2, arg3)
 }
 
-pub fn hf_interrupt_set(intid: InterruptId, int_type: InterruptType, enable: bool) -> Result<(), i64> {
-    let result = hf_call(HfCall::InterruptEnable, intid.0 as u64, enable as u64, int_type as u64);
-    match result {
-        0 => {
-            log::debug!("hf_interrupt_set: {:?} - {} - {:?}", intid, enable, int_type);
-            Ok(())
-        }
-        _ => Err(result),
-    }
+/// Returns the ID of a VM whose mailbox has become writable and that asked to be notified, if any.
+pub fn hf_mailbox_writable_get() -> Result<Option<u16>, HfError> {
+    vm_id(hf_call(HfCall::MailboxWritableGet, 0, 0, 0))
+}
+
+/// Returns the ID of the first VM waiting for the mailbox of `vm_id` to become writable, if any.
+pub fn hf_mailbox_waiter_get(vm_id: u16) -> Result<Option<u16>, HfError> {
+    self::vm_id(hf_call(HfCall::MailboxWaiterGet, vm_id as u64, 0, 0))
+}
+
+pub fn hf_interrupt_set(intid: InterruptId, int_type: InterruptType, enable: bool) -> Result<(), HfError> {
+    status(hf_call(
+        HfCall::InterruptEnable,
+        intid.0 as u64,
+        enable as u64,
+        int_type as u64,
+    ))?;
+    log::debug!("hf_interrupt_set: {:?} - {} - {:?}", intid, enable, int_type);
+    Ok(())
 }
 
 const INVALID_ID: u32 = 0xffffffff;
@@ -99,25 +152,102 @@ pub fn hf_interrupt_get() -> Option<InterruptId> {
     Some(InterruptId(intid))
 }
 
-/// Deactivate the physical interrupt.
-pub fn hf_interrupt_deactivate(intid: InterruptId) -> Result<(), i64> {
-    let intid = intid.0 as u64;
-    let result = hf_call(HfCall::InterruptDeactivate, intid, intid, 0);
+/// Inject a virtual interrupt into the given vCPU of another VM.
+///
+/// Returns true if the target vCPU is not running and should be run to handle the interrupt.
+pub fn hf_interrupt_inject(target_vm_id: u16, target_vcpu_idx: u16, intid: InterruptId) -> Result<bool, HfError> {
+    let result = hf_call(
+        HfCall::InterruptInject,
+        target_vm_id as u64,
+        target_vcpu_idx as u64,
+        intid.0 as u64,
+    );
     match result {
-        0 => Ok(()),
-        _ => Err(result),
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(HfError::from_code(result)),
     }
 }
 
+/// Deactivate the physical interrupt.
+pub fn hf_interrupt_deactivate(intid: InterruptId) -> Result<(), HfError> {
+    let intid = intid.0 as u64;
+    status(hf_call(HfCall::InterruptDeactivate, intid, intid, 0))
+}
+
 pub fn hf_interrupt_reconfigure(
     intid: InterruptId,
     command: InterruptReconfigureCommand,
     value: u64,
-) -> Result<(), i64> {
-    let intid = intid.0 as u64;
-    let result = hf_call(HfCall::InterruptReconfigure, intid, command as u64, value);
-    match result {
-        0 => Ok(()),
-        _ => Err(result),
+) -> Result<(), HfError> {
+    status(hf_call(
+        HfCall::InterruptReconfigure,
+        intid.0 as u64,
+        command as u64,
+        value,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::Call;
+
+    #[test]
+    fn test_interrupt_set_encodes_arguments() {
+        hf_interrupt_set(InterruptId(27), InterruptType::Fiq, true).unwrap();
+        assert_eq!(
+            mock::calls(),
+            [Call {
+                function: HfCall::InterruptEnable,
+                args: [27, 1, 1]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_errors_are_typed() {
+        mock::push_result(HfCall::InterruptEnable, -1);
+        mock::push_result(HfCall::InterruptDeactivate, -3);
+        assert_eq!(
+            hf_interrupt_set(InterruptId(1), InterruptType::Irq, false),
+            Err(HfError::Rejected)
+        );
+        assert_eq!(hf_interrupt_deactivate(InterruptId(1)), Err(HfError::Unexpected(-3)));
+    }
+
+    #[test]
+    fn test_interrupt_get() {
+        assert_eq!(hf_interrupt_get(), None);
+        mock::push_result(HfCall::InterruptGet, 5);
+        assert_eq!(hf_interrupt_get(), Some(InterruptId(5)));
+        assert_eq!(hf_interrupt_get(), None);
+    }
+
+    #[test]
+    fn test_mailbox_queries() {
+        assert_eq!(hf_mailbox_writable_get(), Ok(None));
+        mock::push_result(HfCall::MailboxWritableGet, 0x8001);
+        assert_eq!(hf_mailbox_writable_get(), Ok(Some(0x8001)));
+
+        mock::push_result(HfCall::MailboxWaiterGet, 0x10000);
+        assert_eq!(hf_mailbox_waiter_get(2), Err(HfError::Unexpected(0x10000)));
+        assert_eq!(
+            mock::calls().last(),
+            Some(&Call {
+                function: HfCall::MailboxWaiterGet,
+                args: [2, 0, 0]
+            })
+        );
+    }
+
+    #[test]
+    fn test_interrupt_inject() {
+        assert_eq!(hf_interrupt_inject(1, 0, InterruptId(9)), Ok(false));
+        mock::push_result(HfCall::InterruptInject, 1);
+        assert_eq!(hf_interrupt_inject(1, 0, InterruptId(9)), Ok(true));
+        mock::push_result(HfCall::InterruptInject, -1);
+        assert_eq!(hf_interrupt_inject(1, 0, InterruptId(9)), Err(HfError::Rejected));
+        assert_eq!(mock::calls()[0].args, [1, 0, 9]);
     }
 }
@@ -0,0 +1,71 @@
+//! Host stand-in for the Hafnium hypervisor call interface.
+//!
+//! Every call is recorded, and results can be scripted per function so that code built on top
+//! of this crate can be exercised in unit tests. State is per thread, so each test starts clean.
+//! Calls without a scripted result behave like an idle Hafnium: nothing is pending and every
+//! request succeeds.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use crate::{HfCall, INVALID_ID};
+
+/// A hypervisor call made through the mock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    pub function: HfCall,
+    /// Arguments passed in x1 to x3.
+    pub args: [u64; 3],
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    results: Vec<(HfCall, VecDeque<i64>)>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+/// Queue `result` as the return value of the next call to `function`.
+pub fn push_result(function: HfCall, result: i64) {
+    STATE.with_borrow_mut(|state| match state.results.iter_mut().find(|(f, _)| *f == function) {
+        Some((_, queue)) => queue.push_back(result),
+        None => state.results.push((function, VecDeque::from([result]))),
+    });
+}
+
+/// The calls made so far on this thread, oldest first.
+pub fn calls() -> Vec<Call> {
+    STATE.with_borrow(|state| state.calls.clone())
+}
+
+/// Forget recorded calls and scripted results.
+pub fn reset() {
+    STATE.with_borrow_mut(|state| *state = State::default());
+}
+
+fn idle_result(function: HfCall) -> i64 {
+    match function {
+        HfCall::InterruptGet => INVALID_ID as i64,
+        HfCall::MailboxWritableGet | HfCall::MailboxWaiterGet => -1,
+        _ => 0,
+    }
+}
+
+pub(crate) fn call(function: HfCall, arg1: u64, arg2: u64, arg3: u64) -> i64 {
+    STATE.with_borrow_mut(|state| {
+        state.calls.push(Call {
+            function,
+            args: [arg1, arg2, arg3],
+        });
+        state
+            .results
+            .iter_mut()
+            .find(|(f, _)| *f == function)
+            .and_then(|(_, queue)| queue.pop_front())
+            .unwrap_or_else(|| idle_result(function))
+    })
+}
@@ -275,7 +275,7 @@ mod tests {
             String::from_utf8_lossy(bs).into_owned()
         }
 
-        for (i, (expected, actual)) in parts.into_iter().zip(get_smc_calls().into_iter()).enumerate() {
+        for (i, (expected, actual)) in parts.into_iter().zip(get_smc_calls()).enumerate() {
             assert_eq!(FunctionId::ConsoleLog, actual.id);
             let mut expected_bytes = [0u8; 8];
             let to_copy = expected.len().min(8);
@@ -1,4 +1,4 @@
-use crate::{exec_simple, Error, ExecResult, Function, SmcParams};
+use crate::{ffa_smc, handle_result_simple, Error, ExecResult, Function, SmcParams};
 
 use crate::{FunctionId, SmcCall};
 
@@ -12,21 +12,41 @@ pub struct MemRetrieveReq {
 
 impl MemRetrieveReq {
     pub fn new() -> Self {
+        Self::from_tx_buffer(0x40)
+    }
+
+    /// Retrieve with the `length` bytes of transaction descriptor at the start of the TX buffer.
+    pub fn from_tx_buffer(length: u32) -> Self {
         Self {
-            total_length: 0x40,
-            frag_length: 0x40,
+            total_length: length as u64,
+            frag_length: length as u64,
             tx_address: 0,
             page_count: 0,
         }
     }
 }
 
+/// Lengths of the transaction descriptor the SPMC wrote to the RX buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemRetrieveResp {
+    pub total_length: u32,
+    /// Bytes of the descriptor in the RX buffer, less than `total_length` if fragmented.
+    pub frag_length: u32,
+}
+
 impl Function for MemRetrieveReq {
-    type ReturnType = SmcCall;
+    type ReturnType = MemRetrieveResp;
     const ID: FunctionId = FunctionId::MemRetrieveReq;
 
     fn exec(self) -> ExecResult<Self::ReturnType> {
-        exec_simple(self, Ok)
+        let result: SmcCall = ffa_smc(self)?.try_into()?;
+        match result.id {
+            FunctionId::MemRetrieveResp => Ok(MemRetrieveResp {
+                total_length: result.params.x1 as u32,
+                frag_length: result.params.x2 as u32,
+            }),
+            _ => handle_result_simple(result, |result| Err(Error::UnexpectedFunctionId(result.id))),
+        }
     }
 }
 
@@ -75,4 +95,16 @@ mod tests {
 
         assert_eq!(original_req, new_req);
     }
+
+    #[test]
+    fn test_mem_retrieve_req_expects_resp() {
+        crate::smc::reset_smc_calls();
+        // The mock answers FFA_SUCCESS, which is not a retrieve response
+        assert_eq!(
+            MemRetrieveReq::from_tx_buffer(0x50).exec(),
+            Err(Error::UnexpectedFunctionId(FunctionId::Success32))
+        );
+        let calls = crate::smc::get_smc_calls();
+        assert_eq!((calls[0].params.x1, calls[0].params.x2), (0x50, 0x50));
+    }
 }
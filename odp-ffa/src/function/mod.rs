@@ -8,6 +8,7 @@ mod msg;
 mod notification_bind;
 mod notification_get;
 mod notification_set;
+mod rx_release;
 mod rxtx;
 mod version;
 mod yld;
@@ -21,6 +22,7 @@ pub use msg::*;
 pub use notification_bind::*;
 pub use notification_get::*;
 pub use notification_set::*;
+pub use rx_release::*;
 pub use rxtx::*;
 pub use version::*;
 pub use yld::*;
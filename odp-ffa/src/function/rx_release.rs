@@ -0,0 +1,56 @@
+use crate::{exec_simple, Error, ExecResult, Function, FunctionId, SmcParams};
+
+/// Hand the RX buffer back to the producer once its content has been consumed.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct RxRelease {
+    /// Endpoint owning the buffer, 0 for the caller itself.
+    pub endpoint_id: u16,
+}
+
+impl RxRelease {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Function for RxRelease {
+    const ID: FunctionId = FunctionId::RxRelease;
+    type ReturnType = ();
+
+    fn exec(self) -> ExecResult<Self::ReturnType> {
+        exec_simple(self, |_| Ok(()))
+    }
+}
+
+impl TryInto<SmcParams> for RxRelease {
+    type Error = Error;
+
+    fn try_into(self) -> Result<SmcParams, Self::Error> {
+        Ok(SmcParams {
+            x1: self.endpoint_id as u64,
+            ..Default::default()
+        })
+    }
+}
+
+impl TryFrom<SmcParams> for RxRelease {
+    type Error = Error;
+
+    fn try_from(value: SmcParams) -> Result<Self, Self::Error> {
+        Ok(RxRelease {
+            endpoint_id: value.x1 as u16,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rx_release_round_trip() {
+        let original = RxRelease { endpoint_id: 0x8002 };
+        let params: SmcParams = original.clone().try_into().unwrap();
+        assert_eq!(RxRelease::try_from(params).unwrap(), original);
+    }
+}
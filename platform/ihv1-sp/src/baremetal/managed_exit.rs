@@ -0,0 +1,15 @@
+use ec_service_lib::ManagedExitSource;
+use embassy_aarch64_haf::managed_exit::MANAGED_EXIT;
+
+/// Managed-exit requests delivered through the Hafnium virtual interrupt.
+pub struct HafManagedExit;
+
+impl ManagedExitSource for HafManagedExit {
+    async fn wait(&self) {
+        MANAGED_EXIT.wait().await;
+    }
+
+    fn clear(&self) {
+        MANAGED_EXIT.reset();
+    }
+}
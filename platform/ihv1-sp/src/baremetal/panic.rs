@@ -1,59 +0,0 @@
-//! A panic handler that infinitely waits.
-
-use core::panic::PanicInfo;
-
-use aarch64_cpu::asm;
-use log::error;
-
-/// Stop immediately if called a second time.
-///
-/// # Note
-///
-/// Using atomics here relieves us from needing to use `unsafe` for the static variable.
-///
-/// On `AArch64`, which is the only implemented architecture at the time of writing this,
-/// [`AtomicBool::load`] and [`AtomicBool::store`] are lowered to ordinary load and store
-/// instructions. They are therefore safe to use even with MMU + caching deactivated.
-///
-/// [`AtomicBool::load`]: core::sync::atomic::AtomicBool::load
-/// [`AtomicBool::store`]: core::sync::atomic::AtomicBool::store
-fn panic_prevent_reenter() {
-    use core::sync::atomic::{AtomicBool, Ordering};
-
-    static PANIC_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
-
-    if !PANIC_IN_PROGRESS.load(Ordering::Relaxed) {
-        PANIC_IN_PROGRESS.store(true, Ordering::Relaxed);
-
-        return;
-    }
-
-    loop {
-        asm::wfe()
-    }
-}
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    // Protect against panic infinite loops if any of the following code panics itself.
-    panic_prevent_reenter();
-
-    let (location, line, column) = match info.location() {
-        Some(loc) => (loc.file(), loc.line(), loc.column()),
-        _ => ("???", 0, 0),
-    };
-
-    error!(
-        "Kernel panic!\n\n\
-        Panic location:\n      File '{}', line {:?}, column {:?}\n\n\
-        {}",
-        location,
-        line,
-        column,
-        info.message(),
-    );
-
-    loop {
-        asm::wfe()
-    }
-}
@@ -17,11 +17,18 @@ fn main() {
 #[cfg(target_os = "none")]
 #[embassy_executor::main(executor = "embassy_aarch64_haf::Executor")]
 async fn embassy_main(_spawner: embassy_executor::Spawner) {
-    use ec_service_lib::service_list;
+    use ec_service_lib::{service_list, NsInterruptPolicy, ServiceNodeHandler};
+    use hafnium::InterruptType;
 
     log::info!("IHV1 Secure Partition - build time: {}", env!("BUILD_TIME"));
+
+    // Long running requests are checkpointed so non-secure interrupts are not held off. This
+    // relies on `ns-interrupts-action = <1>` without `managed-exit-virq` in linker/ihv1-ec-sp.dts
+    embassy_aarch64_haf::managed_exit::enable(InterruptType::Fiq).expect("Failed to enable managed exit");
+    let policy = NsInterruptPolicy::ManagedExit(baremetal::HafManagedExit);
+
     service_list![ec_service_lib::services::Thermal::new()]
-        .run_message_loop(async |_| Ok(()))
+        .run_message_loop_with_policy(&policy, async |_| Ok(()))
         .await
         .expect("Error in run_message_loop");
 }
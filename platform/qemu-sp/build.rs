@@ -2,12 +2,50 @@
 // This project is dual-licensed under Apache 2.0 and MIT terms.
 // See LICENSE-APACHE and LICENSE-MIT for details.
 
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Short hash of the checked out commit, "unknown" outside of a git checkout.
+fn git_revision() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|revision| revision.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Cargo features enabled for this build, comma separated.
+fn features() -> String {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|f| f.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    features.join(",")
+}
+
 fn main() {
-    if std::env::var("CARGO_CFG_TARGET_OS").unwrap() == "none" {
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() == "none" {
         println!("cargo:rustc-env=BUILD_TIME={}", chrono::Utc::now().to_rfc3339());
+        println!("cargo:rustc-env=GIT_REVISION={}", git_revision());
+        println!("cargo:rustc-env=FEATURES={}", features());
         println!("cargo:rustc-link-arg=-Timage.ld");
         println!("cargo:rustc-link-arg=-Tplatform/qemu-sp/linker/qemu.ld");
         println!("cargo:rerun-if-changed=platform/qemu-sp/linker/qemu.ld");
         println!("cargo:rerun-if-changed=linker/image.ld");
+
+        let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+        let git_dir = manifest_dir.join("..").join("..").join(".git");
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        println!(
+            "cargo:rerun-if-changed={}",
+            git_dir.join("refs").join("heads").display()
+        );
     }
 }
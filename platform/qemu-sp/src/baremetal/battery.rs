@@ -1,5 +1,6 @@
+use ec_service_lib::persist::{self, KeyValueStore, Persist};
 use ec_service_lib::{Result, Service};
-use log::{debug, error};
+use log::{debug, error, info};
 use odp_ffa::{MsgSendDirectReq2, MsgSendDirectResp2, Payload, RegisterPayload};
 use uuid::{uuid, Uuid};
 
@@ -20,6 +21,13 @@ const EC_BAT_GET_BMS: u8 = 0xd;
 const EC_BAT_GET_BMA: u8 = 0xe;
 const EC_BAT_GET_STA: u8 = 0xf;
 
+/// Capacity the battery was designed for in mWh, reported until a calibration cycle learns it.
+const DESIGN_CAPACITY: u32 = 6000;
+/// Capacity in mWh of the emulated cell, as a calibration cycle finds it.
+const CELL_CAPACITY: u32 = 5800;
+/// Flag of EC_BAT_GET_BMC starting a calibration cycle.
+const BMC_CALIBRATE: u32 = 1 << 0;
+
 #[derive(Default)]
 struct GenericRsp {
     status: i64,
@@ -31,6 +39,24 @@ impl From<GenericRsp> for RegisterPayload {
     }
 }
 
+struct BixRsp {
+    status: i64,
+    design_capacity: u32,
+    last_full_charge_capacity: u32,
+}
+
+impl From<BixRsp> for RegisterPayload {
+    fn from(value: BixRsp) -> Self {
+        let iter = value
+            .status
+            .to_le_bytes()
+            .into_iter()
+            .chain(value.design_capacity.to_le_bytes())
+            .chain(value.last_full_charge_capacity.to_le_bytes());
+        RegisterPayload::from_iter(iter)
+    }
+}
+
 #[derive(Default)]
 struct BstRsp {
     state: u32,
@@ -46,21 +72,60 @@ impl From<BstRsp> for RegisterPayload {
     }
 }
 
-#[derive(Default)]
-pub struct Battery {}
+pub struct Battery {
+    /// Full charge capacity in mWh learned by the last calibration cycle.
+    learned_capacity: u32,
+    store: Option<&'static dyn KeyValueStore>,
+}
+
+impl Default for Battery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Battery {
     pub fn new() -> Self {
-        Self::default()
+        Self {
+            learned_capacity: DESIGN_CAPACITY,
+            store: None,
+        }
+    }
+
+    /// Keep the learned capacity in `store`, restoring the one it holds.
+    pub fn with_store(mut self, store: &'static dyn KeyValueStore) -> Self {
+        persist::restore(store, &mut self);
+        self.store = Some(store);
+        self
+    }
+
+    fn get_bix(&self, _msg: &MsgSendDirectReq2) -> BixRsp {
+        BixRsp {
+            status: 0x0,
+            design_capacity: DESIGN_CAPACITY,
+            last_full_charge_capacity: self.learned_capacity,
+        }
     }
 
     fn get_bst(&self, _msg: &MsgSendDirectReq2) -> BstRsp {
         BstRsp {
-            state: 0x1,          // Battery discharging
-            present_rate: 500,   // Power being supplied to battery
-            remaining_cap: 5000, // Remaining capacity of battery
-            present_volt: 12000, // 12V or 12000mV
+            state: 0x1,                                     // Battery discharging
+            present_rate: 500,                              // Power being supplied to battery
+            remaining_cap: 5000.min(self.learned_capacity), // Remaining capacity of battery
+            present_volt: 12000,                            // 12V or 12000mV
+        }
+    }
+
+    /// Run the maintenance the OS asks for, the emulated gauge calibrates at once.
+    fn set_bmc(&mut self, msg: &MsgSendDirectReq2) -> GenericRsp {
+        if msg.u32_at(4) & BMC_CALIBRATE != 0 && self.learned_capacity != CELL_CAPACITY {
+            info!("Battery capacity learned: {} mWh", CELL_CAPACITY);
+            self.learned_capacity = CELL_CAPACITY;
+            if let Some(store) = self.store {
+                persist::save(store, self);
+            }
         }
+        GenericRsp { status: 0x0 }
     }
 
     fn generic_test(&self, _msg: &MsgSendDirectReq2) -> GenericRsp {
@@ -84,7 +149,7 @@ impl Service for Battery {
         debug!("Received Battery command 0x{:x}", cmd);
 
         let payload = match cmd {
-            EC_BAT_GET_BIX => RegisterPayload::from(self.generic_test(&msg)),
+            EC_BAT_GET_BIX => RegisterPayload::from(self.get_bix(&msg)),
             EC_BAT_GET_BST => RegisterPayload::from(self.get_bst(&msg)),
             EC_BAT_GET_PSR => RegisterPayload::from(self.generic_test(&msg)),
             EC_BAT_GET_PIF => RegisterPayload::from(self.generic_test(&msg)),
@@ -92,7 +157,7 @@ impl Service for Battery {
             EC_BAT_GET_BTP => RegisterPayload::from(self.generic_test(&msg)),
             EC_BAT_GET_BPT => RegisterPayload::from(self.generic_test(&msg)),
             EC_BAT_GET_BPC => RegisterPayload::from(self.generic_test(&msg)),
-            EC_BAT_GET_BMC => RegisterPayload::from(self.generic_test(&msg)),
+            EC_BAT_GET_BMC => RegisterPayload::from(self.set_bmc(&msg)),
             EC_BAT_GET_BMD => RegisterPayload::from(self.generic_test(&msg)),
             EC_BAT_GET_BCT => RegisterPayload::from(self.generic_test(&msg)),
             EC_BAT_GET_BTM => RegisterPayload::from(self.generic_test(&msg)),
@@ -108,3 +173,22 @@ impl Service for Battery {
         Ok(MsgSendDirectResp2::from_req_with_payload(&msg, payload))
     }
 }
+
+/// The learned capacity in mWh.
+impl Persist for Battery {
+    const KEY: u16 = 0x0002;
+    const VERSION: u8 = 1;
+
+    fn save(&self, buf: &mut [u8]) -> Option<usize> {
+        buf.get_mut(..4)?.copy_from_slice(&self.learned_capacity.to_le_bytes());
+        Some(4)
+    }
+
+    fn restore(&mut self, version: u8, record: &[u8]) -> bool {
+        let (Self::VERSION, Ok(bytes)) = (version, record.try_into()) else {
+            return false;
+        };
+        self.learned_capacity = u32::from_le_bytes(bytes);
+        true
+    }
+}
@@ -0,0 +1,62 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use ec_service_lib::attestation::{SecurityState, SoftwareKey};
+use ec_service_lib::fw_update::PUBLIC_KEY_SIZE;
+use ec_service_lib::identity::{BoardInfo, FirmwareIdentity};
+
+/// Identity of this image, reported by FwMgmt.
+pub static IDENTITY: FirmwareIdentity = ec_service_lib::firmware_identity!();
+
+/// The QEMU virt machine, a single board with a single SKU.
+pub struct QemuBoard;
+
+impl BoardInfo for QemuBoard {
+    fn board_id(&self) -> u64 {
+        // PCI vendor id of the QEMU virtual devices, board 1
+        0x1af4_0001
+    }
+
+    fn sku(&self) -> u32 {
+        0
+    }
+}
+
+pub static BOARD: QemuBoard = QemuBoard;
+
+/// QEMU has no fuses, it boots anything and leaves debug open. The anti-rollback counter is kept
+/// in RAM, from 0 at every start.
+pub struct QemuSecurity {
+    anti_rollback_counter: AtomicU32,
+}
+
+impl SecurityState for QemuSecurity {
+    fn secure_boot_enabled(&self) -> bool {
+        false
+    }
+
+    fn debug_locked(&self) -> bool {
+        false
+    }
+
+    fn anti_rollback_counter(&self) -> u32 {
+        self.anti_rollback_counter.load(Ordering::Relaxed)
+    }
+
+    fn raise_anti_rollback_counter(&self, counter: u32) {
+        self.anti_rollback_counter.fetch_max(counter, Ordering::Relaxed);
+    }
+}
+
+pub static SECURITY: QemuSecurity = QemuSecurity {
+    anti_rollback_counter: AtomicU32::new(0),
+};
+
+/// Development key signing the attestation reports, QEMU has no device key to keep it secret.
+pub static ATTESTATION_KEY: SoftwareKey = SoftwareKey::new(*b"qemu-ec-sp attestation test key!");
+
+/// Public key the firmware images are signed with, that of the first Ed25519 test vector of
+/// RFC 8032. Its private key is public too, QEMU only exercises the checks.
+pub const FIRMWARE_KEY: [u8; PUBLIC_KEY_SIZE] = [
+    0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a, 0x0e, 0xe1, 0x72,
+    0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+];
@@ -0,0 +1,55 @@
+use ec_service_lib::fan::{CurvePoint, Fan, FanController, FanCurve, SharedFan, TemperatureSensor};
+
+/// Speed of the emulated fan at full duty.
+const MAX_RPM: u32 = 5000;
+
+/// 40C, 60C and 80C.
+static POINTS: [CurvePoint; 3] = [
+    CurvePoint {
+        temperature_dk: 3131,
+        duty: 0,
+    },
+    CurvePoint {
+        temperature_dk: 3331,
+        duty: 40,
+    },
+    CurvePoint {
+        temperature_dk: 3531,
+        duty: 100,
+    },
+];
+
+/// The fan cooling the emulated CPU, run by `fan_task` and reported by the Thermal service.
+pub static FAN: SharedFan<QemuFan, QemuSensor> = SharedFan::new();
+
+/// QEMU has no fan, the tachometer follows the duty.
+pub struct QemuFan {
+    duty: u8,
+}
+
+impl Fan for QemuFan {
+    fn set_duty(&mut self, percent: u8) {
+        self.duty = percent;
+    }
+
+    fn rpm(&self) -> u32 {
+        MAX_RPM * self.duty as u32 / 100
+    }
+}
+
+/// QEMU has no thermal sensor, report a steady 55C.
+pub struct QemuSensor;
+
+impl TemperatureSensor for QemuSensor {
+    fn temperature_dk(&mut self) -> Option<u32> {
+        Some(3281)
+    }
+}
+
+pub fn init() {
+    FAN.set(FanController::new(
+        QemuFan { duty: 0 },
+        QemuSensor,
+        FanCurve::new(&POINTS, 30),
+    ));
+}
@@ -0,0 +1,28 @@
+use ec_service_lib::services::{BootKeyboard, HidAttributes, MatrixKeyboard, MatrixStep, SimulatedMatrix};
+
+/// QEMU has no keyboard matrix, simulate one row with the keys of "hi".
+static KEYMAP: [&[u8]; 1] = [&[0x0b, 0x0c]];
+
+/// Type "hi" over the first scans of the keyboard task.
+static SCRIPT: [MatrixStep; 4] = [
+    MatrixStep::RELEASED.press(0, 0),
+    MatrixStep::RELEASED,
+    MatrixStep::RELEASED.press(0, 1),
+    MatrixStep::RELEASED,
+];
+
+const ATTRIBUTES: HidAttributes = HidAttributes {
+    vendor_id: 0x1af4,
+    product_id: 0x0001,
+    version: 0x0100,
+};
+
+/// The matrix, scanned by the keyboard task.
+pub fn matrix() -> MatrixKeyboard<SimulatedMatrix> {
+    MatrixKeyboard::new(SimulatedMatrix::new(&KEYMAP, &SCRIPT), ATTRIBUTES)
+}
+
+/// The keyboard as the Hid service exposes it, with the reports the keyboard task queues.
+pub fn keyboard() -> BootKeyboard {
+    BootKeyboard::new(ATTRIBUTES)
+}
@@ -1,14 +1,63 @@
 mod battery;
-mod interrupt;
-mod panic;
+pub mod board;
+pub mod fan;
+pub mod keyboard;
+pub mod oem;
+mod power;
 
-use aarch64_rt::entry;
 pub use battery::Battery;
-use ec_service_lib::sp_logger::SpLogger;
+use ec_service_lib::address_space::PAGE_SIZE;
+use ec_service_lib::fw_update::{AbUpdate, Ed25519Verifier, RamFlash, Slot};
+use ec_service_lib::mem_share::RxTxRetriever;
+use ec_service_lib::persist::{PersistentStore, RamStorage, SavePolicy};
+use ec_service_lib::{HafEcError, HafEcService};
+use log::warn;
+pub use power::QemuPower;
+use sp_runtime::Platform;
 
-entry!(aarch64_rt_main);
-fn aarch64_rt_main(_arg0: u64, _arg1: u64, _arg2: u64, _arg3: u64) -> ! {
-    log::set_logger(&SpLogger).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
-    crate::main();
+struct Qemu;
+
+impl Platform for Qemu {}
+
+/// Global notification ids of the services notifying the OS, id 1 being FwMgmt's test notification.
+pub const POWER_NOTIFICATION_ID: u8 = 2;
+pub const TIME_ALARM_NOTIFICATION_ID: u8 = 3;
+pub const UCSI_NOTIFICATION_ID: u8 = 4;
+pub const HID_NOTIFICATION_ID: u8 = 5;
+
+/// Bytes of each firmware slot emulated in RAM, enough to exercise the update flow.
+const FW_SLOT_SIZE: usize = 4096;
+
+/// QEMU has no flash for the EC image, updates land in RAM once signed by [`board::FIRMWARE_KEY`].
+pub fn firmware_update() -> AbUpdate<RamFlash<FW_SLOT_SIZE>, Ed25519Verifier> {
+    AbUpdate::new(RamFlash::new(Slot::A), Ed25519Verifier::new(board::FIRMWARE_KEY))
+        .with_security_state(&board::SECURITY)
 }
+
+/// Bytes of RAM emulating the non-volatile memory of the service state, four snapshots of 1 KiB.
+const STATE_STORAGE_SIZE: usize = 4096;
+
+/// State of the services, written out on power events. QEMU has no non-volatile memory for it, so
+/// it only survives restarts of the services, not of the machine.
+pub static STATE: PersistentStore<RamStorage<STATE_STORAGE_SIZE>, 1000> = PersistentStore::new();
+
+pub fn init_state() {
+    STATE.init(RamStorage::new(1024), SavePolicy::Deferred);
+}
+
+/// RX/TX buffers of the partition, at the start of the `rxtx_buf` region of qemu.ld, inside the
+/// `ns_comm_buffer` region of the manifest.
+const TX_BUFFER: u64 = 0x100_600A_0000;
+const RX_BUFFER: u64 = TX_BUFFER + PAGE_SIZE;
+
+/// Retrieves the memory the OS shares with FwMgmt.
+pub static MEMORY_RETRIEVER: RxTxRetriever = RxTxRetriever::new(&sp_runtime::ADDRESS_SPACE, TX_BUFFER, RX_BUFFER);
+
+/// Register the RX/TX buffers with the SPMC, shared memory is retrieved through them.
+pub fn init_mailbox() {
+    if HafEcService::new().map_rxtx_buffers(TX_BUFFER, RX_BUFFER, 1) != HafEcError::Ok {
+        warn!("No RX/TX buffers, shared memory cannot be retrieved");
+    }
+}
+
+sp_runtime::entry!(Qemu, crate::main);
@@ -0,0 +1,27 @@
+use super::board;
+use ec_service_lib::services::{OemAccess, OemCommand, OEM_REQUEST_SIZE, OEM_RESPONSE_SIZE};
+use odp_ffa::ErrorCode;
+
+/// PCI vendor id of the QEMU virtual devices.
+const QEMU_VENDOR_ID: u16 = 0x1af4;
+
+const QEMU_OEM_GET_BUILD_TIME: u8 = 0x0;
+
+/// Return the build time of the partition image, the one of [`board::IDENTITY`].
+fn get_build_time(
+    _request: &[u8; OEM_REQUEST_SIZE],
+    response: &mut [u8; OEM_RESPONSE_SIZE],
+) -> Result<usize, ErrorCode> {
+    let build_time = board::IDENTITY.build_time.as_bytes();
+    let len = build_time.len().min(OEM_RESPONSE_SIZE);
+    response[..len].copy_from_slice(&build_time[..len]);
+    Ok(len)
+}
+
+pub const GET_BUILD_TIME: OemCommand = OemCommand {
+    vendor_id: QEMU_VENDOR_ID,
+    command: QEMU_OEM_GET_BUILD_TIME,
+    version: 1,
+    access: OemAccess::Any,
+    handler: get_build_time,
+};
@@ -0,0 +1,62 @@
+use ec_service_lib::services::{
+    ChargerControl, ChargerError, ChargerState, PowerPlatform, SourceCapabilities, SourceType,
+};
+
+/// Charge current the emulated charger accepts, in mA.
+const MAX_CHARGE_CURRENT_MA: u32 = 3000;
+
+/// QEMU has no power hardware, report a permanently connected 65W USB-PD adapter.
+pub struct QemuPower {
+    charger: ChargerState,
+}
+
+impl QemuPower {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for QemuPower {
+    fn default() -> Self {
+        Self {
+            charger: ChargerState {
+                control: ChargerControl {
+                    enabled: true,
+                    charge_current_ma: MAX_CHARGE_CURRENT_MA,
+                    input_current_ma: 3250,
+                },
+                charging: true,
+            },
+        }
+    }
+}
+
+impl PowerPlatform for QemuPower {
+    fn ac_online(&self) -> bool {
+        true
+    }
+
+    fn source_capabilities(&self) -> SourceCapabilities {
+        SourceCapabilities {
+            source: SourceType::UsbPd,
+            voltage_mv: 20000,
+            current_ma: 3250,
+            power_mw: 65000,
+        }
+    }
+
+    fn charger(&self) -> ChargerState {
+        self.charger
+    }
+
+    fn set_charger(&mut self, control: ChargerControl) -> Result<(), ChargerError> {
+        if control.charge_current_ma > MAX_CHARGE_CURRENT_MA {
+            return Err(ChargerError::InvalidLimit);
+        }
+        self.charger = ChargerState {
+            control,
+            charging: control.enabled,
+        };
+        Ok(())
+    }
+}
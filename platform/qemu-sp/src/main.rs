@@ -17,17 +17,107 @@ fn main() {
 #[cfg(target_os = "none")]
 #[embassy_executor::main(executor = "embassy_aarch64_haf::Executor")]
 async fn embassy_main(_spawner: embassy_executor::Spawner) {
-    use ec_service_lib::service_list;
+    use ec_service_lib::{service_list, ServiceNodeHandler};
 
-    log::info!("QEMU Secure Partition - build time: {}", env!("BUILD_TIME"));
+    log::info!("QEMU Secure Partition - {}", baremetal::board::IDENTITY);
+
+    baremetal::fan::init();
+    baremetal::init_state();
+    baremetal::init_mailbox();
+
+    #[cfg(feature = "time-driver")]
+    {
+        _spawner
+            .spawn(wake_alarm_task())
+            .expect("Failed to spawn the wake alarm task");
+        _spawner.spawn(fan_task()).expect("Failed to spawn the fan task");
+        _spawner.spawn(power_task()).expect("Failed to spawn the power task");
+        _spawner
+            .spawn(keyboard_task())
+            .expect("Failed to spawn the keyboard task");
+    }
 
     service_list![
-        ec_service_lib::services::Thermal::new(),
-        ec_service_lib::services::FwMgmt::new(),
-        ec_service_lib::services::Notify::new(),
-        baremetal::Battery::new()
+        ec_service_lib::services::Thermal::new()
+            .with_fan(&baremetal::fan::FAN)
+            .with_store(&baremetal::STATE),
+        ec_service_lib::services::FwMgmt::new()
+            .with_firmware_update(baremetal::firmware_update())
+            .with_identity(&baremetal::board::IDENTITY)
+            .with_board_info(&baremetal::board::BOARD)
+            .with_security_state(&baremetal::board::SECURITY)
+            .with_attestation(ec_service_lib::attestation::Attestation::new(
+                &sp_runtime::image_sections(),
+                &baremetal::board::ATTESTATION_KEY,
+            ))
+            .with_crash_dump(&sp_runtime::CRASH_DUMP)
+            .with_heap(&sp_runtime::HEAP)
+            .with_memory_mapper(&sp_runtime::ADDRESS_SPACE)
+            .with_memory_retriever(&baremetal::MEMORY_RETRIEVER),
+        ec_service_lib::services::Notify::new().with_store(&baremetal::STATE),
+        ec_service_lib::services::Debug::new().with_memory_mapper(&sp_runtime::ADDRESS_SPACE),
+        ec_service_lib::services::Power::new(baremetal::QemuPower::new())
+            .with_notification_id(baremetal::POWER_NOTIFICATION_ID)
+            .with_store(&baremetal::STATE),
+        ec_service_lib::services::TimeAlarm::new(
+            ec_service_lib::services::SoftRtc::new(sp_runtime::uptime_ms),
+            sp_runtime::uptime_ms
+        )
+        .with_notification_id(baremetal::TIME_ALARM_NOTIFICATION_ID),
+        ec_service_lib::services::Ucsi::new(ec_service_lib::services::SimulatedPdController::<2>::new())
+            .with_memory_mapper(&sp_runtime::ADDRESS_SPACE)
+            .with_notification_id(baremetal::UCSI_NOTIFICATION_ID),
+        ec_service_lib::services::Hid::new(baremetal::keyboard::keyboard())
+            .with_notification_id(baremetal::HID_NOTIFICATION_ID),
+        ec_service_lib::services::Oem::new().with_command(baremetal::oem::GET_BUILD_TIME),
+        baremetal::Battery::new().with_store(&baremetal::STATE)
     ]
     .run_message_loop(async |_| Ok(()))
     .await
     .expect("Error in run_message_loop");
 }
+
+/// Expire the Time and Alarm wake timers while the OS is not talking to the partition.
+#[cfg(all(target_os = "none", feature = "time-driver"))]
+#[embassy_executor::task]
+async fn wake_alarm_task() {
+    loop {
+        embassy_time::Timer::after_secs(1).await;
+        ec_service_lib::services::check_wake_alarms(sp_runtime::uptime_ms());
+    }
+}
+
+/// Run the fan control loop.
+#[cfg(all(target_os = "none", feature = "time-driver"))]
+#[embassy_executor::task]
+async fn fan_task() {
+    use ec_service_lib::fan::FanControl;
+
+    loop {
+        embassy_time::Timer::after_secs(1).await;
+        baremetal::fan::FAN.update(sp_runtime::uptime_ms());
+    }
+}
+
+/// Raise power events on changes of the power source, which QEMU cannot interrupt on.
+#[cfg(all(target_os = "none", feature = "time-driver"))]
+#[embassy_executor::task]
+async fn power_task() {
+    // The source does not depend on the charger state the Power service's instance keeps
+    let source = baremetal::QemuPower::new();
+    loop {
+        embassy_time::Timer::after_secs(1).await;
+        ec_service_lib::services::check_power_source(&source);
+    }
+}
+
+/// Scan the keyboard matrix, queueing its reports for the OS.
+#[cfg(all(target_os = "none", feature = "time-driver"))]
+#[embassy_executor::task]
+async fn keyboard_task() {
+    let mut matrix = baremetal::keyboard::matrix();
+    loop {
+        embassy_time::Timer::after_millis(100).await;
+        matrix.scan();
+    }
+}
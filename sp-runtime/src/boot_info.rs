@@ -0,0 +1,228 @@
+//! FF-A boot information blob passed to the partition at entry.
+//!
+//! When the manifest has a `boot-info` node, the SPMC passes the address of the blob in the
+//! register selected by `gp-register-num`. The blob starts with a header followed by an array
+//! of descriptors, one of which points at the partition's own FDT manifest.
+
+/// Errors detected while validating the boot information header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    /// The blob does not start with the FF-A boot information signature.
+    BadSignature(u32),
+    /// The header describes more data than the blob holds.
+    Truncated,
+    /// Descriptors are smaller than the layout defined by FF-A.
+    BadDescriptorSize(u32),
+}
+
+const BOOT_INFO_SIGNATURE: u32 = 0x0ffa;
+const HEADER_SIZE: usize = 32;
+const DESCRIPTOR_SIZE: usize = 32;
+
+const TYPE_IMPLEMENTATION_DEFINED: u8 = 1 << 7;
+const TYPE_ID_MASK: u8 = 0x7f;
+const TYPE_ID_FDT: u8 = 0;
+const TYPE_ID_HOB: u8 = 1;
+
+const FLAGS_CONTENT_FORMAT_SHIFT: u16 = 2;
+const FLAGS_CONTENT_FORMAT_MASK: u16 = 0b11;
+const CONTENT_FORMAT_ADDRESS: u16 = 0;
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Kind of information a descriptor refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoType {
+    /// The partition manifest as a flattened device tree.
+    Fdt,
+    /// A UEFI Hand-Off Block list.
+    Hob,
+    /// A standard type this parser does not know.
+    Other(u8),
+    /// An SPMC specific type.
+    ImplementationDefined(u8),
+}
+
+/// One entry of the boot information descriptor array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfoDescriptor {
+    pub name: [u8; 16],
+    pub kind: BootInfoType,
+    pub flags: u16,
+    pub size: u32,
+    pub contents: u64,
+}
+
+impl BootInfoDescriptor {
+    fn parse(bytes: &[u8]) -> Self {
+        let type_field = bytes[16];
+        let type_id = type_field & TYPE_ID_MASK;
+        let kind = match (type_field & TYPE_IMPLEMENTATION_DEFINED != 0, type_id) {
+            (true, id) => BootInfoType::ImplementationDefined(id),
+            (false, TYPE_ID_FDT) => BootInfoType::Fdt,
+            (false, TYPE_ID_HOB) => BootInfoType::Hob,
+            (false, id) => BootInfoType::Other(id),
+        };
+
+        Self {
+            name: bytes[0..16].try_into().unwrap(),
+            kind,
+            flags: u16::from_le_bytes([bytes[18], bytes[19]]),
+            size: le_u32(bytes, 20),
+            contents: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+        }
+    }
+
+    /// True if `contents` is the address of the information rather than its value.
+    pub fn contents_is_address(&self) -> bool {
+        (self.flags >> FLAGS_CONTENT_FORMAT_SHIFT) & FLAGS_CONTENT_FORMAT_MASK == CONTENT_FORMAT_ADDRESS
+    }
+}
+
+/// A validated boot information blob.
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo<'a> {
+    descriptors: &'a [u8],
+    descriptor_size: usize,
+}
+
+impl<'a> BootInfo<'a> {
+    pub fn new(blob: &'a [u8]) -> Result<Self, BootInfoError> {
+        if blob.len() < HEADER_SIZE {
+            return Err(BootInfoError::Truncated);
+        }
+
+        let signature = le_u32(blob, 0);
+        if signature != BOOT_INFO_SIGNATURE {
+            return Err(BootInfoError::BadSignature(signature));
+        }
+
+        let descriptor_size = le_u32(blob, 12);
+        if (descriptor_size as usize) < DESCRIPTOR_SIZE {
+            return Err(BootInfoError::BadDescriptorSize(descriptor_size));
+        }
+
+        let blob = blob
+            .get(..le_u32(blob, 8) as usize)
+            .filter(|blob| blob.len() >= HEADER_SIZE)
+            .ok_or(BootInfoError::Truncated)?;
+        let count = le_u32(blob, 16) as usize;
+        let offset = le_u32(blob, 20) as usize;
+        let descriptors = count
+            .checked_mul(descriptor_size as usize)
+            .and_then(|len| blob.get(offset..offset.checked_add(len)?))
+            .ok_or(BootInfoError::Truncated)?;
+
+        Ok(Self {
+            descriptors,
+            descriptor_size: descriptor_size as usize,
+        })
+    }
+
+    /// Validate the boot information blob at `address`.
+    ///
+    /// # Safety
+    ///
+    /// `address` must point to readable memory holding at least the boot information header,
+    /// and the whole blob it describes must stay readable and unmodified for `'a`.
+    pub unsafe fn from_address(address: u64) -> Result<Self, BootInfoError> {
+        let header = address as *const u8;
+        // SAFETY: The caller guarantees the header is readable.
+        let size = unsafe { le_u32(core::slice::from_raw_parts(header, HEADER_SIZE), 8) };
+        // SAFETY: The caller guarantees the blob described by the header is readable.
+        Self::new(unsafe { core::slice::from_raw_parts(header, (size as usize).max(HEADER_SIZE)) })
+    }
+
+    pub fn descriptors(&self) -> impl Iterator<Item = BootInfoDescriptor> + 'a {
+        self.descriptors
+            .chunks_exact(self.descriptor_size)
+            .map(BootInfoDescriptor::parse)
+    }
+
+    /// Address and size of the partition manifest, if the SPMC passed one.
+    pub fn fdt(&self) -> Option<(u64, u32)> {
+        self.descriptors()
+            .find(|descriptor| descriptor.kind == BootInfoType::Fdt && descriptor.contents_is_address())
+            .map(|descriptor| (descriptor.contents, descriptor.size))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Build a blob with one descriptor per `(type, flags, size, contents)` entry.
+    pub(crate) fn blob(descriptors: &[(u8, u16, u32, u64)]) -> Vec<u8> {
+        let size = HEADER_SIZE + descriptors.len() * DESCRIPTOR_SIZE;
+        let mut blob = Vec::new();
+        blob.extend(BOOT_INFO_SIGNATURE.to_le_bytes());
+        blob.extend(0x0001_0001u32.to_le_bytes());
+        blob.extend((size as u32).to_le_bytes());
+        blob.extend((DESCRIPTOR_SIZE as u32).to_le_bytes());
+        blob.extend((descriptors.len() as u32).to_le_bytes());
+        blob.extend((HEADER_SIZE as u32).to_le_bytes());
+        blob.extend([0; 8]);
+        for &(kind, flags, size, contents) in descriptors {
+            let mut name = [0u8; 16];
+            name[..12].copy_from_slice(b"ffa_manifest");
+            blob.extend(name);
+            blob.push(kind);
+            blob.push(0);
+            blob.extend(flags.to_le_bytes());
+            blob.extend(size.to_le_bytes());
+            blob.extend(contents.to_le_bytes());
+        }
+        blob
+    }
+
+    #[test]
+    fn test_finds_fdt_descriptor() {
+        let blob = blob(&[(TYPE_ID_HOB, 0, 0x100, 0x1000), (TYPE_ID_FDT, 0, 0x2000, 0x2040_0000)]);
+        let boot_info = BootInfo::new(&blob).unwrap();
+
+        let descriptors: Vec<_> = boot_info.descriptors().collect();
+        assert_eq!(descriptors.len(), 2);
+        assert_eq!(descriptors[0].kind, BootInfoType::Hob);
+        assert_eq!(&descriptors[1].name[..12], b"ffa_manifest");
+        assert_eq!(boot_info.fdt(), Some((0x2040_0000, 0x2000)));
+    }
+
+    #[test]
+    fn test_ignores_fdt_passed_by_value_and_custom_types() {
+        let by_value = 1 << FLAGS_CONTENT_FORMAT_SHIFT;
+        let blob = blob(&[(TYPE_ID_FDT, by_value, 8, 0), (TYPE_IMPLEMENTATION_DEFINED, 0, 8, 0)]);
+        let boot_info = BootInfo::new(&blob).unwrap();
+
+        assert_eq!(
+            boot_info.descriptors().nth(1).unwrap().kind,
+            BootInfoType::ImplementationDefined(0)
+        );
+        assert_eq!(boot_info.fdt(), None);
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        let valid = blob(&[(TYPE_ID_FDT, 0, 8, 0)]);
+
+        assert_eq!(BootInfo::new(&valid[..16]).unwrap_err(), BootInfoError::Truncated);
+        assert_eq!(
+            BootInfo::new(&valid[..valid.len() - 1]).unwrap_err(),
+            BootInfoError::Truncated
+        );
+
+        let mut bad = valid.clone();
+        bad[0] = 0;
+        assert_eq!(BootInfo::new(&bad).unwrap_err(), BootInfoError::BadSignature(0x0f00));
+
+        let mut bad = valid.clone();
+        bad[12] = 16;
+        assert_eq!(BootInfo::new(&bad).unwrap_err(), BootInfoError::BadDescriptorSize(16));
+
+        let mut bad = valid;
+        bad[16] = 2;
+        assert_eq!(BootInfo::new(&bad).unwrap_err(), BootInfoError::Truncated);
+    }
+}
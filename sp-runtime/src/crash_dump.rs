@@ -0,0 +1,42 @@
+//! Storage of the crash record and its capture from the panic handler.
+//!
+//! The region lives in the `.crash_dump` section, which the platform linker script must place
+//! in memory that is neither loaded nor cleared.
+
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+
+use ec_service_lib::crash_dump::{CrashDump, CrashRecord, CrashRegs};
+
+use crate::time::uptime_ms;
+
+/// Placed in a NOLOAD section so the record survives the partition being reloaded.
+#[link_section = ".crash_dump"]
+static mut CRASH_DUMP_REGION: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+// SAFETY: The region is reserved for the crash record and only accessed through this handle.
+pub static CRASH_DUMP: CrashDump = unsafe { CrashDump::new((&raw mut CRASH_DUMP_REGION).cast()) };
+
+/// Store a crash record describing the panic, the last exception taken and the recent log.
+pub fn record_panic(info: &PanicInfo) {
+    let (file, line, column) = match info.location() {
+        Some(loc) => (loc.file(), loc.line(), loc.column()),
+        _ => ("???", 0, 0),
+    };
+
+    let mut record = CrashRecord::new(uptime_ms(), file, line, column, format_args!("{}", info.message()));
+    if let Some(frame) = embassy_aarch64_haf::exception::last_exception() {
+        record.set_regs(CrashRegs {
+            elr: frame.elr,
+            spsr: frame.spsr,
+            esr: frame.esr.0,
+            far: frame.far,
+            sp: frame.sp,
+            fp: frame.fp(),
+            lr: frame.lr(),
+        });
+    }
+    record.capture_recent_log();
+
+    CRASH_DUMP.store(&record);
+}
@@ -0,0 +1,419 @@
+//! Minimal read-only flattened device tree parser.
+//!
+//! Only what is needed to read the partition manifest: walking nodes and reading properties.
+//! Malformed structure data ends iteration early instead of panicking.
+
+/// Errors detected while validating the FDT header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    /// The blob does not start with the FDT magic.
+    BadMagic(u32),
+    /// The header describes more data than the blob holds.
+    Truncated,
+    /// The blob uses a format version this parser cannot read.
+    UnsupportedVersion(u32),
+    /// The structure block does not start with the root node.
+    NoRoot,
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// A validated device tree blob.
+#[derive(Debug, Clone, Copy)]
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(Property<'a>),
+    Nop,
+    End,
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(blob: &'a [u8]) -> Result<Self, FdtError> {
+        let field = |index: usize| be_u32(blob, index * 4).ok_or(FdtError::Truncated);
+
+        let magic = field(0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        if blob.len() < FDT_HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+
+        let total_size = field(1)? as usize;
+        let last_comp_version = field(6)?;
+        if last_comp_version > 17 {
+            return Err(FdtError::UnsupportedVersion(last_comp_version));
+        }
+
+        let blob = blob.get(..total_size).ok_or(FdtError::Truncated)?;
+        let block = |offset: u32, size: u32| {
+            let start = offset as usize;
+            let end = start.checked_add(size as usize).ok_or(FdtError::Truncated)?;
+            blob.get(start..end).ok_or(FdtError::Truncated)
+        };
+        let fdt = Fdt {
+            structs: block(field(2)?, field(9)?)?,
+            strings: block(field(3)?, field(8)?)?,
+        };
+
+        match fdt.first_node() {
+            Some(_) => Ok(fdt),
+            None => Err(FdtError::NoRoot),
+        }
+    }
+
+    fn first_node(&self) -> Option<Node<'a>> {
+        let mut offset = 0;
+        loop {
+            let (token, next) = self.token(offset)?;
+            match token {
+                Token::Nop => offset = next,
+                Token::BeginNode(name) => {
+                    return Some(Node {
+                        fdt: *self,
+                        name,
+                        body: next,
+                    })
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// The root node.
+    pub fn root(&self) -> Node<'a> {
+        // Checked by `new`
+        self.first_node().unwrap()
+    }
+
+    /// Decode the token at `offset`, returning it and the offset of the next one.
+    fn token(&self, offset: usize) -> Option<(Token<'a>, usize)> {
+        let structs = self.structs;
+        match be_u32(structs, offset)? {
+            FDT_BEGIN_NODE => {
+                let start = offset + 4;
+                let len = structs.get(start..)?.iter().position(|&b| b == 0)?;
+                let name = core::str::from_utf8(&structs[start..start + len]).ok()?;
+                Some((Token::BeginNode(name), align4(start + len + 1)))
+            }
+            FDT_END_NODE => Some((Token::EndNode, offset + 4)),
+            FDT_PROP => {
+                let len = be_u32(structs, offset + 4)? as usize;
+                let name_offset = be_u32(structs, offset + 8)? as usize;
+                let start = offset + 12;
+                let value = structs.get(start..start.checked_add(len)?)?;
+                let name_len = self.strings.get(name_offset..)?.iter().position(|&b| b == 0)?;
+                let name = core::str::from_utf8(&self.strings[name_offset..name_offset + name_len]).ok()?;
+                Some((Token::Prop(Property { name, value }), align4(start + len)))
+            }
+            FDT_NOP => Some((Token::Nop, offset + 4)),
+            FDT_END => Some((Token::End, offset + 4)),
+            _ => None,
+        }
+    }
+}
+
+/// A property of a device tree node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    /// The value as a single cell.
+    pub fn as_u32(&self) -> Option<u32> {
+        match self.value.len() {
+            4 => be_u32(self.value, 0),
+            _ => None,
+        }
+    }
+
+    /// The value as a single cell or a pair of cells, most significant first.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => self.as_u32().map(u64::from),
+            8 => Some(((be_u32(self.value, 0)? as u64) << 32) | be_u32(self.value, 4)? as u64),
+            _ => None,
+        }
+    }
+
+    /// The value as a nul-terminated string.
+    pub fn as_str(&self) -> Option<&'a str> {
+        let (last, string) = self.value.split_last()?;
+        if *last != 0 {
+            return None;
+        }
+        core::str::from_utf8(string).ok()
+    }
+
+    /// The value as a list of cells.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        let (cells, _) = self.value.as_chunks::<4>();
+        cells.iter().map(|cell| u32::from_be_bytes(*cell))
+    }
+}
+
+/// A node of a device tree.
+#[derive(Debug, Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// Offset of the first token after the node's name.
+    body: usize,
+}
+
+impl<'a> Node<'a> {
+    /// The node name, including the unit address if any.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = Property<'a>> + 'a {
+        let fdt = self.fdt;
+        let mut offset = self.body;
+        core::iter::from_fn(move || loop {
+            let (token, next) = fdt.token(offset)?;
+            offset = next;
+            match token {
+                Token::Prop(property) => return Some(property),
+                Token::Nop => continue,
+                _ => return None,
+            }
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let fdt = self.fdt;
+        let mut offset = self.body;
+        core::iter::from_fn(move || {
+            let mut depth = 0usize;
+            let mut child = None;
+            loop {
+                let (token, next) = fdt.token(offset)?;
+                offset = next;
+                match token {
+                    Token::BeginNode(name) => {
+                        if depth == 0 {
+                            child = Some(Node { fdt, name, body: next });
+                        }
+                        depth += 1;
+                    }
+                    Token::EndNode if depth == 0 => {
+                        // End of this node, leave the offset here so iteration stays finished
+                        offset -= 4;
+                        return None;
+                    }
+                    Token::EndNode => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return child;
+                        }
+                    }
+                    Token::End => return None,
+                    Token::Prop(_) | Token::Nop => {}
+                }
+            }
+        })
+    }
+
+    /// The child called `name`, with or without its unit address.
+    pub fn child(&self, name: &str) -> Option<Node<'a>> {
+        self.children()
+            .find(|child| child.name == name || child.name.split('@').next() == Some(name))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Writes device tree blobs for tests.
+    #[derive(Default)]
+    pub(crate) struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn pad(&mut self) {
+            while !self.structs.len().is_multiple_of(4) {
+                self.structs.push(0);
+            }
+        }
+
+        pub(crate) fn begin_node(&mut self, name: &str) -> &mut Self {
+            self.structs.extend(FDT_BEGIN_NODE.to_be_bytes());
+            self.structs.extend(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        pub(crate) fn end_node(&mut self) -> &mut Self {
+            self.structs.extend(FDT_END_NODE.to_be_bytes());
+            self
+        }
+
+        pub(crate) fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend(name.as_bytes());
+            self.strings.push(0);
+            self.structs.extend(FDT_PROP.to_be_bytes());
+            self.structs.extend((value.len() as u32).to_be_bytes());
+            self.structs.extend(name_offset.to_be_bytes());
+            self.structs.extend(value);
+            self.pad();
+            self
+        }
+
+        pub(crate) fn prop_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        pub(crate) fn prop_str(&mut self, name: &str, value: &str) -> &mut Self {
+            let mut bytes = value.as_bytes().to_vec();
+            bytes.push(0);
+            self.prop(name, &bytes)
+        }
+
+        pub(crate) fn finish(&mut self) -> Vec<u8> {
+            self.structs.extend(FDT_END.to_be_bytes());
+            let structs_offset = FDT_HEADER_SIZE + 16;
+            let strings_offset = structs_offset + self.structs.len();
+            let total_size = strings_offset + self.strings.len();
+
+            let header = [
+                FDT_MAGIC,
+                total_size as u32,
+                structs_offset as u32,
+                strings_offset as u32,
+                FDT_HEADER_SIZE as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|field| field.to_be_bytes()).collect();
+            // Empty memory reservation map
+            blob.extend([0; 16]);
+            blob.extend(&self.structs);
+            blob.extend(&self.strings);
+            blob
+        }
+    }
+
+    fn sample() -> Vec<u8> {
+        Builder::default()
+            .begin_node("")
+            .prop_str("compatible", "arm,ffa-manifest-1.0")
+            .prop_cells("id", &[0x8002])
+            .prop("notification-support", &[])
+            .begin_node("memory-regions")
+            .begin_node("heap@20500000")
+            .prop_cells("base-address", &[0x0, 0x2050_0000])
+            .end_node()
+            .begin_node("ns_comm_buffer")
+            .begin_node("nested")
+            .end_node()
+            .end_node()
+            .end_node()
+            .begin_node("boot-info")
+            .end_node()
+            .end_node()
+            .finish()
+    }
+
+    #[test]
+    fn test_properties() {
+        let blob = sample();
+        let root = Fdt::new(&blob).unwrap().root();
+
+        assert_eq!(root.name(), "");
+        assert_eq!(root.properties().count(), 3);
+        assert_eq!(
+            root.property("compatible").unwrap().as_str(),
+            Some("arm,ffa-manifest-1.0")
+        );
+        assert_eq!(root.property("id").unwrap().as_u32(), Some(0x8002));
+        assert!(root.property("notification-support").unwrap().value.is_empty());
+        assert!(root.property("missing").is_none());
+    }
+
+    #[test]
+    fn test_children() {
+        let blob = sample();
+        let root = Fdt::new(&blob).unwrap().root();
+
+        let names: Vec<_> = root.children().map(|child| child.name()).collect();
+        assert_eq!(names, ["memory-regions", "boot-info"]);
+
+        let regions = root.child("memory-regions").unwrap();
+        let names: Vec<_> = regions.children().map(|child| child.name()).collect();
+        assert_eq!(names, ["heap@20500000", "ns_comm_buffer"]);
+
+        let heap = regions.child("heap").unwrap();
+        assert_eq!(heap.property("base-address").unwrap().as_u64(), Some(0x2050_0000));
+        assert_eq!(heap.children().count(), 0);
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        let blob = sample();
+        assert!(matches!(Fdt::new(&blob[..blob.len() - 1]), Err(FdtError::Truncated)));
+        assert!(matches!(Fdt::new(&[0u8; 8]), Err(FdtError::BadMagic(0))));
+
+        let mut bad_version = blob.clone();
+        bad_version[24..28].copy_from_slice(&18u32.to_be_bytes());
+        assert!(matches!(Fdt::new(&bad_version), Err(FdtError::UnsupportedVersion(18))));
+
+        let empty = Builder::default().finish();
+        assert!(matches!(Fdt::new(&empty), Err(FdtError::NoRoot)));
+    }
+
+    #[test]
+    fn test_malformed_structure_stops_iteration() {
+        let mut blob = Builder::default()
+            .begin_node("")
+            .prop_cells("a", &[1])
+            .prop_cells("b", &[2])
+            .end_node()
+            .finish();
+        // Corrupt the token of the second property
+        let second = FDT_HEADER_SIZE + 16 + 8 + 16;
+        blob[second..second + 4].copy_from_slice(&0xffu32.to_be_bytes());
+
+        let root = Fdt::new(&blob).unwrap().root();
+        let names: Vec<_> = root.properties().map(|property| property.name).collect();
+        assert_eq!(names, ["a"]);
+        assert_eq!(root.children().count(), 0);
+    }
+}
@@ -0,0 +1,22 @@
+//! The global allocator, backed by the `heap` memory region of the manifest.
+
+use ec_service_lib::heap::Heap;
+use log::{info, warn};
+
+use crate::Manifest;
+
+#[global_allocator]
+pub static HEAP: Heap = Heap::empty();
+
+/// Give the manifest's `heap` region to the allocator.
+pub fn init(manifest: &Manifest) {
+    let Some(region) = manifest.memory_region("heap") else {
+        warn!("The manifest has no heap region, allocations will fail");
+        return;
+    };
+
+    // SAFETY: The SPMC maps the manifest's memory regions for this partition only, and nothing
+    // else in the image refers to the heap region.
+    unsafe { HEAP.init(region.base_address as *mut u8, region.size() as usize) };
+    info!("Heap: {:#x} bytes at {:#x}", region.size(), region.base_address);
+}
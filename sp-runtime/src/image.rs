@@ -0,0 +1,28 @@
+//! The sections of the partition image, as laid out by the platform's image.ld.
+
+unsafe extern "C" {
+    static text_begin: u8;
+    static text_end: u8;
+    static rodata_begin: u8;
+    static rodata_end: u8;
+}
+
+/// # Safety
+///
+/// `begin` and `end` must bound a section of the image that is never written.
+unsafe fn section(begin: *const u8, end: *const u8) -> &'static [u8] {
+    // SAFETY: The caller guarantees the range is within the image and never written.
+    unsafe { core::slice::from_raw_parts(begin, end as usize - begin as usize) }
+}
+
+/// The code and read-only data of the image, to measure it.
+pub fn image_sections() -> [&'static [u8]; 2] {
+    // SAFETY: The linker script bounds the code and read-only data with these symbols, and
+    // neither is written once loaded.
+    unsafe {
+        [
+            section(&raw const text_begin, &raw const text_end),
+            section(&raw const rodata_begin, &raw const rodata_end),
+        ]
+    }
+}
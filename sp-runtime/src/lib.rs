@@ -0,0 +1,146 @@
+//! Runtime shared by the EC secure partitions.
+//!
+//! A board crate describes itself with a [`Platform`] implementation and hands it to [`entry!`],
+//! which provides the entry point, logger setup and panic handler. The exception vectors and the
+//! crash dump region are part of this crate and need no per-board code. The partition manifest
+//! passed by the SPMC through the FF-A boot information is available from [`manifest`]. With the
+//! `alloc` feature, the manifest's `heap` region backs the global allocator, and with the `paging`
+//! feature the runtime owns the stage-1 translation tables, see [`ADDRESS_SPACE`].
+//!
+//! ```ignore
+//! struct Qemu;
+//!
+//! impl sp_runtime::Platform for Qemu {
+//!     const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+//! }
+//!
+//! sp_runtime::entry!(Qemu, crate::main);
+//! ```
+
+#![cfg_attr(target_os = "none", no_std)]
+
+pub mod boot_info;
+#[cfg(target_os = "none")]
+mod crash_dump;
+pub mod fdt;
+#[cfg(all(target_os = "none", feature = "alloc"))]
+mod heap;
+#[cfg(target_os = "none")]
+mod image;
+pub mod manifest;
+#[cfg(all(target_os = "none", feature = "paging"))]
+mod paging;
+#[cfg(target_os = "none")]
+mod panic;
+#[cfg(target_os = "none")]
+mod time;
+#[cfg(target_os = "none")]
+mod vectors;
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use ec_service_lib::sp_logger::{self, SpLogger};
+use log::{info, warn, LevelFilter};
+pub use manifest::Manifest;
+
+#[cfg(target_os = "none")]
+pub use crash_dump::CRASH_DUMP;
+#[cfg(all(target_os = "none", feature = "alloc"))]
+pub use heap::HEAP;
+#[cfg(target_os = "none")]
+pub use image::image_sections;
+#[cfg(all(target_os = "none", feature = "paging"))]
+pub use paging::ADDRESS_SPACE;
+#[cfg(target_os = "none")]
+pub use panic::panic;
+#[cfg(target_os = "none")]
+pub use time::uptime_ms;
+
+/// Board specific configuration of the runtime.
+pub trait Platform {
+    /// Maximum level of the messages passed to the logger, unless the manifest has a `log-level`.
+    const LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+    /// Called once a panic has been logged and recorded, must not return.
+    ///
+    /// The default parks the CPU. A board with a watchdog or reset controller can restart instead.
+    fn halt() -> ! {
+        loop {
+            aarch64_cpu::asm::wfe();
+        }
+    }
+}
+
+static MANIFEST: Mutex<Cell<Option<Manifest<'static>>>> = Mutex::new(Cell::new(None));
+
+/// The partition manifest, if the SPMC passed one in the boot information.
+pub fn manifest() -> Option<Manifest<'static>> {
+    critical_section::with(|cs| MANIFEST.borrow(cs).get())
+}
+
+/// Bring up the runtime services needed before the board's main function runs.
+///
+/// `boot_info` is the address of the FF-A boot information blob, or 0 if the manifest does not
+/// request one.
+pub fn init<P: Platform>(boot_info: u64) {
+    #[cfg(target_os = "none")]
+    vectors::install();
+    log::set_logger(&SpLogger).unwrap();
+    sp_logger::set_default_level(P::LOG_LEVEL);
+    #[cfg(target_os = "none")]
+    sp_logger::set_clock(time::uptime_ms);
+
+    if boot_info == 0 {
+        warn!("No boot information, running without a manifest");
+        return;
+    }
+
+    // SAFETY: A non-zero address is the boot information the SPMC placed in the partition's
+    // memory, which is never reused.
+    match unsafe { Manifest::from_boot_info(boot_info) } {
+        Ok(manifest) => {
+            if let Some(level) = manifest.log_level() {
+                sp_logger::set_default_level(level);
+            }
+            info!("Partition ID: {:#x?}", manifest.id());
+            for uuid in manifest.uuids() {
+                info!("Partition UUID: {}", uuid);
+            }
+            #[cfg(all(target_os = "none", feature = "alloc"))]
+            heap::init(&manifest);
+            // The translation tables are allocated from the heap
+            #[cfg(all(target_os = "none", feature = "paging"))]
+            paging::init(boot_info, &manifest);
+            critical_section::with(|cs| MANIFEST.borrow(cs).set(Some(manifest)));
+        }
+        Err(e) => warn!("Failed to parse the boot information: {:?}", e),
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(target_os = "none")]
+    pub use aarch64_rt;
+}
+
+/// Define the entry point and panic handler of a partition.
+///
+/// `$platform` implements [`Platform`], `$main` is the function that runs the partition once the
+/// runtime is initialized, typically the one generated by `#[embassy_executor::main]`.
+#[macro_export]
+macro_rules! entry {
+    ($platform:ty, $main:path) => {
+        $crate::__private::aarch64_rt::entry!(__sp_runtime_entry);
+        fn __sp_runtime_entry(arg0: u64, _arg1: u64, _arg2: u64, _arg3: u64) -> ! {
+            // The manifests use gp-register-num = <0>, the boot information is passed in x0
+            $crate::init::<$platform>(arg0);
+            $main()
+        }
+
+        #[panic_handler]
+        fn __sp_runtime_panic(info: &core::panic::PanicInfo) -> ! {
+            $crate::panic::<$platform>(info)
+        }
+    };
+}
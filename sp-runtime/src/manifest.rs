@@ -0,0 +1,296 @@
+//! Typed access to the partition manifest.
+
+use ec_service_lib::address_space::{Access, Attributes, MemoryType};
+use log::LevelFilter;
+use uuid::Uuid;
+
+use crate::boot_info::{BootInfo, BootInfoError};
+use crate::fdt::{Fdt, FdtError, Node, Property};
+
+/// Size of a page as counted by `pages-count`, the manifests use a 4KiB translation granule.
+pub const PAGE_SIZE: u64 = 4096;
+
+// Bits of the `attributes` property of memory and device regions
+const ATTRIBUTE_WRITE: u32 = 1 << 1;
+const ATTRIBUTE_EXECUTE: u32 = 1 << 2;
+const ATTRIBUTE_NON_SECURE: u32 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestError {
+    BootInfo(BootInfoError),
+    /// The boot information has no FDT descriptor.
+    NoManifest,
+    Fdt(FdtError),
+}
+
+impl From<BootInfoError> for ManifestError {
+    fn from(e: BootInfoError) -> Self {
+        ManifestError::BootInfo(e)
+    }
+}
+
+impl From<FdtError> for ManifestError {
+    fn from(e: FdtError) -> Self {
+        ManifestError::Fdt(e)
+    }
+}
+
+/// A node of the `memory-regions` or `device-regions` section of the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion<'a> {
+    pub name: &'a str,
+    pub description: Option<&'a str>,
+    pub base_address: u64,
+    pub pages_count: u32,
+    pub attributes: u32,
+}
+
+impl MemoryRegion<'_> {
+    pub fn size(&self) -> u64 {
+        self.pages_count as u64 * PAGE_SIZE
+    }
+
+    /// Mapping attributes from the `attributes` property. A region that asks to be both
+    /// writable and executable is mapped writable only.
+    pub fn mapping_attributes(&self, memory: MemoryType) -> Attributes {
+        let access = if self.attributes & ATTRIBUTE_WRITE != 0 {
+            Access::ReadWrite
+        } else if self.attributes & ATTRIBUTE_EXECUTE != 0 && memory == MemoryType::Normal {
+            Access::ReadExecute
+        } else {
+            Access::ReadOnly
+        };
+
+        Attributes {
+            access,
+            memory,
+            non_secure: self.attributes & ATTRIBUTE_NON_SECURE != 0,
+        }
+    }
+}
+
+/// The partition manifest.
+#[derive(Debug, Clone, Copy)]
+pub struct Manifest<'a> {
+    root: Node<'a>,
+}
+
+impl<'a> Manifest<'a> {
+    pub fn new(fdt: Fdt<'a>) -> Self {
+        Self { root: fdt.root() }
+    }
+
+    /// Locate and validate the manifest from the boot information blob at `address`.
+    ///
+    /// # Safety
+    ///
+    /// `address` must point to a boot information blob passed by the SPMC, and both the blob and
+    /// the manifest it points to must stay readable and unmodified for `'a`.
+    pub unsafe fn from_boot_info(address: u64) -> Result<Self, ManifestError> {
+        // SAFETY: Guaranteed by the caller.
+        let boot_info = unsafe { BootInfo::from_address(address)? };
+        let (fdt_address, size) = boot_info.fdt().ok_or(ManifestError::NoManifest)?;
+        // SAFETY: The SPMC describes the manifest it loaded, which the caller guarantees is readable.
+        let blob = unsafe { core::slice::from_raw_parts(fdt_address as *const u8, size as usize) };
+        Ok(Self::new(Fdt::new(blob)?))
+    }
+
+    /// The root node, for properties not covered by the typed accessors.
+    pub fn root(&self) -> Node<'a> {
+        self.root
+    }
+
+    /// A custom property of the root node.
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.root.property(name)
+    }
+
+    /// The node at `path`, a `/` separated list of node names relative to the root.
+    pub fn node(&self, path: &str) -> Option<Node<'a>> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(self.root, |node, name| node.child(name))
+    }
+
+    /// The FF-A partition ID.
+    pub fn id(&self) -> Option<u16> {
+        self.property("id")?.as_u32()?.try_into().ok()
+    }
+
+    /// The UUIDs the partition answers to.
+    ///
+    /// Each UUID is four cells, holding the UUID bytes in little endian order.
+    pub fn uuids(&self) -> impl Iterator<Item = Uuid> + 'a {
+        let value = self.property("uuid").map(|property| property.value).unwrap_or_default();
+        let (uuids, _) = value.as_chunks::<16>();
+        uuids.iter().map(|uuid| {
+            let mut bytes = *uuid;
+            for cell in bytes.as_chunks_mut::<4>().0 {
+                *cell = u32::from_be_bytes(*cell).to_le_bytes();
+            }
+            Uuid::from_bytes(bytes)
+        })
+    }
+
+    pub fn memory_regions(&self) -> impl Iterator<Item = MemoryRegion<'a>> + 'a {
+        self.regions("memory-regions")
+    }
+
+    /// The nodes of the `device-regions` section, describing MMIO the partition may access.
+    pub fn device_regions(&self) -> impl Iterator<Item = MemoryRegion<'a>> + 'a {
+        self.regions("device-regions")
+    }
+
+    fn regions(&self, section: &str) -> impl Iterator<Item = MemoryRegion<'a>> + 'a {
+        self.root
+            .child(section)
+            .into_iter()
+            .flat_map(|regions| regions.children())
+            .filter_map(|node| {
+                Some(MemoryRegion {
+                    name: node.name(),
+                    description: node.property("description").and_then(|p| p.as_str()),
+                    base_address: node.property("base-address")?.as_u64()?,
+                    pages_count: node.property("pages-count")?.as_u32()?,
+                    attributes: node.property("attributes").and_then(|p| p.as_u32()).unwrap_or(0),
+                })
+            })
+    }
+
+    /// The memory region whose node name or description is `name`.
+    pub fn memory_region(&self, name: &str) -> Option<MemoryRegion<'a>> {
+        self.memory_regions()
+            .find(|region| region.name == name || region.description == Some(name))
+    }
+
+    /// Maximum log level from the custom `log-level` property, 0 (off) to 5 (trace).
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        let level = match self.property("log-level")?.as_u32()? {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        Some(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fdt::tests::Builder;
+    use std::vec::Vec;
+    use uuid::uuid;
+
+    fn qemu_manifest() -> Vec<u8> {
+        Builder::default()
+            .begin_node("")
+            .prop_str("compatible", "arm,ffa-manifest-1.0")
+            .prop_cells(
+                "uuid",
+                &[
+                    0x7ed874e4, 0x44403157, 0x3ecb27a7, 0xdfc8f38c, 0x73120c33, 0x5747e5fd, 0x655b1998, 0x02750339,
+                ],
+            )
+            .prop_cells("id", &[0x8002])
+            .prop_cells("log-level", &[2])
+            .prop_cells("oem-board-rev", &[7])
+            .begin_node("boot-info")
+            .prop("ffa_manifest", &[])
+            .end_node()
+            .begin_node("memory-regions")
+            .prop_str("compatible", "arm,ffa-manifest-memory-regions")
+            .begin_node("heap")
+            .prop_str("description", "heap")
+            .prop_cells("base-address", &[0x0, 0x2050_0000])
+            .prop_cells("pages-count", &[0x100])
+            .prop_cells("attributes", &[0x3])
+            .end_node()
+            .begin_node("ns_comm_buffer")
+            .prop_str("description", "ns-comm")
+            .prop_cells("base-address", &[0x100, 0x6000_0000])
+            .prop_cells("pages-count", &[0x800])
+            .prop_cells("attributes", &[0xb])
+            .end_node()
+            .end_node()
+            .begin_node("device-regions")
+            .prop_str("compatible", "arm,ffa-manifest-device-regions")
+            .begin_node("uart")
+            .prop_cells("base-address", &[0x0, 0x0904_0000])
+            .prop_cells("pages-count", &[0x1])
+            .prop_cells("attributes", &[0x3])
+            .end_node()
+            .end_node()
+            .end_node()
+            .finish()
+    }
+
+    #[test]
+    fn test_partition_properties() {
+        let blob = qemu_manifest();
+        let manifest = Manifest::new(Fdt::new(&blob).unwrap());
+
+        assert_eq!(manifest.id(), Some(0x8002));
+        let uuids: Vec<_> = manifest.uuids().collect();
+        assert_eq!(
+            uuids,
+            [
+                uuid!("e474d87e-5731-4044-a727-cb3e8cf3c8df"),
+                uuid!("330c1273-fde5-4757-9819-5b6539037502"),
+            ]
+        );
+        assert_eq!(manifest.log_level(), Some(LevelFilter::Warn));
+        assert_eq!(manifest.property("oem-board-rev").unwrap().as_u32(), Some(7));
+        assert!(manifest.node("boot-info").unwrap().property("ffa_manifest").is_some());
+        assert!(manifest.node("/memory-regions/heap").is_some());
+        assert!(manifest.node("memory-regions/missing").is_none());
+    }
+
+    #[test]
+    fn test_memory_regions() {
+        let blob = qemu_manifest();
+        let manifest = Manifest::new(Fdt::new(&blob).unwrap());
+
+        assert_eq!(manifest.memory_regions().count(), 2);
+        let heap = manifest.memory_region("heap").unwrap();
+        assert_eq!(heap.base_address, 0x2050_0000);
+        assert_eq!(heap.size(), 0x10_0000);
+        assert_eq!(heap.attributes, 0x3);
+        assert_eq!(heap.mapping_attributes(MemoryType::Normal), Attributes::DATA);
+        let code = MemoryRegion {
+            attributes: 0x5,
+            ..heap
+        };
+        assert_eq!(code.mapping_attributes(MemoryType::Normal), Attributes::CODE);
+        assert_eq!(code.mapping_attributes(MemoryType::Device).access, Access::ReadOnly);
+
+        let ns_comm = manifest.memory_region("ns-comm").unwrap();
+        assert_eq!(ns_comm.name, "ns_comm_buffer");
+        assert_eq!(ns_comm.base_address, 0x100_6000_0000);
+        assert_eq!(
+            ns_comm.mapping_attributes(MemoryType::Normal),
+            Attributes::DATA.non_secure()
+        );
+
+        let devices: Vec<_> = manifest.device_regions().collect();
+        assert_eq!(devices.len(), 1);
+        assert_eq!((devices[0].name, devices[0].base_address), ("uart", 0x0904_0000));
+    }
+
+    #[test]
+    fn test_from_boot_info() {
+        let fdt = qemu_manifest();
+        let boot_info = crate::boot_info::tests::blob(&[(0, 0, fdt.len() as u32, fdt.as_ptr() as u64)]);
+
+        // SAFETY: Both blobs outlive the manifest.
+        let manifest = unsafe { Manifest::from_boot_info(boot_info.as_ptr() as u64) }.unwrap();
+        assert_eq!(manifest.id(), Some(0x8002));
+
+        let no_fdt = crate::boot_info::tests::blob(&[(1, 0, 0, 0)]);
+        // SAFETY: The blob outlives the call.
+        let result = unsafe { Manifest::from_boot_info(no_fdt.as_ptr() as u64) };
+        assert_eq!(result.unwrap_err(), ManifestError::NoManifest);
+    }
+}
@@ -0,0 +1,176 @@
+//! Stage-1 translation tables of the partition.
+//!
+//! The partition is entered with the MMU off. Once the heap is available, [`init`] builds
+//! identity mapped tables covering the image, the manifest and the regions it declares, then
+//! turns the MMU and caches on. Services map memory retrieved from the normal world or device
+//! MMIO through [`ADDRESS_SPACE`].
+
+use aarch64_cpu::asm::barrier;
+use aarch64_cpu::registers::{ReadWriteable, Writeable, MAIR_EL1, SCTLR_EL1, TCR_EL1};
+use aarch64_paging::idmap::IdMap;
+use aarch64_paging::paging::{Attributes as PageAttributes, MapError, MemoryRegion, TranslationRegime};
+use ec_service_lib::address_space::{
+    Access, AddressSpace, AddressSpaceError, Attributes, MemoryType, SharedAddressSpace, TranslationTable, PAGE_SIZE,
+};
+use log::{error, info, warn};
+
+use crate::boot_info::BootInfo;
+use crate::Manifest;
+
+/// Regions tracked, including the image sections and the manifest regions.
+const MAX_REGIONS: usize = 32;
+const ASID: usize = 1;
+/// A 48-bit input address space with the 4KiB granule starts at level 0.
+const ROOT_LEVEL: usize = 0;
+
+// Memory types, as indices into MAIR_EL1
+const DEVICE: PageAttributes = PageAttributes::ATTRIBUTE_INDEX_0;
+const NORMAL: PageAttributes = PageAttributes::ATTRIBUTE_INDEX_1.union(PageAttributes::INNER_SHAREABLE);
+
+unsafe extern "C" {
+    // Section boundaries, defined by the platform's image.ld
+    static text_begin: u8;
+    static text_end: u8;
+    static rodata_begin: u8;
+    static rodata_end: u8;
+    static data_begin: u8;
+    static dma_region: u8;
+}
+
+/// Identity mapped translation tables.
+pub struct PageTable(IdMap);
+
+fn page_attributes(attributes: Attributes) -> PageAttributes {
+    let mut flags = PageAttributes::VALID | PageAttributes::ACCESSED;
+    flags |= match attributes.memory {
+        MemoryType::Normal => NORMAL,
+        MemoryType::Device => DEVICE,
+    };
+    flags |= match attributes.access {
+        Access::ReadOnly => PageAttributes::READ_ONLY | PageAttributes::UXN | PageAttributes::PXN,
+        Access::ReadWrite => PageAttributes::UXN | PageAttributes::PXN,
+        Access::ReadExecute => PageAttributes::READ_ONLY | PageAttributes::UXN,
+    };
+    if attributes.non_secure {
+        flags |= PageAttributes::NS;
+    }
+    flags
+}
+
+fn memory_region(base: u64, size: u64) -> MemoryRegion {
+    MemoryRegion::new(base as usize, (base + size) as usize)
+}
+
+fn invalidate_tlb() {
+    // SAFETY: Only drops cached translations, the tables themselves are unchanged.
+    unsafe { core::arch::asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb", options(nostack)) };
+}
+
+impl TranslationTable for PageTable {
+    type Error = MapError;
+
+    fn map(&mut self, base: u64, size: u64, attributes: Attributes) -> Result<(), MapError> {
+        self.0
+            .map_range(&memory_region(base, size), page_attributes(attributes))
+    }
+
+    fn unmap(&mut self, base: u64, size: u64) -> Result<(), MapError> {
+        // Mapping a range without the VALID flag unmaps it
+        self.0.map_range(&memory_region(base, size), PageAttributes::empty())?;
+        invalidate_tlb();
+        Ok(())
+    }
+}
+
+pub static ADDRESS_SPACE: SharedAddressSpace<PageTable, MAX_REGIONS> = SharedAddressSpace::new();
+
+fn page_range(begin: u64, end: u64) -> (u64, u64) {
+    let base = begin & !(PAGE_SIZE - 1);
+    (base, (end - base).next_multiple_of(PAGE_SIZE))
+}
+
+/// Map the image sections, which must all succeed for the partition to keep running.
+fn map_image(address_space: &mut AddressSpace<PageTable, MAX_REGIONS>) -> Result<(), AddressSpaceError> {
+    let sections = [
+        (&raw const text_begin, &raw const text_end, Attributes::CODE),
+        (&raw const rodata_begin, &raw const rodata_end, Attributes::RODATA),
+        // Data, bss, the boot stack and the crash dump region
+        (&raw const data_begin, &raw const dma_region, Attributes::DATA),
+    ];
+
+    for (begin, end, attributes) in sections {
+        let (base, size) = page_range(begin as u64, end as u64);
+        address_space.map_image(base, size, attributes)?;
+    }
+    Ok(())
+}
+
+fn enable_mmu(table: &mut PageTable) {
+    MAIR_EL1.write(
+        MAIR_EL1::Attr0_Device::nonGathering_nonReordering_EarlyWriteAck
+            + MAIR_EL1::Attr1_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
+            + MAIR_EL1::Attr1_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc,
+    );
+    TCR_EL1.write(
+        TCR_EL1::T0SZ.val(16)
+            + TCR_EL1::TG0::KiB_4
+            + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::SH0::Inner
+            + TCR_EL1::EPD1::DisableTTBR1Walks
+            + TCR_EL1::IPS::Bits_48,
+    );
+    barrier::isb(barrier::SY);
+
+    // SAFETY: The tables identity map everything the partition uses, including the code and
+    // stack running now, with the memory types configured in MAIR_EL1 above.
+    unsafe { table.0.activate() };
+    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
+    barrier::isb(barrier::SY);
+}
+
+/// Build the translation tables and turn the MMU on.
+///
+/// The MMU is left off if the image or the manifest cannot be mapped, the partition then keeps
+/// running without [`ADDRESS_SPACE`].
+pub fn init(boot_info: u64, manifest: &Manifest) {
+    let mut address_space = AddressSpace::new(PageTable(IdMap::new(ASID, ROOT_LEVEL, TranslationRegime::El1And0)));
+
+    if let Err(e) = map_image(&mut address_space) {
+        error!("Failed to map the image, MMU left off: {:?}", e);
+        return;
+    }
+
+    // The manifest is referenced for the lifetime of the partition
+    // SAFETY: `init` only gets here with boot information that was successfully parsed.
+    if let Some((fdt, size)) = unsafe { BootInfo::from_address(boot_info) }.ok().and_then(|b| b.fdt()) {
+        let (base, size) = page_range(fdt, fdt + size as u64);
+        if let Err(e) = address_space.map_image(base, size, Attributes::RODATA) {
+            error!("Failed to map the manifest, MMU left off: {:?}", e);
+            return;
+        }
+    }
+
+    for region in manifest.memory_regions() {
+        let attributes = region.mapping_attributes(MemoryType::Normal);
+        // The heap belongs to the allocator, every other region is for the services
+        let result = if Some(region) == manifest.memory_region("heap") {
+            address_space.map_image(region.base_address, region.size(), attributes)
+        } else {
+            address_space.map(region.base_address, region.size(), attributes)
+        };
+        if let Err(e) = result {
+            warn!("Failed to map memory region {}: {:?}", region.name, e);
+        }
+    }
+    for region in manifest.device_regions() {
+        let attributes = region.mapping_attributes(MemoryType::Device);
+        if let Err(e) = address_space.map(region.base_address, region.size(), attributes) {
+            warn!("Failed to map device region {}: {:?}", region.name, e);
+        }
+    }
+
+    enable_mmu(address_space.table_mut());
+    info!("MMU enabled, {} regions mapped", address_space.regions().len());
+    ADDRESS_SPACE.set(address_space);
+}
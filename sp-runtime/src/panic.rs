@@ -1,9 +1,11 @@
-//! A panic handler that infinitely waits.
+//! Panic handling: log the panic, record a crash dump and hand over to the platform.
 use core::panic::PanicInfo;
 
 use aarch64_cpu::asm;
 use log::error;
 
+use crate::Platform;
+
 /// Stop immediately if called a second time.
 ///
 /// # Note
@@ -32,8 +34,8 @@ fn panic_prevent_reenter() {
     }
 }
 
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+/// Body of the panic handler defined by [`entry!`](crate::entry).
+pub fn panic<P: Platform>(info: &PanicInfo) -> ! {
     // Protect against panic infinite loops if any of the following code panics itself.
     panic_prevent_reenter();
 
@@ -52,7 +54,7 @@ fn panic(info: &PanicInfo) -> ! {
         info.message(),
     );
 
-    loop {
-        asm::wfe()
-    }
+    crate::crash_dump::record_panic(info);
+
+    P::halt()
 }
@@ -0,0 +1,11 @@
+//! Time read from the arch timer.
+
+use aarch64_cpu::registers::{Readable, CNTFRQ_EL0, CNTPCT_EL0};
+
+/// Milliseconds since the arch timer started counting, usually since the system booted.
+pub fn uptime_ms() -> u64 {
+    match CNTFRQ_EL0.get() {
+        0 => 0,
+        frequency => (CNTPCT_EL0.get() as u128 * 1000 / frequency as u128) as u64,
+    }
+}
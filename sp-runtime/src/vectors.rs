@@ -0,0 +1,162 @@
+//! Exception vector table of the partition.
+//!
+//! Interrupts are routed through the embassy-aarch64-haf registry, every other exception is
+//! reported as fatal. The entries of the fatal vectors save all of x0 to x30 before anything
+//! else runs, so the report shows the registers of the faulting code rather than those of the
+//! handler. Interrupt entries only save the registers the handler may clobber.
+
+use aarch64_cpu::asm::barrier;
+use aarch64_cpu::registers::{Writeable, VBAR_EL1};
+use embassy_aarch64_haf::exception::{fatal, SavedRegisters, Vector};
+use embassy_aarch64_haf::registry;
+
+core::arch::global_asm!(
+    r#"
+.macro sp_runtime_fatal_entry vector:req, sp_el0:req
+	sub sp, sp, #(8 * 34)
+	stp x0, x1, [sp, #8 * 0]
+	stp x2, x3, [sp, #8 * 2]
+	stp x4, x5, [sp, #8 * 4]
+	stp x6, x7, [sp, #8 * 6]
+	stp x8, x9, [sp, #8 * 8]
+	stp x10, x11, [sp, #8 * 10]
+	stp x12, x13, [sp, #8 * 12]
+	stp x14, x15, [sp, #8 * 14]
+	stp x16, x17, [sp, #8 * 16]
+	stp x18, x19, [sp, #8 * 18]
+	stp x20, x21, [sp, #8 * 20]
+	stp x22, x23, [sp, #8 * 22]
+	stp x24, x25, [sp, #8 * 24]
+	stp x26, x27, [sp, #8 * 26]
+	stp x28, x29, [sp, #8 * 28]
+	str x30, [sp, #8 * 30]
+	.if \sp_el0
+	mrs x1, sp_el0
+	.else
+	add x1, sp, #(8 * 34)
+	.endif
+	mov x0, #\vector
+	b sp_runtime_fatal_common
+.endm
+
+.macro sp_runtime_interrupt_entry
+	stp x29, x30, [sp, #-(8 * 24)]!
+	stp x0, x1, [sp, #8 * 2]
+	stp x2, x3, [sp, #8 * 4]
+	stp x4, x5, [sp, #8 * 6]
+	stp x6, x7, [sp, #8 * 8]
+	stp x8, x9, [sp, #8 * 10]
+	stp x10, x11, [sp, #8 * 12]
+	stp x12, x13, [sp, #8 * 14]
+	stp x14, x15, [sp, #8 * 16]
+	stp x16, x17, [sp, #8 * 18]
+	str x18, [sp, #8 * 20]
+	b sp_runtime_interrupt_common
+.endm
+
+.section .text.sp_runtime_vectors, "ax"
+.balign 0x800
+.global sp_runtime_vector_table
+sp_runtime_vector_table:
+	/* Current EL with SP_EL0 */
+	sp_runtime_fatal_entry 0, 1
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_fatal_entry 1, 1
+
+	/* Current EL with SP_ELx */
+.balign 0x80
+	sp_runtime_fatal_entry 0, 0
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_fatal_entry 1, 0
+
+	/* Lower EL using AArch64 */
+.balign 0x80
+	sp_runtime_fatal_entry 2, 1
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_fatal_entry 3, 1
+
+	/* Lower EL using AArch32 */
+.balign 0x80
+	sp_runtime_fatal_entry 2, 1
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_interrupt_entry
+.balign 0x80
+	sp_runtime_fatal_entry 3, 1
+
+.balign 0x80
+sp_runtime_fatal_common:
+	mrs x2, elr_el1
+	mrs x3, spsr_el1
+	stp x1, x2, [sp, #8 * 31]
+	str x3, [sp, #8 * 33]
+	mov x1, sp
+	bl {fatal}
+
+sp_runtime_interrupt_common:
+	mrs x0, elr_el1
+	mrs x1, spsr_el1
+	stp x0, x1, [sp, #8 * 22]
+	bl {interrupt}
+	ldp x0, x1, [sp, #8 * 22]
+	msr elr_el1, x0
+	msr spsr_el1, x1
+	ldp x0, x1, [sp, #8 * 2]
+	ldp x2, x3, [sp, #8 * 4]
+	ldp x4, x5, [sp, #8 * 6]
+	ldp x6, x7, [sp, #8 * 8]
+	ldp x8, x9, [sp, #8 * 10]
+	ldp x10, x11, [sp, #8 * 12]
+	ldp x12, x13, [sp, #8 * 14]
+	ldp x14, x15, [sp, #8 * 16]
+	ldp x16, x17, [sp, #8 * 18]
+	ldr x18, [sp, #8 * 20]
+	ldp x29, x30, [sp], #(8 * 24)
+	eret
+
+.purgem sp_runtime_fatal_entry
+.purgem sp_runtime_interrupt_entry
+"#,
+    fatal = sym fatal_exception,
+    interrupt = sym interrupt,
+);
+
+unsafe extern "C" {
+    static sp_runtime_vector_table: u8;
+}
+
+/// Take exceptions through the vector table of this module.
+pub fn install() {
+    // Only the address of the table is taken
+    VBAR_EL1.set((&raw const sp_runtime_vector_table) as u64);
+    barrier::isb(barrier::SY);
+}
+
+/// Called by the fatal entries with the number of the vector taken and the saved registers.
+extern "C" fn fatal_exception(vector: u64, saved: &SavedRegisters) -> ! {
+    let vector = match vector {
+        0 => Vector::SyncCurrent,
+        1 => Vector::SErrorCurrent,
+        2 => Vector::SyncLower,
+        _ => Vector::SErrorLower,
+    };
+    fatal(vector, saved)
+}
+
+/// IRQs and FIQs share this path, see [`registry::handle_pending`].
+extern "C" fn interrupt() {
+    registry::handle_pending();
+}